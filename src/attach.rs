@@ -0,0 +1,274 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{element, elements, xml_unescape};
+use crate::ports::{KeeperPorts, PortError, ServerPorts};
+use crate::{
+    ClickwardMetadata, Deployment, DeploymentConfig, KeeperId, ServerId,
+    CLICKWARD_CONFIG_FILENAME, CLICKWARD_META_FILENAME,
+    DEFAULT_KEEPER_PRIORITY, DEPLOYMENT_DIR,
+};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeSet;
+use tracing::{info, warn};
+
+/// What we could read from the config of a keeper
+struct AttachedKeeper {
+    id: KeeperId,
+    ports: KeeperPorts,
+    observer: bool,
+    priority: u32,
+}
+
+/// What we could read from the config of a server
+struct AttachedServer {
+    id: ServerId,
+    ports: ServerPorts,
+    xml: String,
+}
+
+impl Deployment {
+    /// Adopt the deployment under `path` that has no metadata, i.e. one
+    /// generated by an older clickward or written by hand, so that it can be
+    /// managed again
+    ///
+    /// Nodes are found by their `keeper-<id>` and `clickhouse-<id>`
+    /// directories, and their ports, keeper roles, cluster secret and
+    /// zookeeper root are read from the configs in them. Without a saved
+    /// config, the deployment gets the default config, named after the
+    /// `<display_name>` of its servers or else `cluster_name`, with as many
+    /// shards as its cluster has. Configs are left as they are until they are
+    /// next generated.
+    pub fn attach<S: Into<String>>(
+        path: Utf8PathBuf,
+        cluster_name: S,
+    ) -> Result<Deployment> {
+        let dir = path.join(DEPLOYMENT_DIR);
+        if dir.join(CLICKWARD_META_FILENAME).exists() {
+            bail!("{dir} already has clickward metadata");
+        }
+        if !dir.is_dir() {
+            bail!("No deployment found at {dir}");
+        }
+        let saved = dir.join(CLICKWARD_CONFIG_FILENAME).exists();
+        let config = if saved {
+            DeploymentConfig::load(path)?
+        } else {
+            DeploymentConfig::new_with_default_ports(path, cluster_name)
+        };
+
+        let mut d = Deployment::new(config);
+
+        let mut keepers = vec![];
+        let mut servers = vec![];
+        for entry in dir.read_dir_utf8()? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name();
+            if let Some(id) = node_dir_id(name, "keeper-") {
+                let id = KeeperId(id);
+                match read_config(entry.path(), "keeper-config.xml")? {
+                    Some(xml) => keepers.push(d.attach_keeper(id, &xml)?),
+                    None => warn!(%name, "Skipping keeper without a config"),
+                }
+            } else if let Some(id) = node_dir_id(name, "clickhouse-") {
+                let id = ServerId(id);
+                match read_config(entry.path(), "clickhouse-config.xml")? {
+                    Some(xml) => servers.push(d.attach_server(id, xml)?),
+                    None => warn!(%name, "Skipping server without a config"),
+                }
+            }
+        }
+        if keepers.is_empty() && servers.is_empty() {
+            bail!("No keeper or clickhouse configs found under {dir}");
+        }
+
+        let first = servers.iter().min_by_key(|s| s.id);
+        if let Some(server) = first.filter(|_| !saved) {
+            let config = &mut d.config;
+            let suffix = format!("-{}", server.id);
+            if let Some(name) = element(&server.xml, "display_name")
+                .and_then(|name| name.strip_suffix(&suffix))
+            {
+                config.cluster_name = name.to_string();
+            }
+            let shards = element(&server.xml, "remote_servers")
+                .and_then(|xml| element(xml, &config.cluster_name))
+                .map(|xml| xml.matches("<shard>").count() as u64)
+                .unwrap_or(1);
+            config.num_shards = Some(shards.max(1));
+            config.prometheus = element(&server.xml, "prometheus").is_some();
+        }
+
+        let keeper_ids: BTreeSet<_> = keepers.iter().map(|k| k.id).collect();
+        let server_ids: BTreeSet<_> = servers.iter().map(|s| s.id).collect();
+        info!(
+            path = %dir,
+            keepers = keeper_ids.len(),
+            servers = server_ids.len(),
+            "Attaching to deployment"
+        );
+        let mut meta = ClickwardMetadata::new(keeper_ids, server_ids);
+        meta.cluster_secret = first.and_then(|server| {
+            let remote_servers = element(&server.xml, "remote_servers")?;
+            element(remote_servers, "secret").map(xml_unescape)
+        });
+        meta.zookeeper_root = first.and_then(|server| {
+            let ddl = element(&server.xml, "distributed_ddl")?;
+            let root = element(ddl, "path")?.strip_suffix("/task_queue/ddl")?;
+            Some(root.to_string())
+        });
+
+        // Only keep ports that differ from the ones our config gives the
+        // node, so that changing base ports later still moves the others
+        for keeper in keepers {
+            let id = keeper.id;
+            if keeper.observer {
+                meta.keeper_observers.insert(id);
+            }
+            if keeper.priority != DEFAULT_KEEPER_PRIORITY {
+                meta.keeper_priorities.insert(id, keeper.priority);
+            }
+            if Ok(keeper.ports) != d.expected_keeper_ports(id) {
+                meta.keeper_ports.insert(id, keeper.ports);
+            }
+        }
+        for server in servers {
+            if Ok(server.ports) != d.expected_server_ports(server.id) {
+                meta.server_ports.insert(server.id, server.ports);
+            }
+        }
+
+        if !saved {
+            d.config.save()?;
+        }
+        meta.save(&d.config.path)?;
+        d.meta = Some(meta);
+        d.record("attach", serde_json::json!({}))?;
+        Ok(d)
+    }
+
+    /// Return the ports that our config gives keeper `id`
+    fn expected_keeper_ports(
+        &self,
+        id: KeeperId,
+    ) -> Result<KeeperPorts, PortError> {
+        Ok(KeeperPorts {
+            keeper: self.keeper_port(id)?,
+            raft: self.raft_port(id)?,
+            metrics: self.keeper_metrics_port(id)?,
+            http_control: self.keeper_http_control_port(id)?,
+        })
+    }
+
+    /// Return the ports that our config gives server `id`
+    fn expected_server_ports(
+        &self,
+        id: ServerId,
+    ) -> Result<ServerPorts, PortError> {
+        Ok(ServerPorts {
+            tcp: self.tcp_port(id)?,
+            http: self.http_port(id)?,
+            interserver_http: self.interserver_http_port(id)?,
+            metrics: self.clickhouse_metrics_port(id)?,
+        })
+    }
+
+    /// Read what we need from the config of keeper `id`, falling back to
+    /// the ports our config gives it for optional ones
+    fn attach_keeper(&self, id: KeeperId, xml: &str) -> Result<AttachedKeeper> {
+        let Some(keeper_server) = element(xml, "keeper_server") else {
+            bail!("keeper {id} has no <keeper_server> in its config");
+        };
+        let Some(raft) =
+            elements(keeper_server, "server").into_iter().find(|server| {
+                element(server, "id") == Some(id.to_string().as_str())
+            })
+        else {
+            bail!("keeper {id} is not in its own <raft_configuration>");
+        };
+        let Some(keeper) = port(keeper_server, "tcp_port") else {
+            bail!("keeper {id} has no <tcp_port> in its config");
+        };
+        let Some(raft_port) = port(raft, "port") else {
+            bail!("keeper {id} has no raft <port> in its config");
+        };
+        Ok(AttachedKeeper {
+            id,
+            ports: KeeperPorts {
+                keeper,
+                raft: raft_port,
+                metrics: match element(xml, "prometheus")
+                    .and_then(|xml| port(xml, "port"))
+                {
+                    Some(port) => port,
+                    None => self.keeper_metrics_port(id)?,
+                },
+                http_control: match element(keeper_server, "http_control")
+                    .and_then(|xml| port(xml, "port"))
+                {
+                    Some(port) => port,
+                    None => self.keeper_http_control_port(id)?,
+                },
+            },
+            observer: element(raft, "can_become_leader") == Some("false"),
+            priority: element(raft, "priority")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(DEFAULT_KEEPER_PRIORITY),
+        })
+    }
+
+    /// Read what we need from the config of server `id`
+    fn attach_server(
+        &self,
+        id: ServerId,
+        xml: String,
+    ) -> Result<AttachedServer> {
+        let (Some(tcp), Some(http), Some(interserver_http)) = (
+            port(&xml, "tcp_port"),
+            port(&xml, "http_port"),
+            port(&xml, "interserver_http_port"),
+        ) else {
+            bail!(
+                "server {id} has no <tcp_port>, <http_port> or \
+                 <interserver_http_port> in its config"
+            );
+        };
+        let metrics = match element(&xml, "prometheus")
+            .and_then(|xml| port(xml, "port"))
+        {
+            Some(port) => port,
+            None => self.clickhouse_metrics_port(id)?,
+        };
+        Ok(AttachedServer {
+            id,
+            ports: ServerPorts { tcp, http, interserver_http, metrics },
+            xml,
+        })
+    }
+}
+
+/// Return the id of a node directory named `<prefix><id>`
+fn node_dir_id(name: &str, prefix: &str) -> Option<u64> {
+    name.strip_prefix(prefix)?.parse().ok().filter(|&id| id > 0)
+}
+
+/// Read the config file `name` in `dir`, if there is one
+fn read_config(dir: &Utf8Path, name: &str) -> Result<Option<String>> {
+    let path = dir.join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let xml = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {path}"))?;
+    Ok(Some(xml))
+}
+
+/// Return the port in the first `<tag>` of `xml`
+fn port(xml: &str, tag: &str) -> Option<u16> {
+    element(xml, tag)?.parse().ok()
+}
@@ -0,0 +1,315 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::process::output_files;
+use crate::remote::{quote, remote_path, REMOTE_HOST_FILENAME};
+use crate::{
+    platform, Deployment, KeeperId, NodeId, ServerId, SpawnError,
+    DEFAULT_CLICKHOUSE_BINARY,
+};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::process::{Command, Stdio};
+use tracing::info;
+
+/// The official clickhouse image, which contains both the server and keeper
+pub const DEFAULT_DOCKER_IMAGE: &str = "clickhouse/clickhouse-server";
+
+/// The file in a node's directory that docker writes the container id to
+pub const CONTAINER_ID_FILENAME: &str = "container.cid";
+
+/// The host that nodes of local deployments listen on
+pub const LOCALHOST: &str = "::1";
+
+/// How the nodes of a deployment are run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Backend {
+    /// Run each node as a local process
+    #[default]
+    Process,
+    /// Run each node in its own docker container using the host network
+    ///
+    /// Node directories are mounted at the same paths inside the
+    /// containers, so generated configs work unchanged.
+    Docker { image: String },
+    /// Run each node on a remote host over ssh
+    ///
+    /// Configs are generated locally with real hostnames and copied to the
+    /// same absolute paths on each host, where the clickhouse binary must be
+    /// installed at the same path. Logs and data stay on the remote hosts.
+    Ssh {
+        /// Hosts that nodes are assigned to round robin by id
+        hosts: Vec<String>,
+        /// Hosts of individual nodes keyed by node name, i.e. `keeper-1`,
+        /// overriding round robin assignment
+        #[serde(default)]
+        node_hosts: BTreeMap<String, String>,
+    },
+}
+
+/// The hosts of an individual node, overriding
+/// `DeploymentConfig::bind_host` and `DeploymentConfig::advertise_host`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeAddresses {
+    /// The address that the node listens on
+    #[serde(default)]
+    pub bind_host: Option<String>,
+    /// The host that other nodes and clients reach the node at
+    #[serde(default)]
+    pub advertise_host: Option<String>,
+}
+
+impl Backend {
+    /// Return true if nodes run on this machine
+    pub fn is_local(&self) -> bool {
+        !matches!(self, Backend::Ssh { .. })
+    }
+}
+
+impl Deployment {
+    /// Return the host that other nodes and clients reach a keeper at
+    pub fn keeper_host(&self, id: KeeperId) -> String {
+        self.advertise_host(NodeId::Keeper(id))
+    }
+
+    /// Return the host that other nodes and clients reach a server at
+    pub fn server_host(&self, id: ServerId) -> String {
+        self.advertise_host(NodeId::Server(id))
+    }
+
+    /// Return the host that a node is reached at
+    ///
+    /// Unless it's overridden for the node, this is the host that it runs on
+    /// with ssh, and otherwise the `advertise_host` of the deployment.
+    fn advertise_host(&self, node: NodeId) -> String {
+        let name = node.to_string();
+        let id = match node {
+            NodeId::Keeper(id) => id.0,
+            NodeId::Server(id) => id.0,
+        };
+        self.config
+            .node_addresses
+            .get(&name)
+            .and_then(|addresses| addresses.advertise_host.clone())
+            .or_else(|| self.remote_host(&name, id))
+            .or_else(|| self.config.advertise_host.clone())
+            .unwrap_or_else(|| LOCALHOST.to_string())
+    }
+
+    /// Return the address that a node listens on
+    ///
+    /// Unless configured otherwise, local nodes only listen on `LOCALHOST`,
+    /// while remote nodes must be reachable from other hosts and from us.
+    pub(crate) fn bind_host(&self, node: NodeId) -> String {
+        self.config
+            .node_addresses
+            .get(&node.to_string())
+            .and_then(|addresses| addresses.bind_host.clone())
+            .or_else(|| self.config.bind_host.clone())
+            .unwrap_or_else(|| {
+                if self.config.backend.is_local() {
+                    LOCALHOST.to_string()
+                } else {
+                    "::".to_string()
+                }
+            })
+    }
+
+    /// Return the directory containing all files for a node
+    pub fn node_dir(&self, node: NodeId) -> Utf8PathBuf {
+        match node {
+            NodeId::Keeper(id) => self.keeper_dir(id),
+            NodeId::Server(id) => self.server_dir(id),
+        }
+    }
+
+    /// Return true if the node was started with our backend and not stopped
+    /// since, judging by the files the backend leaves in its directory
+    pub(crate) fn is_started_with_backend(&self, node: NodeId) -> bool {
+        let dir = self.node_dir(node);
+        let file = match (&self.config.backend, node) {
+            (Backend::Process, NodeId::Keeper(_)) => "keeper.pid",
+            (Backend::Process, NodeId::Server(_)) => "clickhouse.pid",
+            (Backend::Docker { .. }, _) => CONTAINER_ID_FILENAME,
+            (Backend::Ssh { .. }, _) => REMOTE_HOST_FILENAME,
+        };
+        dir.join(file).exists()
+    }
+
+    /// Return true if the node is running according to our backend
+    pub(crate) fn is_alive_with_backend(&self, node: NodeId) -> bool {
+        let dir = self.node_dir(node);
+        let (pidfile, config) = match node {
+            NodeId::Keeper(_) => ("keeper.pid", "keeper-config.xml"),
+            NodeId::Server(_) => ("clickhouse.pid", "clickhouse-config.xml"),
+        };
+        let (mut cmd, expected_stdout) = match &self.config.backend {
+            Backend::Process => {
+                let config = format!("{node}/{config}");
+                return platform::read_pidfile(&dir.join(pidfile))
+                    .is_ok_and(|pid| platform::is_running_as(pid, &config));
+            }
+            Backend::Docker { .. } => {
+                let path = dir.join(CONTAINER_ID_FILENAME);
+                let Ok(cid) = std::fs::read_to_string(path) else {
+                    return false;
+                };
+                let mut cmd = Command::new("docker");
+                cmd.arg("inspect")
+                    .arg("--format={{.State.Running}}")
+                    .arg(cid.trim_end());
+                (cmd, Some("true"))
+            }
+            Backend::Ssh { .. } => {
+                let path = dir.join(REMOTE_HOST_FILENAME);
+                let Ok(host) = std::fs::read_to_string(path) else {
+                    return false;
+                };
+                let Ok(pidfile) = remote_path(&dir.join(pidfile)) else {
+                    return false;
+                };
+                let mut cmd = Command::new("ssh");
+                cmd.arg("-o")
+                    .arg("BatchMode=yes")
+                    .arg(host.trim_end())
+                    .arg(format!("kill -0 $(cat {})", quote(pidfile)));
+                (cmd, None)
+            }
+        };
+        let Ok(output) = self.output(cmd.stdin(Stdio::null())) else {
+            return false;
+        };
+        output.status.success()
+            && expected_stdout.is_none_or(|m| {
+                String::from_utf8_lossy(&output.stdout).trim() == m
+            })
+    }
+
+    pub(crate) fn start_keeper_container(
+        &self,
+        id: KeeperId,
+        image: &str,
+    ) -> Result<()> {
+        let dir = self.keeper_dir(id);
+        info!(%dir, "Deploying keeper container");
+        let config = dir.join("keeper-config.xml");
+        let binary = Utf8Path::new(DEFAULT_CLICKHOUSE_BINARY);
+        self.start_container(&format!("keeper-{id}"), &dir, image, binary)?
+            .arg("keeper")
+            .arg("-C")
+            .arg(config)
+            .spawn()
+            .with_context(|| SpawnError { node: format!("keeper-{id}") })?;
+        Ok(())
+    }
+
+    pub(crate) fn start_server_container(
+        &self,
+        id: ServerId,
+        image: &str,
+    ) -> Result<()> {
+        let dir = self.server_dir(id);
+        info!(%dir, "Deploying clickhouse server container");
+        let config = dir.join("clickhouse-config.xml");
+        let binary = self.server_binary(id);
+        self.start_container(
+            &format!("clickhouse-{id}"),
+            &dir,
+            image,
+            &binary,
+        )?
+        .arg("server")
+        .arg("-C")
+        .arg(config)
+        .spawn()
+        .with_context(|| SpawnError { node: format!("clickhouse-{id}") })?;
+        Ok(())
+    }
+
+    /// Build a `docker run` command for the node in `dir`, to which the
+    /// arguments of `binary` must be appended
+    ///
+    /// The container runs as the owner of `dir` so that files it writes
+    /// remain accessible, and its output goes to the node's output files.
+    fn start_container(
+        &self,
+        node: &str,
+        dir: &Utf8Path,
+        image: &str,
+        binary: &Utf8Path,
+    ) -> Result<Command> {
+        let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)?;
+        let dir_abs = dir.canonicalize_utf8()?;
+        #[cfg(unix)]
+        let owner = dir_abs.metadata()?;
+
+        // Docker refuses to overwrite the id file of a container that is gone
+        let cidfile = dir_abs.join(CONTAINER_ID_FILENAME);
+        if cidfile.exists() {
+            std::fs::remove_file(&cidfile)?;
+        }
+
+        let (env, limits) = self.node_process(node);
+        let (stdout, stderr) = output_files(dir)?;
+        let mut cmd = Command::new("docker");
+        cmd.arg("run")
+            .arg("--rm")
+            .arg("--network=host")
+            .arg(format!("--cidfile={cidfile}"))
+            .arg(format!("--volume={dir_abs}:{dir_abs}"))
+            .arg(format!("--workdir={cwd}"));
+
+        #[cfg(unix)]
+        cmd.arg(format!("--user={}:{}", owner.uid(), owner.gid()));
+
+        // A custom binary must be visible inside the container
+        if binary.is_absolute() {
+            cmd.arg(format!("--volume={binary}:{binary}:ro"));
+        }
+        for (key, value) in env {
+            cmd.arg(format!("--env={key}={value}"));
+        }
+        for ulimit in limits.docker_ulimits() {
+            cmd.arg(format!("--ulimit={ulimit}"));
+        }
+        cmd.args(self.docker_pinning_args(node));
+        cmd.arg(format!("--entrypoint={binary}"))
+            .arg(image)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr);
+        Ok(cmd)
+    }
+
+    /// Stop and remove the container running the node in `dir`
+    pub(crate) fn stop_container(&self, dir: &Utf8Path) -> Result<()> {
+        let cidfile = dir.join(CONTAINER_ID_FILENAME);
+        let cid = std::fs::read_to_string(&cidfile)
+            .with_context(|| format!("failed to read {cidfile}"))?;
+        let cid = cid.trim_end();
+        info!(%cid, %dir, "Stopping container");
+        let output = self
+            .output(
+                Command::new("docker")
+                    .arg("rm")
+                    .arg("--force")
+                    .arg(cid)
+                    .stdin(Stdio::null()),
+            )
+            .context("Failed to run docker")?;
+        if !output.status.success() {
+            bail!(
+                "failed to remove container {cid}: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        std::fs::remove_file(&cidfile)?;
+        Ok(())
+    }
+}
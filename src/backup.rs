@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::ServerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[cfg(feature = "http-client")]
+use crate::{config::BACKUPS_DISK, Deployment, MISSING_META};
+#[cfg(feature = "http-client")]
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "http-client")]
+use tracing::info;
+
+/// What a backup contains
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupTarget {
+    Table { database: String, table: String },
+    Database(String),
+}
+
+/// Formats as the target of `BACKUP` or `RESTORE`, i.e. ``TABLE `db`.`t` ``
+impl std::fmt::Display for BackupTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupTarget::Table { database, table } => {
+                write!(f, "TABLE `{database}`.`{table}`")
+            }
+            BackupTarget::Database(database) => {
+                write!(f, "DATABASE `{database}`")
+            }
+        }
+    }
+}
+
+/// A backup taken with `Deployment::backup`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub target: BackupTarget,
+    /// The servers that hold a copy of the backup on their `backups` disk
+    pub servers: BTreeSet<ServerId>,
+}
+
+#[cfg(feature = "http-client")]
+impl Deployment {
+    /// Back up `target` on every server to a backup called `name` on its
+    /// `backups` disk, with clickhouse's `BACKUP`
+    ///
+    /// Each server backs up its own data, so that backups don't need
+    /// storage shared between servers.
+    pub async fn backup(
+        &mut self,
+        target: BackupTarget,
+        name: &str,
+    ) -> Result<()> {
+        if name.is_empty() || name.contains(['/', '\\', '\'']) {
+            bail!("invalid backup name: {name}");
+        }
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if meta.backups.contains_key(name) {
+            bail!("backup {name} already exists");
+        }
+        let servers = meta.server_ids.clone();
+        let sql =
+            format!("BACKUP {target} TO Disk('{BACKUPS_DISK}', '{name}')");
+        for &id in &servers {
+            info!(%id, %name, "Backing up {target}");
+            self.http_client(id)?
+                .execute(&sql)
+                .await
+                .with_context(|| format!("failed to back up server {id}"))?;
+        }
+
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.backups.insert(name.to_string(), BackupInfo { target, servers });
+        self.writer.save_meta(meta, &self.config.path)?;
+        self.record("backup", serde_json::json!({ "name": name }))
+    }
+
+    /// Restore the backup called `name` on every server that took it,
+    /// with clickhouse's `RESTORE`
+    ///
+    /// Unlike `restore`, this goes through the servers and leaves them
+    /// running. Like `RESTORE`, it fails if the backed up tables already
+    /// exist, so drop them first.
+    pub async fn restore_backup(&self, name: &str) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let Some(BackupInfo { target, servers }) = meta.backups.get(name)
+        else {
+            bail!("No such backup: {name}");
+        };
+        let sql =
+            format!("RESTORE {target} FROM Disk('{BACKUPS_DISK}', '{name}')");
+        for &id in servers {
+            if !meta.server_ids.contains(&id) {
+                continue;
+            }
+            info!(%id, %name, "Restoring {target}");
+            self.http_client(id)?
+                .execute(&sql)
+                .await
+                .with_context(|| format!("failed to restore server {id}"))?;
+        }
+        self.record("restore-backup", serde_json::json!({ "name": name }))
+    }
+}
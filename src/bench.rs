@@ -0,0 +1,139 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, ServerId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::process::{Command, Stdio};
+use tracing::info;
+
+/// The file in a server's directory that `clickhouse benchmark` writes its
+/// JSON report to
+const BENCH_REPORT_FILENAME: &str = "benchmark.json";
+
+/// How to run `clickhouse benchmark`
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// A file of queries, one per line
+    pub query_file: Utf8PathBuf,
+    /// The number of queries run at the same time
+    pub concurrency: u32,
+    /// Stop after running this many queries
+    pub iterations: Option<u64>,
+    /// Stop after this many seconds
+    pub time_limit_secs: Option<u64>,
+    /// Only benchmark this server, rather than every server in turn
+    pub server: Option<ServerId>,
+}
+
+/// The results of benchmarking a single server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerBenchResult {
+    pub qps: f64,
+    pub num_queries: u64,
+    pub num_errors: u64,
+    /// Query latency in seconds, keyed by percentile, i.e. `99.9`
+    pub latency_percentiles: BTreeMap<String, f64>,
+}
+
+/// The results of `Deployment::bench`, keyed by node name, i.e.
+/// `clickhouse-1`
+pub type BenchReport = BTreeMap<String, ServerBenchResult>;
+
+/// The report written by `clickhouse benchmark --json`, keyed by
+/// `host:port`
+#[derive(Debug, Deserialize)]
+struct RawReport {
+    statistics: RawStatistics,
+    query_time_percentiles: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawStatistics {
+    #[serde(rename = "QPS")]
+    qps: f64,
+    num_queries: u64,
+    #[serde(default)]
+    num_errors: u64,
+}
+
+impl Deployment {
+    /// Run `clickhouse benchmark` against one or all servers in turn
+    pub fn bench(&self, options: &BenchOptions) -> Result<BenchReport> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let servers: Vec<ServerId> = match options.server {
+            Some(id) if !meta.server_ids.contains(&id) => {
+                bail!("No such replica: {id}")
+            }
+            Some(id) => vec![id],
+            None => meta.server_ids.iter().copied().collect(),
+        };
+
+        let mut report = BenchReport::new();
+        for id in servers {
+            info!(%id, "Benchmarking clickhouse server");
+            let result = self.bench_server(id, options)?;
+            report.insert(format!("clickhouse-{id}"), result);
+        }
+        Ok(report)
+    }
+
+    fn bench_server(
+        &self,
+        id: ServerId,
+        options: &BenchOptions,
+    ) -> Result<ServerBenchResult> {
+        let queries = File::open(&options.query_file).with_context(|| {
+            format!("failed to open {}", options.query_file)
+        })?;
+        let json = self.server_dir(id).join(BENCH_REPORT_FILENAME);
+
+        let mut cmd = Command::new(self.server_binary(id));
+        cmd.arg("benchmark")
+            .arg("--host")
+            .arg(self.server_host(id))
+            .arg("--port")
+            .arg(self.tcp_port(id)?.to_string())
+            .arg("--concurrency")
+            .arg(options.concurrency.to_string())
+            .arg("--json")
+            .arg(&json);
+        if let Some(iterations) = options.iterations {
+            cmd.arg("--iterations").arg(iterations.to_string());
+        }
+        if let Some(secs) = options.time_limit_secs {
+            cmd.arg("--timelimit").arg(secs.to_string());
+        }
+        let output = cmd
+            .stdin(queries)
+            .stdout(Stdio::null())
+            .output()
+            .context("Failed to run clickhouse benchmark")?;
+        if !output.status.success() {
+            bail!(
+                "clickhouse benchmark failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+
+        let contents = std::fs::read_to_string(&json)
+            .with_context(|| format!("failed to read {json}"))?;
+        let raw: BTreeMap<String, RawReport> = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse {json}"))?;
+        let Some(raw) = raw.into_values().next() else {
+            bail!("no results in {json}");
+        };
+        Ok(ServerBenchResult {
+            qps: raw.statistics.qps,
+            num_queries: raw.statistics.num_queries,
+            num_errors: raw.statistics.num_errors,
+            latency_percentiles: raw.query_time_percentiles,
+        })
+    }
+}
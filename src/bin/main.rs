@@ -3,18 +3,54 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
 
-use clickward::{Deployment, KeeperClient};
+use clickward::config::{
+    BrokerConfig, InterserverCredentials, LogLevel, ServerConfig, StorageConfig,
+};
+use clickward::{
+    cluster_names, cluster_path, format_endpoints, Backend, BasePorts,
+    BenchOptions, ConfigTemplates, Deployment, DeploymentConfig,
+    DeploymentSpec, EndpointFormat, HealthTimeout, InvalidConfig, JsonProgress,
+    KeeperClient, KeeperId, LogFilter, MetadataConflict, MinioConfig,
+    NodeAdminState, NodeId, NodeKind, NodeProcessConfig, NodeResourceUsage,
+    NodeStatus, OperationContext, OperationError, PinningPolicy, PortConflicts,
+    PortError, Preset, ProcessLimits, Recovery, ResourceLimit, RestartPolicy,
+    ServerId, SpawnError, CLUSTER_PORT_STRIDE, DEFAULT_BASE_PORTS,
+    DEFAULT_DOCKER_IMAGE, DEFAULT_MINIO_PORT,
+};
+#[cfg(feature = "http-client")]
+use clickward::{BackupTarget, QueryLogEntry, QueryLogFilter};
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Cli {
+    /// Operate on the named cluster under the path rather than the one
+    /// directly in it
+    #[arg(long, global = true)]
+    cluster: Option<String>,
+
+    /// Report the progress of operations as log lines, or as one JSON event
+    /// per line on stdout for other tools to follow
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    progress: ProgressKind,
+
+    /// Print errors as text on stdout, or as one JSON object on stderr for
+    /// other tools to inspect
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    error_format: ErrorFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Generate configuration for our clickhouse and keeper clusters
     GenConfig {
@@ -22,107 +58,1950 @@ enum Commands {
         #[arg(short, long)]
         path: Utf8PathBuf,
 
-        /// Number of clickhouse keepers
-        #[arg(long)]
-        num_keepers: u64,
-
-        /// Number of clickhouse replicas
+        /// JSON or YAML file describing the deployment's topology and
+        /// settings, which take precedence over those of other flags
+        #[arg(
+            long,
+            conflicts_with_all = ["num_keepers", "num_replicas", "num_shards"]
+        )]
+        spec: Option<Utf8PathBuf>,
+
+        /// Number of clickhouse keepers
+        #[arg(
+            long,
+            required_unless_present_any = [
+                "external_keepers",
+                "share_keepers_with",
+                "spec",
+            ]
+        )]
+        num_keepers: Option<u64>,
+
+        /// Number of clickhouse replicas of each shard
+        #[arg(long, required_unless_present = "spec")]
+        num_replicas: Option<u64>,
+
+        /// Number of shards
+        #[arg(long)]
+        num_shards: Option<u64>,
+
+        /// Comma separated weights of the shards for distributed inserts,
+        /// i.e. `2,1,1`
+        #[arg(long, value_delimiter = ',')]
+        shard_weights: Vec<u32>,
+
+        /// Set the `<remote_servers>` priority of servers in a zone as
+        /// `ZONE=PRIORITY`, where lower priorities are preferred. May be
+        /// repeated.
+        #[arg(long = "zone-priority", value_parser = parse_zone_priority)]
+        zone_priorities: Vec<(String, u32)>,
+
+        /// A macro that every server gets, as NAME=VALUE, i.e.
+        /// `datacenter=dc1`
+        #[arg(long = "macro", value_parser = parse_macro)]
+        macros: Vec<(String, String)>,
+
+        /// A macro of one server, as ID:NAME=VALUE, i.e. `2:rack=r2`
+        #[arg(long = "server-macro", value_parser = parse_server_macro)]
+        server_macros: Vec<(ServerId, String, String)>,
+
+        /// Level at which all nodes log, by default that of the preset
+        #[arg(long)]
+        log_level: Option<LogLevel>,
+
+        /// Bundle of resource settings for all nodes
+        #[arg(long, value_enum)]
+        preset: Option<PresetKind>,
+
+        /// Directory of extra XML files to merge into generated configs
+        ///
+        /// Files directly inside apply to all clickhouse servers, and files
+        /// in `clickhouse-<id>` or `keeper-<id>` subdirectories apply only
+        /// to that node.
+        #[arg(long)]
+        extra_config_dir: Option<Utf8PathBuf>,
+
+        /// Template of keeper configs, with `{{name}}` placeholders, to use
+        /// instead of the config clickward generates
+        #[arg(long)]
+        keeper_template: Option<Utf8PathBuf>,
+
+        /// Template of clickhouse server configs, with `{{name}}`
+        /// placeholders, to use instead of the config clickward generates
+        #[arg(long)]
+        server_template: Option<Utf8PathBuf>,
+
+        /// Use an existing keeper or zookeeper node at `<host>:<port>`
+        /// instead of clickward managed keepers. May be repeated.
+        ///
+        /// IPv6 hosts must be in brackets, i.e. `[::1]:9181`.
+        #[arg(long = "external-keeper", conflicts_with = "num_keepers")]
+        external_keepers: Vec<ServerConfig>,
+
+        /// Use the keepers of the named cluster under the same path instead
+        /// of clickward managed keepers
+        #[arg(long, conflicts_with = "num_keepers")]
+        share_keepers_with: Option<String>,
+
+        /// Set an environment variable for all nodes as `KEY=VALUE`. May be
+        /// repeated.
+        #[arg(long = "env", value_parser = parse_env_var)]
+        process_env: Vec<(String, String)>,
+
+        /// Maximum number of open file descriptors for all nodes
+        #[arg(long)]
+        ulimit_nofile: Option<ResourceLimit>,
+
+        /// Maximum core file size for all nodes
+        #[arg(long)]
+        ulimit_core: Option<ResourceLimit>,
+
+        /// Run a node as another OS user via `sudo`, as NODE=USER, i.e.
+        /// `keeper-1=ch1`. May be repeated.
+        #[arg(long = "node-user", value_parser = parse_node_user)]
+        node_users: Vec<(String, String)>,
+
+        /// How to run nodes
+        #[arg(long, value_enum, default_value_t = BackendKind::Process)]
+        backend: BackendKind,
+
+        /// The image to run nodes in with the docker backend, by default the
+        /// official clickhouse server image
+        #[arg(long)]
+        docker_image: Option<String>,
+
+        /// A host to run nodes on with the ssh backend. May be repeated, in
+        /// which case nodes are assigned to hosts round robin.
+        #[arg(long = "host", required_if_eq("backend", "ssh"))]
+        hosts: Vec<String>,
+
+        /// Secret that servers authenticate distributed queries with, by
+        /// default a randomly generated one
+        #[arg(long)]
+        cluster_secret: Option<String>,
+
+        /// Znode under which servers keep replicated tables and the
+        /// distributed DDL queue, by default a unique one per deployment
+        #[arg(long)]
+        zookeeper_root: Option<String>,
+
+        /// Name of a database using the `Replicated` engine to create on
+        /// every server when deploying
+        #[arg(long)]
+        replicated_database: Option<String>,
+
+        /// Database that queries use unless they name another, instead of
+        /// `default`, created on every server when deploying
+        #[arg(long)]
+        default_database: Option<String>,
+
+        /// Give every server a `hot` and a `cold` disk and a `hot_cold`
+        /// storage policy that moves parts between them
+        #[arg(long)]
+        hot_cold_storage: bool,
+
+        /// Run a MinIO server alongside the deployment and store all parts
+        /// on an `s3` disk in it, with zero-copy replication
+        #[arg(long, conflicts_with = "hot_cold_storage")]
+        minio: bool,
+
+        /// Address of a Kafka broker for Kafka engine tables, available to
+        /// them as the `kafka` named collection
+        #[arg(long)]
+        kafka: Option<ServerConfig>,
+
+        /// Address of a RabbitMQ broker for RabbitMQ engine tables, available
+        /// to them as the `rabbitmq` named collection
+        #[arg(long)]
+        rabbitmq: Option<ServerConfig>,
+
+        /// Enable the `query_log`, `metric_log`, `trace_log` and `part_log`
+        /// system tables
+        #[arg(long)]
+        system_logs: bool,
+
+        /// Serve keeper's HTTP control endpoint, with a readiness check, on
+        /// every keeper
+        #[arg(long)]
+        keeper_http_control: bool,
+
+        /// Comma separated four letter word commands that keepers accept,
+        /// i.e. `mntr,stat`, or `*` for all of them. The words clickward
+        /// sends itself are always accepted.
+        #[arg(long, value_delimiter = ',')]
+        keeper_four_letter_words: Option<Vec<String>>,
+
+        /// Make inserts asynchronous by default
+        #[arg(long)]
+        async_insert: bool,
+
+        /// Give each node free ports picked by the OS rather than ports
+        /// offset from base ports, so that deployments can share a machine
+        /// without choosing ports
+        #[arg(long)]
+        free_ports: bool,
+
+        /// Pin each node to this many cores of its own with `taskset`
+        #[arg(long, conflicts_with = "numa_nodes")]
+        cores_per_node: Option<usize>,
+
+        /// Comma separated NUMA nodes that nodes are bound to round robin
+        /// with `numactl`
+        #[arg(long, value_delimiter = ',')]
+        numa_nodes: Option<Vec<u32>>,
+
+        /// Address that every node listens on, i.e. `::` or `0.0.0.0`
+        #[arg(long)]
+        bind_host: Option<String>,
+
+        /// Host that other nodes and clients reach local nodes at, when they
+        /// listen on an address other than `::1`
+        #[arg(long)]
+        advertise_host: Option<String>,
+
+        /// Amount to add to every default base port, i.e. to stay clear of
+        /// other local services. Other base port flags are applied after it.
+        #[arg(long, default_value_t = 0)]
+        base_port_offset: u16,
+
+        /// Client port of the first keeper, incremented for each other keeper
+        #[arg(long)]
+        keeper_base_port: Option<u16>,
+
+        /// Raft port of the first keeper
+        #[arg(long)]
+        raft_base_port: Option<u16>,
+
+        /// Native TCP port of the first server
+        #[arg(long)]
+        tcp_base_port: Option<u16>,
+
+        /// HTTP port of the first server
+        #[arg(long)]
+        http_base_port: Option<u16>,
+
+        /// Interserver HTTP port of the first server
+        #[arg(long)]
+        interserver_base_port: Option<u16>,
+
+        /// Prometheus metrics port of the first server
+        #[arg(long)]
+        clickhouse_metrics_base_port: Option<u16>,
+
+        /// Prometheus metrics port of the first keeper
+        #[arg(long)]
+        keeper_metrics_base_port: Option<u16>,
+
+        /// HTTP control port of the first keeper
+        #[arg(long)]
+        keeper_http_control_base_port: Option<u16>,
+
+        /// User that replicas authenticate interserver http requests as
+        #[arg(long, requires = "interserver_password")]
+        interserver_user: Option<String>,
+
+        /// Password that replicas authenticate interserver http requests with
+        #[arg(long, requires = "interserver_user")]
+        interserver_password: Option<String>,
+
+        /// Print what would be done without doing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Re-render all configs from the saved deployment config and metadata
+    /// without changing membership
+    RegenConfig {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Print a JSON spec that `gen-config --spec` recreates the deployment
+    /// from
+    ExportSpec {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Show how the configs on disk differ from what would be generated
+    /// from the saved deployment config and metadata
+    DiffConfig {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Check that node directories and configs match the metadata
+    Validate {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Regenerate stale configs and move orphaned directories aside
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Complete or roll back an interrupted membership change
+    Recover {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Undo the interrupted change rather than completing it
+        #[arg(long)]
+        rollback: bool,
+    },
+
+    /// Launch our deployment given generated configs
+    Deploy {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Start all nodes that aren't running, keepers before servers
+    Start {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Seconds to wait for keeper quorum and for each server to become
+        /// healthy
+        #[arg(long, default_value_t = 60)]
+        timeout_secs: u64,
+    },
+
+    /// Stop all running nodes, servers before keepers
+    Stop {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Start a single node
+    StartNode {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+    },
+
+    /// Stop a single node
+    StopNode {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+    },
+
+    /// Put a node in a zone, or take it out of its zone without `--zone`
+    SetZone {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+
+        /// Name of the zone
+        #[arg(long)]
+        zone: Option<String>,
+    },
+
+    /// Mark a node active, cordoned, draining, or stopped
+    ///
+    /// Servers that aren't active are left out of `<remote_servers>`, and
+    /// stopped nodes are stopped and left stopped by `start`.
+    SetState {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+
+        /// State to put the node in
+        #[arg(long)]
+        state: StateKind,
+    },
+
+    /// Cordon a server, wait for its running queries to finish, and stop it
+    #[cfg(feature = "http-client")]
+    Drain {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the clickhouse server node to drain
+        #[arg(long)]
+        id: ServerId,
+
+        /// Seconds to wait for running queries to finish
+        #[arg(long, default_value_t = 60)]
+        timeout_secs: u64,
+    },
+
+    /// Run a node with its clock ahead of or behind the real clock from its
+    /// next start, using `faketime`
+    SetClockSkew {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+
+        /// Seconds that the clock is ahead, or behind if negative. 0 removes
+        /// the skew.
+        #[arg(long, allow_hyphen_values = true)]
+        offset_secs: i64,
+    },
+
+    /// Delay and drop packets sent from one node to another with `tc netem`
+    DegradeLink {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node that sends packets
+        #[arg(long)]
+        from_kind: Kind,
+
+        /// Id of the node that sends packets
+        #[arg(long)]
+        from_id: u64,
+
+        /// Kind of the node that receives packets
+        #[arg(long)]
+        to_kind: Kind,
+
+        /// Id of the node that receives packets
+        #[arg(long)]
+        to_id: u64,
+
+        /// Milliseconds to delay every packet by
+        #[arg(long, default_value_t = 0)]
+        latency_ms: u32,
+
+        /// Percentage of packets to drop
+        #[arg(long, default_value_t = 0.0)]
+        loss_pct: f64,
+    },
+
+    /// Remove the degradation of all links
+    RestoreLinks {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Leave a node only a little free space for its data, using a small
+    /// loop mounted filesystem
+    FillDisk {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+
+        /// Bytes of free space to leave
+        #[arg(long, default_value_t = 0)]
+        leave_bytes: u64,
+    },
+
+    /// Move the data of a node off the filesystem mounted by `fill-disk`
+    UnfillDisk {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+    },
+
+    /// List the nodes of each zone
+    Zones {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Stop every node in a zone at once
+    FailZone {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Name of the zone
+        #[arg(long)]
+        zone: String,
+    },
+
+    /// Show whether each node is running, exited, or crash looping, along
+    /// with the end of the error log of nodes that exited
+    Status {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Show the version of clickhouse that nodes would run now, and the
+    /// one that configs were generated for
+    ClickhouseVersion {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Show the memory, CPU time, open files, and disk usage of each node
+    Top {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Refresh every this many seconds until interrupted
+        #[arg(long)]
+        interval_secs: Option<u64>,
+    },
+
+    /// Write the configs that would be generated into another directory
+    /// without touching the deployment
+    RenderConfig {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Directory to write the configs into
+        #[arg(long)]
+        out: Utf8PathBuf,
+    },
+
+    /// Print the client endpoints of all nodes
+    Endpoints {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Format to print the endpoints in
+        #[arg(long, value_enum)]
+        format: Option<FormatKind>,
+    },
+
+    /// Print the ports, paths, and role of a node as JSON
+    NodeInfo {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Kind of the node
+        #[arg(long)]
+        kind: Kind,
+
+        /// Id of the node
+        #[arg(long)]
+        id: u64,
+    },
+
+    /// Stop all nodes and remove their data and logs, keeping configs
+    Clean {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Copy a deployment to a new path with its own ports, so that both can
+    /// run at once
+    Clone {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Root path of the clone
+        #[arg(long)]
+        dest: Utf8PathBuf,
+
+        /// Amount to add to every port of the deployment for the clone
+        #[arg(long, default_value_t = CLUSTER_PORT_STRIDE)]
+        port_offset: u16,
+    },
+
+    /// Replace the data of a server with a copy of a clickhouse data
+    /// directory from elsewhere
+    ImportData {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the server to import into
+        #[arg(long)]
+        id: ServerId,
+
+        /// The data directory to import, i.e. `/var/lib/clickhouse`
+        #[arg(long)]
+        source: Utf8PathBuf,
+    },
+
+    /// Stop all our deployed processes
+    Teardown {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Make the servers of another deployment queryable from this one as
+    /// an extra cluster
+    LinkRemote {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Root path of the other deployment
+        #[arg(long)]
+        other_path: Utf8PathBuf,
+
+        /// Named cluster under the other path to link, by default the one
+        /// directly in it
+        #[arg(long)]
+        other_cluster: Option<String>,
+
+        /// Name that the other cluster is known by in this deployment
+        #[arg(long)]
+        alias: String,
+    },
+
+    /// Remove a cluster added with `link-remote`
+    UnlinkRemote {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Name of the linked cluster
+        #[arg(long)]
+        alias: String,
+    },
+
+    /// List the named clusters under a path
+    Clusters {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Show metadata about the deployment
+    Show {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Add a keeper node to the keeper cluster
+    AddKeeper {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Add the keeper as an observer, which never votes or becomes leader
+        #[arg(long, conflicts_with = "count")]
+        observer: bool,
+
+        /// Number of keepers to add at once
+        #[arg(long)]
+        count: Option<usize>,
+
+        /// Seconds to wait for keepers added with `--count` to become
+        /// healthy
+        #[arg(long, default_value_t = 60)]
+        timeout_secs: u64,
+
+        /// Print what would be done without doing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Remove a keeper node
+    RemoveKeeper {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node to remove
+        #[arg(long)]
+        id: KeeperId,
+
+        /// Remove the keeper even if that loses quorum, and without handing
+        /// over its leadership first
+        #[arg(long)]
+        force: bool,
+
+        /// Print what would be done without doing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Add back a removed keeper with the data in its directory
+    ReaddKeeper {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the removed keeper node
+        #[arg(long)]
+        id: KeeperId,
+    },
+
+    /// Get the keeper config from a given keeper
+    KeeperConfig {
+        /// Id of the keeper node to query
+        #[arg(long)]
+        id: KeeperId,
+    },
+
+    /// Set the raft priority of a keeper, which is preferred as leader over
+    /// keepers with lower priorities
+    SetKeeperPriority {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node to set the priority of
+        #[arg(long)]
+        id: KeeperId,
+
+        /// The new priority, where 0 means never becoming leader
+        #[arg(long)]
+        priority: u32,
+    },
+
+    /// Ask a keeper to become the leader of the keeper cluster
+    RequestLeadership {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node that should become leader
+        #[arg(long)]
+        id: KeeperId,
+    },
+
+    /// Open `clickhouse client` connected to a server
+    Shell {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the clickhouse server to connect to
+        #[arg(long)]
+        id: ServerId,
+
+        /// Database to use instead of `default`
+        #[arg(long)]
+        database: Option<String>,
+    },
+
+    /// Open `clickhouse keeper-client` against a keeper, or run the given
+    /// commands with it, i.e. `ls /`, and print their output
+    KeeperShell {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node to connect to
+        #[arg(long)]
+        id: KeeperId,
+
+        /// Commands to run instead of an interactive shell
+        #[arg(trailing_var_arg = true)]
+        commands: Vec<String>,
+    },
+
+    /// Show what a keeper has stored in its raft log and snapshots
+    KeeperStorage {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node to inspect
+        #[arg(long)]
+        id: KeeperId,
+    },
+
+    /// Remove old snapshots of a keeper and the log segments they cover
+    CleanKeeperLogs {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node to clean up
+        #[arg(long)]
+        id: KeeperId,
+
+        /// Number of the newest snapshots to keep
+        #[arg(long, default_value_t = 1)]
+        keep: usize,
+    },
+
+    /// Copy the raft log and snapshots of a keeper out of the deployment
+    ExportKeeperState {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node to export
+        #[arg(long)]
+        id: KeeperId,
+
+        /// Directory to copy the keeper state into
+        #[arg(long)]
+        dest: Utf8PathBuf,
+    },
+
+    /// Replace the raft log and snapshots of a keeper with exported ones
+    ImportKeeperState {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the keeper node to import into
+        #[arg(long)]
+        id: KeeperId,
+
+        /// Directory that the keeper state was exported to
+        #[arg(long)]
+        src: Utf8PathBuf,
+    },
+
+    /// Add a clickhouse server
+    AddServer {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Number of servers to add at once
+        #[arg(long)]
+        count: Option<usize>,
+
+        /// Seconds to wait for servers added with `--count` to become
+        /// healthy
+        #[arg(long, default_value_t = 60)]
+        timeout_secs: u64,
+
+        /// Print what would be done without doing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Remove a clickhouse server
+    RemoveServer {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the clickhouse server node to remove
+        #[arg(long)]
+        id: ServerId,
+
+        /// Print what would be done without doing it
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Refuse to remove the server unless another replica in its shard
+        /// has caught up on each of its replicated tables
+        #[arg(long)]
+        check_replication: bool,
+
+        /// After removing the server, drop its replica metadata from keeper
+        /// with `SYSTEM DROP REPLICA` on the rest of its shard
+        #[arg(long)]
+        drop_replica: bool,
+    },
+
+    /// Add back a removed clickhouse server with the data in its directory
+    ReaddServer {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Id of the removed clickhouse server node
+        #[arg(long)]
+        id: ServerId,
+    },
+
+    /// Add or remove nodes one at a time until there are as many as given
+    Scale {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Number of clickhouse servers to end up with
+        #[arg(long, required_unless_present = "keepers")]
+        servers: Option<usize>,
+
+        /// Number of keepers to end up with
+        #[arg(long)]
+        keepers: Option<usize>,
+
+        /// Seconds to wait for each added node to become healthy
+        #[arg(long, default_value_t = 60)]
+        timeout_secs: u64,
+    },
+
+    /// Show the logs of all nodes, prefixing each line with its node
+    Logs {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Only show logs of nodes with this id
+        #[arg(long)]
+        id: Option<u64>,
+
+        /// Only show logs of nodes of this kind
+        #[arg(long)]
+        kind: Option<Kind>,
+
+        /// Show the error logs instead of the full logs
+        #[arg(long)]
+        errors: bool,
+
+        /// Number of trailing lines to show from each log
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+
+        /// Keep printing new lines as they are written
+        #[arg(short, long)]
+        follow: bool,
+    },
+
+    /// Write metadata for an existing deployment that has none, inferring
+    /// its nodes and ports from their configs
+    Attach {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Stop the deployment and save a copy of its state under a name
+    Snapshot {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Name of the snapshot
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Reset the deployment to the state in a snapshot
+    Restore {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Name of the snapshot
+        #[arg(long)]
+        name: String,
+    },
+
+    /// List all snapshots of the deployment
+    Snapshots {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Restart clickhouse servers one at a time with a different binary
+    RollingUpgrade {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Path to the clickhouse binary to run
+        #[arg(long)]
+        binary: Utf8PathBuf,
+
+        /// Seconds to wait for each server to become healthy
+        #[arg(long, default_value_t = 60)]
+        timeout_secs: u64,
+    },
+
+    /// Restart nodes that exit unexpectedly until interrupted
+    Supervise {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Number of times to restart a node before giving up on it
+        #[arg(long, default_value_t = 5)]
+        max_restarts: u32,
+    },
+
+    /// Show every operation that changed the deployment, oldest first
+    History {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+    },
+
+    /// Apply a SQL file of CREATE DATABASE/TABLE statements across the
+    /// cluster, unless it was already applied
+    #[cfg(feature = "http-client")]
+    Seed {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Path to the SQL file
+        #[arg(long)]
+        schema: Utf8PathBuf,
+
+        /// Seconds to wait for each statement to complete on all servers
+        #[arg(long, default_value_t = 60)]
+        timeout_secs: u64,
+    },
+
+    /// Back up a database or table on every server with clickhouse's
+    /// `BACKUP`
+    #[cfg(feature = "http-client")]
+    Backup {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Database to back up
+        #[arg(long)]
+        database: String,
+
+        /// Table to back up, rather than the whole database
+        #[arg(long)]
+        table: Option<String>,
+
+        /// Name of the backup
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Restore a backup taken with `backup` with clickhouse's `RESTORE`
+    #[cfg(feature = "http-client")]
+    RestoreBackup {
+        /// Root path of all configuration
+        #[arg(short, long)]
+        path: Utf8PathBuf,
+
+        /// Name of the backup
         #[arg(long)]
-        num_replicas: u64,
+        name: String,
     },
 
-    /// Launch our deployment given generated configs
-    Deploy {
+    /// List the backups taken with `backup`
+    Backups {
         /// Root path of all configuration
         #[arg(short, long)]
         path: Utf8PathBuf,
     },
 
-    /// Stop all our deployed processes
-    Teardown {
+    /// Run `clickhouse benchmark` against the servers
+    Bench {
         /// Root path of all configuration
         #[arg(short, long)]
         path: Utf8PathBuf,
+
+        /// File of queries to run, one per line
+        #[arg(long)]
+        query_file: Utf8PathBuf,
+
+        /// Number of queries run at the same time
+        #[arg(long, default_value_t = 1)]
+        concurrency: u32,
+
+        /// Stop after running this many queries against each server
+        #[arg(long)]
+        iterations: Option<u64>,
+
+        /// Stop after this many seconds for each server
+        #[arg(long)]
+        time_limit_secs: Option<u64>,
+
+        /// Only benchmark the server with this id
+        #[arg(long)]
+        id: Option<ServerId>,
+
+        /// Save the report as JSON to this file
+        #[arg(long)]
+        output: Option<Utf8PathBuf>,
     },
 
-    /// Show metadata about the deployment
-    Show {
+    /// Make running nodes apply their regenerated configs without a
+    /// restart, and check that they did
+    #[cfg(feature = "http-client")]
+    Reload {
         /// Root path of all configuration
         #[arg(short, long)]
         path: Utf8PathBuf,
+
+        /// Kind of the node to reload, rather than every node
+        #[arg(long, requires = "id")]
+        kind: Option<Kind>,
+
+        /// Id of the node to reload
+        #[arg(long, requires = "kind")]
+        id: Option<u64>,
     },
 
-    /// Add a keeper node to the keeper cluster
-    AddKeeper {
+    /// Show the replication state of every replicated table on every server
+    #[cfg(feature = "http-client")]
+    ReplStatus {
         /// Root path of all configuration
         #[arg(short, long)]
         path: Utf8PathBuf,
     },
 
-    /// Remove a keeper node
-    RemoveKeeper {
+    /// Insert random rows into a table
+    #[cfg(feature = "http-client")]
+    Loadgen {
         /// Root path of all configuration
         #[arg(short, long)]
         path: Utf8PathBuf,
 
-        /// Id of the keeper node to remove
+        /// The table to insert into, optionally qualified with a database
         #[arg(long)]
-        id: u64,
-    },
+        table: String,
 
-    /// Get the keeper config from a given keeper
-    KeeperConfig {
-        /// Id of the keeper node to remove
+        /// Number of rows to insert
         #[arg(long)]
-        id: u64,
-    },
+        rows: u64,
 
-    /// Add a clickhouse server
-    AddServer {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        /// Number of rows per insert
+        #[arg(long, default_value_t = 10000)]
+        batch_size: u64,
+
+        /// Insert through all live servers concurrently
+        #[arg(long)]
+        parallel: bool,
     },
 
-    /// Remove a clickhouse server
-    RemoveServer {
+    /// Show recent queries from `system.query_log` of a server
+    #[cfg(feature = "http-client")]
+    QueryLog {
         /// Root path of all configuration
         #[arg(short, long)]
         path: Utf8PathBuf,
 
-        /// Id of the clickhouse server node to remove
+        /// Id of the server to show queries of
         #[arg(long)]
-        id: u64,
+        id: ServerId,
+
+        /// Only show queries containing this string
+        #[arg(long)]
+        contains: Option<String>,
+
+        /// Only show queries that failed
+        #[arg(long)]
+        errors: bool,
+
+        /// Maximum number of queries to show
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
     },
 }
 
-//const CLUSTER: &str = "test_cluster";
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Kind {
+    Keeper,
+    Server,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum BackendKind {
+    Process,
+    Docker,
+    Ssh,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum PresetKind {
+    Minimal,
+    Default,
+    Stress,
+}
+
+impl From<PresetKind> for Preset {
+    fn from(preset: PresetKind) -> Preset {
+        match preset {
+            PresetKind::Minimal => Preset::Minimal,
+            PresetKind::Default => Preset::Default,
+            PresetKind::Stress => Preset::Stress,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum StateKind {
+    Active,
+    Cordoned,
+    Draining,
+    Stopped,
+}
+
+impl From<StateKind> for NodeAdminState {
+    fn from(state: StateKind) -> NodeAdminState {
+        match state {
+            StateKind::Active => NodeAdminState::Active,
+            StateKind::Cordoned => NodeAdminState::Cordoned,
+            StateKind::Draining => NodeAdminState::Draining,
+            StateKind::Stopped => NodeAdminState::Stopped,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ProgressKind {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ErrorFormat {
+    Text,
+    Json,
+}
+
+/// The kinds of failure that clickward exits with distinct codes for
+///
+/// Bad arguments exit with 2, as clap makes us.
+#[derive(Debug, Clone, Copy)]
+enum ErrorKind {
+    Other,
+    /// A config, spec or set of ports is invalid
+    Config,
+    /// A node's process couldn't be started
+    Spawn,
+    /// Nodes didn't become healthy in time
+    HealthTimeout,
+    /// Someone else changed the deployment's metadata at the same time
+    MetadataConflict,
+    /// The operation was cancelled or ran out of time
+    Interrupted,
+}
+
+impl ErrorKind {
+    fn of(e: &anyhow::Error) -> ErrorKind {
+        if caused_by::<MetadataConflict>(e) {
+            ErrorKind::MetadataConflict
+        } else if caused_by::<OperationError>(e) {
+            ErrorKind::Interrupted
+        } else if caused_by::<HealthTimeout>(e) {
+            ErrorKind::HealthTimeout
+        } else if caused_by::<SpawnError>(e) {
+            ErrorKind::Spawn
+        } else if caused_by::<InvalidConfig>(e)
+            || caused_by::<PortError>(e)
+            || caused_by::<PortConflicts>(e)
+        {
+            ErrorKind::Config
+        } else {
+            ErrorKind::Other
+        }
+    }
+
+    fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Config => 3,
+            ErrorKind::Spawn => 4,
+            ErrorKind::HealthTimeout => 5,
+            ErrorKind::MetadataConflict => 6,
+            ErrorKind::Interrupted => 7,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Other => "error",
+            ErrorKind::Config => "config",
+            ErrorKind::Spawn => "spawn",
+            ErrorKind::HealthTimeout => "health_timeout",
+            ErrorKind::MetadataConflict => "metadata_conflict",
+            ErrorKind::Interrupted => "interrupted",
+        }
+    }
+}
+
+/// Return true if `E` is `e` or anything that caused it, including the
+/// context that `e` was wrapped in
+fn caused_by<E>(e: &anyhow::Error) -> bool
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    e.downcast_ref::<E>().is_some() || e.chain().any(|cause| cause.is::<E>())
+}
+
+/// Print `e` in `format` and return the code to exit with
+fn report_error(e: &anyhow::Error, format: ErrorFormat) -> ExitCode {
+    let kind = ErrorKind::of(e);
+    match format {
+        ErrorFormat::Text => println!("Error: {e}"),
+        ErrorFormat::Json => {
+            let causes: Vec<_> =
+                e.chain().skip(1).map(|cause| cause.to_string()).collect();
+            let error = serde_json::json!({
+                "kind": kind.name(),
+                "exit_code": kind.exit_code(),
+                "message": e.to_string(),
+                "causes": causes,
+            });
+            eprintln!("{error}");
+        }
+    }
+    ExitCode::from(kind.exit_code())
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum FormatKind {
+    Env,
+    Json,
+    Yaml,
+    Dotenv,
+}
+
+impl From<FormatKind> for EndpointFormat {
+    fn from(format: FormatKind) -> EndpointFormat {
+        match format {
+            FormatKind::Env => EndpointFormat::Env,
+            FormatKind::Json => EndpointFormat::Json,
+            FormatKind::Yaml => EndpointFormat::Yaml,
+            FormatKind::Dotenv => EndpointFormat::Dotenv,
+        }
+    }
+}
+
+impl From<Kind> for NodeKind {
+    fn from(kind: Kind) -> NodeKind {
+        match kind {
+            Kind::Keeper => NodeKind::Keeper,
+            Kind::Server => NodeKind::Server,
+        }
+    }
+}
+
+fn node_id(kind: Kind, id: u64) -> NodeId {
+    match kind {
+        Kind::Keeper => NodeId::Keeper(KeeperId(id)),
+        Kind::Server => NodeId::Server(ServerId(id)),
+    }
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) =
+        s.split_once('=').ok_or_else(|| format!("expected KEY=VALUE: {s}"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_node_user(s: &str) -> Result<(String, String), String> {
+    let (node, user) =
+        s.split_once('=').ok_or_else(|| format!("expected NODE=USER: {s}"))?;
+    Ok((node.to_string(), user.to_string()))
+}
+
+fn parse_zone_priority(s: &str) -> Result<(String, u32), String> {
+    let (zone, priority) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ZONE=PRIORITY: {s}"))?;
+    let priority = priority
+        .parse()
+        .map_err(|e| format!("bad priority {priority}: {e}"))?;
+    Ok((zone.to_string(), priority))
+}
+
+fn parse_macro(s: &str) -> Result<(String, String), String> {
+    let (name, value) =
+        s.split_once('=').ok_or_else(|| format!("expected NAME=VALUE: {s}"))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn parse_server_macro(s: &str) -> Result<(ServerId, String, String), String> {
+    let (id, rest) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected ID:NAME=VALUE: {s}"))?;
+    let id = id.parse().map_err(|e| format!("bad server id {id}: {e}"))?;
+    let (name, value) = parse_macro(rest)?;
+    Ok((id, name, value))
+}
+
 const CLUSTER: &str = "oximeter_cluster";
 
 #[tokio::main]
-async fn main() {
-    if let Err(e) = handle().await {
-        println!("Error: {e}");
+async fn main() -> ExitCode {
+    // Log what clickward does to stdout, at `info` unless `RUST_LOG` says
+    // otherwise
+    let cli = Cli::parse();
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .without_time();
+    // JSON progress events get stdout to themselves
+    match cli.progress {
+        ProgressKind::Text => logs.init(),
+        ProgressKind::Json => logs.with_writer(std::io::stderr).init(),
+    }
+
+    let error_format = cli.error_format;
+    match handle(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => report_error(&e, error_format),
     }
 }
 
-async fn handle() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+/// Return the root path of `cluster` under `path`, where the default cluster
+/// lives directly in `path`
+fn cluster_root(path: Utf8PathBuf, cluster: Option<&str>) -> Utf8PathBuf {
+    match cluster {
+        Some(name) if name != CLUSTER => cluster_path(&path, name),
+        _ => path,
+    }
+}
+
+/// Open the deployment of `cluster` under `path`, reporting progress as
+/// `progress` says
+fn open(
+    path: Utf8PathBuf,
+    cluster: Option<&str>,
+    progress: ProgressKind,
+) -> anyhow::Result<Deployment> {
+    let d = Deployment::open(cluster_root(path, cluster), CLUSTER)?;
+    Ok(with_progress(d, progress))
+}
+
+fn with_progress(d: Deployment, progress: ProgressKind) -> Deployment {
+    match progress {
+        ProgressKind::Text => d,
+        ProgressKind::Json => d.with_progress(Arc::new(JsonProgress)),
+    }
+}
+
+/// Replace this process with `cmd`, or run it to completion where that's
+/// not possible
+fn exec(mut cmd: std::process::Command) -> anyhow::Result<()> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let e = cmd.exec();
+        anyhow::bail!("failed to run {program}: {e}")
+    }
+    #[cfg(not(unix))]
+    {
+        let status = cmd
+            .status()
+            .map_err(|e| anyhow::anyhow!("failed to run {program}: {e}"))?;
+        if !status.success() {
+            anyhow::bail!("{program} failed: {status}");
+        }
+        Ok(())
+    }
+}
+
+async fn handle(cli: Cli) -> anyhow::Result<()> {
+    let cluster = cli.cluster.as_deref();
+    let progress = cli.progress;
     match cli.command {
-        Commands::GenConfig { path, num_keepers, num_replicas } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.generate_config(num_keepers, num_replicas)
+        Commands::GenConfig {
+            path,
+            spec,
+            num_keepers,
+            num_replicas,
+            num_shards,
+            shard_weights,
+            zone_priorities,
+            macros,
+            server_macros,
+            log_level,
+            preset,
+            extra_config_dir,
+            keeper_template,
+            server_template,
+            mut external_keepers,
+            share_keepers_with,
+            process_env,
+            ulimit_nofile,
+            ulimit_core,
+            node_users,
+            backend,
+            docker_image,
+            hosts,
+            cluster_secret,
+            zookeeper_root,
+            replicated_database,
+            default_database,
+            hot_cold_storage,
+            minio,
+            kafka,
+            rabbitmq,
+            system_logs,
+            keeper_http_control,
+            keeper_four_letter_words,
+            async_insert,
+            free_ports,
+            cores_per_node,
+            numa_nodes,
+            bind_host,
+            advertise_host,
+            base_port_offset,
+            keeper_base_port,
+            raft_base_port,
+            tcp_base_port,
+            http_base_port,
+            interserver_base_port,
+            clickhouse_metrics_base_port,
+            keeper_metrics_base_port,
+            keeper_http_control_base_port,
+            interserver_user,
+            interserver_password,
+            dry_run,
+        } => {
+            // The config is saved, so make sure the path works from anywhere
+            let extra_config_dir = extra_config_dir
+                .map(|dir| dir.canonicalize_utf8())
+                .transpose()?;
+            let config_templates = ConfigTemplates {
+                keeper: keeper_template
+                    .map(|path| path.canonicalize_utf8())
+                    .transpose()?,
+                server: server_template
+                    .map(|path| path.canonicalize_utf8())
+                    .transpose()?,
+            };
+            if let Some(other) = share_keepers_with {
+                let other = open(path.clone(), Some(&other), progress)?;
+                external_keepers.extend(other.keeper_client_configs()?);
+            }
+            let base = match cluster {
+                Some(name) if name != CLUSTER => {
+                    DeploymentConfig::new_cluster(&path, name)?
+                }
+                _ => DeploymentConfig::new_with_default_ports(path, CLUSTER),
+            };
+            let base = match preset {
+                Some(preset) => base.preset(preset.into()),
+                None => base,
+            };
+            let base = DeploymentConfig {
+                base_ports: base.base_ports.offset(base_port_offset)?,
+                ..base
+            };
+            // Keep MinIO clear of the ports of other named clusters
+            let minio_config = minio.then(|| {
+                MinioConfig::new(
+                    DEFAULT_MINIO_PORT + base.base_ports.keeper
+                        - DEFAULT_BASE_PORTS.keeper,
+                )
+            });
+            let base_ports = BasePorts {
+                keeper: keeper_base_port.unwrap_or(base.base_ports.keeper),
+                raft: raft_base_port.unwrap_or(base.base_ports.raft),
+                clickhouse_tcp: tcp_base_port
+                    .unwrap_or(base.base_ports.clickhouse_tcp),
+                clickhouse_http: http_base_port
+                    .unwrap_or(base.base_ports.clickhouse_http),
+                clickhouse_interserver_http: interserver_base_port
+                    .unwrap_or(base.base_ports.clickhouse_interserver_http),
+                clickhouse_metrics: clickhouse_metrics_base_port
+                    .unwrap_or(base.base_ports.clickhouse_metrics),
+                keeper_metrics: keeper_metrics_base_port
+                    .unwrap_or(base.base_ports.keeper_metrics),
+                keeper_http_control: keeper_http_control_base_port
+                    .or(base.base_ports.keeper_http_control),
+            };
+            let mut macros_by_server: BTreeMap<_, BTreeMap<_, _>> =
+                BTreeMap::new();
+            for (id, name, value) in server_macros {
+                macros_by_server.entry(id).or_default().insert(name, value);
+            }
+            let config = DeploymentConfig {
+                log_level: log_level.unwrap_or(base.log_level),
+                extra_config_dir,
+                config_templates,
+                external_keepers,
+                process_env: process_env.into_iter().collect(),
+                process_limits: ProcessLimits {
+                    nofile: ulimit_nofile,
+                    core: ulimit_core,
+                    virtual_memory: None,
+                },
+                node_process: node_users
+                    .into_iter()
+                    .map(|(node, user)| {
+                        let process = NodeProcessConfig {
+                            user: Some(user),
+                            ..Default::default()
+                        };
+                        (node, process)
+                    })
+                    .collect(),
+                backend: match backend {
+                    BackendKind::Process => Backend::Process,
+                    BackendKind::Docker => Backend::Docker {
+                        image: docker_image.unwrap_or_else(|| {
+                            DEFAULT_DOCKER_IMAGE.to_string()
+                        }),
+                    },
+                    BackendKind::Ssh => {
+                        Backend::Ssh { hosts, node_hosts: BTreeMap::new() }
+                    }
+                },
+                cluster_secret,
+                zookeeper_root,
+                replicated_database,
+                default_database,
+                storage: minio_config
+                    .as_ref()
+                    .map(|minio| StorageConfig::s3(minio.disk("s3"), true))
+                    .or_else(|| hot_cold_storage.then(StorageConfig::hot_cold)),
+                minio: minio_config,
+                kafka: kafka.map(BrokerConfig::new),
+                rabbitmq: rabbitmq.map(BrokerConfig::new),
+                system_logs,
+                keeper_http_control,
+                keeper_four_letter_words,
+                async_insert,
+                free_ports,
+                cpu_pinning: match (cores_per_node, numa_nodes) {
+                    (Some(cores_per_node), _) => {
+                        Some(PinningPolicy::Exclusive {
+                            cores_per_node,
+                            first_core: 0,
+                        })
+                    }
+                    (None, Some(nodes)) => Some(PinningPolicy::Numa { nodes }),
+                    (None, None) => None,
+                },
+                bind_host,
+                advertise_host,
+                num_shards,
+                shard_weights,
+                zone_priorities: zone_priorities.into_iter().collect(),
+                extra_macros: macros.into_iter().collect(),
+                server_macros: macros_by_server,
+                interserver_credentials: interserver_user
+                    .zip(interserver_password)
+                    .map(|(user, password)| InterserverCredentials {
+                        user,
+                        password,
+                    }),
+                base_ports,
+                ..base
+            };
+            let (config, num_keepers, num_servers) = match spec {
+                Some(spec) => {
+                    let spec = DeploymentSpec::load(&spec)?;
+                    (spec.apply(config), spec.keepers, spec.num_servers())
+                }
+                None => {
+                    let Some(num_replicas) = num_replicas else {
+                        anyhow::bail!("--num-replicas is required");
+                    };
+                    let num_servers = num_replicas * num_shards.unwrap_or(1);
+                    (config, num_keepers.unwrap_or(0), num_servers)
+                }
+            };
+            let mut d = with_progress(Deployment::new(config), progress);
+            if dry_run {
+                let plan = d.plan_generate_config(num_keepers, num_servers)?;
+                println!("{plan}");
+                return Ok(());
+            }
+            d.generate_config(num_keepers, num_servers)
+        }
+        Commands::RegenConfig { path } => {
+            let d = open(path, cluster, progress)?;
+            d.regenerate_configs()
+        }
+        Commands::ExportSpec { path } => {
+            let d = open(path, cluster, progress)?;
+            let spec = d.to_spec()?;
+            println!("{}", serde_json::to_string_pretty(&spec)?);
+            Ok(())
+        }
+        Commands::DiffConfig { path } => {
+            let d = open(path, cluster, progress)?;
+            let diffs = d.diff_configs()?;
+            if diffs.is_empty() {
+                println!("All configs are up to date");
+            }
+            for diff in diffs {
+                print!("{}", diff.diff);
+            }
+            Ok(())
+        }
+        Commands::Validate { path, repair } => {
+            let d = open(path, cluster, progress)?;
+            let inconsistencies = d.validate(repair)?;
+            for inconsistency in &inconsistencies {
+                println!("{inconsistency}");
+            }
+            if inconsistencies.is_empty() {
+                println!("Deployment is consistent");
+            } else if !repair {
+                anyhow::bail!(
+                    "Found {} inconsistencies",
+                    inconsistencies.len()
+                );
+            }
+            Ok(())
+        }
+        Commands::Recover { path, rollback } => {
+            let mut d = open(path, cluster, progress)?;
+            let recovery =
+                if rollback { Recovery::RollBack } else { Recovery::Complete };
+            let entries = d.recover(recovery)?;
+            if entries.is_empty() {
+                println!("No interrupted membership change");
+            }
+            for entry in entries {
+                println!("Recovered interrupted {entry}");
+            }
+            Ok(())
         }
         Commands::Deploy { path } => {
-            let d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.deploy()
+            let d = open(path, cluster, progress)?;
+            d.deploy(&OperationContext::default())?;
+            #[cfg(feature = "http-client")]
+            {
+                let mut d = d;
+                let timeout = Duration::from_secs(60);
+                d.create_replicated_database(timeout).await?;
+                d.create_default_database(timeout).await?;
+            }
+            Ok(())
+        }
+        Commands::Start { path, timeout_secs } => {
+            let d = open(path, cluster, progress)?;
+            d.start_all(Duration::from_secs(timeout_secs)).await
+        }
+        Commands::Stop { path } => {
+            let d = open(path, cluster, progress)?;
+            d.stop_all()
+        }
+        Commands::StartNode { path, kind, id } => {
+            let d = open(path, cluster, progress)?;
+            let node = node_id(kind, id);
+            if d.is_alive(node) {
+                anyhow::bail!("{node} is already running");
+            }
+            match node {
+                NodeId::Keeper(id) => d.start_keeper(id),
+                NodeId::Server(id) => d.start_server(id),
+            }
+        }
+        Commands::StopNode { path, kind, id } => {
+            let d = open(path, cluster, progress)?;
+            match node_id(kind, id) {
+                NodeId::Keeper(id) => d.stop_keeper(id),
+                NodeId::Server(id) => d.stop_server(id),
+            }
+        }
+        Commands::SetZone { path, kind, id, zone } => {
+            let mut d = open(path, cluster, progress)?;
+            d.set_zone(node_id(kind, id), zone)
+        }
+        Commands::SetState { path, kind, id, state } => {
+            let mut d = open(path, cluster, progress)?;
+            d.set_admin_state(node_id(kind, id), state.into())
+        }
+        #[cfg(feature = "http-client")]
+        Commands::Drain { path, id, timeout_secs } => {
+            let mut d = open(path, cluster, progress)?;
+            d.drain(id, Duration::from_secs(timeout_secs)).await
+        }
+        Commands::SetClockSkew { path, kind, id, offset_secs } => {
+            let mut d = open(path, cluster, progress)?;
+            let node = node_id(kind, id);
+            d.set_clock_skew(node, offset_secs)?;
+            if d.is_alive(node) {
+                println!("Restart {node} for the clock skew to take effect");
+            }
+            Ok(())
+        }
+        Commands::DegradeLink {
+            path,
+            from_kind,
+            from_id,
+            to_kind,
+            to_id,
+            latency_ms,
+            loss_pct,
+        } => {
+            let mut d = open(path, cluster, progress)?;
+            d.degrade_link(
+                node_id(from_kind, from_id),
+                node_id(to_kind, to_id),
+                latency_ms,
+                loss_pct,
+            )
+        }
+        Commands::RestoreLinks { path } => {
+            let mut d = open(path, cluster, progress)?;
+            d.restore_links()
+        }
+        Commands::FillDisk { path, kind, id, leave_bytes } => {
+            let mut d = open(path, cluster, progress)?;
+            d.fill_disk(node_id(kind, id), leave_bytes)
+        }
+        Commands::UnfillDisk { path, kind, id } => {
+            let mut d = open(path, cluster, progress)?;
+            d.unfill_disk(node_id(kind, id))
+        }
+        Commands::Zones { path } => {
+            let d = open(path, cluster, progress)?;
+            for (zone, nodes) in d.zones() {
+                let nodes: Vec<_> =
+                    nodes.iter().map(|node| node.to_string()).collect();
+                println!("{zone}: {}", nodes.join(", "));
+            }
+            Ok(())
+        }
+        Commands::FailZone { path, zone } => {
+            let d = open(path, cluster, progress)?;
+            let stopped = d.fail_zone(&zone)?;
+            if stopped.is_empty() {
+                println!("No nodes in zone {zone} were running");
+            }
+            for node in stopped {
+                println!("Stopped {node}");
+            }
+            Ok(())
+        }
+        Commands::Status { path } => {
+            let d = open(path, cluster, progress)?;
+            println!("{}", NodeStatus::HEADER);
+            for status in d.statuses()? {
+                println!("{status}");
+                for line in &status.error_tail {
+                    println!("    {line}");
+                }
+            }
+            Ok(())
+        }
+        Commands::ClickhouseVersion { path } => {
+            let d = open(path, cluster, progress)?;
+            println!("installed: {}", d.clickhouse_version()?);
+            match d.generated_clickhouse_version() {
+                Some(version) => println!("generated for: {version}"),
+                None => println!("generated for: unknown"),
+            }
+            Ok(())
+        }
+        Commands::Top { path, interval_secs } => {
+            let d = open(path, cluster, progress)?;
+            loop {
+                println!("{}", NodeResourceUsage::HEADER);
+                for usage in d.resource_usage()? {
+                    println!("{usage}");
+                }
+                let Some(secs) = interval_secs else {
+                    return Ok(());
+                };
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                println!();
+            }
+        }
+        Commands::RenderConfig { path, out } => {
+            let d = open(path, cluster, progress)?;
+            d.write_rendered_configs(&out)
+        }
+        Commands::Endpoints { path, format } => {
+            let d = open(path, cluster, progress)?;
+            let format = format.unwrap_or(FormatKind::Env).into();
+            print!("{}", format_endpoints(&d.endpoints()?, format)?);
+            Ok(())
+        }
+        Commands::NodeInfo { path, kind, id } => {
+            let d = open(path, cluster, progress)?;
+            let info = d.node_info(node_id(kind, id))?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
+            Ok(())
+        }
+        Commands::Clean { path } => {
+            let mut d = open(path, cluster, progress)?;
+            d.clean()
+        }
+        Commands::Clone { path, dest, port_offset } => {
+            let d = open(path, cluster, progress)?;
+            let base_ports = d.config().base_ports.offset(port_offset)?;
+            let clone = d.clone_to(&cluster_root(dest, cluster), base_ports)?;
+            println!("Cloned to {}", clone.config().path);
+            Ok(())
+        }
+        Commands::ImportData { path, id, source } => {
+            let d = open(path, cluster, progress)?;
+            d.import_server_data(id, &source)
         }
         Commands::Teardown { path } => {
-            let d = Deployment::new_with_default_port_config(path, CLUSTER);
+            let d = open(path, cluster, progress)?;
             d.teardown()
         }
+        Commands::LinkRemote { path, other_path, other_cluster, alias } => {
+            let mut d = open(path, cluster, progress)?;
+            let other = open(other_path, other_cluster.as_deref(), progress)?;
+            d.link_remote(&other, &alias)
+        }
+        Commands::UnlinkRemote { path, alias } => {
+            let mut d = open(path, cluster, progress)?;
+            d.unlink_remote(&alias)
+        }
+        Commands::Clusters { path } => {
+            println!("{CLUSTER}");
+            for name in cluster_names(&path)? {
+                println!("{name}");
+            }
+            Ok(())
+        }
         Commands::Show { path } => {
-            let d = Deployment::new_with_default_port_config(path, CLUSTER);
+            let d = open(path, cluster, progress)?;
             match &d.meta() {
                 Some(meta) => println!("{:#?}", meta),
                 None => println!(
@@ -131,32 +2010,358 @@ async fn handle() -> anyhow::Result<()> {
             }
             Ok(())
         }
-        Commands::AddKeeper { path } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.add_keeper()
+        Commands::AddKeeper {
+            path,
+            observer,
+            count,
+            timeout_secs,
+            dry_run,
+        } => {
+            let mut d = open(path, cluster, progress)?;
+            if let Some(count) = count {
+                if dry_run {
+                    println!("{}", d.plan_add_keepers(count).await?);
+                } else {
+                    let timeout = Duration::from_secs(timeout_secs);
+                    let context = OperationContext::default();
+                    d.add_keepers(count, timeout, &context).await?;
+                }
+                Ok(())
+            } else if dry_run {
+                println!("{}", d.plan_add_keeper(observer)?);
+                Ok(())
+            } else if observer {
+                d.add_keeper_observer(&OperationContext::default())
+            } else {
+                d.add_keeper(&OperationContext::default())
+            }
+        }
+        Commands::RemoveKeeper { path, id, force, dry_run } => {
+            let mut d = open(path, cluster, progress)?;
+            if dry_run {
+                println!("{}", d.plan_remove_keeper(id, force).await?);
+                return Ok(());
+            }
+            d.remove_keeper_safely(id, force, &OperationContext::default())
+                .await
         }
-        Commands::RemoveKeeper { path, id } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.remove_keeper(id.into())
+        Commands::ReaddKeeper { path, id } => {
+            let mut d = open(path, cluster, progress)?;
+            d.readd_keeper(id, &OperationContext::default())
         }
         Commands::KeeperConfig { id } => {
             // Unused
             let dummy_path = ".".into();
             let d =
                 Deployment::new_with_default_port_config(dummy_path, CLUSTER);
-            let addr = d.keeper_addr(id.into())?;
+            let addr = d.keeper_addr(id)?;
             let zk = KeeperClient::new(addr);
             let output = zk.config().await?;
             println!("{output:#?}");
             Ok(())
         }
-        Commands::AddServer { path } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.add_server()
+        Commands::SetKeeperPriority { path, id, priority } => {
+            let mut d = open(path, cluster, progress)?;
+            d.set_keeper_priority(id, priority)
+        }
+        Commands::RequestLeadership { path, id } => {
+            let d = open(path, cluster, progress)?;
+            d.request_leadership(id).await
+        }
+        Commands::KeeperShell { path, id, commands } => {
+            let d = open(path, cluster, progress)?;
+            if !commands.is_empty() {
+                let commands: Vec<_> =
+                    commands.iter().map(|c| c.as_str()).collect();
+                print!("{}", d.keeper_client_run(id, &commands).await?);
+                return Ok(());
+            }
+            exec(d.keeper_shell(id)?)
+        }
+        Commands::Shell { path, id, database } => {
+            let d = open(path, cluster, progress)?;
+            exec(d.server_shell(id, database.as_deref())?)
+        }
+        Commands::KeeperStorage { path, id } => {
+            let d = open(path, cluster, progress)?;
+            let stats = d.keeper_storage_stats(id).await?;
+            println!("{stats:#?}");
+            Ok(())
+        }
+        Commands::CleanKeeperLogs { path, id, keep } => {
+            let d = open(path, cluster, progress)?;
+            let cleanup = d.clean_keeper_logs(id, keep)?;
+            println!(
+                "Removed {} snapshots and {} log segments",
+                cleanup.snapshots_removed, cleanup.log_segments_removed
+            );
+            Ok(())
+        }
+        Commands::ExportKeeperState { path, id, dest } => {
+            let d = open(path, cluster, progress)?;
+            d.export_keeper_state(id, &dest)
+        }
+        Commands::ImportKeeperState { path, id, src } => {
+            let d = open(path, cluster, progress)?;
+            d.import_keeper_state(id, &src)
+        }
+        Commands::AddServer { path, count, timeout_secs, dry_run } => {
+            let mut d = open(path, cluster, progress)?;
+            if let Some(count) = count {
+                if dry_run {
+                    println!("{}", d.plan_add_servers(count).await?);
+                } else {
+                    let timeout = Duration::from_secs(timeout_secs);
+                    let context = OperationContext::default();
+                    d.add_servers(count, timeout, &context).await?;
+                }
+                return Ok(());
+            }
+            if dry_run {
+                println!("{}", d.plan_add_server()?);
+                return Ok(());
+            }
+            d.add_server(&OperationContext::default())
+        }
+        Commands::RemoveServer {
+            path,
+            id,
+            dry_run,
+            check_replication,
+            drop_replica,
+        } => {
+            let mut d = open(path, cluster, progress)?;
+            #[cfg(not(feature = "http-client"))]
+            if check_replication || drop_replica {
+                anyhow::bail!(
+                    "--check-replication and --drop-replica need the \
+                     http-client feature"
+                );
+            }
+            #[cfg(feature = "http-client")]
+            if check_replication {
+                d.check_server_removal(id).await?;
+            }
+            if dry_run {
+                println!("{}", d.plan_remove_server(id)?);
+                return Ok(());
+            }
+            d.remove_server(id, &OperationContext::default())?;
+            #[cfg(feature = "http-client")]
+            if drop_replica {
+                d.drop_server_replica(id).await?;
+            }
+            Ok(())
+        }
+        Commands::ReaddServer { path, id } => {
+            let mut d = open(path, cluster, progress)?;
+            d.readd_server(id, &OperationContext::default())
+        }
+        Commands::Scale { path, servers, keepers, timeout_secs } => {
+            let mut d = open(path, cluster, progress)?;
+            let timeout = Duration::from_secs(timeout_secs);
+            d.scale_to(servers, keepers, timeout, &OperationContext::default())
+                .await
+        }
+        Commands::Logs { path, id, kind, errors, lines, follow } => {
+            let d = open(path, cluster, progress)?;
+            let filter =
+                LogFilter { id, kind: kind.map(Into::into), errors, lines };
+            if follow {
+                d.follow_logs(&filter, |line| println!("{line}")).await
+            } else {
+                for line in d.logs(&filter)? {
+                    println!("{line}");
+                }
+                Ok(())
+            }
+        }
+        Commands::Attach { path } => {
+            let d = Deployment::attach(cluster_root(path, cluster), CLUSTER)?;
+            let meta = d.meta().as_ref().expect("attached");
+            println!(
+                "Attached {} keepers and {} servers",
+                meta.keeper_ids.len(),
+                meta.server_ids.len()
+            );
+            Ok(())
+        }
+        Commands::Snapshot { path, name } => {
+            let d = open(path, cluster, progress)?;
+            d.snapshot(&name)
+        }
+        Commands::Restore { path, name } => {
+            let mut d = open(path, cluster, progress)?;
+            d.restore(&name)
+        }
+        Commands::Snapshots { path } => {
+            let d = open(path, cluster, progress)?;
+            for name in d.snapshots()? {
+                println!("{name}");
+            }
+            Ok(())
+        }
+        Commands::RollingUpgrade { path, binary, timeout_secs } => {
+            let mut d = open(path, cluster, progress)?;
+            let binary = binary.canonicalize_utf8()?;
+            d.rolling_upgrade(&binary, Duration::from_secs(timeout_secs)).await
+        }
+        Commands::Supervise { path, max_restarts } => {
+            let d = open(path, cluster, progress)?;
+            let policy = RestartPolicy { max_restarts, ..Default::default() };
+            d.supervise(&policy).await
+        }
+        Commands::History { path } => {
+            let d = open(path, cluster, progress)?;
+            for entry in d.history()? {
+                println!("{entry}");
+            }
+            Ok(())
+        }
+        #[cfg(feature = "http-client")]
+        Commands::Backup { path, database, table, name } => {
+            let mut d = open(path, cluster, progress)?;
+            let target = match table {
+                Some(table) => BackupTarget::Table { database, table },
+                None => BackupTarget::Database(database),
+            };
+            d.backup(target, &name).await
+        }
+        #[cfg(feature = "http-client")]
+        Commands::RestoreBackup { path, name } => {
+            let d = open(path, cluster, progress)?;
+            d.restore_backup(&name).await
+        }
+        Commands::Backups { path } => {
+            let d = open(path, cluster, progress)?;
+            let Some(meta) = d.meta() else {
+                anyhow::bail!(
+                    "No deployment generated: Please call `gen-config`"
+                );
+            };
+            for (name, backup) in &meta.backups {
+                let servers: Vec<_> =
+                    backup.servers.iter().map(|id| id.to_string()).collect();
+                println!(
+                    "{name}: {} on servers {}",
+                    backup.target,
+                    servers.join(", ")
+                );
+            }
+            Ok(())
+        }
+        #[cfg(feature = "http-client")]
+        Commands::Seed { path, schema, timeout_secs } => {
+            let mut d = open(path, cluster, progress)?;
+            d.seed(&schema, Duration::from_secs(timeout_secs)).await?;
+            Ok(())
+        }
+        Commands::Bench {
+            path,
+            query_file,
+            concurrency,
+            iterations,
+            time_limit_secs,
+            id,
+            output,
+        } => {
+            let d = open(path, cluster, progress)?;
+            let options = BenchOptions {
+                query_file,
+                concurrency,
+                iterations,
+                time_limit_secs,
+                server: id,
+            };
+            let report = d.bench(&options)?;
+            for (node, result) in &report {
+                let percentile = |k: &str| {
+                    result.latency_percentiles.get(k).copied().unwrap_or(0.0)
+                };
+                println!(
+                    "{node}: {:.1} QPS, {} queries, {} errors, \
+                     p50 {:.3}s, p90 {:.3}s, p99 {:.3}s",
+                    result.qps,
+                    result.num_queries,
+                    result.num_errors,
+                    percentile("50"),
+                    percentile("90"),
+                    percentile("99")
+                );
+            }
+            if let Some(output) = output {
+                std::fs::write(
+                    &output,
+                    serde_json::to_string_pretty(&report)?,
+                )?;
+            }
+            Ok(())
+        }
+        #[cfg(feature = "http-client")]
+        Commands::Reload { path, kind, id } => {
+            let d = open(path, cluster, progress)?;
+            match kind.zip(id) {
+                Some((kind, id)) => d.reload_config(node_id(kind, id)).await,
+                None => d.reload_configs().await,
+            }
+        }
+        #[cfg(feature = "http-client")]
+        Commands::ReplStatus { path } => {
+            let d = open(path, cluster, progress)?;
+            let status = d.replication_status().await?;
+            println!(
+                "{:<8} {:<40} {:>8} {:>6} {:>7} {:>6} {:>8} {:>7}",
+                "SERVER",
+                "TABLE",
+                "READONLY",
+                "DELAY",
+                "QUEUE",
+                "FAILED",
+                "LOG LAG",
+                "ACTIVE"
+            );
+            for r in &status.replicas {
+                println!(
+                    "{:<8} {:<40} {:>8} {:>6} {:>7} {:>6} {:>8} {:>7}",
+                    r.server,
+                    format!("{}.{}", r.database, r.table),
+                    r.is_readonly,
+                    r.absolute_delay,
+                    r.queue_size,
+                    r.failed_in_queue,
+                    r.log_lag,
+                    format!("{}/{}", r.active_replicas, r.total_replicas)
+                );
+            }
+            for id in &status.unreachable {
+                println!("clickhouse server {id} is unreachable");
+            }
+            Ok(())
+        }
+        #[cfg(feature = "http-client")]
+        Commands::Loadgen { path, table, rows, batch_size, parallel } => {
+            let d = open(path, cluster, progress)?;
+            let report =
+                d.insert_random(&table, rows, batch_size, parallel).await?;
+            println!(
+                "Inserted {} rows through {} servers in {:.2?} ({:.0} rows/s)",
+                report.rows,
+                report.servers,
+                report.elapsed,
+                report.rows_per_sec()
+            );
+            Ok(())
         }
-        Commands::RemoveServer { path, id } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.remove_server(id.into())
+        #[cfg(feature = "http-client")]
+        Commands::QueryLog { path, id, contains, errors, limit } => {
+            let d = open(path, cluster, progress)?;
+            let filter =
+                QueryLogFilter { query_contains: contains, errors, limit };
+            println!("{}", QueryLogEntry::HEADER);
+            for entry in d.query_log(id, &filter).await? {
+                println!("{entry}");
+            }
+            Ok(())
         }
     }
 }
@@ -0,0 +1,423 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Injecting network and disk faults into nodes
+
+use crate::clone::copy_tree;
+use crate::{Deployment, NodeId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::process::{Command, Stdio};
+use tracing::info;
+
+/// The interface that all local nodes talk to each other over
+const LOOPBACK: &str = "lo";
+
+/// `prio` qdiscs have at most 16 bands, the first 3 of which carry traffic
+/// that isn't degraded
+const MAX_DEGRADED_LINKS: usize = 13;
+
+/// The filesystem image mounted over the data path of a node by `fill_disk`
+const DISK_IMAGE_FILENAME: &str = "disk.img";
+
+/// The file that takes up the space of a filesystem mounted by `fill_disk`
+const FILLER_FILENAME: &str = "clickward-filler";
+
+/// Space in the filesystem mounted by `fill_disk` beyond the node's data and
+/// the free space left, for filesystem metadata
+const DISK_IMAGE_HEADROOM: u64 = 64 * 1024 * 1024;
+
+/// A link between two nodes degraded with `Deployment::degrade_link`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DegradedLink {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// Delay added to every packet
+    pub latency_ms: u32,
+    /// Percentage of packets dropped
+    pub loss_pct: f64,
+}
+
+impl Deployment {
+    /// Return the links degraded with `degrade_link`
+    pub fn degraded_links(&self) -> &[DegradedLink] {
+        self.meta
+            .as_ref()
+            .map(|meta| meta.degraded_links.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Delay packets sent from `from` to `to` by `latency_ms` and drop
+    /// `loss_pct` percent of them, replacing any earlier degradation of the
+    /// same link
+    ///
+    /// This uses `tc netem` on the loopback interface, so it needs Linux and
+    /// `CAP_NET_ADMIN`, and nodes running on this host. As all local nodes
+    /// share an address and connect from ephemeral ports, packets can only be
+    /// matched by the ports of `to`, so its traffic from every other node is
+    /// degraded too. For the same reason, a link to a node that already has a
+    /// degraded link from another node is rejected. Our `tc` config replaces
+    /// any other on the loopback interface, including that of other
+    /// deployments.
+    pub fn degrade_link(
+        &mut self,
+        from: NodeId,
+        to: NodeId,
+        latency_ms: u32,
+        loss_pct: f64,
+    ) -> Result<()> {
+        if !(0.0..=100.0).contains(&loss_pct) {
+            bail!("packet loss must be a percentage: {loss_pct}");
+        }
+        if from == to {
+            bail!("cannot degrade the link from {from} to itself");
+        }
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        for node in [from, to] {
+            let exists = match node {
+                NodeId::Keeper(id) => meta.keeper_ids.contains(&id),
+                NodeId::Server(id) => meta.server_ids.contains(&id),
+            };
+            if !exists {
+                bail!("No such node: {node}");
+            }
+        }
+        let mut links = meta.degraded_links.clone();
+        if let Some(other) = links.iter().find(|l| l.to == to && l.from != from)
+        {
+            bail!(
+                "the link from {} to {to} is already degraded, and links to \
+                 the same node can't be degraded separately",
+                other.from
+            );
+        }
+        let link = DegradedLink { from, to, latency_ms, loss_pct };
+        match links.iter().position(|l| l.from == from && l.to == to) {
+            Some(i) => links[i] = link,
+            None if links.len() >= MAX_DEGRADED_LINKS => {
+                bail!(
+                    "at most {MAX_DEGRADED_LINKS} links can be degraded at once"
+                );
+            }
+            None => links.push(link),
+        }
+        // Only keep the link once `tc` degraded it
+        self.apply_degraded_links(&links)?;
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.degraded_links = links;
+        self.writer.save_meta(meta, &self.config.path)?;
+        self.record(
+            "degrade-link",
+            serde_json::json!({
+                "from": from.to_string(),
+                "to": to.to_string(),
+                "latency_ms": latency_ms,
+                "loss_pct": loss_pct,
+            }),
+        )
+    }
+
+    /// Remove the degradation of every link
+    pub fn restore_links(&mut self) -> Result<()> {
+        if self.meta.is_none() {
+            bail!(MISSING_META);
+        }
+        self.apply_degraded_links(&[])?;
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.degraded_links.clear();
+        self.writer.save_meta(meta, &self.config.path)?;
+        self.record("restore-links", serde_json::Value::Null)
+    }
+
+    /// Return the directory that a node keeps its data in
+    fn node_data_path(&self, node: NodeId) -> Utf8PathBuf {
+        match node {
+            NodeId::Keeper(id) => self.keeper_dir(id).join("coordination"),
+            NodeId::Server(id) => self.server_dir(id).join("data"),
+        }
+    }
+
+    /// Return true if `fill_disk` mounted a filesystem over the data path of
+    /// a node that `unfill_disk` hasn't removed since
+    pub fn is_disk_filled(&self, node: NodeId) -> bool {
+        self.meta
+            .as_ref()
+            .is_some_and(|meta| meta.filled_disks.contains(&node.to_string()))
+    }
+
+    /// Leave only `leave_bytes` of free space for the data of a node, so
+    /// that its disk fills up
+    ///
+    /// The first time, the node's data is moved to a small filesystem that
+    /// is loop mounted over its data path, with the node stopped while this
+    /// happens and started again afterwards. A file then takes up all but
+    /// `leave_bytes` of the filesystem, and later calls only resize it.
+    /// Mounting needs Linux and root, and `unfill_disk` undoes it all.
+    pub fn fill_disk(&mut self, node: NodeId, leave_bytes: u64) -> Result<()> {
+        self.ensure_local_linux_node(node)?;
+        let data = self.node_data_path(node);
+        if !self.is_disk_filled(node) {
+            let _span = self.span("fill-disk").entered();
+            let was_alive = self.is_alive(node);
+            if was_alive {
+                self.stop_node(node)?;
+            }
+            self.mount_disk_image(node, &data, leave_bytes)?;
+            let Some(meta) = &mut self.meta else {
+                bail!(MISSING_META);
+            };
+            meta.filled_disks.insert(node.to_string());
+            self.writer.save_meta(meta, &self.config.path)?;
+            if was_alive {
+                self.start_node(node)?;
+            }
+        }
+
+        let filler = data.join(FILLER_FILENAME);
+        if filler.exists() {
+            std::fs::remove_file(&filler)
+                .with_context(|| format!("failed to remove {filler}"))?;
+        }
+        let fill = available_bytes(&data)?.saturating_sub(leave_bytes);
+        info!(%node, fill, leave_bytes, "Filling disk");
+        if fill > 0 {
+            run("fallocate", &["-l", &fill.to_string(), filler.as_str()])?;
+        }
+        self.record(
+            "fill-disk",
+            serde_json::json!({
+                "node": node.to_string(),
+                "leave_bytes": leave_bytes,
+            }),
+        )
+    }
+
+    /// Undo `fill_disk`, moving the data of a node back to its own directory
+    ///
+    /// The node is stopped while this happens and started again afterwards.
+    pub fn unfill_disk(&mut self, node: NodeId) -> Result<()> {
+        if !self.is_disk_filled(node) {
+            bail!("The disk of {node} was not filled");
+        }
+        self.ensure_local_linux_node(node)?;
+        let _span = self.span("unfill-disk").entered();
+        let was_alive = self.is_alive(node);
+        if was_alive {
+            self.stop_node(node)?;
+        }
+
+        let data = self.node_data_path(node);
+        let image = data.with_file_name(DISK_IMAGE_FILENAME);
+        let staging = data.with_extension("unfill");
+        info!(%node, %data, "Unmounting filled disk");
+        // Copying back rather than renaming points symlinks at `data` again
+        copy_tree(&data, &staging, &|path| path.as_str() == FILLER_FILENAME)?;
+        run("umount", &[data.as_str()])?;
+        copy_tree(&staging, &data, &|_| false)?;
+        std::fs::remove_dir_all(&staging)
+            .with_context(|| format!("failed to remove {staging}"))?;
+        std::fs::remove_file(&image)
+            .with_context(|| format!("failed to remove {image}"))?;
+
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.filled_disks.remove(&node.to_string());
+        self.writer.save_meta(meta, &self.config.path)?;
+        if was_alive {
+            self.start_node(node)?;
+        }
+        self.record(
+            "unfill-disk",
+            serde_json::json!({ "node": node.to_string() }),
+        )
+    }
+
+    /// Move the contents of `data` onto a new filesystem image mounted over
+    /// it, with room for `leave_bytes` more
+    fn mount_disk_image(
+        &self,
+        node: NodeId,
+        data: &Utf8Path,
+        leave_bytes: u64,
+    ) -> Result<()> {
+        let image = data.with_file_name(DISK_IMAGE_FILENAME);
+        let staging = data.with_extension("fill");
+        std::fs::create_dir_all(data)?;
+        let size = used_bytes(data)? + leave_bytes + DISK_IMAGE_HEADROOM;
+        info!(%node, %image, size, "Mounting disk image");
+
+        std::fs::File::create(&image)
+            .and_then(|file| file.set_len(size))
+            .with_context(|| format!("failed to create {image}"))?;
+        // Keep the filesystem owned by whoever owns the node's directory
+        #[cfg(unix)]
+        let root_owner = {
+            use std::os::unix::fs::MetadataExt;
+            let owner = data.metadata()?;
+            format!("root_owner={}:{}", owner.uid(), owner.gid())
+        };
+        #[cfg(not(unix))]
+        let root_owner = String::new();
+        run("mkfs.ext4", &["-q", "-F", "-E", &root_owner, image.as_str()])?;
+
+        std::fs::rename(data, &staging)
+            .with_context(|| format!("failed to move {data} to {staging}"))?;
+        std::fs::create_dir(data)?;
+        run("mount", &["-o", "loop", image.as_str(), data.as_str()])?;
+        // ext4 creates `lost+found`, which clickhouse would take for a
+        // database
+        let lost_found = data.join("lost+found");
+        if lost_found.exists() {
+            std::fs::remove_dir(&lost_found)?;
+        }
+        copy_tree(&staging, data, &|_| false)?;
+        std::fs::remove_dir_all(&staging)
+            .with_context(|| format!("failed to remove {staging}"))?;
+        Ok(())
+    }
+
+    fn ensure_local_linux_node(&self, node: NodeId) -> Result<()> {
+        if !cfg!(target_os = "linux") {
+            bail!("Filling disks needs loop mounts, which are Linux only");
+        }
+        if !self.config.backend.is_local() {
+            bail!("Filling disks needs nodes on this host");
+        }
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let exists = match node {
+            NodeId::Keeper(id) => meta.keeper_ids.contains(&id),
+            NodeId::Server(id) => meta.server_ids.contains(&id),
+        };
+        if !exists {
+            bail!("No such node: {node}");
+        }
+        Ok(())
+    }
+
+    fn stop_node(&self, node: NodeId) -> Result<()> {
+        match node {
+            NodeId::Keeper(id) => self.stop_keeper(id),
+            NodeId::Server(id) => self.stop_server(id),
+        }
+    }
+
+    fn start_node(&self, node: NodeId) -> Result<()> {
+        match node {
+            NodeId::Keeper(id) => self.start_keeper(id),
+            NodeId::Server(id) => self.start_server(id),
+        }
+    }
+
+    /// Replace the `tc` config of the loopback interface with one that
+    /// degrades `links`
+    ///
+    /// Each link gets its own band of a `prio` qdisc with a `netem` qdisc
+    /// below it, and filters that send packets to the ports of the link's
+    /// destination to that band. Other packets use the default bands.
+    fn apply_degraded_links(&self, links: &[DegradedLink]) -> Result<()> {
+        if !cfg!(target_os = "linux") {
+            bail!("Degrading links needs `tc`, which is only on Linux");
+        }
+        if !self.config.backend.is_local() {
+            bail!("Degrading links needs all nodes on this host");
+        }
+        // This fails if there's no qdisc to remove
+        let _ = tc(&["qdisc", "del", "dev", LOOPBACK, "root"]);
+        if links.is_empty() {
+            return Ok(());
+        }
+
+        let bands = (3 + links.len()).to_string();
+        let mut root =
+            vec!["qdisc", "add", "dev", LOOPBACK, "root", "handle", "1:"];
+        root.extend(["prio", "bands", &bands, "priomap"]);
+        // The default priomap, which only uses the first 3 bands
+        root.extend("1 2 2 2 1 2 0 0 1 1 1 1 1 1 1 1".split(' '));
+        tc(&root)?;
+
+        for (i, link) in links.iter().enumerate() {
+            info!(
+                from = %link.from,
+                to = %link.to,
+                latency_ms = link.latency_ms,
+                loss_pct = link.loss_pct,
+                "Degrading link"
+            );
+            // Bands are numbered from 1 in class ids
+            let band = i + 4;
+            let class = format!("1:{band:x}");
+            let handle = format!("{band:x}0:");
+            let delay = format!("{}ms", link.latency_ms);
+            let loss = format!("{}%", link.loss_pct);
+            tc(&[
+                "qdisc", "add", "dev", LOOPBACK, "parent", &class, "handle",
+                &handle, "netem", "delay", &delay, "loss", &loss,
+            ])?;
+
+            let ports = match link.to {
+                NodeId::Keeper(id) => self.node_ports(&[id], &[])?,
+                NodeId::Server(id) => self.node_ports(&[], &[id])?,
+            };
+            for port in ports {
+                let port = port.port.to_string();
+                for (protocol, matcher) in [("ip", "ip"), ("ipv6", "ip6")] {
+                    tc(&[
+                        "filter", "add", "dev", LOOPBACK, "parent", "1:",
+                        "protocol", protocol, "u32", "match", matcher, "dport",
+                        &port, "0xffff", "flowid", &class,
+                    ])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn tc(args: &[&str]) -> Result<()> {
+    run("tc", args).map(|_| ())
+}
+
+/// Run `program` with `args` to completion, returning its stdout
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {program}"))?;
+    if !output.status.success() {
+        bail!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Return the bytes available to us on the filesystem of `path`
+fn available_bytes(path: &Utf8Path) -> Result<u64> {
+    let out = run("df", &["--output=avail", "-B1", path.as_str()])?;
+    let avail = out.lines().nth(1).unwrap_or_default().trim();
+    avail
+        .parse()
+        .with_context(|| format!("unexpected df output for {path}: {out}"))
+}
+
+/// Return the bytes used by the files below `path`
+fn used_bytes(path: &Utf8Path) -> Result<u64> {
+    let out = run("du", &["-s", "-B1", path.as_str()])?;
+    let used = out.split_whitespace().next().unwrap_or_default();
+    used.parse()
+        .with_context(|| format!("unexpected du output for {path}: {out}"))
+}
@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::process::{STDERR_FILENAME, STDOUT_FILENAME};
+use crate::status::LIFECYCLE_FILENAME;
+use crate::{Deployment, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use tracing::info;
+
+impl Deployment {
+    /// Stop all nodes and remove everything they wrote, keeping generated
+    /// configs and metadata so the same topology can be deployed again from
+    /// scratch
+    ///
+    /// This removes server data and disks, keeper coordination logs and
+    /// snapshots, node logs and output, and the contents of the MinIO
+    /// bucket.
+    pub fn clean(&mut self) -> Result<()> {
+        self.stop_all()?;
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+
+        let mut dirs = vec![];
+        for &id in &meta.keeper_ids {
+            let dir = self.keeper_dir(id);
+            self.reclaim_node_dir(&format!("keeper-{id}"), &dir)?;
+            dirs.push((dir.join("coordination"), false));
+            dirs.push((dir.join("logs"), true));
+            remove_output(&dir)?;
+        }
+        for &id in &meta.server_ids {
+            let dir = self.server_dir(id);
+            self.reclaim_node_dir(&format!("clickhouse-{id}"), &dir)?;
+            dirs.push((dir.join("data"), false));
+            dirs.push((dir.join("logs"), true));
+            if let Some(storage) = &self.config.storage {
+                for path in storage.for_node(&dir).local_paths() {
+                    dirs.push((path.to_path_buf(), true));
+                }
+            }
+            remove_output(&dir)?;
+        }
+        if let Some(minio) = &self.config.minio {
+            let bucket = self.minio_dir().join("data").join(&minio.bucket);
+            dirs.push((bucket, true));
+            remove_output(&self.minio_dir())?;
+        }
+        for (dir, recreate) in dirs {
+            if dir.exists() {
+                info!(%dir, "Removing");
+                std::fs::remove_dir_all(&dir)
+                    .with_context(|| format!("failed to remove {dir}"))?;
+            }
+            if recreate {
+                std::fs::create_dir_all(&dir)?;
+            }
+        }
+
+        // The schema is gone with the data, so it must be seeded again
+        if let Some(meta) = &mut self.meta {
+            meta.seed_hash = None;
+            self.writer.save_meta(meta, &self.config.path)?;
+        }
+        self.record("clean", serde_json::Value::Null)
+    }
+}
+
+/// Remove the stdout, stderr, and lifecycle files of the node in `dir`
+fn remove_output(dir: &Utf8Path) -> Result<()> {
+    for name in [STDOUT_FILENAME, STDERR_FILENAME, LIFECYCLE_FILENAME] {
+        let path = dir.join(name);
+        if path.exists() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to remove {path}"))?;
+        }
+    }
+    Ok(())
+}
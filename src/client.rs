@@ -0,0 +1,127 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, ServerId};
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error("query error: query = {query}, error = {error}")]
+    Query { query: String, error: String },
+
+    #[error("failed to parse row: {row}")]
+    Parse {
+        row: String,
+        #[source]
+        error: serde_json::Error,
+    },
+}
+
+/// A client for running queries against a clickhouse server over its HTTP
+/// interface
+#[derive(Debug, Clone)]
+pub struct ClickhouseClient {
+    addr: SocketAddr,
+    client: reqwest::Client,
+}
+
+impl ClickhouseClient {
+    pub fn new(addr: SocketAddr) -> ClickhouseClient {
+        ClickhouseClient { addr, client: reqwest::Client::new() }
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Run a statement and return the raw response body
+    ///
+    /// This is suitable for statements that don't return rows, such as DDL
+    /// and inserts.
+    pub async fn execute(&self, sql: &str) -> Result<String, ClientError> {
+        self.execute_with_settings(sql, &[]).await
+    }
+
+    /// Run a statement with the given clickhouse settings applied
+    pub async fn execute_with_settings(
+        &self,
+        sql: &str,
+        settings: &[(&str, &str)],
+    ) -> Result<String, ClientError> {
+        let response = self
+            .client
+            .post(format!("http://{}/", self.addr))
+            .query(settings)
+            .body(sql.to_string())
+            .send()
+            .await?;
+        let success = response.status().is_success();
+        let body = response.text().await?;
+        if !success {
+            return Err(ClientError::Query {
+                query: sql.to_string(),
+                error: body.trim_end().to_string(),
+            });
+        }
+        Ok(body)
+    }
+
+    /// Run a query with `FORMAT JSONEachRow` appended to it
+    pub async fn query(&self, sql: &str) -> Result<QueryResult, ClientError> {
+        let sql = sql.trim_end().trim_end_matches(';');
+        let body = self.execute(&format!("{sql} FORMAT JSONEachRow")).await?;
+        Ok(QueryResult { body })
+    }
+}
+
+/// The output of a query run in the `JSONEachRow` format
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    body: String,
+}
+
+impl QueryResult {
+    /// The response body, with one JSON object per row
+    pub fn raw(&self) -> &str {
+        &self.body
+    }
+
+    /// Deserialize each row into a `T`
+    pub fn rows<T: DeserializeOwned>(&self) -> Result<Vec<T>, ClientError> {
+        self.body
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|row| {
+                serde_json::from_str(row).map_err(|error| ClientError::Parse {
+                    row: row.to_string(),
+                    error,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Deployment {
+    /// Return a client for the HTTP interface of a given server id
+    pub fn http_client(
+        &self,
+        id: ServerId,
+    ) -> anyhow::Result<ClickhouseClient> {
+        Ok(ClickhouseClient::new(self.http_addr(id)?))
+    }
+
+    /// Run a query against the HTTP interface of a given server id
+    pub async fn query_http(
+        &self,
+        id: ServerId,
+        sql: &str,
+    ) -> anyhow::Result<QueryResult> {
+        Ok(self.http_client(id)?.query(sql).await?)
+    }
+}
@@ -0,0 +1,73 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Backend, Deployment, NodeId, MISSING_META};
+use anyhow::{bail, Result};
+
+/// The `LD_PRELOAD` clock shim that nodes with a clock skew run under
+pub const FAKETIME_BINARY: &str = "faketime";
+
+impl Deployment {
+    /// Return the number of seconds that the clock of a node is ahead of the
+    /// real clock, or behind it if negative
+    pub fn clock_skew(&self, node: NodeId) -> i64 {
+        self.meta
+            .as_ref()
+            .and_then(|meta| meta.clock_skews.get(&node.to_string()).copied())
+            .unwrap_or(0)
+    }
+
+    /// Run a node with its clock `offset_secs` ahead of the real clock, or
+    /// behind it if negative, using `faketime`
+    ///
+    /// The skew takes effect the next time the node starts, and an offset
+    /// of 0 removes it. The shim only works for dynamically linked
+    /// clickhouse binaries running on the host, so this is not supported
+    /// with docker or on Windows.
+    pub fn set_clock_skew(
+        &mut self,
+        node: NodeId,
+        offset_secs: i64,
+    ) -> Result<()> {
+        if matches!(self.config.backend, Backend::Docker { .. })
+            || cfg!(windows)
+        {
+            bail!("Clock skew needs nodes that run on a Unix host");
+        }
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        let exists = match node {
+            NodeId::Keeper(id) => meta.keeper_ids.contains(&id),
+            NodeId::Server(id) => meta.server_ids.contains(&id),
+        };
+        if !exists {
+            bail!("No such node: {node}");
+        }
+        if offset_secs == 0 {
+            meta.clock_skews.remove(&node.to_string());
+        } else {
+            meta.clock_skews.insert(node.to_string(), offset_secs);
+        }
+        self.writer.save_meta(meta, &self.config.path)?;
+        self.record(
+            "set-clock-skew",
+            serde_json::json!({
+                "node": node.to_string(),
+                "offset_secs": offset_secs,
+            }),
+        )
+    }
+
+    /// Return the command and arguments that run the node named `node`
+    /// under its clock skew, if it has one
+    pub(crate) fn clock_shim(&self, node: &str) -> Option<[String; 3]> {
+        let offset = self.meta.as_ref()?.clock_skews.get(node)?;
+        Some([
+            FAKETIME_BINARY.to_string(),
+            "-f".to_string(),
+            format!("{offset:+}"),
+        ])
+    }
+}
@@ -0,0 +1,153 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::DiskKind;
+use crate::ports::pick_free_ports;
+use crate::{
+    platform, BasePorts, Deployment, DeploymentConfig, CONTAINER_ID_FILENAME,
+    DEPLOYMENT_DIR, MISSING_META, REMOTE_HOST_FILENAME, SNAPSHOTS_DIR,
+};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::info;
+
+impl Deployment {
+    /// Copy the configs, data, logs, and metadata of this deployment to a
+    /// new deployment under `new_path` that uses `new_base_ports`, and
+    /// return it
+    ///
+    /// All configs of the clone are regenerated with its own paths and
+    /// ports, so it can run at the same time as this deployment. Nodes are
+    /// not stopped while they are copied, so the data of running nodes may
+    /// be caught mid-write; stop them first for a consistent copy. None of
+    /// the clone's nodes are started, and snapshots are not copied, as they
+    /// hold the configs of this deployment.
+    pub fn clone_to(
+        &self,
+        new_path: &Utf8Path,
+        new_base_ports: BasePorts,
+    ) -> Result<Deployment> {
+        if self.meta.is_none() {
+            bail!(MISSING_META);
+        }
+        if !self.config.backend.is_local() {
+            bail!("Cloning is not supported for deployments on remote hosts");
+        }
+        let dest = new_path.join(DEPLOYMENT_DIR);
+        if dest.exists() {
+            bail!("{dest} already exists");
+        }
+        let _span = self.span("clone").entered();
+        info!(%dest, "Cloning deployment");
+
+        copy_tree(&self.config.path, &dest, &|path| {
+            path.as_str() == SNAPSHOTS_DIR
+                || path.extension() == Some("pid")
+                || path.file_name() == Some(CONTAINER_ID_FILENAME)
+                || path.file_name() == Some(REMOTE_HOST_FILENAME)
+        })?;
+
+        // Shift every port we own by the same amount as the base ports
+        let shift = i32::from(new_base_ports.keeper)
+            - i32::from(self.config.base_ports.keeper);
+        let mut config = DeploymentConfig {
+            path: dest,
+            base_ports: new_base_ports,
+            ..self.config.clone()
+        };
+        if let Some(minio) = &mut config.minio {
+            let old_endpoint = minio.endpoint();
+            minio.port = u16::try_from(i32::from(minio.port) + shift)
+                .context("MinIO port of the clone is out of range")?;
+            let new_endpoint = minio.endpoint();
+            for disk in config.storage.iter_mut().flat_map(|s| &mut s.disks) {
+                if let DiskKind::S3 { endpoint, .. } = &mut disk.kind {
+                    if let Some(rest) = endpoint.strip_prefix(&old_endpoint) {
+                        *endpoint = format!("{new_endpoint}{rest}");
+                    }
+                }
+            }
+        }
+        config.save()?;
+
+        let mut clone = Deployment::new(config);
+        // Picked ports are those of this deployment, so pick new ones
+        if clone.config.free_ports {
+            if let Some(meta) = &mut clone.meta {
+                meta.keeper_ports.clear();
+                meta.server_ports.clear();
+                pick_free_ports(meta, &clone.writer)?;
+                clone.writer.save_meta(meta, &clone.config.path)?;
+            }
+        }
+        clone.regenerate_configs()?;
+        self.record("clone", serde_json::json!({ "path": new_path }))?;
+        Ok(clone)
+    }
+}
+
+/// Recursively copy `src` to `dest`, skipping any path relative to `src`
+/// for which `skip` returns true
+///
+/// Clickhouse links table directories to their data with absolute
+/// symlinks, so symlinks to paths inside `src` are pointed at the same
+/// paths inside `dest`. Copies are always writable by us, whoever owned
+/// the originals.
+pub(crate) fn copy_tree(
+    src: &Utf8Path,
+    dest: &Utf8Path,
+    skip: &dyn Fn(&Utf8Path) -> bool,
+) -> Result<()> {
+    std::fs::create_dir_all(dest)
+        .with_context(|| format!("failed to create {dest}"))?;
+    let src = src.canonicalize_utf8()?;
+    let dest = dest.canonicalize_utf8()?;
+    let mut symlinks = vec![];
+    copy_entries(&src, &dest, Utf8Path::new(""), skip, &mut symlinks)?;
+    // Symlinks last, so that their targets exist
+    for (target, link) in symlinks {
+        platform::symlink(&target, &link)?;
+    }
+    Ok(())
+}
+
+/// Copy the entries of `dir`, relative to `src`, to the same place in
+/// `dest`, returning symlinks in `symlinks` as their target and path rather
+/// than creating them
+fn copy_entries(
+    src: &Utf8Path,
+    dest: &Utf8Path,
+    dir: &Utf8Path,
+    skip: &dyn Fn(&Utf8Path) -> bool,
+    symlinks: &mut Vec<(Utf8PathBuf, Utf8PathBuf)>,
+) -> Result<()> {
+    for entry in src.join(dir).read_dir_utf8()? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = dir.join(entry.file_name());
+        if skip(&relative) {
+            continue;
+        }
+        let target = dest.join(&relative);
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let link = path.read_link_utf8()?;
+            let link = match link.strip_prefix(src) {
+                Ok(rest) => dest.join(rest),
+                Err(_) => link,
+            };
+            symlinks.push((link, target));
+        } else if file_type.is_dir() {
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("failed to create {target}"))?;
+            platform::make_writable(&target)?;
+            copy_entries(src, dest, &relative, skip, symlinks)?;
+        } else {
+            std::fs::copy(path, &target)
+                .with_context(|| format!("failed to copy {path}"))?;
+            platform::make_writable(&target)?;
+        }
+    }
+    Ok(())
+}
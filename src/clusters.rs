@@ -0,0 +1,187 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{is_valid_macro_name, ServerConfig};
+use crate::{
+    url_host, BasePorts, Deployment, DeploymentConfig,
+    CLICKWARD_CONFIG_FILENAME, DEFAULT_BASE_PORTS, DEPLOYMENT_DIR,
+    MISSING_META,
+};
+use anyhow::{anyhow, bail, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeSet;
+
+/// The directory under a root path that holds additional named clusters
+///
+/// The first cluster under a root path lives directly in it, and each
+/// additional cluster is a complete deployment of its own under
+/// `clusters/<name>`.
+pub const CLUSTERS_DIR: &str = "clusters";
+
+/// The distance between the base ports of clusters under one root path,
+/// which limits the ids of nodes in each cluster
+pub const CLUSTER_PORT_STRIDE: u16 = 100;
+
+/// Return the root path of the named cluster under `path`
+pub fn cluster_path(path: &Utf8Path, cluster: &str) -> Utf8PathBuf {
+    path.join(CLUSTERS_DIR).join(cluster)
+}
+
+/// Return the names of the additional clusters under `path`
+pub fn cluster_names(path: &Utf8Path) -> Result<Vec<String>> {
+    let dir = path.join(CLUSTERS_DIR);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+impl BasePorts {
+    /// Return these ports shifted up by `offset`
+    ///
+    /// Fails if that shifts any of them past the highest port.
+    pub fn offset(&self, offset: u16) -> Result<BasePorts> {
+        let shift = |port: u16| {
+            port.checked_add(offset).ok_or_else(|| {
+                anyhow!("port {port} can't be offset by {offset}")
+            })
+        };
+        Ok(BasePorts {
+            keeper: shift(self.keeper)?,
+            raft: shift(self.raft)?,
+            clickhouse_tcp: shift(self.clickhouse_tcp)?,
+            clickhouse_http: shift(self.clickhouse_http)?,
+            clickhouse_interserver_http: shift(
+                self.clickhouse_interserver_http,
+            )?,
+            clickhouse_metrics: shift(self.clickhouse_metrics)?,
+            keeper_metrics: shift(self.keeper_metrics)?,
+            keeper_http_control: Some(shift(self.keeper_http_control())?),
+        })
+    }
+
+    /// Return the base port of keepers' HTTP control endpoints
+    pub fn keeper_http_control(&self) -> u16 {
+        self.keeper_http_control.unwrap_or_else(|| {
+            let default = DEFAULT_BASE_PORTS;
+            let http_control = default.keeper_http_control.unwrap_or_default();
+            self.keeper + (http_control - default.keeper)
+        })
+    }
+}
+
+impl DeploymentConfig {
+    /// Create a config for an additional cluster named `cluster_name` under
+    /// the root path `path`
+    ///
+    /// Its base ports are offset from the defaults by a multiple of
+    /// `CLUSTER_PORT_STRIDE` that no other cluster under `path` uses, so
+    /// that all clusters can run at once. An existing cluster of the same
+    /// name keeps its ports, and fails if its config can't be loaded.
+    pub fn new_cluster(
+        path: &Utf8Path,
+        cluster_name: &str,
+    ) -> Result<DeploymentConfig> {
+        // The name is a directory under `path`, and an element name in
+        // `<remote_servers>`
+        if !is_valid_macro_name(cluster_name) {
+            bail!("Invalid cluster name: {cluster_name}");
+        }
+        let own_path = cluster_path(path, cluster_name);
+        let file =
+            own_path.join(DEPLOYMENT_DIR).join(CLICKWARD_CONFIG_FILENAME);
+        let base_ports = if file.exists() {
+            DeploymentConfig::load(own_path.clone())?.base_ports
+        } else {
+            DEFAULT_BASE_PORTS.offset(unused_port_offset(path)?)?
+        };
+        Ok(DeploymentConfig {
+            base_ports,
+            ..DeploymentConfig::new_with_default_ports(own_path, cluster_name)
+        })
+    }
+}
+
+/// Return the smallest port offset that no cluster under `path` uses
+fn unused_port_offset(path: &Utf8Path) -> Result<u16> {
+    let mut used = BTreeSet::from([DEFAULT_BASE_PORTS.keeper]);
+    for name in cluster_names(path)? {
+        if let Ok(config) = DeploymentConfig::load(cluster_path(path, &name)) {
+            used.insert(config.base_ports.keeper);
+        }
+    }
+    // Offsets must stay below the distance between the kinds of base ports
+    let max = DEFAULT_BASE_PORTS.raft - DEFAULT_BASE_PORTS.keeper;
+    (1..)
+        .map(|i| i * CLUSTER_PORT_STRIDE)
+        .take_while(|&offset| offset < max)
+        .find(|&offset| !used.contains(&(DEFAULT_BASE_PORTS.keeper + offset)))
+        .ok_or_else(|| anyhow!("no ports left for another cluster in {path}"))
+}
+
+impl Deployment {
+    /// Return the client addresses of our keepers
+    ///
+    /// These can be used as `DeploymentConfig::external_keepers` of another
+    /// cluster, so that both share our keeper ensemble.
+    pub fn keeper_client_configs(&self) -> Result<Vec<ServerConfig>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.keeper_ids
+            .iter()
+            .map(|&id| {
+                Ok(ServerConfig {
+                    host: url_host(&self.keeper_host(id)),
+                    port: self.keeper_port(id)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_secret;
+
+    #[test]
+    fn new_cluster_rejects_invalid_names() {
+        let path = Utf8Path::new("/nonexistent");
+        for name in ["", "../x", "a/b", "my-cluster", "1x", "a<b"] {
+            assert!(
+                DeploymentConfig::new_cluster(path, name).is_err(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn new_cluster_fails_on_unreadable_config() {
+        let root =
+            Utf8PathBuf::try_from(std::env::temp_dir()).unwrap().join(format!(
+                "clickward-test-{}-{}",
+                std::process::id(),
+                &generate_secret()[..8]
+            ));
+        let dir = cluster_path(&root, "other").join(DEPLOYMENT_DIR);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = DeploymentConfig::new_cluster(&root, "other").unwrap();
+        assert_ne!(config.base_ports.keeper, DEFAULT_BASE_PORTS.keeper);
+
+        std::fs::write(dir.join(CLICKWARD_CONFIG_FILENAME), "{").unwrap();
+        let result = DeploymentConfig::new_cluster(&root, "other");
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -2,9 +2,16 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{KeeperId, ServerId};
-use camino::Utf8PathBuf;
+use crate::{
+    ClickhouseVersion, KeeperId, ServerId, VersionedFeature,
+    KEEPER_READINESS_ENDPOINT,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
 
 /// Config for an individual Clickhouse Replica
 pub struct ReplicaConfig {
@@ -14,9 +21,43 @@ pub struct ReplicaConfig {
     pub http_port: u16,
     pub tcp_port: u16,
     pub interserver_http_port: u16,
+    pub interserver_http_host: String,
+    pub interserver_http_credentials: Option<InterserverCredentials>,
     pub remote_servers: RemoteServers,
     pub keepers: KeeperConfigsForReplica,
+    /// The znode under which replicated tables and the distributed DDL queue
+    /// live, so that deployments sharing keepers don't collide
+    pub zookeeper_root: Option<String>,
+    /// Allow the experimental `Replicated` database engine
+    pub allow_replicated_database: bool,
+    /// Make inserts asynchronous by default
+    pub async_insert: bool,
+    /// The database that queries use unless they name another, instead of
+    /// `default`
+    pub default_database: Option<String>,
+    /// Settings of the `default` profile, in addition to those we set
+    pub profile_settings: BTreeMap<String, String>,
+    /// Users besides `default`
+    pub users: Vec<UserConfig>,
+    /// Disks and storage policies for MergeTree tables
+    pub storage: Option<StorageConfig>,
+    /// Named collections, keyed by collection name and then parameter name
+    pub named_collections: BTreeMap<String, BTreeMap<String, String>>,
+    /// A glob matching the files of external dictionaries
+    pub dictionaries_config: Option<Utf8PathBuf>,
+    pub kafka: Option<BrokerConfig>,
+    pub rabbitmq: Option<BrokerConfig>,
+    /// Enable the `query_log`, `metric_log`, `trace_log` and `part_log`
+    /// system tables
+    pub system_logs: bool,
+    pub limits: ServerLimits,
     pub data_path: Utf8PathBuf,
+    /// The path of the `backups` disk that `BACKUP` and `RESTORE` use
+    pub backups_path: Utf8PathBuf,
+    pub prometheus: Option<PrometheusConfig>,
+    /// The clickhouse that runs this config, or `None` to use every
+    /// setting we know of
+    pub clickhouse_version: Option<ClickhouseVersion>,
 }
 
 impl ReplicaConfig {
@@ -28,16 +69,127 @@ impl ReplicaConfig {
             http_port,
             tcp_port,
             interserver_http_port,
+            interserver_http_host,
+            interserver_http_credentials,
             remote_servers,
             keepers,
+            zookeeper_root,
+            allow_replicated_database,
+            async_insert,
+            default_database,
+            profile_settings,
+            users,
+            storage,
+            named_collections,
+            dictionaries_config,
+            kafka,
+            rabbitmq,
+            system_logs,
+            limits,
             data_path,
+            backups_path,
+            prometheus,
+            clickhouse_version,
         } = self;
         let logger = logger.to_xml();
+        let (replica_path, ddl_path) = match zookeeper_root {
+            Some(root) => {
+                let tables =
+                    format!("{root}/tables/{{shard}}/{{database}}/{{table}}");
+                let replica_path = format!(
+                    "
+    <default_replica_path>{tables}</default_replica_path>
+    <default_replica_name>{{replica}}</default_replica_name>"
+                );
+                let ddl_path = format!(
+                    "
+        <path>{root}/task_queue/ddl</path>"
+                );
+                (replica_path, ddl_path)
+            }
+            None => (String::new(), String::new()),
+        };
+        let replicated_database = if *allow_replicated_database {
+            let setting = "allow_experimental_database_replicated";
+            format!(
+                "
+            <{setting}>1</{setting}>"
+            )
+        } else {
+            String::new()
+        };
+        let async_insert = if *async_insert {
+            async_insert_xml(*clickhouse_version)
+        } else {
+            String::new()
+        };
+        let extra_settings: String = profile_settings
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "
+            <{name}>{}</{name}>",
+                    xml_escape(value)
+                )
+            })
+            .collect();
+        let profile_settings =
+            format!("{replicated_database}{async_insert}{extra_settings}");
+        let users: String = users.iter().map(|user| user.to_xml()).collect();
+        let default_database = default_database
+            .as_ref()
+            .map(|database| {
+                format!(
+                    "
+    <default_database>{}</default_database>",
+                    xml_escape(database)
+                )
+            })
+            .unwrap_or_default();
+        // Backups get a local disk of their own alongside any others
+        let mut storage = storage.clone().unwrap_or_default();
+        storage.disks.push(DiskConfig {
+            name: BACKUPS_DISK.to_string(),
+            kind: DiskKind::Local {
+                path: backups_path.clone(),
+                keep_free_space_bytes: None,
+            },
+        });
+        let storage = format!(
+            "{}
+    <backups>
+        <allowed_disk>{BACKUPS_DISK}</allowed_disk>
+        <allowed_path>{backups_path}</allowed_path>
+    </backups>",
+            storage.to_xml()
+        );
+        let named_collections = named_collections_xml(named_collections);
+        let kafka = kafka.as_ref().map(|k| k.kafka_xml()).unwrap_or_default();
+        let rabbitmq =
+            rabbitmq.as_ref().map(|r| r.rabbitmq_xml()).unwrap_or_default();
+        let limits = limits.to_xml();
+        let system_logs =
+            if *system_logs { system_logs_xml() } else { String::new() };
+        let dictionaries_config = dictionaries_config
+            .as_ref()
+            .map(|glob| {
+                format!(
+                    "
+    <dictionaries_config>{glob}</dictionaries_config>"
+                )
+            })
+            .unwrap_or_default();
+        let prometheus =
+            prometheus.as_ref().map(|p| p.to_xml()).unwrap_or_default();
         let cluster = macros.cluster.clone();
         let id = macros.replica;
         let macros = macros.to_xml();
         let keepers = keepers.to_xml();
         let remote_servers = remote_servers.to_xml();
+        let interserver_http_credentials = interserver_http_credentials
+            .as_ref()
+            .map(|c| c.to_xml())
+            .unwrap_or_default();
         let user_files_path = data_path.clone().join("user_files");
         //let access_path = data_path.clone().join("access");
         let format_schema_path = data_path.clone().join("format_schemas");
@@ -49,7 +201,7 @@ impl ReplicaConfig {
 
     <profiles>
         <default>
-            <load_balancing>random</load_balancing>
+            <load_balancing>random</load_balancing>{profile_settings}
         </default>
 
     </profiles>
@@ -62,7 +214,7 @@ impl ReplicaConfig {
             </networks>
             <profile>default</profile>
             <quota>default</quota>
-        </default>
+        </default>{users}
     </users>
 
     <quotas>
@@ -81,13 +233,14 @@ impl ReplicaConfig {
     <user_files_path>{user_files_path}</user_files_path>
     <default_profile>default</default_profile>
     <format_schema_path>{format_schema_path}</format_schema_path>
-    <display_name>{cluster}-{id}</display_name>
+    <display_name>{cluster}-{id}</display_name>{default_database}{limits}
     <listen_host>{listen_host}</listen_host>
     <http_port>{http_port}</http_port>
     <tcp_port>{tcp_port}</tcp_port>
     <interserver_http_port>{interserver_http_port}</interserver_http_port>
-    <interserver_http_host>::1</interserver_http_host>
-    <distributed_ddl>
+    <interserver_http_host>{interserver_http_host}</interserver_http_host>
+{interserver_http_credentials}{replica_path}
+    <distributed_ddl>{ddl_path}
         <!-- Cleanup settings (active tasks will not be removed) -->
 
         <!-- Controls task TTL (default 1 week) -->
@@ -101,7 +254,9 @@ impl ReplicaConfig {
      </distributed_ddl>
 {macros}
 {remote_servers}
-{keepers}
+{keepers}{system_logs}{storage}
+{named_collections}{dictionaries_config}{kafka}{rabbitmq}
+{prometheus}
 
 </clickhouse>
 "
@@ -109,67 +264,1048 @@ impl ReplicaConfig {
     }
 }
 
+/// Limits on the resources a clickhouse server uses
+///
+/// Upstream defaults let each server assume that it owns the whole machine.
+/// Our defaults are small enough to run 10 or more servers on a laptop.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerLimits {
+    /// The fraction of the machine's memory that the server may use
+    pub max_server_memory_usage_to_ram_ratio: f64,
+    pub max_concurrent_queries: u64,
+    pub max_connections: u64,
+    pub mark_cache_size: u64,
+    pub uncompressed_cache_size: u64,
+    /// Threads for merges and mutations of MergeTree tables
+    pub background_pool_size: u64,
+    /// Threads for replication and other periodic tasks
+    pub background_schedule_pool_size: u64,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            max_server_memory_usage_to_ram_ratio: 0.05,
+            max_concurrent_queries: 50,
+            max_connections: 256,
+            mark_cache_size: 128 * 1024 * 1024,
+            uncompressed_cache_size: 64 * 1024 * 1024,
+            background_pool_size: 16,
+            background_schedule_pool_size: 128,
+        }
+    }
+}
+
+impl ServerLimits {
+    pub fn to_xml(&self) -> String {
+        let ServerLimits {
+            max_server_memory_usage_to_ram_ratio,
+            max_concurrent_queries,
+            max_connections,
+            mark_cache_size,
+            uncompressed_cache_size,
+            background_pool_size,
+            background_schedule_pool_size,
+        } = self;
+        let schedule_pool = "background_schedule_pool_size";
+        let ratio = "max_server_memory_usage_to_ram_ratio";
+        let uncompressed = "uncompressed_cache_size";
+        format!(
+            "
+    <{ratio}>{max_server_memory_usage_to_ram_ratio}</{ratio}>
+    <max_concurrent_queries>{max_concurrent_queries}</max_concurrent_queries>
+    <max_connections>{max_connections}</max_connections>
+    <mark_cache_size>{mark_cache_size}</mark_cache_size>
+    <{uncompressed}>{uncompressed_cache_size}</{uncompressed}>
+    <background_pool_size>{background_pool_size}</background_pool_size>
+    <{schedule_pool}>{background_schedule_pool_size}</{schedule_pool}>"
+        )
+    }
+}
+
+/// The credentials that replicas use to authenticate interserver http
+/// requests, i.e. when fetching parts from each other
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterserverCredentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl InterserverCredentials {
+    pub fn to_xml(&self) -> String {
+        let InterserverCredentials { user, password } = self;
+        let user = xml_escape(user);
+        let password = xml_escape(password);
+        format!(
+            "
+    <interserver_http_credentials>
+        <user>{user}</user>
+        <password>{password}</password>
+    </interserver_http_credentials>"
+        )
+    }
+}
+
+/// A user that every server has besides `default`, with the same profile
+/// and quota
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    /// The user's password, where an empty one lets them log in without one
+    #[serde(default)]
+    pub password: String,
+}
+
+impl UserConfig {
+    pub fn to_xml(&self) -> String {
+        let UserConfig { name, password } = self;
+        let password = xml_escape(password);
+        format!(
+            "
+        <{name}>
+            <password>{password}</password>
+            <networks>
+                <ip>::/0</ip>
+            </networks>
+            <profile>default</profile>
+            <quota>default</quota>
+        </{name}>"
+        )
+    }
+}
+
+/// The name of the disk that every server writes backups to
+pub const BACKUPS_DISK: &str = "backups";
+
+/// The disks and storage policies available to MergeTree tables
+///
+/// Local disk paths that are relative are resolved against each server's
+/// directory, so that every server gets its own disks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageConfig {
+    pub disks: Vec<DiskConfig>,
+    pub policies: Vec<StoragePolicy>,
+    /// Let replicas of parts on remote disks share one copy of the data
+    /// rather than each fetching their own
+    #[serde(default)]
+    pub zero_copy_replication: bool,
+}
+
+impl StorageConfig {
+    /// A `hot_cold` policy that moves parts from a `hot` disk to a `cold`
+    /// disk once the hot disk is 80% full
+    pub fn hot_cold() -> StorageConfig {
+        let local = |name: &str| DiskConfig {
+            name: name.to_string(),
+            kind: DiskKind::Local {
+                path: Utf8PathBuf::from(name),
+                keep_free_space_bytes: None,
+            },
+        };
+        let volume = |name: &str| VolumeConfig {
+            name: name.to_string(),
+            disks: vec![name.to_string()],
+            max_data_part_size_bytes: None,
+        };
+        StorageConfig {
+            disks: vec![local("hot"), local("cold")],
+            policies: vec![StoragePolicy {
+                name: "hot_cold".to_string(),
+                volumes: vec![volume("hot"), volume("cold")],
+                move_factor: Some(0.2),
+            }],
+            zero_copy_replication: false,
+        }
+    }
+
+    /// An `s3` policy that stores all parts on `disk`
+    pub fn s3(disk: DiskConfig, zero_copy_replication: bool) -> StorageConfig {
+        StorageConfig {
+            policies: vec![StoragePolicy {
+                name: "s3".to_string(),
+                volumes: vec![VolumeConfig {
+                    name: "main".to_string(),
+                    disks: vec![disk.name.clone()],
+                    max_data_part_size_bytes: None,
+                }],
+                move_factor: None,
+            }],
+            disks: vec![disk],
+            zero_copy_replication,
+        }
+    }
+
+    /// Return a copy of this config with relative disk paths resolved
+    /// against `dir`
+    pub fn for_node(&self, dir: &Utf8Path) -> StorageConfig {
+        let mut config = self.clone();
+        for disk in &mut config.disks {
+            if let DiskKind::Local { path, .. } = &mut disk.kind {
+                if path.is_relative() {
+                    *path = dir.join(&*path);
+                }
+            }
+        }
+        config
+    }
+
+    /// Return the paths of all local disks
+    pub fn local_paths(&self) -> Vec<&Utf8Path> {
+        self.disks
+            .iter()
+            .filter_map(|disk| match &disk.kind {
+                DiskKind::Local { path, .. } => Some(path.as_path()),
+                DiskKind::S3 { .. } => None,
+            })
+            .collect()
+    }
+
+    pub fn to_xml(&self) -> String {
+        let StorageConfig { disks, policies, zero_copy_replication } = self;
+        let mut s = String::from(
+            "
+    <storage_configuration>
+        <disks>",
+        );
+        for disk in disks {
+            s.push_str(&disk.to_xml());
+        }
+        s.push_str(
+            "
+        </disks>
+        <policies>",
+        );
+        for policy in policies {
+            s.push_str(&policy.to_xml());
+        }
+        s.push_str(
+            "
+        </policies>
+    </storage_configuration>",
+        );
+        if *zero_copy_replication {
+            let setting = "allow_remote_fs_zero_copy_replication";
+            s.push_str(&format!(
+                "
+    <merge_tree>
+        <{setting}>1</{setting}>
+    </merge_tree>"
+            ));
+        }
+        s
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: DiskKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiskKind {
+    /// A directory on the local filesystem
+    Local {
+        path: Utf8PathBuf,
+        #[serde(default)]
+        keep_free_space_bytes: Option<u64>,
+    },
+    /// A bucket in S3 or an S3 compatible store such as MinIO
+    S3 {
+        /// The URL of the bucket, including any prefix for this disk
+        endpoint: String,
+        access_key_id: String,
+        secret_access_key: String,
+    },
+}
+
+impl DiskConfig {
+    pub fn to_xml(&self) -> String {
+        let DiskConfig { name, kind } = self;
+        let body = match kind {
+            DiskKind::Local { path, keep_free_space_bytes } => {
+                // Clickhouse requires disk paths to end with a slash
+                let mut s = format!(
+                    "
+                <path>{}/</path>",
+                    xml_escape(path.as_str().trim_end_matches('/'))
+                );
+                if let Some(bytes) = keep_free_space_bytes {
+                    s.push_str(&format!(
+                        "
+                <keep_free_space_bytes>{bytes}</keep_free_space_bytes>"
+                    ));
+                }
+                s
+            }
+            DiskKind::S3 { endpoint, access_key_id, secret_access_key } => {
+                let endpoint = xml_escape(endpoint.trim_end_matches('/'));
+                let access_key_id = xml_escape(access_key_id);
+                let secret_access_key = xml_escape(secret_access_key);
+                format!(
+                    "
+                <type>s3</type>
+                <endpoint>{endpoint}/</endpoint>
+                <access_key_id>{access_key_id}</access_key_id>
+                <secret_access_key>{secret_access_key}</secret_access_key>"
+                )
+            }
+        };
+        format!(
+            "
+            <{name}>{body}
+            </{name}>"
+        )
+    }
+}
+
+/// A storage policy: an ordered list of volumes that parts move through
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoragePolicy {
+    pub name: String,
+    pub volumes: Vec<VolumeConfig>,
+    /// When the free space of a volume drops below this fraction, parts are
+    /// moved to the next volume
+    #[serde(default)]
+    pub move_factor: Option<f64>,
+}
+
+impl StoragePolicy {
+    pub fn to_xml(&self) -> String {
+        let StoragePolicy { name, volumes, move_factor } = self;
+        let mut s = format!(
+            "
+            <{name}>
+                <volumes>"
+        );
+        for volume in volumes {
+            s.push_str(&volume.to_xml());
+        }
+        s.push_str(
+            "
+                </volumes>",
+        );
+        if let Some(move_factor) = move_factor {
+            s.push_str(&format!(
+                "
+                <move_factor>{move_factor}</move_factor>"
+            ));
+        }
+        s.push_str(&format!(
+            "
+            </{name}>"
+        ));
+        s
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeConfig {
+    pub name: String,
+    /// The names of the disks in this volume
+    pub disks: Vec<String>,
+    #[serde(default)]
+    pub max_data_part_size_bytes: Option<u64>,
+}
+
+impl VolumeConfig {
+    pub fn to_xml(&self) -> String {
+        let VolumeConfig { name, disks, max_data_part_size_bytes } = self;
+        let mut s = format!(
+            "
+                    <{name}>"
+        );
+        for disk in disks {
+            s.push_str(&format!(
+                "
+                        <disk>{disk}</disk>"
+            ));
+        }
+        if let Some(bytes) = max_data_part_size_bytes {
+            let setting = "max_data_part_size_bytes";
+            s.push_str(&format!(
+                "
+                        <{setting}>{bytes}</{setting}>"
+            ));
+        }
+        s.push_str(&format!(
+            "
+                    </{name}>"
+        ));
+        s
+    }
+}
+
+/// Return `s` with the characters that are special in XML text escaped
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Undo `xml_escape`
+pub(crate) fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+/// Return the trimmed contents of the first `<tag>` of `xml`
+///
+/// This is only meant for configs shaped like the ones we generate, and
+/// doesn't handle comments, CDATA, or elements nested in ones of the same
+/// name.
+pub(crate) fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    elements(xml, tag).into_iter().next()
+}
+
+/// Return the trimmed contents of every `<tag>` of `xml`
+pub(crate) fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}");
+    let close = format!("</{tag}>");
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        // Skip longer tags that start with the same name, i.e. `<port_x>`
+        if !after.starts_with(['>', ' ', '\t', '\n']) {
+            rest = after;
+            continue;
+        }
+        let Some(body_start) = after.find('>') else {
+            break;
+        };
+        let body = &after[body_start + 1..];
+        let Some(end) = body.find(&close) else {
+            break;
+        };
+        found.push(body[..end].trim());
+        rest = &body[end + close.len()..];
+    }
+    found
+}
+
+/// Return the XML of named collections, keyed by collection name and then
+/// parameter name
+pub fn named_collections_xml(
+    collections: &BTreeMap<String, BTreeMap<String, String>>,
+) -> String {
+    if collections.is_empty() {
+        return String::new();
+    }
+    let mut s = String::from(
+        "
+    <named_collections>",
+    );
+    for (name, params) in collections {
+        s.push_str(&format!(
+            "
+        <{name}>"
+        ));
+        for (key, value) in params {
+            let value = xml_escape(value);
+            s.push_str(&format!(
+                "
+            <{key}>{value}</{key}>"
+            ));
+        }
+        s.push_str(&format!(
+            "
+        </{name}>"
+        ));
+    }
+    s.push_str(
+        "
+    </named_collections>",
+    );
+    s
+}
+
+/// An external dictionary, loaded by every server from its own file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DictionaryConfig {
+    pub name: String,
+    pub source: DictionarySource,
+    pub layout: DictionaryLayout,
+    pub key: DictionaryKey,
+    pub attributes: Vec<DictionaryAttribute>,
+    /// The range in seconds from which the reload interval is picked
+    pub lifetime_min: u64,
+    pub lifetime_max: u64,
+}
+
+/// Where a dictionary loads its data from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DictionarySource {
+    /// A table or query on the server loading the dictionary
+    Clickhouse {
+        db: String,
+        table: String,
+        #[serde(default)]
+        query: Option<String>,
+    },
+    /// A local file
+    File { path: Utf8PathBuf, format: String },
+    /// An HTTP endpoint
+    Http { url: String, format: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DictionaryLayout {
+    Flat,
+    Hashed,
+    ComplexKeyHashed,
+    Direct,
+    ComplexKeyDirect,
+}
+
+impl Display for DictionaryLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DictionaryLayout::Flat => "flat",
+            DictionaryLayout::Hashed => "hashed",
+            DictionaryLayout::ComplexKeyHashed => "complex_key_hashed",
+            DictionaryLayout::Direct => "direct",
+            DictionaryLayout::ComplexKeyDirect => "complex_key_direct",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DictionaryKey {
+    /// A single `UInt64` key column
+    Id(String),
+    /// A key of any number of columns of any type, for `complex_key_*`
+    /// layouts
+    Complex(Vec<DictionaryAttribute>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DictionaryAttribute {
+    pub name: String,
+    /// The clickhouse type, i.e. `String` or `UInt64`
+    #[serde(rename = "type")]
+    pub ty: String,
+    /// The value returned for keys that aren't in the dictionary
+    #[serde(default)]
+    pub null_value: String,
+}
+
+impl DictionaryAttribute {
+    fn to_xml(&self, indent: &str) -> String {
+        let DictionaryAttribute { name, ty, null_value } = self;
+        let name = xml_escape(name);
+        let ty = xml_escape(ty);
+        let null_value = xml_escape(null_value);
+        format!(
+            "
+{indent}<attribute>
+{indent}    <name>{name}</name>
+{indent}    <type>{ty}</type>
+{indent}    <null_value>{null_value}</null_value>
+{indent}</attribute>"
+        )
+    }
+}
+
+impl DictionaryConfig {
+    /// Return the contents of the dictionary's file for a server listening
+    /// on `listen_host` and `tcp_port`
+    pub fn to_xml(&self, listen_host: &str, tcp_port: u16) -> String {
+        let DictionaryConfig {
+            name,
+            source,
+            layout,
+            key,
+            attributes,
+            lifetime_min,
+            lifetime_max,
+        } = self;
+        let source = match source {
+            DictionarySource::Clickhouse { db, table, query } => {
+                // Servers listening on every address are reached on loopback
+                let host = match listen_host {
+                    "::" => "::1",
+                    "0.0.0.0" => "127.0.0.1",
+                    host => host,
+                };
+                let host = xml_escape(host);
+                let db = xml_escape(db);
+                let table = xml_escape(table);
+                let query = query
+                    .as_ref()
+                    .map(|query| {
+                        format!(
+                            "
+                <query>{}</query>",
+                            xml_escape(query)
+                        )
+                    })
+                    .unwrap_or_default();
+                format!(
+                    "
+            <clickhouse>
+                <host>{host}</host>
+                <port>{tcp_port}</port>
+                <user>default</user>
+                <password></password>
+                <db>{db}</db>
+                <table>{table}</table>{query}
+            </clickhouse>"
+                )
+            }
+            DictionarySource::File { path, format } => format!(
+                "
+            <file>
+                <path>{}</path>
+                <format>{}</format>
+            </file>",
+                xml_escape(path.as_str()),
+                xml_escape(format)
+            ),
+            DictionarySource::Http { url, format } => format!(
+                "
+            <http>
+                <url>{}</url>
+                <format>{}</format>
+            </http>",
+                xml_escape(url),
+                xml_escape(format)
+            ),
+        };
+        let key = match key {
+            DictionaryKey::Id(name) => format!(
+                "
+            <id>
+                <name>{}</name>
+            </id>",
+                xml_escape(name)
+            ),
+            DictionaryKey::Complex(columns) => {
+                let mut s = String::from(
+                    "
+            <key>",
+                );
+                for column in columns {
+                    s.push_str(&column.to_xml("                "));
+                }
+                s.push_str(
+                    "
+            </key>",
+                );
+                s
+            }
+        };
+        let attributes: String =
+            attributes.iter().map(|a| a.to_xml("            ")).collect();
+        format!(
+            "
+<clickhouse>
+    <dictionary>
+        <name>{name}</name>
+        <source>{source}
+        </source>
+        <layout>
+            <{layout}/>
+        </layout>
+        <structure>{key}{attributes}
+        </structure>
+        <lifetime>
+            <min>{lifetime_min}</min>
+            <max>{lifetime_max}</max>
+        </lifetime>
+    </dictionary>
+</clickhouse>
+"
+        )
+    }
+}
+
+/// A message broker that Kafka or RabbitMQ engine tables consume from
+///
+/// Besides the engine's global config section, servers get a named
+/// collection with the broker's address, so tables can be created with
+/// i.e. `ENGINE = Kafka(kafka) SETTINGS kafka_topic_list = 'events', ...`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrokerConfig {
+    pub address: ServerConfig,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Extra settings of the engine's config section, i.e. librdkafka
+    /// properties for Kafka with dots replaced by underscores
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+impl BrokerConfig {
+    pub fn new(address: ServerConfig) -> BrokerConfig {
+        BrokerConfig {
+            address,
+            user: None,
+            password: None,
+            settings: BTreeMap::new(),
+        }
+    }
+
+    /// Return the `<kafka>` section for this broker
+    pub fn kafka_xml(&self) -> String {
+        let mut settings = BTreeMap::new();
+        if let Some(user) = &self.user {
+            settings.insert("security_protocol", "SASL_PLAINTEXT");
+            settings.insert("sasl_mechanism", "PLAIN");
+            settings.insert("sasl_username", user);
+        }
+        if let Some(password) = &self.password {
+            settings.insert("sasl_password", password);
+        }
+        self.section_xml("kafka", settings)
+    }
+
+    /// Return the `<rabbitmq>` section for this broker
+    pub fn rabbitmq_xml(&self) -> String {
+        let mut settings = BTreeMap::new();
+        if let Some(user) = &self.user {
+            settings.insert("username", user.as_str());
+        }
+        if let Some(password) = &self.password {
+            settings.insert("password", password);
+        }
+        self.section_xml("rabbitmq", settings)
+    }
+
+    /// Return the parameters of the named collection for a Kafka broker
+    pub fn kafka_collection(&self) -> BTreeMap<String, String> {
+        let ServerConfig { host, port } = &self.address;
+        BTreeMap::from([(
+            "kafka_broker_list".to_string(),
+            format!("{host}:{port}"),
+        )])
+    }
+
+    /// Return the parameters of the named collection for a RabbitMQ broker
+    pub fn rabbitmq_collection(&self) -> BTreeMap<String, String> {
+        let ServerConfig { host, port } = &self.address;
+        let mut params = BTreeMap::from([(
+            "rabbitmq_host_port".to_string(),
+            format!("{host}:{port}"),
+        )]);
+        if let Some(user) = &self.user {
+            params.insert("rabbitmq_username".to_string(), user.clone());
+        }
+        if let Some(password) = &self.password {
+            params.insert("rabbitmq_password".to_string(), password.clone());
+        }
+        params
+    }
+
+    /// Return a config section named `name` holding `settings` followed by
+    /// our extra settings, which take precedence
+    fn section_xml<'a>(
+        &'a self,
+        name: &str,
+        mut settings: BTreeMap<&'a str, &'a str>,
+    ) -> String {
+        for (key, value) in &self.settings {
+            settings.insert(key, value);
+        }
+        let mut s = format!(
+            "
+    <{name}>"
+        );
+        for (key, value) in settings {
+            let value = xml_escape(value);
+            s.push_str(&format!(
+                "
+        <{key}>{value}</{key}>"
+            ));
+        }
+        s.push_str(&format!(
+            "
+    </{name}>"
+        ));
+        s
+    }
+}
+
+/// The interval at which system log tables are flushed
+///
+/// This is much shorter than clickhouse's default, so that tests see their
+/// queries in the logs almost immediately.
+const SYSTEM_LOG_FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// Return the sections enabling the `query_log`, `metric_log`, `trace_log`
+/// and `part_log` system tables
+pub fn system_logs_xml() -> String {
+    let mut s = String::new();
+    for table in ["query_log", "metric_log", "trace_log", "part_log"] {
+        let interval = SYSTEM_LOG_FLUSH_INTERVAL_MS;
+        let collect = if table == "metric_log" {
+            format!(
+                "
+        <collect_interval_milliseconds>{interval}\
+        </collect_interval_milliseconds>"
+            )
+        } else {
+            String::new()
+        };
+        s.push_str(&format!(
+            "
+    <{table}>
+        <database>system</database>
+        <table>{table}</table>
+        <flush_interval_milliseconds>{interval}\
+        </flush_interval_milliseconds>{collect}
+    </{table}>"
+        ));
+    }
+    s
+}
+
+/// How long async inserts are buffered before being flushed
+const ASYNC_INSERT_BUSY_TIMEOUT_MS: u64 = 200;
+
+/// Return the profile settings that make inserts asynchronous for
+/// `version`
+///
+/// Newer versions adapt the flush interval to the insert rate, up to the
+/// timeout that older versions always wait for. Older versions reject
+/// profiles with settings they don't know.
+fn async_insert_xml(version: Option<ClickhouseVersion>) -> String {
+    let timeout = ASYNC_INSERT_BUSY_TIMEOUT_MS;
+    let adaptive = VersionedFeature::AdaptiveAsyncInsert;
+    let busy_timeout = if version.is_none_or(|v| adaptive.is_supported(v)) {
+        format!(
+            "
+            <async_insert_use_adaptive_busy_timeout>1\
+            </async_insert_use_adaptive_busy_timeout>
+            <async_insert_busy_timeout_max_ms>{timeout}\
+            </async_insert_busy_timeout_max_ms>"
+        )
+    } else {
+        format!(
+            "
+            <async_insert_busy_timeout_ms>{timeout}\
+            </async_insert_busy_timeout_ms>"
+        )
+    };
+    format!(
+        "
+            <async_insert>1</async_insert>
+            <wait_for_async_insert>1</wait_for_async_insert>{busy_timeout}"
+    )
+}
+
 pub struct Macros {
     pub shard: u64,
     pub replica: ServerId,
     pub cluster: String,
+    /// The failure domain of the server, if it has one
+    pub zone: Option<String>,
+    /// Macros beyond the ones above, keyed by name, which replace them if
+    /// they share a name
+    pub extra: BTreeMap<String, String>,
 }
 
 impl Macros {
     pub fn to_xml(&self) -> String {
-        let Macros { shard, replica, cluster } = self;
-        format!(
+        let Macros { shard, replica, cluster, zone, extra } = self;
+        let mut macros = BTreeMap::new();
+        macros.insert("shard", shard.to_string());
+        macros.insert("replica", replica.to_string());
+        macros.insert("cluster", cluster.clone());
+        if let Some(zone) = zone {
+            macros.insert("zone", zone.clone());
+        }
+        for (name, value) in extra {
+            macros.insert(name.as_str(), value.clone());
+        }
+        let mut s = "
+    <macros>"
+            .to_string();
+        for (name, value) in macros {
+            s.push_str(&format!(
+                "
+        <{name}>{}</{name}>",
+                xml_escape(&value)
+            ));
+        }
+        s.push_str(
             "
-    <macros>
-        <shard>{shard}</shard>
-        <replica>{replica}</replica>
-        <cluster>{cluster}</cluster>
-    </macros>"
-        )
+    </macros>",
+        );
+        s
     }
 }
 
+/// Return true if `name` can be used as the name of a macro
+pub fn is_valid_macro_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 #[derive(Debug, Clone)]
 pub struct RemoteServers {
     pub cluster: String,
     pub secret: String,
+    pub shards: Vec<Shard>,
+    /// Clusters of other deployments that servers can query, keyed by the
+    /// name they are known by in this deployment
+    pub linked: BTreeMap<String, LinkedCluster>,
+}
+
+/// The servers of a cluster of another deployment, linked with
+/// `Deployment::link_remote`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "LinkedClusterRepr")]
+pub struct LinkedCluster {
+    pub shards: Vec<LinkedShard>,
+}
+
+/// A shard of a `LinkedCluster`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkedShard {
+    pub weight: u32,
     pub replicas: Vec<ServerConfig>,
 }
 
+/// How linked clusters are saved, which used to be a single shard of
+/// replicas
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LinkedClusterRepr {
+    Shards { shards: Vec<LinkedShard> },
+    Replicas(Vec<ServerConfig>),
+}
+
+impl From<LinkedClusterRepr> for LinkedCluster {
+    fn from(repr: LinkedClusterRepr) -> LinkedCluster {
+        match repr {
+            LinkedClusterRepr::Shards { shards } => LinkedCluster { shards },
+            LinkedClusterRepr::Replicas(replicas) => LinkedCluster {
+                shards: vec![LinkedShard { weight: 1, replicas }],
+            },
+        }
+    }
+}
+
+/// The replicas of one shard of a cluster
+#[derive(Debug, Clone)]
+pub struct Shard {
+    /// The share of rows that distributed inserts write to this shard,
+    /// relative to the weights of the other shards
+    pub weight: u32,
+    pub replicas: Vec<ShardReplica>,
+}
+
+/// A replica of a shard in `<remote_servers>`
+#[derive(Debug, Clone)]
+pub struct ShardReplica {
+    pub server: ServerConfig,
+    /// Distributed queries prefer replicas with a lower priority, the
+    /// clickhouse default being 1
+    pub priority: Option<u32>,
+}
+
 impl RemoteServers {
     pub fn to_xml(&self) -> String {
-        let RemoteServers { cluster, secret, replicas } = self;
+        let RemoteServers { cluster, secret, shards, linked } = self;
 
-        let mut s = format!(
+        let mut s = String::from(
             "
-    <remote_servers replace=\"true\">
-        <{cluster}>
-            <secret>{secret}</secret>
-            <shard>
-                <internal_replication>true</internal_replication>"
+    <remote_servers replace=\"true\">",
         );
+        s.push_str(&cluster_xml(cluster, Some(secret), shards));
+        for (alias, linked) in linked {
+            let shards: Vec<_> = linked
+                .shards
+                .iter()
+                .map(|shard| Shard {
+                    weight: shard.weight,
+                    replicas: shard
+                        .replicas
+                        .iter()
+                        .map(|server| ShardReplica {
+                            server: server.clone(),
+                            priority: None,
+                        })
+                        .collect(),
+                })
+                .collect();
+            s.push_str(&cluster_xml(alias, None, &shards));
+        }
+        s.push_str(
+            "
+    </remote_servers>
+        ",
+        );
+        s
+    }
+}
+
+/// Return the XML of a cluster inside `<remote_servers>`
+///
+/// Without a secret, queries to the cluster authenticate as the default user.
+fn cluster_xml(
+    cluster: &str,
+    secret: Option<&str>,
+    shards: &[Shard],
+) -> String {
+    let secret = secret
+        .map(|secret| {
+            let secret = xml_escape(secret);
+            format!(
+                "
+            <secret>{secret}</secret>"
+            )
+        })
+        .unwrap_or_default();
+    let mut s = format!(
+        "
+        <{cluster}>{secret}"
+    );
 
-        for r in replicas {
-            let ServerConfig { host, port } = r;
+    for Shard { weight, replicas } in shards {
+        s.push_str(&format!(
+            "
+            <shard>
+                <weight>{weight}</weight>
+                <internal_replication>true</internal_replication>"
+        ));
+        for ShardReplica { server, priority } in replicas {
+            let ServerConfig { host, port } = server;
+            let priority = priority
+                .map(|priority| {
+                    format!(
+                        "
+                    <priority>{priority}</priority>"
+                    )
+                })
+                .unwrap_or_default();
             s.push_str(&format!(
                 "
                 <replica>
                     <host>{host}</host>
-                    <port>{port}</port>
+                    <port>{port}</port>{priority}
                 </replica>"
             ));
         }
-
-        s.push_str(&format!(
+        s.push_str(
             "
-            </shard>
-        </{cluster}>
-    </remote_servers>
+            </shard>",
+        );
+    }
+
+    s.push_str(&format!(
         "
-        ));
+        </{cluster}>"
+    ));
 
-        s
-    }
+    s
 }
 
 #[derive(Debug, Clone)]
@@ -195,18 +1331,54 @@ impl KeeperConfigsForReplica {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
 }
 
+impl FromStr for ServerConfig {
+    type Err = String;
+
+    /// Parse a `host:port` pair, where IPv6 hosts are in brackets
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected <host>:<port>, got {s}"))?;
+        let port = port.parse().map_err(|_| format!("invalid port in {s}"))?;
+        Ok(ServerConfig { host: host.to_string(), port })
+    }
+}
+
+/// Config for serving prometheus metrics from a server or keeper
+#[derive(Debug, Clone)]
+pub struct PrometheusConfig {
+    pub endpoint: String,
+    pub port: u16,
+}
+
+impl PrometheusConfig {
+    pub fn to_xml(&self) -> String {
+        let PrometheusConfig { endpoint, port } = self;
+        format!(
+            "
+    <prometheus>
+        <endpoint>{endpoint}</endpoint>
+        <port>{port}</port>
+        <metrics>true</metrics>
+        <events>true</events>
+        <asynchronous_metrics>true</asynchronous_metrics>
+    </prometheus>
+"
+        )
+    }
+}
+
 pub struct LogConfig {
     pub level: LogLevel,
     pub log: Utf8PathBuf,
     pub errorlog: Utf8PathBuf,
-    // TODO: stronger type?
-    pub size: String,
+    pub size: ByteSize,
     pub count: usize,
 }
 
@@ -227,10 +1399,81 @@ impl LogConfig {
     }
 }
 
+/// A size in bytes, written the way clickhouse's logger reads sizes, i.e.
+/// `100M`
+///
+/// Sizes parse from a number of bytes with an optional `K`, `M` or `G`
+/// suffix for powers of 1024.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub const fn kib(n: u64) -> ByteSize {
+        ByteSize(n * 1024)
+    }
+
+    pub const fn mib(n: u64) -> ByteSize {
+        ByteSize(n * 1024 * 1024)
+    }
+}
+
+/// Uses the largest of `M` and `K` that divides the size, as the logger
+/// knows no larger units
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ByteSize(bytes) = *self;
+        if bytes != 0 && bytes % (1024 * 1024) == 0 {
+            write!(f, "{}M", bytes / (1024 * 1024))
+        } else if bytes != 0 && bytes % 1024 == 0 {
+            write!(f, "{}K", bytes / 1024)
+        } else {
+            write!(f, "{bytes}")
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let (digits, multiplier) = match trimmed.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&trimmed[..i], 1024),
+            Some((i, 'M' | 'm')) => (&trimmed[..i], 1024 * 1024),
+            Some((i, 'G' | 'g')) => (&trimmed[..i], 1024 * 1024 * 1024),
+            _ => (trimmed, 1),
+        };
+        digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(ByteSize)
+            .ok_or_else(|| format!("invalid size: {s}"))
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ByteSize> for String {
+    fn from(size: ByteSize) -> String {
+        size.to_string()
+    }
+}
+
 pub struct KeeperCoordinationSettings {
-    pub operation_timeout_ms: u32,
-    pub session_timeout_ms: u32,
+    pub operation_timeout: Duration,
+    pub session_timeout: Duration,
     pub raft_logs_level: LogLevel,
+    pub snapshot_distance: u64,
 }
 
 pub struct RaftServers {
@@ -241,13 +1484,21 @@ impl RaftServers {
     pub fn to_xml(&self) -> String {
         let mut s = String::new();
         for server in &self.servers {
-            let RaftServerConfig { id, hostname, port } = server;
+            let RaftServerConfig {
+                id,
+                hostname,
+                port,
+                can_become_leader,
+                priority,
+            } = server;
             s.push_str(&format!(
                 "
             <server>
                 <id>{id}</id>
                 <hostname>{hostname}</hostname>
                 <port>{port}</port>
+                <can_become_leader>{can_become_leader}</can_become_leader>
+                <priority>{priority}</priority>
             </server>
             "
             ));
@@ -262,6 +1513,10 @@ pub struct RaftServerConfig {
     pub id: KeeperId,
     pub hostname: String,
     pub port: u16,
+    /// False for observers, which replicate the log without voting
+    pub can_become_leader: bool,
+    /// Servers with a higher priority are preferred as leader
+    pub priority: u32,
 }
 
 /// Config for an individual Clickhouse Keeper
@@ -274,6 +1529,15 @@ pub struct KeeperConfig {
     pub snapshot_storage_path: Utf8PathBuf,
     pub coordination_settings: KeeperCoordinationSettings,
     pub raft_config: RaftServers,
+    pub prometheus: Option<PrometheusConfig>,
+    /// The port of the HTTP control endpoint, if it's enabled
+    pub http_control_port: Option<u16>,
+    /// The four letter word commands that the keeper accepts, or `None` for
+    /// clickhouse's default list
+    pub four_letter_words: Option<Vec<String>>,
+    /// The clickhouse that runs this config, or `None` to use every
+    /// setting we know of
+    pub clickhouse_version: Option<ClickhouseVersion>,
 }
 
 impl KeeperConfig {
@@ -287,22 +1551,67 @@ impl KeeperConfig {
             snapshot_storage_path,
             coordination_settings,
             raft_config,
+            prometheus,
+            http_control_port,
+            four_letter_words,
+            clickhouse_version,
         } = self;
         let logger = logger.to_xml();
+        let prometheus =
+            prometheus.as_ref().map(|p| p.to_xml()).unwrap_or_default();
+        let http_control = VersionedFeature::KeeperHttpControl;
+        let http_control = match http_control_port {
+            Some(port)
+                if clickhouse_version
+                    .is_none_or(|v| http_control.is_supported(v)) =>
+            {
+                format!(
+                    "
+        <http_control>
+            <port>{port}</port>
+            <readiness>
+                <endpoint>{KEEPER_READINESS_ENDPOINT}</endpoint>
+            </readiness>
+        </http_control>"
+                )
+            }
+            _ => String::new(),
+        };
+        let four_letter_words = four_letter_words
+            .as_ref()
+            .map(|words| {
+                format!(
+                    "
+        <four_letter_word_white_list>{}</four_letter_word_white_list>",
+                    xml_escape(&words.join(","))
+                )
+            })
+            .unwrap_or_default();
         let KeeperCoordinationSettings {
-            operation_timeout_ms,
-            session_timeout_ms,
+            operation_timeout,
+            session_timeout,
             raft_logs_level,
+            snapshot_distance,
         } = coordination_settings;
+        let operation_timeout_ms = operation_timeout.as_millis();
+        let session_timeout_ms = session_timeout.as_millis();
         let raft_servers = raft_config.to_xml();
+        let reconfiguration = VersionedFeature::KeeperReconfiguration;
+        let enable_reconfiguration = if clickhouse_version
+            .is_none_or(|v| reconfiguration.is_supported(v))
+        {
+            "
+        <enable_reconfiguration>false</enable_reconfiguration>"
+        } else {
+            ""
+        };
         format!(
             "
 <clickhouse>
 {logger}
     <listen_host>{listen_host}</listen_host>
-    <keeper_server>
-        <enable_reconfiguration>false</enable_reconfiguration>
-        <tcp_port>{tcp_port}</tcp_port>
+    <keeper_server>{enable_reconfiguration}
+        <tcp_port>{tcp_port}</tcp_port>{http_control}{four_letter_words}
         <server_id>{server_id}</server_id>
         <log_storage_path>{log_storage_path}</log_storage_path>
         <snapshot_storage_path>{snapshot_storage_path}</snapshot_storage_path>
@@ -310,11 +1619,13 @@ impl KeeperConfig {
             <operation_timeout_ms>{operation_timeout_ms}</operation_timeout_ms>
             <session_timeout_ms>{session_timeout_ms}</session_timeout_ms>
             <raft_logs_level>{raft_logs_level}</raft_logs_level>
+            <snapshot_distance>{snapshot_distance}</snapshot_distance>
         </coordination_settings>
         <raft_configuration>
 {raft_servers}
         </raft_configuration>
     </keeper_server>
+{prometheus}
 
 </clickhouse>
 "
@@ -322,10 +1633,14 @@ impl KeeperConfig {
     }
 }
 
-#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Trace,
     Debug,
+    Information,
+    Warning,
+    Error,
 }
 
 impl Display for LogLevel {
@@ -333,7 +1648,138 @@ impl Display for LogLevel {
         let s = match self {
             LogLevel::Trace => "trace",
             LogLevel::Debug => "debug",
+            LogLevel::Information => "information",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
         };
         write!(f, "{s}")
     }
 }
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trace" => Ok(LogLevel::Trace),
+            "debug" => Ok(LogLevel::Debug),
+            "information" => Ok(LogLevel::Information),
+            "warning" => Ok(LogLevel::Warning),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(format!("unknown log level: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xml_unescape_undoes_xml_escape() {
+        for s in ["", "plain", "a<b>c", "&", "&amp;", "a&lt;b", "<&>&gt;"] {
+            assert_eq!(xml_unescape(&xml_escape(s)), s);
+        }
+    }
+
+    #[test]
+    fn linked_clusters_keep_their_shards() {
+        // Linked clusters used to be saved as the replicas of one shard
+        let old: LinkedCluster =
+            serde_json::from_str(r#"[{ "host": "::1", "port": 22001 }]"#)
+                .unwrap();
+        assert_eq!(old.shards.len(), 1);
+        assert_eq!(old.shards[0].weight, 1);
+        assert_eq!(old.shards[0].replicas[0].port, 22001);
+
+        let linked: LinkedCluster = serde_json::from_str(
+            r#"{ "shards": [
+                { "weight": 1, "replicas": [{ "host": "::1", "port": 22001 }] },
+                { "weight": 3, "replicas": [{ "host": "::1", "port": 22002 }] }
+            ] }"#,
+        )
+        .unwrap();
+        let json = serde_json::to_string(&linked).unwrap();
+        let linked: LinkedCluster = serde_json::from_str(&json).unwrap();
+        let xml = RemoteServers {
+            cluster: "test".to_string(),
+            secret: "secret".to_string(),
+            shards: vec![],
+            linked: BTreeMap::from([("other".to_string(), linked)]),
+        }
+        .to_xml();
+        let other = element(&xml, "other").unwrap();
+        let shards = elements(other, "shard");
+        assert_eq!(shards.len(), 2);
+        assert_eq!(element(shards[0], "weight"), Some("1"));
+        assert_eq!(element(shards[0], "port"), Some("22001"));
+        assert_eq!(element(shards[1], "weight"), Some("3"));
+        assert_eq!(element(shards[1], "port"), Some("22002"));
+    }
+
+    #[test]
+    fn dictionaries_query_their_own_server() {
+        let dictionary = DictionaryConfig {
+            name: "dict".to_string(),
+            source: DictionarySource::Clickhouse {
+                db: "default".to_string(),
+                table: "source".to_string(),
+                query: None,
+            },
+            layout: DictionaryLayout::ComplexKeyHashed,
+            key: DictionaryKey::Complex(vec![DictionaryAttribute {
+                name: "k<ey".to_string(),
+                ty: "String".to_string(),
+                null_value: String::new(),
+            }]),
+            attributes: vec![DictionaryAttribute {
+                name: "value".to_string(),
+                ty: "Map(String, Array(UInt8))</type>".to_string(),
+                null_value: "<none>".to_string(),
+            }],
+            lifetime_min: 0,
+            lifetime_max: 10,
+        };
+        for (listen_host, host) in [
+            ("::1", "::1"),
+            ("::", "::1"),
+            ("0.0.0.0", "127.0.0.1"),
+            ("fd00::2", "fd00::2"),
+        ] {
+            let xml = dictionary.to_xml(listen_host, 22001);
+            let source = element(&xml, "clickhouse").unwrap();
+            assert_eq!(element(source, "host"), Some(host));
+            assert_eq!(element(source, "port"), Some("22001"));
+        }
+
+        let xml = dictionary.to_xml("::1", 22001);
+        let key = element(&xml, "key").unwrap();
+        assert_eq!(element(key, "name").map(xml_unescape).unwrap(), "k<ey");
+        let attributes = elements(&xml, "attribute");
+        assert_eq!(attributes.len(), 2);
+        let value = attributes[1];
+        assert_eq!(
+            element(value, "type").map(xml_unescape).unwrap(),
+            "Map(String, Array(UInt8))</type>"
+        );
+        assert_eq!(
+            element(value, "null_value").map(xml_unescape).unwrap(),
+            "<none>"
+        );
+    }
+
+    #[test]
+    fn element_skips_tags_with_the_same_prefix() {
+        let xml = "
+            <port_x>1</port_x>
+            <ports>2</ports>
+            <port>
+                3
+            </port>
+            <port id=\"4\">4</port>";
+        assert_eq!(element(xml, "port"), Some("3"));
+        assert_eq!(elements(xml, "port"), ["3", "4"]);
+        assert_eq!(element(xml, "port_x"), Some("1"));
+        assert_eq!(element(xml, "por"), None);
+    }
+}
@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::Deployment;
+use anyhow::Result;
+use std::io::{self, Read};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Cancels the operations of the `OperationContext`s it was given to, from
+/// any thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Bounds an operation of a `Deployment`, which fails with an
+/// `OperationError` once it is cancelled or its timeout passes
+///
+/// Long-running operations like `deploy`, `add_server` and `scale_to` take a
+/// context. They check it whenever they start a step, such as starting a
+/// node, and whenever they poll while waiting, such as for a node to become
+/// healthy. Commands they run, like `docker` or `ssh`, are killed once the
+/// context says to stop.
+#[derive(Debug, Clone, Default)]
+pub struct OperationContext {
+    /// How long each operation given this context may run, counting from
+    /// when it starts, or `None` for no limit
+    pub timeout: Option<Duration>,
+    pub cancel_token: CancelToken,
+}
+
+impl OperationContext {
+    pub fn new(timeout: Option<Duration>) -> OperationContext {
+        OperationContext { timeout, cancel_token: CancelToken::new() }
+    }
+
+    /// Cancel operations with `cancel_token` rather than a token of our own
+    pub fn with_cancel_token(
+        mut self,
+        cancel_token: CancelToken,
+    ) -> OperationContext {
+        self.cancel_token = cancel_token;
+        self
+    }
+}
+
+/// Returned by operations that stopped because of their `OperationContext`
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationError {
+    #[error("operation cancelled")]
+    Cancelled,
+    #[error("operation timed out after {0:?}")]
+    TimedOut(Duration),
+}
+
+/// The context of the operation a `Deployment` is running, if any
+///
+/// This is shared with the `OperationGuard` of the operation, which ends it
+/// without borrowing the deployment.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CurrentOperation(Arc<Mutex<Option<Bounds>>>);
+
+#[derive(Debug)]
+struct Bounds {
+    context: OperationContext,
+    started: Instant,
+}
+
+impl CurrentOperation {
+    /// Fail if the current operation should stop
+    fn check(&self) -> Result<(), OperationError> {
+        let current = self.0.lock().unwrap();
+        let Some(Bounds { context, started }) = &*current else {
+            return Ok(());
+        };
+        if context.cancel_token.is_cancelled() {
+            return Err(OperationError::Cancelled);
+        }
+        match context.timeout {
+            Some(timeout) if started.elapsed() >= timeout => {
+                Err(OperationError::TimedOut(timeout))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Ends the operation started by `Deployment::begin_operation` when dropped
+pub(crate) struct OperationGuard(Option<CurrentOperation>);
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        if let Some(current) = &self.0 {
+            *current.0.lock().unwrap() = None;
+        }
+    }
+}
+
+/// How often we check whether to kill a command we are waiting for
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+impl Deployment {
+    /// Bound the operation that is starting by `context`, until the returned
+    /// guard is dropped
+    ///
+    /// Operations run by another operation, like the ones `scale_to` runs,
+    /// stay bounded by the context of the outer one.
+    pub(crate) fn begin_operation(
+        &self,
+        context: &OperationContext,
+    ) -> OperationGuard {
+        let mut current = self.operation.0.lock().unwrap();
+        if current.is_some() {
+            return OperationGuard(None);
+        }
+        *current =
+            Some(Bounds { context: context.clone(), started: Instant::now() });
+        OperationGuard(Some(self.operation.clone()))
+    }
+
+    /// Fail if our current operation should stop
+    pub(crate) fn check_context(&self) -> Result<()> {
+        Ok(self.operation.check()?)
+    }
+
+    /// Wait for `interval` between polls, failing if our current operation
+    /// should stop
+    pub(crate) async fn pause(&self, interval: Duration) -> Result<()> {
+        self.check_context()?;
+        tokio::time::sleep(interval).await;
+        self.check_context()
+    }
+
+    /// Run `cmd` and collect its output like `Command::output`, killing it
+    /// if our current operation should stop before it exits
+    pub(crate) fn output(&self, cmd: &mut Command) -> Result<Output> {
+        self.check_context()?;
+        let mut child =
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        // Read the pipes as we go, so that the command can't block on them
+        let stdout = child.stdout.take().map(read_pipe);
+        let stderr = child.stderr.take().map(read_pipe);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if let Err(e) = self.check_context() {
+                child.kill()?;
+                child.wait()?;
+                return Err(e);
+            }
+            std::thread::sleep(COMMAND_POLL_INTERVAL);
+        };
+        let join = |reader: Option<JoinHandle<io::Result<Vec<u8>>>>| {
+            reader.map_or(Ok(vec![]), |reader| {
+                reader.join().unwrap_or_else(|_| Ok(vec![]))
+            })
+        };
+        Ok(Output { status, stdout: join(stdout)?, stderr: join(stderr)? })
+    }
+}
+
+/// Read `pipe` to its end on a thread of its own
+fn read_pipe(
+    mut pipe: impl Read + Send + 'static,
+) -> JoinHandle<io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut buf = vec![];
+        pipe.read_to_end(&mut buf).map(|_| buf)
+    })
+}
@@ -0,0 +1,188 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, NodeId, ServerId, MISSING_META};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use tracing::info;
+
+#[cfg(feature = "http-client")]
+use crate::health::HEALTH_POLL_INTERVAL;
+#[cfg(feature = "http-client")]
+use std::time::{Duration, Instant};
+
+/// Whether an operator wants a node to serve, as opposed to what its process
+/// is doing, which is its `NodeState`
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeAdminState {
+    #[default]
+    Active,
+    /// The server keeps running, but other servers leave it out of
+    /// `<remote_servers>`, so distributed queries don't reach it
+    Cordoned,
+    /// Like `Cordoned`, while we wait for the queries running on the server
+    /// to finish before stopping it
+    Draining,
+    /// The node was stopped on purpose, and `start_all` leaves it stopped.
+    /// Stopped servers are left out of `<remote_servers>` too.
+    Stopped,
+}
+
+impl NodeAdminState {
+    /// Return true if other servers should send distributed queries to a
+    /// server in this state
+    pub fn is_routable(self) -> bool {
+        self == NodeAdminState::Active
+    }
+}
+
+impl Display for NodeAdminState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            NodeAdminState::Active => "active",
+            NodeAdminState::Cordoned => "cordoned",
+            NodeAdminState::Draining => "draining",
+            NodeAdminState::Stopped => "stopped",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl Deployment {
+    /// Return the state that a node was put in with `set_admin_state`
+    pub fn admin_state(&self, node: NodeId) -> NodeAdminState {
+        self.meta
+            .as_ref()
+            .and_then(|meta| meta.admin_states.get(&node.to_string()).copied())
+            .unwrap_or_default()
+    }
+
+    /// Put a node in `state`
+    ///
+    /// Servers that aren't active are left out of the `<remote_servers>` of
+    /// the other servers, which pick up their regenerated configs on their
+    /// own. Keepers can only be active or stopped. Stopping a node stops its
+    /// process, and leaving the stopped state starts it again.
+    pub fn set_admin_state(
+        &mut self,
+        node: NodeId,
+        state: NodeAdminState,
+    ) -> Result<()> {
+        let previous = self.admin_state(node);
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        let exists = match node {
+            NodeId::Keeper(id) => meta.keeper_ids.contains(&id),
+            NodeId::Server(id) => meta.server_ids.contains(&id),
+        };
+        if !exists {
+            bail!("No such node: {node}");
+        }
+        if matches!(node, NodeId::Keeper(_))
+            && matches!(
+                state,
+                NodeAdminState::Cordoned | NodeAdminState::Draining
+            )
+        {
+            bail!("Only servers can be {state}");
+        }
+        info!(%node, %previous, %state, "Changing node state");
+        if state == NodeAdminState::Active {
+            meta.admin_states.remove(&node.to_string());
+        } else {
+            meta.admin_states.insert(node.to_string(), state);
+        }
+        self.writer.save_meta(meta, &self.config.path)?;
+
+        if let NodeId::Server(_) = node {
+            if state.is_routable() != previous.is_routable() {
+                let keeper_ids = meta.keeper_ids.clone();
+                let server_ids = meta.server_ids.clone();
+                self.generate_clickhouse_config(keeper_ids, server_ids)?;
+            }
+        }
+        let alive = self.is_alive(node);
+        match (node, state) {
+            (NodeId::Keeper(id), NodeAdminState::Stopped) if alive => {
+                self.stop_keeper(id)?
+            }
+            (NodeId::Server(id), NodeAdminState::Stopped) if alive => {
+                self.stop_server(id)?
+            }
+            (_, NodeAdminState::Stopped) => (),
+            (NodeId::Keeper(id), _) if !alive => self.start_keeper(id)?,
+            (NodeId::Server(id), _) if !alive => self.start_server(id)?,
+            _ => (),
+        }
+        self.record(
+            "set-admin-state",
+            serde_json::json!({
+                "node": node.to_string(),
+                "state": state.to_string(),
+            }),
+        )
+    }
+
+    /// Take server `id` out of `<remote_servers>` while leaving it running
+    pub fn cordon(&mut self, id: ServerId) -> Result<()> {
+        self.set_admin_state(NodeId::Server(id), NodeAdminState::Cordoned)
+    }
+
+    /// Put a node back in service, starting it if it was stopped
+    pub fn uncordon(&mut self, node: NodeId) -> Result<()> {
+        self.set_admin_state(node, NodeAdminState::Active)
+    }
+}
+
+#[cfg(feature = "http-client")]
+#[derive(Debug, Deserialize)]
+struct RunningRow {
+    running: u64,
+}
+
+#[cfg(feature = "http-client")]
+impl Deployment {
+    /// Cordon server `id`, wait up to `timeout` for the queries running on
+    /// it to finish, and then stop it
+    ///
+    /// The server is left draining if its queries don't finish in time.
+    pub async fn drain(
+        &mut self,
+        id: ServerId,
+        timeout: Duration,
+    ) -> Result<()> {
+        let node = NodeId::Server(id);
+        self.set_admin_state(node, NodeAdminState::Draining)?;
+        let client = self.http_client(id)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let running = client
+                .query(
+                    "SELECT count() AS running FROM system.processes \
+                     WHERE query_id != queryID() \
+                     SETTINGS output_format_json_quote_64bit_integers = 0",
+                )
+                .await?
+                .rows::<RunningRow>()?
+                .first()
+                .map_or(0, |row| row.running);
+            if running == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "timed out waiting for {running} queries to finish on \
+                     server {id}"
+                );
+            }
+            self.pause(HEALTH_POLL_INTERVAL).await?;
+        }
+        self.set_admin_state(node, NodeAdminState::Stopped)
+    }
+}
@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, MISSING_META};
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tracing::info;
+
+/// The znode under which replicated databases live when the deployment has
+/// no `zookeeper_root`
+const DEFAULT_DATABASES_ROOT: &str = "/clickhouse";
+
+impl Deployment {
+    /// Create the database configured as `replicated_database` on every
+    /// server, using the `Replicated` engine
+    ///
+    /// We wait up to `timeout` for each server to become healthy and then
+    /// for the `CREATE DATABASE` to complete on all of them. This does
+    /// nothing if no replicated database is configured, and is safe to run
+    /// again for an existing database.
+    pub async fn create_replicated_database(
+        &self,
+        timeout: Duration,
+    ) -> Result<()> {
+        let Some(name) = &self.config.replicated_database else {
+            return Ok(());
+        };
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        for &id in &meta.server_ids {
+            self.wait_for_server(id, timeout).await?;
+        }
+
+        let root = self
+            .zookeeper_root()
+            .unwrap_or_else(|| DEFAULT_DATABASES_ROOT.to_string());
+        let sql = format!(
+            "CREATE DATABASE IF NOT EXISTS `{name}` \
+             ENGINE = Replicated('{root}/databases/{name}', '{{shard}}', \
+             '{{replica}}')"
+        );
+        info!(%name, "Creating replicated database");
+        self.ddl_on_cluster(&sql, timeout).await?;
+        self.record(
+            "create-replicated-database",
+            serde_json::json!({ "name": name }),
+        )
+    }
+
+    /// Create the database configured as `default_database` on every server
+    ///
+    /// We wait up to `timeout` for each server to become healthy first. This
+    /// does nothing if no default database is configured, and is safe to
+    /// run again for an existing database.
+    pub async fn create_default_database(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<()> {
+        let Some(name) = self.config.default_database.clone() else {
+            return Ok(());
+        };
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let server_ids = meta.server_ids.clone();
+        let sql = format!("CREATE DATABASE IF NOT EXISTS `{name}`");
+        for id in server_ids {
+            self.wait_for_server(id, timeout).await?;
+            info!(%id, %name, "Creating default database");
+            self.http_client(id)?
+                .execute(&sql)
+                .await
+                .with_context(|| format!("failed to create {name} on {id}"))?;
+        }
+
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.default_database = Some(name.clone());
+        self.writer.save_meta(meta, &self.config.path)?;
+        self.record(
+            "create-default-database",
+            serde_json::json!({ "name": name }),
+        )
+    }
+}
@@ -0,0 +1,194 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ClickhouseClient, Deployment, MISSING_META};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// How often we check the distributed DDL queue for completion
+const DDL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Statements where `ON CLUSTER` follows the name of the object acted on
+const DDL_STATEMENTS: &[&str] =
+    &["CREATE", "ALTER", "DROP", "TRUNCATE", "ATTACH", "DETACH"];
+
+/// Keywords that may precede the name of the object a DDL statement acts on
+const DDL_KEYWORDS: &[&str] = &[
+    "CREATE",
+    "ALTER",
+    "DROP",
+    "TRUNCATE",
+    "ATTACH",
+    "DETACH",
+    "OR",
+    "REPLACE",
+    "TEMPORARY",
+    "TABLE",
+    "DATABASE",
+    "VIEW",
+    "MATERIALIZED",
+    "LIVE",
+    "DICTIONARY",
+    "FUNCTION",
+    "IF",
+    "NOT",
+    "EXISTS",
+];
+
+#[derive(Debug, Deserialize)]
+struct DdlQueueRow {
+    host: Option<String>,
+    port: Option<u16>,
+    status: Option<String>,
+    exception_code: Option<u16>,
+    exception_text: Option<String>,
+}
+
+impl Deployment {
+    /// Run a DDL statement `ON CLUSTER` and wait for every host to apply it
+    ///
+    /// The statement is run against the first server that responds. We then
+    /// poll `system.distributed_ddl_queue` until all hosts have finished the
+    /// task, any host fails it, or `timeout` elapses.
+    pub async fn ddl_on_cluster(
+        &self,
+        sql: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let client = self.any_live_server().await?;
+        let cluster = &self.config.cluster_name;
+        let sql = with_on_cluster(sql, cluster);
+
+        // Distributed DDL entries only record their creation time with second
+        // granularity, so we can't tell apart tasks created within the same
+        // second as ours. This is fine for serialized test setup.
+        let start: Vec<ServerTime> =
+            client.query("SELECT toString(now()) AS now").await?.rows()?;
+        let Some(start) = start.into_iter().next() else {
+            bail!("failed to read current time from {}", client.addr());
+        };
+
+        // Don't let the server wait for the hosts, as we do that ourselves
+        client
+            .execute_with_settings(
+                &sql,
+                &[("distributed_ddl_output_mode", "none")],
+            )
+            .await?;
+
+        let query = format!(
+            "SELECT host, port, toString(status) AS status, exception_code, \
+                exception_text \
+             FROM system.distributed_ddl_queue \
+             WHERE cluster = '{cluster}' AND query_create_time >= '{}'",
+            start.now
+        );
+        let deadline = Instant::now() + timeout;
+        loop {
+            let rows: Vec<DdlQueueRow> = client.query(&query).await?.rows()?;
+            if let Some(failed) =
+                rows.iter().find(|r| r.exception_code.unwrap_or(0) != 0)
+            {
+                bail!(
+                    "DDL failed on {}:{}: {}",
+                    failed.host.as_deref().unwrap_or("unknown"),
+                    failed.port.unwrap_or(0),
+                    failed.exception_text.as_deref().unwrap_or_default()
+                );
+            }
+            let pending: Vec<String> = rows
+                .iter()
+                .filter(|r| r.status.as_deref() != Some("Finished"))
+                .map(|r| {
+                    format!(
+                        "{}:{}",
+                        r.host.as_deref().unwrap_or("unknown"),
+                        r.port.unwrap_or(0)
+                    )
+                })
+                .collect();
+            if !rows.is_empty() && pending.is_empty() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "timed out waiting for DDL to complete on cluster \
+                     {cluster}: pending hosts = {pending:?}"
+                );
+            }
+            self.pause(DDL_POLL_INTERVAL).await?;
+        }
+    }
+
+    /// Return a client for the first server that responds to a query
+    pub(crate) async fn any_live_server(&self) -> Result<ClickhouseClient> {
+        let Some(meta) = self.meta() else {
+            bail!(MISSING_META);
+        };
+        for id in &meta.server_ids {
+            let client = self.http_client(*id)?;
+            if client.execute("SELECT 1").await.is_ok() {
+                return Ok(client);
+            }
+        }
+        bail!("no clickhouse servers are responding");
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerTime {
+    now: String,
+}
+
+/// Add an `ON CLUSTER` clause to `sql` directly after the name of the object
+/// it acts on
+///
+/// Statements that already contain `ON CLUSTER` are left alone. For other
+/// statements, such as `SYSTEM` queries, or if we can't find the object name,
+/// the clause is appended to the end of the statement.
+fn with_on_cluster(sql: &str, cluster: &str) -> String {
+    let sql = sql.trim().trim_end_matches(';');
+    let upper = sql.to_uppercase();
+    if upper.contains(" ON CLUSTER ") {
+        return sql.to_string();
+    }
+    if !DDL_STATEMENTS.iter().any(|s| upper.starts_with(s)) {
+        return format!("{sql} ON CLUSTER {cluster}");
+    }
+
+    // Skip past the leading keywords to find the object name
+    let mut rest = sql;
+    let mut offset = 0;
+    loop {
+        let trimmed = rest.trim_start();
+        offset += rest.len() - trimmed.len();
+        rest = trimmed;
+        let word_len =
+            rest.find(|c: char| c.is_whitespace()).unwrap_or(rest.len());
+        if word_len == 0 {
+            return format!("{sql} ON CLUSTER {cluster}");
+        }
+        if !DDL_KEYWORDS.contains(&rest[..word_len].to_uppercase().as_str()) {
+            break;
+        }
+        offset += word_len;
+        rest = &rest[word_len..];
+    }
+
+    // The name may be qualified, quoted, or directly followed by `(`
+    let name_len = rest
+        .find(|c: char| {
+            !(c.is_alphanumeric() || c == '_' || c == '.' || c == '`')
+        })
+        .unwrap_or(rest.len());
+    if name_len == 0 {
+        return format!("{sql} ON CLUSTER {cluster}");
+    }
+    let split = offset + name_len;
+    let rest = sql[split..].trim_start();
+    format!("{} ON CLUSTER {cluster} {rest}", &sql[..split])
+        .trim_end()
+        .to_string()
+}
@@ -0,0 +1,85 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, NodeId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// How a node's config on disk differs from what we would generate
+#[derive(Debug, Clone)]
+pub struct ConfigDiff {
+    pub node: NodeId,
+    pub path: Utf8PathBuf,
+    /// A unified diff from the config on disk to the generated config
+    pub diff: String,
+}
+
+impl Deployment {
+    /// Render the configs of all nodes from our metadata and compare them
+    /// with the configs on disk
+    ///
+    /// Only nodes whose configs differ are returned. Missing configs are
+    /// compared as if they were empty. Extra configs in `config.d` are not
+    /// compared.
+    pub fn diff_configs(&self) -> Result<Vec<ConfigDiff>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut rendered = vec![];
+        for &id in &meta.keeper_ids {
+            let path = self.keeper_dir(id).join("keeper-config.xml");
+            let config = self.keeper_config(id, &meta.keeper_ids)?;
+            let xml = self.keeper_config_xml(id, &config)?;
+            rendered.push((NodeId::Keeper(id), path, xml));
+        }
+        for (id, config) in
+            self.clickhouse_configs(&meta.keeper_ids, &meta.server_ids)?
+        {
+            let path = self.server_dir(id).join("clickhouse-config.xml");
+            let xml = self.server_config_xml(id, &config)?;
+            rendered.push((NodeId::Server(id), path, xml));
+        }
+
+        let mut diffs = vec![];
+        for (node, path, rendered) in rendered {
+            if let Some(diff) = unified_diff(&path, &rendered)? {
+                diffs.push(ConfigDiff { node, path, diff });
+            }
+        }
+        Ok(diffs)
+    }
+}
+
+/// Return a unified diff from the file at `path` to `rendered`, or `None`
+/// if they are the same
+fn unified_diff(path: &Utf8Path, rendered: &str) -> Result<Option<String>> {
+    let old = if path.exists() { path } else { Utf8Path::new("/dev/null") };
+    let mut child = Command::new("diff")
+        .arg("-u")
+        .arg(format!("--label={path}"))
+        .arg(format!("--label={path} (generated)"))
+        .arg(old)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run diff")?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(rendered.as_bytes())?;
+    let output = child.wait_with_output()?;
+    match output.status.code() {
+        Some(0) => Ok(None),
+        Some(1) => Ok(Some(String::from_utf8_lossy(&output.stdout).into())),
+        _ => bail!(
+            "failed to diff {path}: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ),
+    }
+}
@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::remote::quote;
+use crate::{url_host, Deployment, NodeId, MISSING_META};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+/// An address that clients connect to a node at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub node: NodeId,
+    /// The protocol spoken at the address: `native` or `http` for servers
+    /// and `client` for keepers
+    pub protocol: &'static str,
+    /// A URL for `http`, and `host:port` otherwise
+    pub address: String,
+}
+
+impl Endpoint {
+    /// The name of the environment variable holding this endpoint, i.e.
+    /// `CLICKHOUSE_1_HTTP`
+    pub fn env_var(&self) -> String {
+        let (kind, id) = match self.node {
+            NodeId::Keeper(id) => ("KEEPER", id.0),
+            NodeId::Server(id) => ("CLICKHOUSE", id.0),
+        };
+        format!("{kind}_{id}_{}", self.protocol.to_uppercase())
+    }
+}
+
+/// How to print endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointFormat {
+    /// `export` statements for a shell to source, with single-quoted values
+    Env,
+    /// An object keyed by node name and then protocol
+    Json,
+    /// The same structure as `Json`
+    Yaml,
+    /// `KEY=value` lines, as read by dotenv libraries and docker compose
+    Dotenv,
+}
+
+impl Deployment {
+    /// Return the client endpoints of all keepers and servers
+    pub fn endpoints(&self) -> Result<Vec<Endpoint>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut endpoints = vec![];
+        for &id in &meta.keeper_ids {
+            let host = url_host(&self.keeper_host(id));
+            endpoints.push(Endpoint {
+                node: NodeId::Keeper(id),
+                protocol: "client",
+                address: format!("{host}:{}", self.keeper_port(id)?),
+            });
+        }
+        for &id in &meta.server_ids {
+            let host = url_host(&self.server_host(id));
+            endpoints.push(Endpoint {
+                node: NodeId::Server(id),
+                protocol: "native",
+                address: format!("{host}:{}", self.tcp_port(id)?),
+            });
+            endpoints.push(Endpoint {
+                node: NodeId::Server(id),
+                protocol: "http",
+                address: self.http_url(id)?,
+            });
+        }
+        Ok(endpoints)
+    }
+}
+
+/// Return `endpoints` in the given format
+pub fn format_endpoints(
+    endpoints: &[Endpoint],
+    format: EndpointFormat,
+) -> Result<String> {
+    let mut by_node: BTreeMap<String, BTreeMap<&str, &str>> = BTreeMap::new();
+    for endpoint in endpoints {
+        by_node
+            .entry(endpoint.node.to_string())
+            .or_default()
+            .insert(endpoint.protocol, &endpoint.address);
+    }
+    let s = match format {
+        EndpointFormat::Env => endpoints
+            .iter()
+            .map(|e| format!("export {}={}\n", e.env_var(), quote(&e.address)))
+            .collect(),
+        EndpointFormat::Dotenv => endpoints
+            .iter()
+            .map(|e| format!("{}={}\n", e.env_var(), e.address))
+            .collect(),
+        EndpointFormat::Json => {
+            let mut s = serde_json::to_string_pretty(&by_node)?;
+            s.push('\n');
+            s
+        }
+        EndpointFormat::Yaml => {
+            let mut s = String::new();
+            for (node, protocols) in &by_node {
+                s.push_str(&format!("{node}:\n"));
+                for (protocol, address) in protocols {
+                    // Quote addresses, as IPv6 brackets are YAML syntax
+                    s.push_str(&format!("  {protocol}: \"{address}\"\n"));
+                }
+            }
+            s
+        }
+    };
+    Ok(s)
+}
@@ -0,0 +1,30 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::history::timestamp;
+use crate::Deployment;
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use tracing::info;
+
+/// The file in the deployment directory that events such as node restarts
+/// are appended to
+pub const EVENTS_LOG_FILENAME: &str = "events.log";
+
+impl Deployment {
+    /// Print `message` and append it to the events log with a timestamp
+    pub fn log_event(&self, message: &str) -> Result<()> {
+        info!("{message}");
+        let path = self.config.path.join(EVENTS_LOG_FILENAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {path}"))?;
+        writeln!(file, "{} {message}", timestamp())
+            .with_context(|| format!("failed to write {path}"))?;
+        Ok(())
+    }
+}
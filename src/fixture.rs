@@ -0,0 +1,137 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    generate_secret, ClickwardMetadata, Deployment, DeploymentConfig,
+    DeploymentGuard, OperationContext, MISSING_META,
+};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::time::Duration;
+use tracing::warn;
+
+/// The name of the cluster that `ClickwardCluster::start` deploys
+pub const FIXTURE_CLUSTER_NAME: &str = "test_cluster";
+
+/// How long `ClickwardCluster` waits for keepers to reach quorum and for
+/// each server to become healthy
+pub const FIXTURE_START_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A running deployment in a temporary directory, for tests
+///
+/// Dropping it stops every node and removes the directory. Nodes get free
+/// ports, so tests can run many clusters at once.
+pub struct ClickwardCluster {
+    /// The temporary directory under which the deployment lives
+    root: Utf8PathBuf,
+    /// Always `Some` until we're dropped
+    guard: Option<DeploymentGuard>,
+}
+
+impl ClickwardCluster {
+    /// Generate configs for `num_keepers` keepers and `num_servers` servers
+    /// in a new temporary directory, start them, and wait until they're
+    /// healthy
+    pub async fn start(
+        num_keepers: u64,
+        num_servers: u64,
+    ) -> Result<ClickwardCluster> {
+        ClickwardCluster::start_with(num_keepers, num_servers, |_| ()).await
+    }
+
+    /// Like `start`, but let `configure` change the default config first
+    ///
+    /// The config's path is that of the temporary directory, and should be
+    /// left alone.
+    pub async fn start_with(
+        num_keepers: u64,
+        num_servers: u64,
+        configure: impl FnOnce(&mut DeploymentConfig),
+    ) -> Result<ClickwardCluster> {
+        let root = Utf8PathBuf::try_from(std::env::temp_dir())?.join(format!(
+            "clickward-{}-{}",
+            std::process::id(),
+            &generate_secret()[..8]
+        ));
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("failed to create {root}"))?;
+        let mut config = DeploymentConfig::new_with_default_ports(
+            root.clone(),
+            FIXTURE_CLUSTER_NAME,
+        );
+        config.free_ports = true;
+        configure(&mut config);
+
+        let mut d = Deployment::new(config);
+        let guard = match d
+            .generate_config(num_keepers, num_servers)
+            .and_then(|()| d.deploy_owned(&OperationContext::default()))
+        {
+            Ok(guard) => guard,
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&root);
+                return Err(e);
+            }
+        };
+        // From here on, dropping the cluster cleans up after us
+        let cluster = ClickwardCluster { root, guard: Some(guard) };
+        if num_keepers > 0 {
+            cluster.wait_for_keeper_quorum(FIXTURE_START_TIMEOUT).await?;
+        }
+        for id in cluster.meta_ref()?.server_ids.clone() {
+            cluster.wait_for_server(id, FIXTURE_START_TIMEOUT).await?;
+        }
+        Ok(cluster)
+    }
+
+    /// Return the temporary directory under which the deployment lives
+    pub fn root(&self) -> &Utf8Path {
+        &self.root
+    }
+
+    /// Return the client addresses of all keepers
+    pub fn keeper_addrs(&self) -> Result<Vec<SocketAddr>> {
+        let meta = self.meta_ref()?;
+        meta.keeper_ids.iter().map(|&id| self.keeper_addr(id)).collect()
+    }
+
+    /// Return the native protocol addresses of all servers
+    pub fn native_addrs(&self) -> Result<Vec<SocketAddr>> {
+        let meta = self.meta_ref()?;
+        meta.server_ids.iter().map(|&id| self.native_addr(id)).collect()
+    }
+
+    /// Return the URLs of the http interfaces of all servers
+    pub fn http_urls(&self) -> Result<Vec<String>> {
+        let meta = self.meta_ref()?;
+        meta.server_ids.iter().map(|&id| self.http_url(id)).collect()
+    }
+
+    fn meta_ref(&self) -> Result<&ClickwardMetadata> {
+        let Some(meta) = self.meta() else {
+            bail!(MISSING_META);
+        };
+        Ok(meta)
+    }
+}
+
+impl Deref for ClickwardCluster {
+    type Target = Deployment;
+
+    fn deref(&self) -> &Deployment {
+        self.guard.as_ref().expect("cluster is not dropped")
+    }
+}
+
+impl Drop for ClickwardCluster {
+    fn drop(&mut self) {
+        // Stop the nodes before removing their directories
+        drop(self.guard.take());
+        if let Err(e) = std::fs::remove_dir_all(&self.root) {
+            warn!(root = %self.root, "Failed to remove cluster: {e}");
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    Deployment, NodeId, OperationContext, ProgressEvent, ProgressSink,
+    MISSING_META,
+};
+use anyhow::{bail, Result};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long we wait for a node that was just spawned to write its pidfile
+/// before stopping it
+const STARTED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A deployment whose nodes, and MinIO if it was started along with them,
+/// are stopped when it is dropped
+///
+/// This is returned by `Deployment::deploy_owned`, so that tests using
+/// clickward as a fixture don't leak clickhouse processes when they panic.
+pub struct DeploymentGuard {
+    deployment: Deployment,
+    /// The nodes we started, in the order we started them
+    started: Vec<NodeId>,
+    /// Whether we started MinIO, which is stopped after the nodes
+    minio: bool,
+    remove_dir: bool,
+}
+
+impl DeploymentGuard {
+    /// Return the nodes that this guard stops when dropped
+    pub fn started(&self) -> &[NodeId] {
+        &self.started
+    }
+
+    /// Remove the deployment directory after stopping all nodes on drop
+    pub fn remove_dir_on_drop(mut self) -> DeploymentGuard {
+        self.remove_dir = true;
+        self
+    }
+
+    /// Stop `node`, waiting for it to finish starting first
+    fn stop(&self, node: NodeId) -> Result<()> {
+        let d = &self.deployment;
+        let deadline = Instant::now() + STARTED_TIMEOUT;
+        while !d.processes.is_started(d, node) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        match node {
+            NodeId::Keeper(id) => d.stop_keeper(id),
+            NodeId::Server(id) => d.stop_server(id),
+        }
+    }
+}
+
+impl Deref for DeploymentGuard {
+    type Target = Deployment;
+
+    fn deref(&self) -> &Deployment {
+        &self.deployment
+    }
+}
+
+impl Drop for DeploymentGuard {
+    fn drop(&mut self) {
+        // Drop can't fail, so we do as much cleanup as we can
+        for &node in self.started.iter().rev() {
+            if let Err(e) = self.stop(node) {
+                warn!(%node, "Failed to stop node: {e:#}");
+            }
+        }
+        if self.minio {
+            if let Err(e) = self.deployment.stop_minio() {
+                warn!("Failed to stop MinIO: {e:#}");
+            }
+        }
+        if self.remove_dir {
+            let path = &self.deployment.config.path;
+            if let Err(e) = std::fs::remove_dir_all(path) {
+                warn!(%path, "Failed to remove deployment: {e}");
+            }
+        }
+    }
+}
+
+/// Forwards progress to the sink of a deployment, noting the nodes that
+/// were started
+struct StartedNodes {
+    progress: Arc<dyn ProgressSink>,
+    started: Mutex<Vec<String>>,
+}
+
+impl ProgressSink for StartedNodes {
+    fn report(&self, event: &ProgressEvent) {
+        if let ProgressEvent::NodeStarted { node } = event {
+            self.started.lock().unwrap().push(node.clone());
+        }
+        self.progress.report(event);
+    }
+}
+
+impl Deployment {
+    /// Deploy our clickhouse replicas and keeper cluster like `deploy`,
+    /// returning a guard that stops them again when dropped
+    ///
+    /// If starting any node fails, the nodes that were already started are
+    /// stopped before returning the error.
+    pub fn deploy_owned(
+        mut self,
+        context: &OperationContext,
+    ) -> Result<DeploymentGuard> {
+        let Some(meta) = self.meta.clone() else {
+            bail!(MISSING_META);
+        };
+        let progress = self.progress.clone();
+        let nodes = Arc::new(StartedNodes {
+            progress: progress.clone(),
+            started: Mutex::new(vec![]),
+        });
+        self.progress = nodes.clone();
+        let minio_was_started = self.is_minio_started();
+        let result = self.deploy(context);
+        self.progress = progress;
+
+        let started = nodes.started.lock().unwrap().clone();
+        let minio = !minio_was_started && self.is_minio_started();
+        let guard = DeploymentGuard {
+            deployment: self,
+            started: meta
+                .keeper_ids
+                .iter()
+                .map(|&id| NodeId::Keeper(id))
+                .chain(meta.server_ids.iter().map(|&id| NodeId::Server(id)))
+                .filter(|node| started.contains(&node.to_string()))
+                .collect(),
+            minio,
+            remove_dir: false,
+        };
+        // On failure, dropping the guard stops the nodes that did start
+        result?;
+        Ok(guard)
+    }
+}
@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::process::output_tail;
+use crate::{
+    resolve, Deployment, KeeperClient, KeeperId, NodeId, ProgressEvent,
+    ServerId, KEEPER_READINESS_ENDPOINT, MISSING_META,
+};
+use anyhow::{bail, Result};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// How often we check whether a node has become healthy
+pub(crate) const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long we wait for a single health check to respond
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Returned when nodes don't become healthy in time
+#[derive(Error, Debug)]
+#[error("timed out waiting for {what}{output}")]
+pub struct HealthTimeout {
+    /// What we waited for, i.e. `keeper 1`
+    pub what: String,
+    /// The tail of the node's output, if any, starting with a newline
+    pub output: String,
+}
+
+impl Deployment {
+    /// Return true if the server responds to `/ping` on its HTTP port
+    pub async fn server_is_healthy(&self, id: ServerId) -> bool {
+        let Ok(addr) = self.http_addr(id) else {
+            return false;
+        };
+        let request = b"GET /ping HTTP/1.0\r\nHost: localhost\r\n\r\n";
+        match exchange(addr, request).await {
+            Ok(response) => response.trim_end().ends_with("Ok."),
+            Err(_) => false,
+        }
+    }
+
+    /// Return true if the keeper responds to the `ruok` four letter word
+    pub async fn keeper_is_healthy(&self, id: KeeperId) -> bool {
+        let Ok(addr) = self.keeper_addr(id) else {
+            return false;
+        };
+        match exchange(addr, b"ruok").await {
+            Ok(response) => response == "imok",
+            Err(_) => false,
+        }
+    }
+
+    /// Return true if the keeper's HTTP control endpoint reports that it's
+    /// ready, which means it's part of a cluster with a leader
+    ///
+    /// This is always false unless `DeploymentConfig::keeper_http_control`
+    /// is set.
+    pub async fn keeper_is_ready(&self, id: KeeperId) -> bool {
+        if !self.config.keeper_http_control {
+            return false;
+        }
+        let Ok(port) = self.keeper_http_control_port(id) else {
+            return false;
+        };
+        let Ok(addr) = resolve(&self.keeper_host(id), port) else {
+            return false;
+        };
+        let request =
+            format!("GET {KEEPER_READINESS_ENDPOINT} HTTP/1.0\r\n\r\n");
+        match exchange(addr, request.as_bytes()).await {
+            Ok(response) => {
+                response.starts_with("HTTP/1.0 200")
+                    || response.starts_with("HTTP/1.1 200")
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Wait until the server is healthy, or fail after `timeout`
+    pub async fn wait_for_server(
+        &self,
+        id: ServerId,
+        timeout: Duration,
+    ) -> Result<()> {
+        let node = NodeId::Server(id).to_string();
+        self.report(ProgressEvent::WaitingForNode { node: node.clone() });
+        let deadline = Instant::now() + timeout;
+        while !self.server_is_healthy(id).await {
+            if Instant::now() >= deadline {
+                return Err(HealthTimeout {
+                    what: format!("clickhouse server {id}"),
+                    output: output_tail(&self.server_dir(id)),
+                }
+                .into());
+            }
+            self.pause(HEALTH_POLL_INTERVAL).await?;
+        }
+        self.report(ProgressEvent::NodeHealthy { node });
+        Ok(())
+    }
+
+    /// Return true if some keeper reports that it leads the keeper cluster
+    ///
+    /// A leader can only be elected by a quorum of keepers.
+    pub async fn keeper_has_quorum(&self) -> bool {
+        let Some(meta) = &self.meta else {
+            return false;
+        };
+        for &id in &meta.keeper_ids {
+            let Ok(addr) = self.keeper_addr(id) else {
+                continue;
+            };
+            let mode = KeeperClient::new(addr).mode().await;
+            if matches!(mode.as_deref(), Ok("leader" | "standalone")) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wait until the keeper cluster has elected a leader, or fail after
+    /// `timeout`
+    pub async fn wait_for_keeper_quorum(
+        &self,
+        timeout: Duration,
+    ) -> Result<()> {
+        if self.meta.is_none() {
+            bail!(MISSING_META);
+        }
+        self.report(ProgressEvent::WaitingForQuorum);
+        let deadline = Instant::now() + timeout;
+        while !self.keeper_has_quorum().await {
+            if Instant::now() >= deadline {
+                return Err(HealthTimeout {
+                    what: "the keepers to elect a leader".to_string(),
+                    output: String::new(),
+                }
+                .into());
+            }
+            self.pause(HEALTH_POLL_INTERVAL).await?;
+        }
+        self.report(ProgressEvent::QuorumReached);
+        Ok(())
+    }
+
+    /// Wait until the keeper is healthy, or fail after `timeout`
+    pub async fn wait_for_keeper(
+        &self,
+        id: KeeperId,
+        timeout: Duration,
+    ) -> Result<()> {
+        let node = NodeId::Keeper(id).to_string();
+        self.report(ProgressEvent::WaitingForNode { node: node.clone() });
+        let deadline = Instant::now() + timeout;
+        while !self.keeper_is_healthy(id).await {
+            if Instant::now() >= deadline {
+                return Err(HealthTimeout {
+                    what: format!("keeper {id}"),
+                    output: output_tail(&self.keeper_dir(id)),
+                }
+                .into());
+            }
+            self.pause(HEALTH_POLL_INTERVAL).await?;
+        }
+        self.report(ProgressEvent::NodeHealthy { node });
+        Ok(())
+    }
+}
+
+/// A wait for a node to become healthy
+type Wait<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+impl Deployment {
+    /// Wait until all of `nodes` are healthy, waiting for them together,
+    /// or fail after `timeout`
+    ///
+    /// Dry runs don't start nodes, so there is nothing to wait for.
+    pub(crate) async fn wait_for_nodes(
+        &self,
+        nodes: &[NodeId],
+        timeout: Duration,
+    ) -> Result<()> {
+        if self.writer.is_dry_run() {
+            return Ok(());
+        }
+        let waits = nodes
+            .iter()
+            .map(|&node| -> Wait<'_> {
+                match node {
+                    NodeId::Keeper(id) => {
+                        Box::pin(self.wait_for_keeper(id, timeout))
+                    }
+                    NodeId::Server(id) => {
+                        Box::pin(self.wait_for_server(id, timeout))
+                    }
+                }
+            })
+            .collect();
+        try_join_all(waits).await
+    }
+}
+
+/// Run `waits` concurrently until all of them finish, or one of them fails
+async fn try_join_all(mut waits: Vec<Wait<'_>>) -> Result<()> {
+    std::future::poll_fn(|cx| {
+        let mut error = None;
+        waits.retain_mut(|wait| match wait.as_mut().poll(cx) {
+            Poll::Ready(Ok(())) => false,
+            Poll::Ready(Err(e)) => {
+                error.get_or_insert(e);
+                false
+            }
+            Poll::Pending => true,
+        });
+        match error {
+            Some(e) => Poll::Ready(Err(e)),
+            None if waits.is_empty() => Poll::Ready(Ok(())),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Send `request` to `addr` and return everything it sends back before
+/// closing the connection
+async fn exchange(addr: SocketAddr, request: &[u8]) -> Result<String> {
+    let f = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    Ok(tokio::time::timeout(HEALTH_CHECK_TIMEOUT, f).await??)
+}
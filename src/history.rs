@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, ProgressEvent};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The file in the deployment directory that every mutating operation is
+/// recorded in, one JSON object per line
+pub const HISTORY_FILENAME: &str = "history.jsonl";
+
+/// A single recorded operation on a deployment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the operation completed, as `YYYY-MM-DDTHH:MM:SSZ`
+    pub timestamp: String,
+    /// The user that ran the operation
+    pub user: String,
+    /// The name of the operation, i.e. `add-keeper`
+    pub operation: String,
+    /// The parameters of the operation, such as the id of an added node
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.timestamp, self.user, self.operation)?;
+        if !self.params.is_null() {
+            write!(f, " {}", self.params)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deployment {
+    /// Append an entry for `operation` to the history
+    pub(crate) fn record(
+        &self,
+        operation: &str,
+        params: serde_json::Value,
+    ) -> Result<()> {
+        self.append_history(operation, params)?;
+        let operation = operation.to_string();
+        self.report(ProgressEvent::OperationFinished { operation });
+        Ok(())
+    }
+
+    /// Append an operation to the history without reporting it as progress
+    pub(crate) fn append_history(
+        &self,
+        operation: &str,
+        params: serde_json::Value,
+    ) -> Result<()> {
+        // Plans list what an operation changes, not the operation itself
+        if self.writer.is_dry_run() {
+            return Ok(());
+        }
+        let entry = HistoryEntry {
+            timestamp: timestamp(),
+            user: current_user(),
+            operation: operation.to_string(),
+            params,
+        };
+        let path = self.config.path.join(HISTORY_FILENAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {path}"))?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)
+            .with_context(|| format!("failed to write {path}"))?;
+        Ok(())
+    }
+
+    /// Return all recorded operations, oldest first
+    pub fn history(&self) -> Result<Vec<HistoryEntry>> {
+        let path = self.config.path.join(HISTORY_FILENAME);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {path}"))?;
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("invalid entry in {path}"))
+            })
+            .collect()
+    }
+}
+
+/// Return the name of the user running us
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Return the current UTC time as `YYYY-MM-DDTHH:MM:SSZ`
+pub(crate) fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let (hour, min, sec) = (rem / 3600, rem % 3600 / 60, rem % 60);
+
+    // Convert days since the epoch to a civil date. See
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{min:02}:{sec:02}Z")
+}
@@ -0,0 +1,62 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::clone::copy_tree;
+use crate::{Deployment, NodeId, ServerId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use tracing::info;
+
+/// Files in a clickhouse data directory that belong to the server that wrote
+/// it rather than to its data
+const SERVER_FILES: [&str; 2] = ["status", "preprocessed_configs"];
+
+impl Deployment {
+    /// Replace the data of server `id` with a copy of the data directory of
+    /// a clickhouse server that clickward didn't deploy, i.e. one copied
+    /// from production
+    ///
+    /// The server is stopped while its data is replaced and started again
+    /// afterwards. The copy is owned by us and symlinks inside `source_dir`
+    /// point into the copy, so `source_dir` is left untouched. Replicated
+    /// tables find no metadata in our keepers and stay read only until
+    /// restored with `SYSTEM RESTORE REPLICA`.
+    pub fn import_server_data(
+        &self,
+        id: ServerId,
+        source_dir: &Utf8Path,
+    ) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !meta.server_ids.contains(&id) {
+            bail!("No such replica: {id}");
+        }
+        if !self.config.backend.is_local() {
+            bail!("Importing data is not supported on remote hosts");
+        }
+        if !source_dir.join("metadata").is_dir() {
+            bail!("{source_dir} is not a clickhouse data directory");
+        }
+        let _span = self.span("import-server-data").entered();
+
+        if self.is_alive(NodeId::Server(id)) {
+            self.stop_server(id)?;
+        }
+        let data = self.server_dir(id).join("data");
+        info!(%source_dir, %data, "Importing server data");
+        if data.exists() {
+            std::fs::remove_dir_all(&data)
+                .with_context(|| format!("failed to remove {data}"))?;
+        }
+        copy_tree(source_dir, &data, &|path| {
+            SERVER_FILES.contains(&path.as_str())
+        })?;
+        self.start_server(id)?;
+        self.record(
+            "import-server-data",
+            serde_json::json!({ "id": id, "source": source_dir }),
+        )
+    }
+}
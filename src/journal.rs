@@ -0,0 +1,211 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    ClickwardMetadata, Deployment, FileWriter, KeeperId, NodeId, ServerId,
+    MISSING_META,
+};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use tracing::info;
+
+/// The file in the deployment directory that records membership changes
+/// while they are in progress
+pub const JOURNAL_FILENAME: &str = "journal.json";
+
+/// A membership change that was started but may not have completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "kebab-case")]
+pub enum JournalEntry {
+    AddKeeper { id: KeeperId, observer: bool },
+    AddServer { id: ServerId },
+    RemoveKeeper { id: KeeperId, observer: bool },
+    RemoveServer { id: ServerId },
+}
+
+impl JournalEntry {
+    /// Return the node being added or removed
+    pub fn node(&self) -> NodeId {
+        match *self {
+            JournalEntry::AddKeeper { id, .. }
+            | JournalEntry::RemoveKeeper { id, .. } => NodeId::Keeper(id),
+            JournalEntry::AddServer { id }
+            | JournalEntry::RemoveServer { id } => NodeId::Server(id),
+        }
+    }
+
+    fn is_add(&self) -> bool {
+        matches!(
+            self,
+            JournalEntry::AddKeeper { .. } | JournalEntry::AddServer { .. }
+        )
+    }
+
+    /// Update `meta` so that the node is a member if `member` is true, and
+    /// isn't otherwise
+    fn set_membership(&self, meta: &mut ClickwardMetadata, member: bool) {
+        match *self {
+            JournalEntry::AddKeeper { id, observer }
+            | JournalEntry::RemoveKeeper { id, observer } => {
+                if member {
+                    meta.keeper_ids.insert(id);
+                    meta.max_keeper_id = meta.max_keeper_id.max(id);
+                    if observer {
+                        meta.keeper_observers.insert(id);
+                    }
+                } else {
+                    meta.forget_keeper(id);
+                }
+            }
+            JournalEntry::AddServer { id }
+            | JournalEntry::RemoveServer { id } => {
+                if member {
+                    meta.server_ids.insert(id);
+                    meta.max_server_id = meta.max_server_id.max(id);
+                } else {
+                    meta.forget_server(id);
+                }
+            }
+        }
+    }
+}
+
+impl Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operation = if self.is_add() { "add" } else { "remove" };
+        write!(f, "{operation} {}", self.node())
+    }
+}
+
+/// What to do with an interrupted membership change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Finish the change
+    Complete,
+    /// Undo the change
+    RollBack,
+}
+
+/// Record that a batch of membership changes is starting in
+/// `deployment_dir`, to be completed or rolled back together
+///
+/// Fails if a previous change was interrupted, as it must be recovered
+/// before making another.
+pub(crate) fn begin_batch(
+    writer: &FileWriter,
+    deployment_dir: &Utf8Path,
+    entries: &[JournalEntry],
+) -> Result<()> {
+    if let Some(pending) = load(deployment_dir)?.first() {
+        bail!(
+            "An interrupted membership change ({pending}) must be recovered \
+             first: run `clickward recover`"
+        );
+    }
+    let json = serde_json::to_string(entries)?;
+    writer.write(&deployment_dir.join(JOURNAL_FILENAME), json.as_bytes())
+}
+
+/// Record that the membership changes in progress in `deployment_dir` have
+/// completed
+pub(crate) fn finish(
+    writer: &FileWriter,
+    deployment_dir: &Utf8Path,
+) -> Result<()> {
+    writer.remove_file(&deployment_dir.join(JOURNAL_FILENAME))
+}
+
+fn load(deployment_dir: &Utf8Path) -> Result<Vec<JournalEntry>> {
+    let path = deployment_dir.join(JOURNAL_FILENAME);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let json = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {path}"))?;
+    // The journal held a single change before batches of changes were
+    // journaled
+    let value: serde_json::Value = serde_json::from_str(&json)?;
+    if let serde_json::Value::Array(_) = value {
+        Ok(serde_json::from_value(value)?)
+    } else {
+        Ok(vec![serde_json::from_value(value)?])
+    }
+}
+
+impl Deployment {
+    /// Return the membership changes that were interrupted, if any
+    pub fn pending_operations(&self) -> Result<Vec<JournalEntry>> {
+        load(&self.config.path)
+    }
+
+    /// Complete or roll back interrupted membership changes
+    ///
+    /// Membership changes write metadata and configs of several nodes and
+    /// start or stop nodes, none of which is transactional. Recovery brings
+    /// the metadata into the desired state, regenerates all configs from it,
+    /// and starts or stops nodes as needed. Returns the recovered changes,
+    /// which are empty if there was nothing to recover.
+    pub fn recover(&mut self, recovery: Recovery) -> Result<Vec<JournalEntry>> {
+        let entries = self.pending_operations()?;
+        if entries.is_empty() {
+            return Ok(entries);
+        }
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        let member = |entry: &JournalEntry| match recovery {
+            Recovery::Complete => entry.is_add(),
+            Recovery::RollBack => !entry.is_add(),
+        };
+        for entry in &entries {
+            info!(?recovery, "Recovering interrupted {entry}");
+            entry.set_membership(meta, member(entry));
+        }
+        self.writer.save_meta(meta, &self.config.path)?;
+        let meta = meta.clone();
+
+        // As in `add_keeper`, a new node must be online before the other
+        // nodes are told about it
+        for entry in &entries {
+            let member = member(entry);
+            let node = entry.node();
+            let alive = self.is_alive(node);
+            match node {
+                NodeId::Keeper(id) if member && !alive => {
+                    self.generate_keeper_config(id, meta.keeper_ids.clone())?;
+                    self.start_keeper(id)?;
+                }
+                NodeId::Server(id) if member && !alive => {
+                    self.generate_clickhouse_config(
+                        meta.keeper_ids.clone(),
+                        meta.server_ids.clone(),
+                    )?;
+                    self.start_server(id)?;
+                }
+                NodeId::Keeper(id) if !member && alive => {
+                    self.stop_keeper(id)?
+                }
+                NodeId::Server(id) if !member && alive => {
+                    self.stop_server(id)?
+                }
+                _ => (),
+            }
+        }
+        self.regenerate_configs()?;
+
+        finish(&self.writer, &self.config.path)?;
+        for entry in &entries {
+            self.record(
+                "recover",
+                serde_json::json!({
+                    "operation": entry.to_string(),
+                    "rolled_back": recovery == Recovery::RollBack,
+                }),
+            )?;
+        }
+        Ok(entries)
+    }
+}
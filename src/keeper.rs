@@ -2,13 +2,24 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::{url_host, KeeperId};
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::process::Stdio;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::process::Command;
 
+/// How long we wait for a response to a four letter word command
+const FOUR_LETTER_WORD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The four letter words we send to keepers, which keepers must accept for
+/// health checks, leadership transfer and storage stats to work
+pub(crate) const REQUIRED_FOUR_LETTER_WORDS: [&str; 4] =
+    ["ruok", "srvr", "lgif", "rqld"];
+
 #[derive(Error, Debug)]
 pub enum KeeperError {
     #[error("no config present")]
@@ -22,6 +33,9 @@ pub enum KeeperError {
 
     #[error("query error: query = {query}, error = {error}")]
     Query { query: String, error: String },
+
+    #[error("keeper-client commands can't contain `;`: {0}")]
+    InvalidCommand(String),
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +43,19 @@ pub struct KeeperConfig {
     pub addr: String,
 }
 
+/// The state of a keeper's raft log, as reported by the `lgif` command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeeperLogInfo {
+    pub first_log_idx: u64,
+    pub first_log_term: u64,
+    pub last_log_idx: u64,
+    pub last_log_term: u64,
+    pub last_committed_log_idx: u64,
+    pub leader_committed_log_idx: u64,
+    pub target_committed_log_idx: u64,
+    pub last_snapshot_idx: u64,
+}
+
 /// A client for interacting with keeper instances
 #[derive(Debug, Clone)]
 pub struct KeeperClient {
@@ -46,8 +73,8 @@ impl KeeperClient {
 
     pub async fn config(
         &self,
-    ) -> Result<BTreeMap<u64, KeeperConfig>, KeeperError> {
-        let output = self.query("get /keeper/config").await?;
+    ) -> Result<BTreeMap<KeeperId, KeeperConfig>, KeeperError> {
+        let output = self.run(&["get /keeper/config"]).await?;
         let mut config = BTreeMap::new();
         for line in output.lines() {
             let s = line
@@ -61,36 +88,171 @@ impl KeeperClient {
                 .next()
                 .ok_or(KeeperError::UnexpectedResponse)?;
             let id = id
-                .parse::<u64>()
+                .parse::<KeeperId>()
                 .map_err(|_| KeeperError::UnexpectedResponse)?;
             config.insert(id, KeeperConfig { addr: addr.to_string() });
         }
         Ok(config)
     }
 
+    /// Send a four letter word admin command, i.e. `mntr`, and return the
+    /// response
+    pub async fn four_letter_word(
+        &self,
+        command: &str,
+    ) -> Result<String, KeeperError> {
+        let f = async {
+            let mut stream = TcpStream::connect(self.addr).await?;
+            stream.write_all(command.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+        tokio::time::timeout(FOUR_LETTER_WORD_TIMEOUT, f)
+            .await
+            .map_err(|_| KeeperError::UnexpectedResponse)?
+            .map_err(KeeperError::Io)
+    }
+
+    /// Return the state of the keeper's raft log
+    pub async fn log_info(&self) -> Result<KeeperLogInfo, KeeperError> {
+        let output = self.four_letter_word("lgif").await?;
+        let mut info = KeeperLogInfo::default();
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once('\t') else {
+                continue;
+            };
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|_| KeeperError::UnexpectedResponse)?;
+            match key.trim() {
+                "first_log_idx" => info.first_log_idx = value,
+                "first_log_term" => info.first_log_term = value,
+                "last_log_idx" => info.last_log_idx = value,
+                "last_log_term" => info.last_log_term = value,
+                "last_committed_log_idx" => info.last_committed_log_idx = value,
+                "leader_committed_log_idx" => {
+                    info.leader_committed_log_idx = value
+                }
+                "target_committed_log_idx" => {
+                    info.target_committed_log_idx = value
+                }
+                "last_snapshot_idx" => info.last_snapshot_idx = value,
+                _ => (),
+            }
+        }
+        Ok(info)
+    }
+
+    /// Return the last zxid applied by the keeper, as reported by `srvr`
+    pub async fn last_zxid(&self) -> Result<u64, KeeperError> {
+        let output = self.four_letter_word("srvr").await?;
+        let zxid = output
+            .lines()
+            .find_map(|line| line.strip_prefix("Zxid:"))
+            .ok_or(KeeperError::UnexpectedResponse)?
+            .trim();
+        let parsed = match zxid.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => zxid.parse(),
+        };
+        parsed.map_err(|_| KeeperError::UnexpectedResponse)
+    }
+
+    /// Return the role of the keeper in its cluster, i.e. `leader`,
+    /// `follower` or `standalone`, as reported by `srvr`
+    pub async fn mode(&self) -> Result<String, KeeperError> {
+        let output = self.four_letter_word("srvr").await?;
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix("Mode:"))
+            .map(|mode| mode.trim().to_string())
+            .ok_or(KeeperError::UnexpectedResponse)
+    }
+
+    /// Ask the keeper to become the leader of its cluster
+    ///
+    /// The request is only sent. Whether it succeeds depends on the current
+    /// leader and on the keeper's priority.
+    pub async fn request_leadership(&self) -> Result<(), KeeperError> {
+        let output = self.four_letter_word("rqld").await?;
+        if !output.starts_with("Sent leadership request") {
+            return Err(KeeperError::Query {
+                query: "rqld".to_string(),
+                error: output.trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Run `commands` of `clickhouse keeper-client`, i.e. `ls /` or
+    /// `stat /keeper`, one after the other and return their output
+    ///
+    /// keeper-client splits its query on `;`, so commands can't contain one.
+    pub async fn run(&self, commands: &[&str]) -> Result<String, KeeperError> {
+        if let Some(command) = commands.iter().find(|c| c.contains(';')) {
+            return Err(KeeperError::InvalidCommand(command.to_string()));
+        }
+        self.query(&commands.join("; ")).await
+    }
+
+    /// Return the command that runs an interactive `clickhouse
+    /// keeper-client` connected to the keeper
+    pub fn shell(&self) -> std::process::Command {
+        let mut cmd = std::process::Command::new("clickhouse");
+        cmd.args(self.client_args());
+        cmd
+    }
+
+    fn client_args(&self) -> [String; 5] {
+        [
+            "keeper-client".to_string(),
+            "--host".to_string(),
+            url_host(&self.addr.ip().to_string()),
+            "--port".to_string(),
+            self.addr.port().to_string(),
+        ]
+    }
+
     async fn query(&self, query: &str) -> Result<String, KeeperError> {
-        let mut child = Command::new("clickhouse")
-            .arg("keeper-client")
-            .arg("--host")
-            .arg(format!("[{}]", self.addr.ip().to_string()))
-            .arg("--port")
-            .arg(self.addr.port().to_string())
+        // Read stdout and stderr together, as the client blocks once either
+        // pipe is full
+        let output = Command::new("clickhouse")
+            .args(self.client_args())
             .arg("--query")
             .arg(query)
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn()?;
+            .spawn()?
+            .wait_with_output()
+            .await?;
 
-        let mut stderr = child.stderr.take().unwrap();
-        let mut error = String::new();
-        stderr.read_to_string(&mut error).await?;
-        if !error.is_empty() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() || !error.is_empty() {
+            let error = if error.is_empty() {
+                output.status.to_string()
+            } else {
+                error.into_owned()
+            };
             return Err(KeeperError::Query { query: query.to_string(), error });
         }
-        let mut stdout = child.stdout.take().unwrap();
-        let mut output = String::new();
-        stdout.read_to_string(&mut output).await?;
-        Ok(output)
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn run_rejects_commands_with_semicolons() {
+        let client = KeeperClient::new("[::1]:9181".parse().unwrap());
+        let err = client.run(&["ls /", "set /a 'b;c'"]).await.unwrap_err();
+        assert!(
+            matches!(&err, KeeperError::InvalidCommand(c) if c == "set /a 'b;c'"),
+            "{err}"
+        );
     }
 }
@@ -0,0 +1,153 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::health::HEALTH_POLL_INTERVAL;
+use crate::{
+    Deployment, KeeperClient, KeeperId, OperationContext, PlanStep,
+    MISSING_META,
+};
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// The raft priority of keepers, unless set with `set_keeper_priority`
+pub const DEFAULT_KEEPER_PRIORITY: u32 = 1;
+
+/// How long we wait for another keeper to take over from a leader that we
+/// are about to remove
+const LEADERSHIP_TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl Deployment {
+    /// Return the raft priority of a keeper
+    pub fn keeper_priority(&self, id: KeeperId) -> u32 {
+        self.meta
+            .as_ref()
+            .and_then(|meta| meta.keeper_priorities.get(&id).copied())
+            .unwrap_or(DEFAULT_KEEPER_PRIORITY)
+    }
+
+    /// Set the raft priority of a keeper and regenerate all keeper configs
+    ///
+    /// Running keepers reload their configs automatically. Keepers with a
+    /// higher priority are preferred when electing a leader, and a priority
+    /// of 0 means the keeper never becomes leader.
+    pub fn set_keeper_priority(
+        &mut self,
+        id: KeeperId,
+        priority: u32,
+    ) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        if !meta.keeper_ids.contains(&id) {
+            bail!("No such keeper: {id}");
+        }
+        if priority == DEFAULT_KEEPER_PRIORITY {
+            meta.keeper_priorities.remove(&id);
+        } else {
+            meta.keeper_priorities.insert(id, priority);
+        }
+        self.writer.save_meta(meta, &self.config.path)?;
+        let keeper_ids = meta.keeper_ids.clone();
+
+        for keeper in &keeper_ids {
+            self.generate_keeper_config(*keeper, keeper_ids.clone())?;
+        }
+        self.record(
+            "set-keeper-priority",
+            serde_json::json!({ "id": id, "priority": priority }),
+        )
+    }
+
+    /// Ask a keeper to take over leadership of the keeper cluster
+    pub async fn request_leadership(&self, id: KeeperId) -> Result<()> {
+        let client = KeeperClient::new(self.keeper_addr(id)?);
+        client.request_leadership().await?;
+        self.record("request-leadership", serde_json::json!({ "id": id }))
+    }
+
+    /// Remove a keeper like `remove_keeper`, unless that would lose quorum
+    ///
+    /// The keepers that remain must include a quorum of healthy voters, and
+    /// if the keeper leads the cluster, leadership is handed to the healthy
+    /// voter with the highest priority first. `force` skips both.
+    pub async fn remove_keeper_safely(
+        &mut self,
+        id: KeeperId,
+        force: bool,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let _op = self.begin_operation(context);
+        if !force {
+            self.prepare_keeper_removal(id).await?;
+        }
+        self.remove_keeper(id, context)
+    }
+
+    /// Check that removing keeper `id` keeps quorum, and move leadership
+    /// away from it
+    async fn prepare_keeper_removal(&self, id: KeeperId) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !meta.keeper_ids.contains(&id) {
+            bail!("No such keeper: {id}");
+        }
+        // Observers neither vote nor lead
+        if meta.is_keeper_observer(id) {
+            return Ok(());
+        }
+        let voters: Vec<KeeperId> = meta
+            .keeper_ids
+            .iter()
+            .filter(|&&k| k != id && !meta.is_keeper_observer(k))
+            .copied()
+            .collect();
+        if voters.is_empty() {
+            return Ok(());
+        }
+        let mut healthy = vec![];
+        for &k in &voters {
+            if self.keeper_is_healthy(k).await {
+                healthy.push(k);
+            }
+        }
+        let quorum = voters.len() / 2 + 1;
+        if healthy.len() < quorum {
+            bail!(
+                "Removing keeper {id} would leave {} healthy voters, short of \
+                 a quorum of {quorum} of the remaining {}; force the removal \
+                 to do it anyway",
+                healthy.len(),
+                voters.len()
+            );
+        }
+
+        let mode = KeeperClient::new(self.keeper_addr(id)?).mode().await;
+        if mode.ok().as_deref() != Some("leader") {
+            return Ok(());
+        }
+        let Some(successor) = healthy
+            .into_iter()
+            .filter(|&k| self.keeper_priority(k) > 0)
+            .max_by_key(|&k| self.keeper_priority(k))
+        else {
+            bail!("No healthy keeper can take over leadership from {id}");
+        };
+        if self.writer.skip(PlanStep::RequestLeadership(successor)) {
+            return Ok(());
+        }
+        info!(%id, %successor, "Transferring keeper leadership");
+        let client = KeeperClient::new(self.keeper_addr(successor)?);
+        client.request_leadership().await?;
+        let deadline = Instant::now() + LEADERSHIP_TRANSFER_TIMEOUT;
+        while client.mode().await.ok().as_deref() != Some("leader") {
+            if Instant::now() >= deadline {
+                bail!("timed out waiting for keeper {successor} to lead");
+            }
+            self.pause(HEALTH_POLL_INTERVAL).await?;
+        }
+        Ok(())
+    }
+}
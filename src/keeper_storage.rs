@@ -0,0 +1,253 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::clone::copy_tree;
+use crate::keeper::{KeeperClient, KeeperLogInfo};
+use crate::{Deployment, KeeperId, NodeId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::info;
+
+/// What a keeper has stored on disk, along with what it reports about its
+/// raft log if it is running
+#[derive(Debug, Clone)]
+pub struct KeeperStorageStats {
+    /// The number of changelog segment files
+    pub log_segments: usize,
+    pub log_bytes: u64,
+    pub snapshots: usize,
+    pub snapshot_bytes: u64,
+    /// The log index of the newest snapshot on disk
+    pub latest_snapshot_idx: Option<u64>,
+    /// `None` if the keeper didn't respond
+    pub log_info: Option<KeeperLogInfo>,
+    /// `None` if the keeper didn't respond
+    pub last_zxid: Option<u64>,
+}
+
+/// What `Deployment::clean_keeper_logs` removed
+#[derive(Debug, Clone, Default)]
+pub struct KeeperCleanup {
+    pub snapshots_removed: usize,
+    pub log_segments_removed: usize,
+}
+
+/// Keeper state lives on remote hosts, where we can't copy it
+const REMOTE_KEEPER_STATE: &str =
+    "Moving keeper state is not supported for deployments on remote hosts";
+
+/// A file in a keeper's coordination directory and the log indexes in it
+struct StorageFile {
+    path: Utf8PathBuf,
+    size: u64,
+    /// The first log index covered, which for snapshots is the only one
+    start_idx: u64,
+    /// The last log index covered
+    end_idx: u64,
+}
+
+impl Deployment {
+    /// Return the directory where a keeper stores its raft log
+    pub fn keeper_log_dir(&self, id: KeeperId) -> Utf8PathBuf {
+        self.keeper_dir(id).join("coordination").join("log")
+    }
+
+    /// Return the directory where a keeper stores its snapshots
+    pub fn keeper_snapshot_dir(&self, id: KeeperId) -> Utf8PathBuf {
+        self.keeper_dir(id).join("coordination").join("snapshots")
+    }
+
+    /// Inspect a keeper's coordination directory, and ask the keeper about
+    /// its log via the four letter word admin protocol
+    pub async fn keeper_storage_stats(
+        &self,
+        id: KeeperId,
+    ) -> Result<KeeperStorageStats> {
+        let logs = storage_files(&self.keeper_log_dir(id), "changelog_")?;
+        let snapshots =
+            storage_files(&self.keeper_snapshot_dir(id), "snapshot_")?;
+        let client = KeeperClient::new(self.keeper_addr(id)?);
+        Ok(KeeperStorageStats {
+            log_segments: logs.len(),
+            log_bytes: logs.iter().map(|f| f.size).sum(),
+            snapshots: snapshots.len(),
+            snapshot_bytes: snapshots.iter().map(|f| f.size).sum(),
+            latest_snapshot_idx: snapshots.iter().map(|f| f.end_idx).max(),
+            log_info: client.log_info().await.ok(),
+            last_zxid: client.last_zxid().await.ok(),
+        })
+    }
+
+    /// Remove all but the newest `keep` snapshots of a keeper, along with
+    /// changelog segments that end before the oldest remaining snapshot
+    ///
+    /// The newest changelog segment is never removed, as the keeper may be
+    /// writing to it.
+    pub fn clean_keeper_logs(
+        &self,
+        id: KeeperId,
+        keep: usize,
+    ) -> Result<KeeperCleanup> {
+        if keep == 0 {
+            bail!("at least one snapshot must be kept");
+        }
+        let mut snapshots =
+            storage_files(&self.keeper_snapshot_dir(id), "snapshot_")?;
+        let mut logs = storage_files(&self.keeper_log_dir(id), "changelog_")?;
+        snapshots.sort_by_key(|f| f.end_idx);
+        logs.sort_by_key(|f| f.start_idx);
+
+        let mut cleanup = KeeperCleanup::default();
+        let remove = snapshots.len().saturating_sub(keep);
+        for snapshot in snapshots.drain(..remove) {
+            remove_file(&snapshot.path)?;
+            cleanup.snapshots_removed += 1;
+        }
+
+        if let Some(oldest) = snapshots.first() {
+            logs.pop();
+            for log in logs.iter().filter(|f| f.end_idx < oldest.end_idx) {
+                remove_file(&log.path)?;
+                cleanup.log_segments_removed += 1;
+            }
+        }
+        self.record(
+            "clean-keeper-logs",
+            serde_json::json!({ "id": id, "keep": keep }),
+        )?;
+        Ok(cleanup)
+    }
+
+    /// Copy the raft log and snapshots of keeper `id` into `dest/log` and
+    /// `dest/snapshots`
+    ///
+    /// A running keeper is stopped while the copy is taken, so that it
+    /// doesn't write to its log halfway through, and started again
+    /// afterwards.
+    pub fn export_keeper_state(
+        &self,
+        id: KeeperId,
+        dest: &Utf8Path,
+    ) -> Result<()> {
+        self.check_keeper_state_move(id)?;
+        if dest.exists() && dest.read_dir_utf8()?.next().is_some() {
+            bail!("{dest} is not empty");
+        }
+        let alive = self.is_alive(NodeId::Keeper(id));
+        if alive {
+            self.stop_keeper(id)?;
+        }
+        info!(%id, %dest, "Exporting keeper state");
+        for (src, name) in [
+            (self.keeper_log_dir(id), "log"),
+            (self.keeper_snapshot_dir(id), "snapshots"),
+        ] {
+            if src.exists() {
+                copy_tree(&src, &dest.join(name), &|_| false)?;
+            }
+        }
+        if alive {
+            self.start_keeper(id)?;
+        }
+        self.record(
+            "export-keeper-state",
+            serde_json::json!({ "id": id, "dest": dest }),
+        )
+    }
+
+    /// Replace the raft log and snapshots of keeper `id` with those exported
+    /// to `src` by `export_keeper_state`
+    ///
+    /// A running keeper is stopped while its state is replaced and started
+    /// again afterwards. To move a whole ensemble, import the state of each
+    /// keeper into the keeper with the same id before starting any of them.
+    pub fn import_keeper_state(
+        &self,
+        id: KeeperId,
+        src: &Utf8Path,
+    ) -> Result<()> {
+        self.check_keeper_state_move(id)?;
+        let log = src.join("log");
+        let snapshots = src.join("snapshots");
+        if !log.exists() && !snapshots.exists() {
+            bail!("{src} holds no keeper log or snapshots");
+        }
+        let alive = self.is_alive(NodeId::Keeper(id));
+        if alive {
+            self.stop_keeper(id)?;
+        }
+        info!(%id, %src, "Importing keeper state");
+        for (from, to) in [
+            (log, self.keeper_log_dir(id)),
+            (snapshots, self.keeper_snapshot_dir(id)),
+        ] {
+            if to.exists() {
+                std::fs::remove_dir_all(&to)
+                    .with_context(|| format!("failed to remove {to}"))?;
+            }
+            if from.exists() {
+                copy_tree(&from, &to, &|_| false)?;
+            }
+        }
+        if alive {
+            self.start_keeper(id)?;
+        }
+        self.record(
+            "import-keeper-state",
+            serde_json::json!({ "id": id, "src": src }),
+        )
+    }
+
+    /// Fail if we can't move the state of keeper `id` in or out
+    fn check_keeper_state_move(&self, id: KeeperId) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !meta.keeper_ids.contains(&id) {
+            bail!("No such keeper: {id}");
+        }
+        if !self.config.backend.is_local() {
+            bail!(REMOTE_KEEPER_STATE);
+        }
+        Ok(())
+    }
+}
+
+fn remove_file(path: &Utf8Path) -> Result<()> {
+    info!(%path, "Removing keeper storage file");
+    std::fs::remove_file(path)
+        .with_context(|| format!("failed to remove {path}"))
+}
+
+/// Return the files in `dir` named `<prefix><idx>[_<idx>].bin[.<ext>]`
+///
+/// Changelogs are named after the range of log indexes they hold, and
+/// snapshots after the log index they were taken at. A missing directory has
+/// no files.
+fn storage_files(dir: &Utf8Path, prefix: &str) -> Result<Vec<StorageFile>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut files = vec![];
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        let Some(rest) = entry.file_name().strip_prefix(prefix) else {
+            continue;
+        };
+        let Some((range, _)) = rest.split_once(".bin") else {
+            continue;
+        };
+        let (start, end) = range.split_once('_').unwrap_or((range, range));
+        let (Ok(start_idx), Ok(end_idx)) = (start.parse(), end.parse()) else {
+            continue;
+        };
+        files.push(StorageFile {
+            path: entry.path().to_path_buf(),
+            size: entry.metadata()?.len(),
+            start_idx,
+            end_idx,
+        });
+    }
+    Ok(files)
+}
@@ -4,19 +4,223 @@
 
 use anyhow::{bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use derive_more::{Add, AddAssign, Display, From};
+use derive_more::{Add, AddAssign, Display, From, FromStr};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
+use std::hash::{BuildHasher, RandomState};
 use std::io::Write;
-use std::net::SocketAddr;
-use std::process::{Command, Stdio};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, info_span, warn, Instrument};
 
 pub mod config;
 use config::*;
 
 mod keeper;
-pub use keeper::{KeeperClient, KeeperError};
+use keeper::REQUIRED_FOUR_LETTER_WORDS;
+pub use keeper::{KeeperClient, KeeperError, KeeperLogInfo};
+
+mod journal;
+pub use journal::{JournalEntry, Recovery, JOURNAL_FILENAME};
+
+mod keeper_leader;
+pub use keeper_leader::DEFAULT_KEEPER_PRIORITY;
+
+mod keeper_storage;
+pub use keeper_storage::{KeeperCleanup, KeeperStorageStats};
+
+mod shell;
+
+mod link;
+
+mod logs;
+pub use logs::{LogFilter, LogLine};
+
+mod snapshot;
+pub use snapshot::SNAPSHOTS_DIR;
+
+mod guard;
+pub use guard::DeploymentGuard;
+
+#[cfg(feature = "test-fixture")]
+mod fixture;
+#[cfg(feature = "test-fixture")]
+pub use fixture::{
+    ClickwardCluster, FIXTURE_CLUSTER_NAME, FIXTURE_START_TIMEOUT,
+};
+
+mod health;
+pub use health::HealthTimeout;
+
+mod upgrade;
+pub use upgrade::binary_version;
+
+mod version;
+pub use version::{
+    ClickhouseVersion, VersionedFeature, MINIMUM_CLICKHOUSE_VERSION,
+};
+
+mod backend;
+pub use backend::{
+    Backend, NodeAddresses, CONTAINER_ID_FILENAME, DEFAULT_DOCKER_IMAGE,
+    LOCALHOST,
+};
+
+mod remote;
+pub use remote::REMOTE_HOST_FILENAME;
+
+mod render;
+
+mod manager;
+pub use manager::{BackendProcessManager, ProcessManager};
+
+mod bench;
+pub use bench::{BenchOptions, BenchReport, ServerBenchResult};
+
+mod backup;
+pub use backup::{BackupInfo, BackupTarget};
+
+mod chaos;
+pub use chaos::DegradedLink;
+
+mod clean;
+
+mod clock;
+pub use clock::FAKETIME_BINARY;
+
+mod clone;
+
+mod import;
+
+mod attach;
+
+mod template;
+pub use template::ConfigTemplates;
+
+mod clusters;
+pub use clusters::{
+    cluster_names, cluster_path, CLUSTERS_DIR, CLUSTER_PORT_STRIDE,
+};
+
+mod diff;
+pub use diff::ConfigDiff;
+
+mod endpoints;
+pub use endpoints::{format_endpoints, Endpoint, EndpointFormat};
+
+mod events;
+pub use events::EVENTS_LOG_FILENAME;
+
+mod context;
+use context::CurrentOperation;
+pub use context::{CancelToken, OperationContext, OperationError};
+
+mod progress;
+pub use progress::{JsonProgress, NoProgress, ProgressEvent, ProgressSink};
+
+mod history;
+pub use history::{HistoryEntry, HISTORY_FILENAME};
+
+mod supervise;
+pub use supervise::RestartPolicy;
+
+mod minio;
+pub use minio::{MinioConfig, DEFAULT_MINIO_BINARY, DEFAULT_MINIO_PORT};
+
+mod node_info;
+pub use node_info::{NodeInfo, NodeRole, NODE_INFO_FILENAME};
+
+mod platform;
+
+mod zones;
+
+mod cordon;
+pub use cordon::NodeAdminState;
+
+mod plan;
+use plan::FileWriter;
+pub use plan::{Plan, PlanStep};
+
+mod ports;
+pub use ports::{
+    KeeperPorts, NodePort, PortAllocator, PortBlock, PortConflict,
+    PortConflicts, PortError, ServerPorts, MAX_PORT_NODE_ID,
+};
+
+mod spec;
+pub use spec::DeploymentSpec;
+
+mod users;
+
+mod usage;
+pub use usage::NodeResourceUsage;
+
+mod status;
+pub use status::{
+    NodeState, NodeStatus, CRASH_LOOP_STARTS, CRASH_LOOP_WINDOW,
+    LIFECYCLE_FILENAME,
+};
+
+mod validate;
+pub use validate::Inconsistency;
+
+mod preset;
+pub use preset::{Preset, DEFAULT_KEEPER_SNAPSHOT_DISTANCE};
+
+mod pinning;
+pub use pinning::{PinningPolicy, NUMACTL_BINARY, TASKSET_BINARY};
+
+mod process;
+pub use process::{
+    NodeProcessConfig, ProcessLimits, ResourceLimit, SpawnError,
+    STDERR_FILENAME, STDOUT_FILENAME,
+};
+
+#[cfg(feature = "http-client")]
+mod client;
+#[cfg(feature = "http-client")]
+pub use client::{ClickhouseClient, ClientError, QueryResult};
+
+#[cfg(feature = "http-client")]
+mod ddl;
+
+#[cfg(feature = "http-client")]
+mod database;
+
+mod scale;
+
+#[cfg(feature = "http-client")]
+mod seed;
+
+#[cfg(feature = "http-client")]
+mod replication;
+#[cfg(feature = "http-client")]
+pub use replication::{ReplicaStatus, ReplicationStatus};
+
+#[cfg(feature = "http-client")]
+mod system;
+#[cfg(feature = "http-client")]
+pub use system::{ClusterReplica, SystemClusterView, ZookeeperNode};
+
+#[cfg(feature = "http-client")]
+mod query_log;
+#[cfg(feature = "http-client")]
+pub use query_log::{QueryLogEntry, QueryLogFilter};
+
+#[cfg(feature = "http-client")]
+mod loadgen;
+#[cfg(feature = "http-client")]
+pub use loadgen::InsertReport;
+
+#[cfg(feature = "http-client")]
+mod reload;
+
+/// The clickhouse binary used by nodes unless configured otherwise
+pub const DEFAULT_CLICKHOUSE_BINARY: &str = "clickhouse";
 
 /// We put things in a subdirectory of the user path for easy cleanup
 pub const DEPLOYMENT_DIR: &str = "deployment";
@@ -25,8 +229,33 @@ pub const DEPLOYMENT_DIR: &str = "deployment";
 /// directly below <path>/deployment.
 pub const CLICKWARD_META_FILENAME: &str = "clickward-metadata.json";
 
+/// The name of the lock file held while `ClickwardMetadata` is saved. This
+/// lives next to the metadata file.
+pub const CLICKWARD_META_LOCK_FILENAME: &str = "clickward-metadata.lock";
+
+/// The name of the file where the `DeploymentConfig` used to generate a
+/// deployment lives. This is *always* directly below <path>/deployment.
+pub const CLICKWARD_CONFIG_FILENAME: &str = "clickward-config.json";
+
 const MISSING_META: &str = "No deployment found: Is your path correct?";
 
+const EXTERNAL_KEEPERS: &str =
+    "This deployment uses external keepers, which clickward doesn't manage";
+
+/// The HTTP path where prometheus metrics are served by each node
+pub const PROMETHEUS_ENDPOINT: &str = "/metrics";
+
+/// The HTTP path where keepers serving their HTTP control endpoint report
+/// whether they are ready
+pub const KEEPER_READINESS_ENDPOINT: &str = "/ready";
+
+/// The directory in each server's directory holding its dictionary files
+pub const DICTIONARIES_DIR: &str = "dictionaries";
+
+/// The cluster secret of deployments generated before secrets were stored in
+/// metadata
+const LEGACY_CLUSTER_SECRET: &str = "some-unique-value";
+
 /// A unique ID for a clickhouse keeper
 #[derive(
     Debug,
@@ -40,9 +269,11 @@ const MISSING_META: &str = "No deployment found: Is your path correct?";
     Add,
     AddAssign,
     Display,
+    FromStr,
     Serialize,
     Deserialize,
 )]
+#[serde(transparent)]
 pub struct KeeperId(pub u64);
 
 /// A unique ID for a clickhouse server
@@ -58,24 +289,249 @@ pub struct KeeperId(pub u64);
     Add,
     AddAssign,
     Display,
+    FromStr,
     Serialize,
     Deserialize,
 )]
+#[serde(transparent)]
 pub struct ServerId(pub u64);
 
+/// The kind of a node in a deployment
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum NodeKind {
+    Keeper,
+    Server,
+}
+
+/// The id of a node of either kind
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub enum NodeId {
+    Keeper(KeeperId),
+    Server(ServerId),
+}
+
+impl NodeId {
+    pub fn kind(&self) -> NodeKind {
+        match self {
+            NodeId::Keeper(_) => NodeKind::Keeper,
+            NodeId::Server(_) => NodeKind::Server,
+        }
+    }
+}
+
+/// Formats as the name of the node's directory, i.e. `keeper-1`
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeId::Keeper(id) => write!(f, "keeper-{id}"),
+            NodeId::Server(id) => write!(f, "clickhouse-{id}"),
+        }
+    }
+}
+
+impl From<KeeperId> for NodeId {
+    fn from(id: KeeperId) -> NodeId {
+        NodeId::Keeper(id)
+    }
+}
+
+impl From<ServerId> for NodeId {
+    fn from(id: ServerId) -> NodeId {
+        NodeId::Server(id)
+    }
+}
+
 pub const DEFAULT_BASE_PORTS: BasePorts = BasePorts {
     keeper: 20000,
     raft: 21000,
     clickhouse_tcp: 22000,
     clickhouse_http: 23000,
     clickhouse_interserver_http: 24000,
+    clickhouse_metrics: 25000,
+    keeper_metrics: 26000,
+    keeper_http_control: Some(27000),
 };
 
 // A configuration for a given clickward deployment
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeploymentConfig {
+    /// The deployment directory. This is not saved, as it's always the
+    /// directory the config was loaded from.
+    #[serde(skip)]
     pub path: Utf8PathBuf,
     pub base_ports: BasePorts,
     pub cluster_name: String,
+    /// Emit `<prometheus>` sections so every node serves a metrics endpoint
+    pub prometheus: bool,
+    /// The level at which all nodes log
+    pub log_level: LogLevel,
+    /// The size at which log files are rotated
+    pub log_size: ByteSize,
+    /// The number of rotated log files to keep
+    pub log_count: usize,
+    /// A directory of extra XML files merged into the generated configs
+    ///
+    /// Files directly inside this directory are applied to every clickhouse
+    /// server. Files inside `clickhouse-<id>` or `keeper-<id>` subdirectories
+    /// are only applied to that node. They are copied into the `config.d`
+    /// directory next to each node's config, which clickhouse merges in.
+    #[serde(default)]
+    pub extra_config_dir: Option<Utf8PathBuf>,
+    /// Templates that replace the configs we generate for keepers or servers
+    #[serde(default)]
+    pub config_templates: ConfigTemplates,
+    /// An existing keeper or zookeeper ensemble for the clickhouse servers to
+    /// use instead of clickward managed keepers
+    ///
+    /// When this is non-empty the deployment has no keepers of its own.
+    #[serde(default)]
+    pub external_keepers: Vec<ServerConfig>,
+    /// Environment variables set for every spawned node
+    #[serde(default)]
+    pub process_env: BTreeMap<String, String>,
+    /// Resource limits applied to every spawned node
+    #[serde(default)]
+    pub process_limits: ProcessLimits,
+    /// Process settings for individual nodes, keyed by node name, i.e.
+    /// `keeper-1` or `clickhouse-2`
+    #[serde(default)]
+    pub node_process: BTreeMap<String, NodeProcessConfig>,
+    /// How spawned nodes are pinned to CPUs, if at all
+    #[serde(default)]
+    pub cpu_pinning: Option<PinningPolicy>,
+    /// How nodes are run
+    #[serde(default)]
+    pub backend: Backend,
+    /// The address that every node listens on, rather than `::1` for local
+    /// backends and `::` for ssh
+    #[serde(default)]
+    pub bind_host: Option<String>,
+    /// The host that other nodes and clients reach nodes on this machine
+    /// at, rather than `::1`
+    ///
+    /// Together with `bind_host`, this lets nodes listen on `::` or
+    /// `0.0.0.0` while other hosts reach them at a routable address. Nodes
+    /// run with ssh are reached at their hosts instead.
+    #[serde(default)]
+    pub advertise_host: Option<String>,
+    /// Hosts of individual nodes, keyed by node name, i.e. `keeper-1`
+    #[serde(default)]
+    pub node_addresses: BTreeMap<String, NodeAddresses>,
+    /// The secret that servers use to authenticate distributed queries to
+    /// each other
+    ///
+    /// If unset, a random secret is generated for each deployment and stored
+    /// in its metadata.
+    #[serde(default)]
+    pub cluster_secret: Option<String>,
+    /// Credentials required for interserver http requests between replicas
+    #[serde(default)]
+    pub interserver_credentials: Option<InterserverCredentials>,
+    /// The znode under which servers keep replicated tables and the
+    /// distributed DDL queue
+    ///
+    /// If unset, a unique one is generated for each deployment and stored in
+    /// its metadata.
+    #[serde(default)]
+    pub zookeeper_root: Option<String>,
+    /// The name of a database using the `Replicated` engine to create on
+    /// every server after deploying
+    ///
+    /// This also allows the experimental engine in the default profile.
+    #[serde(default)]
+    pub replicated_database: Option<String>,
+    /// The database that queries on every server use unless they name
+    /// another, instead of `default`, which is created after deploying
+    #[serde(default)]
+    pub default_database: Option<String>,
+    /// Disks and storage policies for every server
+    #[serde(default)]
+    pub storage: Option<StorageConfig>,
+    /// A MinIO server to run alongside the deployment for `s3` disks
+    #[serde(default)]
+    pub minio: Option<MinioConfig>,
+    /// Named collections on every server, keyed by collection name and then
+    /// parameter name
+    #[serde(default)]
+    pub named_collections: BTreeMap<String, BTreeMap<String, String>>,
+    /// External dictionaries loaded by every server
+    #[serde(default)]
+    pub dictionaries: Vec<DictionaryConfig>,
+    /// A Kafka broker for Kafka engine tables
+    ///
+    /// Servers get a `kafka` named collection with its address.
+    #[serde(default)]
+    pub kafka: Option<BrokerConfig>,
+    /// A RabbitMQ broker for RabbitMQ engine tables
+    ///
+    /// Servers get a `rabbitmq` named collection with its address and
+    /// credentials.
+    #[serde(default)]
+    pub rabbitmq: Option<BrokerConfig>,
+    /// Enable the `query_log`, `metric_log`, `trace_log` and `part_log`
+    /// system tables on every server
+    #[serde(default)]
+    pub system_logs: bool,
+    /// Make inserts asynchronous by default on every server
+    #[serde(default)]
+    pub async_insert: bool,
+    /// Settings of the `default` profile of every server, keyed by setting
+    /// name, in addition to those we set
+    #[serde(default)]
+    pub profile_settings: BTreeMap<String, String>,
+    /// Users that every server has besides `default`
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// Give each node free ports picked when it's added, recorded in our
+    /// metadata, rather than ports offset from `base_ports` by its id
+    ///
+    /// This lets deployments share a machine without agreeing on blocks of
+    /// base ports. It's only supported for local backends.
+    #[serde(default)]
+    pub free_ports: bool,
+    /// Resource limits of every server
+    #[serde(default)]
+    pub server_limits: ServerLimits,
+    /// The number of log entries between keeper snapshots, by default
+    /// `DEFAULT_KEEPER_SNAPSHOT_DISTANCE`
+    #[serde(default)]
+    pub keeper_snapshot_distance: Option<u64>,
+    /// Serve keeper's HTTP control endpoint, with a readiness check at
+    /// `KEEPER_READINESS_ENDPOINT`, on every keeper
+    #[serde(default)]
+    pub keeper_http_control: bool,
+    /// The four letter word commands that keepers accept, or `*` for all of
+    /// them, rather than clickhouse's default list
+    ///
+    /// The words that clickward itself sends, `ruok`, `srvr`, `lgif` and
+    /// `rqld`, are always accepted.
+    #[serde(default)]
+    pub keeper_four_letter_words: Option<Vec<String>>,
+    /// The number of shards that servers are spread over, by default 1
+    ///
+    /// Server `n` belongs to shard `(n - 1) % num_shards + 1`, so added
+    /// servers fill the shards in turn.
+    #[serde(default)]
+    pub num_shards: Option<u64>,
+    /// The weight of each shard for distributed inserts, by default 1 for
+    /// every shard
+    #[serde(default)]
+    pub shard_weights: Vec<u32>,
+    /// The `<remote_servers>` priority of servers in each zone, where lower
+    /// priorities are preferred by distributed queries
+    #[serde(default)]
+    pub zone_priorities: BTreeMap<String, u32>,
+    /// Macros, keyed by name, that every server gets on top of `shard`,
+    /// `replica`, `cluster` and `zone`, or instead of them if they share a
+    /// name
+    #[serde(default)]
+    pub extra_macros: BTreeMap<String, String>,
+    /// Macros of individual servers, which take precedence over
+    /// `extra_macros`
+    #[serde(default)]
+    pub server_macros: BTreeMap<ServerId, BTreeMap<String, String>>,
 }
 
 impl DeploymentConfig {
@@ -88,17 +544,85 @@ impl DeploymentConfig {
             path,
             base_ports: DEFAULT_BASE_PORTS,
             cluster_name: cluster_name.into(),
+            prometheus: false,
+            log_level: LogLevel::Trace,
+            log_size: ByteSize::mib(100),
+            log_count: 1,
+            extra_config_dir: None,
+            config_templates: ConfigTemplates::default(),
+            external_keepers: vec![],
+            process_env: BTreeMap::new(),
+            process_limits: ProcessLimits::default(),
+            node_process: BTreeMap::new(),
+            cpu_pinning: None,
+            backend: Backend::Process,
+            bind_host: None,
+            advertise_host: None,
+            node_addresses: BTreeMap::new(),
+            cluster_secret: None,
+            interserver_credentials: None,
+            zookeeper_root: None,
+            replicated_database: None,
+            default_database: None,
+            storage: None,
+            minio: None,
+            named_collections: BTreeMap::new(),
+            dictionaries: vec![],
+            kafka: None,
+            rabbitmq: None,
+            system_logs: false,
+            async_insert: false,
+            profile_settings: BTreeMap::new(),
+            users: vec![],
+            free_ports: false,
+            server_limits: ServerLimits::default(),
+            keeper_snapshot_distance: None,
+            keeper_http_control: false,
+            keeper_four_letter_words: None,
+            num_shards: None,
+            shard_weights: vec![],
+            zone_priorities: BTreeMap::new(),
+            extra_macros: BTreeMap::new(),
+            server_macros: BTreeMap::new(),
         }
     }
+
+    /// Load the config that a deployment under `path` was generated with
+    pub fn load(path: Utf8PathBuf) -> Result<DeploymentConfig> {
+        let path = path.join(DEPLOYMENT_DIR);
+        let file = path.join(CLICKWARD_CONFIG_FILENAME);
+        let json = std::fs::read_to_string(&file)
+            .with_context(|| format!("failed to read {file}"))?;
+        let config: DeploymentConfig = serde_json::from_str(&json)
+            .with_context(|| InvalidConfig {
+                what: "deployment config",
+                path: file.clone(),
+            })?;
+        Ok(DeploymentConfig { path, ..config })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = self.path.join(CLICKWARD_CONFIG_FILENAME);
+        let json = serde_json::to_string(self)?;
+        write_atomic(&path, json.as_bytes())?;
+        Ok(())
+    }
 }
 
 // Port allocation used for config generation
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BasePorts {
     pub keeper: u16,
     pub raft: u16,
     pub clickhouse_tcp: u16,
     pub clickhouse_http: u16,
     pub clickhouse_interserver_http: u16,
+    pub clickhouse_metrics: u16,
+    pub keeper_metrics: u16,
+    /// `None` in configs saved before this was added, where it's as far
+    /// above `keeper` as in `DEFAULT_BASE_PORTS`
+    #[serde(default)]
+    pub keeper_http_control: Option<u16>,
 }
 
 /// Metadata stored for use by clickward
@@ -115,6 +639,15 @@ pub struct ClickwardMetadata {
     /// We only ever increment when adding a new id.
     pub max_keeper_id: KeeperId,
 
+    /// IDs of keepers in `keeper_ids` that are observers
+    /// Observers replicate the log but never vote or become leader.
+    #[serde(default)]
+    pub keeper_observers: BTreeSet<KeeperId>,
+
+    /// Raft priorities of keepers that don't have the default priority
+    #[serde(default)]
+    pub keeper_priorities: BTreeMap<KeeperId, u32>,
+
     /// IDs of clickhouse servers
     /// We never reuse IDs.
     pub server_ids: BTreeSet<ServerId>,
@@ -122,6 +655,113 @@ pub struct ClickwardMetadata {
     /// The maximum allocated clickhouse server id so far
     /// We only ever increment when adding a new id.
     pub max_server_id: ServerId,
+
+    /// Incremented on every save, so that we can detect concurrent updates
+    #[serde(default)]
+    pub generation: u64,
+
+    /// The clickhouse binaries run by servers that don't use the default
+    /// `clickhouse` binary on the `PATH`
+    #[serde(default)]
+    pub server_binaries: BTreeMap<ServerId, NodeBinary>,
+
+    /// The hash of the last schema applied with `Deployment::seed`
+    #[serde(default)]
+    pub seed_hash: Option<String>,
+
+    /// The `<secret>` of the cluster in `remote_servers`, unless overridden
+    /// by `DeploymentConfig::cluster_secret`
+    #[serde(default)]
+    pub cluster_secret: Option<String>,
+
+    /// The znode under which servers keep their data in keeper, unless
+    /// overridden by `DeploymentConfig::zookeeper_root`
+    ///
+    /// Deployments generated before this was added use clickhouse defaults.
+    #[serde(default)]
+    pub zookeeper_root: Option<String>,
+
+    /// The servers of other deployments linked with `Deployment::link_remote`,
+    /// keyed by the cluster name they are known by in this deployment
+    #[serde(default)]
+    pub linked_clusters: BTreeMap<String, LinkedCluster>,
+
+    /// The failure domains of keepers set with `Deployment::set_zone`
+    #[serde(default)]
+    pub keeper_zones: BTreeMap<KeeperId, String>,
+
+    /// The failure domains of servers set with `Deployment::set_zone`
+    #[serde(default)]
+    pub server_zones: BTreeMap<ServerId, String>,
+
+    /// Backups taken with `Deployment::backup`, keyed by name
+    #[serde(default)]
+    pub backups: BTreeMap<String, BackupInfo>,
+
+    /// Clock skews set with `Deployment::set_clock_skew` in seconds, keyed
+    /// by node name, i.e. `keeper-1`
+    #[serde(default)]
+    pub clock_skews: BTreeMap<String, i64>,
+
+    /// Links between nodes degraded with `Deployment::degrade_link`
+    #[serde(default)]
+    pub degraded_links: Vec<DegradedLink>,
+
+    /// Names of the nodes whose data path `Deployment::fill_disk` mounted a
+    /// filesystem over
+    #[serde(default)]
+    pub filled_disks: BTreeSet<String>,
+
+    /// The version of clickhouse that configs were generated for, unless we
+    /// couldn't run clickhouse at the time
+    #[serde(default)]
+    pub clickhouse_version: Option<ClickhouseVersion>,
+
+    /// The database created with `Deployment::create_default_database`
+    #[serde(default)]
+    pub default_database: Option<String>,
+
+    /// The ports of keepers picked with `DeploymentConfig::free_ports`, or
+    /// found by `Deployment::attach` where they differ from our config's
+    #[serde(default)]
+    pub keeper_ports: BTreeMap<KeeperId, KeeperPorts>,
+
+    /// The ports of servers picked with `DeploymentConfig::free_ports`, or
+    /// found by `Deployment::attach` where they differ from our config's
+    #[serde(default)]
+    pub server_ports: BTreeMap<ServerId, ServerPorts>,
+    /// The states of nodes set with `Deployment::set_admin_state`, keyed by
+    /// node name, i.e. `clickhouse-1`, for nodes that aren't active
+    #[serde(default)]
+    pub admin_states: BTreeMap<String, NodeAdminState>,
+}
+
+/// A clickhouse binary that a node runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeBinary {
+    pub path: Utf8PathBuf,
+    /// The output of `clickhouse --version` for this binary
+    pub version: String,
+}
+
+/// Returned when saving metadata that was modified on disk since we loaded it
+#[derive(Error, Debug)]
+#[error(
+    "metadata was modified concurrently: \
+     expected generation {expected}, found {found}"
+)]
+pub struct MetadataConflict {
+    pub expected: u64,
+    pub found: u64,
+}
+
+/// Returned when a config or spec file clickward reads can't be parsed
+#[derive(Error, Debug)]
+#[error("invalid {what} in {path}")]
+pub struct InvalidConfig {
+    /// What the file holds, i.e. `deployment config`
+    pub what: &'static str,
+    pub path: Utf8PathBuf,
 }
 
 impl ClickwardMetadata {
@@ -129,13 +769,33 @@ impl ClickwardMetadata {
         keeper_ids: BTreeSet<KeeperId>,
         replica_ids: BTreeSet<ServerId>,
     ) -> ClickwardMetadata {
-        let max_keeper_id = *keeper_ids.last().unwrap();
-        let max_replica_id = *replica_ids.last().unwrap();
+        // Ids start at 1, so a max id of 0 means none have been allocated
+        let max_keeper_id = keeper_ids.last().copied().unwrap_or(KeeperId(0));
+        let max_replica_id = replica_ids.last().copied().unwrap_or(ServerId(0));
         ClickwardMetadata {
             keeper_ids,
             max_keeper_id,
+            keeper_observers: BTreeSet::new(),
+            keeper_priorities: BTreeMap::new(),
             server_ids: replica_ids,
             max_server_id: max_replica_id,
+            generation: 0,
+            server_binaries: BTreeMap::new(),
+            seed_hash: None,
+            cluster_secret: Some(generate_secret()),
+            zookeeper_root: None,
+            linked_clusters: BTreeMap::new(),
+            keeper_zones: BTreeMap::new(),
+            server_zones: BTreeMap::new(),
+            backups: BTreeMap::new(),
+            clock_skews: BTreeMap::new(),
+            degraded_links: vec![],
+            filled_disks: BTreeSet::new(),
+            clickhouse_version: None,
+            default_database: None,
+            keeper_ports: BTreeMap::new(),
+            server_ports: BTreeMap::new(),
+            admin_states: BTreeMap::new(),
         }
     }
 
@@ -145,28 +805,91 @@ impl ClickwardMetadata {
         self.max_keeper_id
     }
 
+    /// Add a keeper that never votes or becomes leader
+    pub fn add_keeper_observer(&mut self) -> KeeperId {
+        let id = self.add_keeper();
+        self.keeper_observers.insert(id);
+        id
+    }
+
+    /// Add back a keeper that was removed, as a participant
+    pub fn readd_keeper(&mut self, id: KeeperId) -> Result<()> {
+        if self.keeper_ids.contains(&id) {
+            bail!("Keeper {id} is already part of the deployment");
+        }
+        if id > self.max_keeper_id {
+            bail!("Keeper {id} was never part of the deployment");
+        }
+        self.keeper_ids.insert(id);
+        Ok(())
+    }
+
     pub fn remove_keeper(&mut self, id: KeeperId) -> Result<()> {
-        let was_removed = self.keeper_ids.remove(&id);
-        if !was_removed {
+        if !self.keeper_ids.contains(&id) {
             bail!("No such keeper: {id}");
         }
+        let has_participants = self
+            .keeper_ids
+            .iter()
+            .any(|&k| k != id && !self.is_keeper_observer(k));
+        if !has_participants && self.keeper_ids.len() > 1 {
+            bail!("Cannot remove the last keeper that isn't an observer");
+        }
+        self.forget_keeper(id);
         Ok(())
     }
 
+    /// Remove keeper `id` and everything we know about it, if it is there
+    pub(crate) fn forget_keeper(&mut self, id: KeeperId) {
+        self.keeper_ids.remove(&id);
+        self.keeper_observers.remove(&id);
+        self.keeper_priorities.remove(&id);
+        self.keeper_zones.remove(&id);
+        self.keeper_ports.remove(&id);
+        self.clock_skews.remove(&NodeId::Keeper(id).to_string());
+        self.admin_states.remove(&NodeId::Keeper(id).to_string());
+    }
+
+    /// Return true if the keeper is an observer rather than a participant
+    pub fn is_keeper_observer(&self, id: KeeperId) -> bool {
+        self.keeper_observers.contains(&id)
+    }
+
     pub fn add_server(&mut self) -> ServerId {
         self.max_server_id += 1.into();
         self.server_ids.insert(self.max_server_id);
         self.max_server_id
     }
 
+    /// Add back a server that was removed
+    pub fn readd_server(&mut self, id: ServerId) -> Result<()> {
+        if self.server_ids.contains(&id) {
+            bail!("Replica {id} is already part of the deployment");
+        }
+        if id > self.max_server_id {
+            bail!("Replica {id} was never part of the deployment");
+        }
+        self.server_ids.insert(id);
+        Ok(())
+    }
+
     pub fn remove_server(&mut self, id: ServerId) -> Result<()> {
-        let was_removed = self.server_ids.remove(&id);
-        if !was_removed {
+        if !self.server_ids.contains(&id) {
             bail!("No such replica: {id}");
         }
+        self.forget_server(id);
         Ok(())
     }
 
+    /// Remove server `id` and everything we know about it, if it is there
+    pub(crate) fn forget_server(&mut self, id: ServerId) {
+        self.server_ids.remove(&id);
+        self.server_zones.remove(&id);
+        self.server_ports.remove(&id);
+        self.clock_skews.remove(&NodeId::Server(id).to_string());
+        self.admin_states.remove(&NodeId::Server(id).to_string());
+    }
+
     pub fn load(deployment_dir: &Utf8Path) -> Result<ClickwardMetadata> {
         let path = deployment_dir.join(CLICKWARD_META_FILENAME);
         let json = std::fs::read_to_string(&path)
@@ -175,21 +898,60 @@ impl ClickwardMetadata {
         Ok(meta)
     }
 
-    pub fn save(&self, deployment_dir: &Utf8Path) -> Result<()> {
+    /// Save the metadata and bump its generation
+    ///
+    /// Fails with a `MetadataConflict` if the metadata on disk was saved by
+    /// someone else since we loaded it. We hold an exclusive lock on
+    /// `CLICKWARD_META_LOCK_FILENAME` while checking and writing, so
+    /// concurrent saves can't both succeed.
+    pub fn save(&mut self, deployment_dir: &Utf8Path) -> Result<()> {
+        let lock_path = deployment_dir.join(CLICKWARD_META_LOCK_FILENAME);
+        let lock = File::create(&lock_path)
+            .with_context(|| format!("failed to create {lock_path}"))?;
+        lock.lock().with_context(|| format!("failed to lock {lock_path}"))?;
+
         let path = deployment_dir.join(CLICKWARD_META_FILENAME);
-        let json = serde_json::to_string(self)?;
-        std::fs::write(&path, &json)
-            .with_context(|| format!("Failed to write {path}"))?;
+        if path.exists() {
+            let found = ClickwardMetadata::load(deployment_dir)?.generation;
+            if found != self.generation {
+                return Err(MetadataConflict {
+                    expected: self.generation,
+                    found,
+                }
+                .into());
+            }
+        }
+
+        let next = ClickwardMetadata {
+            generation: self.generation + 1,
+            ..self.clone()
+        };
+        let json = serde_json::to_string(&next)?;
+        if path.exists() {
+            let backup = path.with_extension("json.bak");
+            std::fs::copy(&path, &backup)
+                .with_context(|| format!("Failed to back up {path}"))?;
+        }
+        write_atomic(&path, json.as_bytes())?;
+        self.generation = next.generation;
+
+        // The lock is released when `lock` is dropped
         Ok(())
     }
 }
 
 /// A deployment of Clickhouse servers and Keeper clusters
 ///
-/// This always generates clusters on localhost and is suitable only for testing
+/// By default this generates clusters on localhost, and with the ssh backend
+/// it runs them on a small set of remote hosts. Either way, it is suitable
+/// only for testing.
 pub struct Deployment {
     config: DeploymentConfig,
     meta: Option<ClickwardMetadata>,
+    processes: Arc<dyn ProcessManager>,
+    progress: Arc<dyn ProgressSink>,
+    operation: CurrentOperation,
+    writer: FileWriter,
 }
 
 impl Deployment {
@@ -204,41 +966,236 @@ impl Deployment {
 
     pub fn new(config: DeploymentConfig) -> Deployment {
         let meta = ClickwardMetadata::load(&config.path).ok();
-        Deployment { config, meta }
+        Deployment {
+            config,
+            meta,
+            processes: Arc::new(BackendProcessManager),
+            progress: Arc::new(NoProgress),
+            operation: CurrentOperation::default(),
+            writer: FileWriter::default(),
+        }
+    }
+
+    /// Open an existing deployment under `path` with the config it was
+    /// generated with
+    ///
+    /// Deployments generated before configs were saved fall back to the
+    /// default port config.
+    pub fn open<S: Into<String>>(
+        path: Utf8PathBuf,
+        cluster_name: S,
+    ) -> Result<Deployment> {
+        let saved =
+            path.join(DEPLOYMENT_DIR).join(CLICKWARD_CONFIG_FILENAME).exists();
+        if saved {
+            Ok(Deployment::new(DeploymentConfig::load(path)?))
+        } else {
+            Ok(Deployment::new_with_default_port_config(path, cluster_name))
+        }
+    }
+
+    pub fn config(&self) -> &DeploymentConfig {
+        &self.config
     }
 
     pub fn meta(&self) -> &Option<ClickwardMetadata> {
         &self.meta
     }
 
+    /// Return the directory containing all files for a given keeper id
+    pub fn keeper_dir(&self, id: KeeperId) -> Utf8PathBuf {
+        self.config.path.join(format!("keeper-{id}"))
+    }
+
+    /// Return the directory containing all files for a given server id
+    pub fn server_dir(&self, id: ServerId) -> Utf8PathBuf {
+        self.config.path.join(format!("clickhouse-{id}"))
+    }
+
+    /// Return the number of shards that servers are spread over
+    pub fn num_shards(&self) -> u64 {
+        self.config.num_shards.unwrap_or(1).max(1)
+    }
+
+    /// Return the shard that a given server belongs to, starting from 1
+    pub fn server_shard(&self, id: ServerId) -> u64 {
+        (id.0.saturating_sub(1)) % self.num_shards() + 1
+    }
+
+    /// Return the macros that a given server gets beyond the standard ones
+    pub fn server_macros(&self, id: ServerId) -> BTreeMap<String, String> {
+        let mut macros = self.config.extra_macros.clone();
+        if let Some(overrides) = self.config.server_macros.get(&id) {
+            macros.extend(overrides.clone());
+        }
+        macros
+    }
+
+    /// Return the weight of a given shard for distributed inserts
+    pub fn shard_weight(&self, shard: u64) -> u32 {
+        usize::try_from(shard - 1)
+            .ok()
+            .and_then(|i| self.config.shard_weights.get(i).copied())
+            .unwrap_or(1)
+    }
+
+    /// Return the expected clickhouse native protocol port for a given
+    /// server id
+    pub fn tcp_port(&self, id: ServerId) -> Result<u16, PortError> {
+        match self.picked_server_ports(id) {
+            Some(ports) => Ok(ports.tcp),
+            None => self.base_port(PortBlock::ClickhouseTcp, id.0),
+        }
+    }
+
     /// Return the expected clickhouse http port for a given server id
-    pub fn http_port(&self, id: ServerId) -> u16 {
-        self.config.base_ports.clickhouse_http + id.0 as u16
+    pub fn http_port(&self, id: ServerId) -> Result<u16, PortError> {
+        match self.picked_server_ports(id) {
+            Some(ports) => Ok(ports.http),
+            None => self.base_port(PortBlock::ClickhouseHttp, id.0),
+        }
+    }
+
+    /// Return the expected interserver http port for a given server id
+    pub fn interserver_http_port(
+        &self,
+        id: ServerId,
+    ) -> Result<u16, PortError> {
+        match self.picked_server_ports(id) {
+            Some(ports) => Ok(ports.interserver_http),
+            None => self.base_port(PortBlock::ClickhouseInterserverHttp, id.0),
+        }
     }
 
-    /// Return the expected localhost http addr for a given server id
+    /// Return the expected http addr for a given server id
     pub fn http_addr(&self, id: ServerId) -> Result<SocketAddr> {
-        let port = self.http_port(id);
-        let addr: SocketAddr = format!("[::1]:{port}")
-            .parse()
-            .context("failed to create address")?;
-        Ok(addr)
+        resolve(&self.server_host(id), self.http_port(id)?)
+    }
+
+    /// Return the URL of the http interface of a server in our deployment,
+    /// i.e. `http://[::1]:23001`
+    pub fn http_url(&self, id: ServerId) -> Result<String> {
+        self.ensure_server(id)?;
+        let host = url_host(&self.server_host(id));
+        Ok(format!("http://{host}:{}", self.http_port(id)?))
+    }
+
+    /// Return the native protocol addr of a server in our deployment
+    pub fn native_addr(&self, id: ServerId) -> Result<SocketAddr> {
+        self.ensure_server(id)?;
+        resolve(&self.server_host(id), self.tcp_port(id)?)
     }
 
-    pub fn keeper_port(&self, id: KeeperId) -> u16 {
-        self.config.base_ports.keeper + id.0 as u16
+    fn ensure_server(&self, id: ServerId) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !meta.server_ids.contains(&id) {
+            bail!("clickhouse-{id} is not part of the deployment");
+        }
+        Ok(())
     }
 
+    pub fn keeper_port(&self, id: KeeperId) -> Result<u16, PortError> {
+        match self.picked_keeper_ports(id) {
+            Some(ports) => Ok(ports.keeper),
+            None => self.base_port(PortBlock::Keeper, id.0),
+        }
+    }
+
+    /// Return the expected raft port for a given keeper id
+    pub fn raft_port(&self, id: KeeperId) -> Result<u16, PortError> {
+        match self.picked_keeper_ports(id) {
+            Some(ports) => Ok(ports.raft),
+            None => self.base_port(PortBlock::Raft, id.0),
+        }
+    }
+
+    /// Return the expected HTTP control port for a given keeper id
+    pub fn keeper_http_control_port(
+        &self,
+        id: KeeperId,
+    ) -> Result<u16, PortError> {
+        match self.picked_keeper_ports(id) {
+            Some(ports) => Ok(ports.http_control),
+            None => self.base_port(PortBlock::KeeperHttpControl, id.0),
+        }
+    }
+
+    /// Return the expected client addr for a given keeper id
     pub fn keeper_addr(&self, id: KeeperId) -> Result<SocketAddr> {
-        let port = self.keeper_port(id);
-        let addr: SocketAddr = format!("[::1]:{port}")
-            .parse()
-            .context("failed to create address")?;
-        Ok(addr)
+        resolve(&self.keeper_host(id), self.keeper_port(id)?)
+    }
+
+    /// Return the expected prometheus port for a given server id
+    pub fn clickhouse_metrics_port(
+        &self,
+        id: ServerId,
+    ) -> Result<u16, PortError> {
+        match self.picked_server_ports(id) {
+            Some(ports) => Ok(ports.metrics),
+            None => self.base_port(PortBlock::ClickhouseMetrics, id.0),
+        }
+    }
+
+    /// Return the expected prometheus port for a given keeper id
+    pub fn keeper_metrics_port(&self, id: KeeperId) -> Result<u16, PortError> {
+        match self.picked_keeper_ports(id) {
+            Some(ports) => Ok(ports.metrics),
+            None => self.base_port(PortBlock::KeeperMetrics, id.0),
+        }
+    }
+
+    /// Return the URLs of the prometheus endpoints of all keepers and servers
+    ///
+    /// This is empty if the deployment was not configured with `prometheus`
+    pub fn metrics_endpoints(&self) -> Result<Vec<String>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !self.config.prometheus {
+            return Ok(vec![]);
+        }
+        let keepers = meta.keeper_ids.iter().map(|&id| {
+            let host = url_host(&self.keeper_host(id));
+            let port = self.keeper_metrics_port(id)?;
+            Ok(format!("http://{host}:{port}{PROMETHEUS_ENDPOINT}"))
+        });
+        let servers = meta.server_ids.iter().map(|&id| {
+            let host = url_host(&self.server_host(id));
+            let port = self.clickhouse_metrics_port(id)?;
+            Ok(format!("http://{host}:{port}{PROMETHEUS_ENDPOINT}"))
+        });
+        keepers.chain(servers).collect()
+    }
+
+    /// Return the znode under which servers keep their data in keeper, or
+    /// `None` if they use the clickhouse defaults
+    pub fn zookeeper_root(&self) -> Option<String> {
+        self.config
+            .zookeeper_root
+            .clone()
+            .or_else(|| self.meta.as_ref()?.zookeeper_root.clone())
+    }
+
+    /// Return the secret that servers authenticate distributed queries with
+    pub fn cluster_secret(&self) -> String {
+        self.config
+            .cluster_secret
+            .clone()
+            .or_else(|| self.meta.as_ref()?.cluster_secret.clone())
+            .unwrap_or_else(|| LEGACY_CLUSTER_SECRET.to_string())
+    }
+
+    /// Return a span for an operation on this deployment, which the events
+    /// logged during it are attributed to
+    fn span(&self, operation: &'static str) -> tracing::Span {
+        info_span!("clickward", operation, path = %self.config.path)
     }
 
     /// Stop all clickhouse servers and keepers
     pub fn teardown(&self) -> Result<()> {
+        let _span = self.span("teardown").entered();
         if let Some(meta) = &self.meta {
             // We don't keep track of which nodes we already stopped, and so we
             // allow stopping to fail.
@@ -250,22 +1207,148 @@ impl Deployment {
                 // TODO: Logging?
                 let _ = self.stop_server(*id);
             }
+            if self.is_minio_started() {
+                let _ = self.stop_minio();
+            }
+            self.record("teardown", serde_json::Value::Null)?;
         }
         Ok(())
     }
 
     /// Add a node to clickhouse keeper config at all replicas and start the new
     /// keeper
-    pub fn add_keeper(&mut self) -> Result<()> {
-        let path = &self.config.path;
-        let (new_id, meta) = if let Some(meta) = &mut self.meta {
-            let new_id = meta.add_keeper();
-            println!("Updating config to include new keeper: {new_id}");
-            meta.save(path)?;
-            (new_id, meta.clone())
-        } else {
+    pub fn add_keeper(&mut self, context: &OperationContext) -> Result<()> {
+        self.add_keeper_node(None, false, context)
+    }
+
+    /// Add an observer to clickhouse keeper config at all replicas and start
+    /// the new keeper
+    ///
+    /// Observers replicate the log but never vote, so they don't count
+    /// towards quorum.
+    pub fn add_keeper_observer(
+        &mut self,
+        context: &OperationContext,
+    ) -> Result<()> {
+        self.add_keeper_node(None, true, context)
+    }
+
+    /// Add `count` new keepers at once, and return their ids
+    ///
+    /// Unlike calling `add_keeper` `count` times, the new keepers start
+    /// together, and the configs of the other nodes are only rewritten once,
+    /// after we waited up to `timeout` for all the new keepers to become
+    /// healthy.
+    pub async fn add_keepers(
+        &mut self,
+        count: usize,
+        timeout: Duration,
+        context: &OperationContext,
+    ) -> Result<Vec<KeeperId>> {
+        let span = self.span("add-keepers");
+        async {
+            let _op = self.begin_operation(context);
+            self.check_context()?;
+            if !self.config.external_keepers.is_empty() {
+                bail!(EXTERNAL_KEEPERS);
+            }
+            let Some(meta) = &self.meta else {
+                bail!(MISSING_META);
+            };
+            let next_ids: Vec<KeeperId> = (1..=count as u64)
+                .map(|i| meta.max_keeper_id + i.into())
+                .collect();
+            self.check_port_ids(&next_ids, [])?;
+            let (new_ids, meta) = self.change_meta(|meta| {
+                let new_ids: Vec<KeeperId> =
+                    (0..count).map(|_| meta.add_keeper()).collect();
+                let entries = new_ids
+                    .iter()
+                    .map(|&id| JournalEntry::AddKeeper { id, observer: false })
+                    .collect();
+                Ok((new_ids, entries))
+            })?;
+            info!(ids = ?new_ids, "Updating config to include new keepers");
+
+            // As in `add_keeper`, the new keepers must be online before the
+            // other keepers are reconfigured to include them
+            for &id in &new_ids {
+                self.generate_keeper_config(id, meta.keeper_ids.clone())?;
+            }
+            for &id in &new_ids {
+                self.check_context()?;
+                self.start_keeper(id)?;
+            }
+            let nodes: Vec<_> =
+                new_ids.iter().map(|&id| NodeId::Keeper(id)).collect();
+            self.wait_for_nodes(&nodes, timeout).await?;
+            let added: BTreeSet<KeeperId> = new_ids.iter().copied().collect();
+            for id in meta.keeper_ids.difference(&added) {
+                self.generate_keeper_config(*id, meta.keeper_ids.clone())?;
+            }
+
+            // Update clickhouse configs so they know about the new keepers
+            self.generate_clickhouse_config(
+                meta.keeper_ids.clone(),
+                meta.server_ids.clone(),
+            )?;
+
+            self.record("add-keepers", serde_json::json!({ "ids": new_ids }))?;
+            journal::finish(&self.writer, &self.config.path)?;
+            Ok(new_ids)
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Add back keeper `id`, which was removed, and start it with the data
+    /// that is still in its directory
+    ///
+    /// Ids are otherwise never reused. The keeper comes back as a
+    /// participant, even if it was an observer.
+    pub fn readd_keeper(
+        &mut self,
+        id: KeeperId,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let dir = self.keeper_dir(id);
+        if !dir.exists() {
+            bail!("Keeper {id} has no directory to come back with: {dir}");
+        }
+        self.add_keeper_node(Some(id), false, context)
+    }
+
+    /// Add keeper `readd` back, or a new keeper if it's `None`, and start it
+    fn add_keeper_node(
+        &mut self,
+        readd: Option<KeeperId>,
+        observer: bool,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let op = if readd.is_some() { "readd-keeper" } else { "add-keeper" };
+        let _span = self.span(op).entered();
+        let _op = self.begin_operation(context);
+        self.check_context()?;
+        if !self.config.external_keepers.is_empty() {
+            bail!(EXTERNAL_KEEPERS);
+        }
+        let Some(meta) = &self.meta else {
             bail!(MISSING_META);
         };
+        let next_id = readd.unwrap_or(meta.max_keeper_id + 1.into());
+        self.check_port_ids([&next_id], [])?;
+        let (new_id, meta) = self.change_meta(|meta| {
+            let new_id = match readd {
+                Some(id) => {
+                    meta.readd_keeper(id)?;
+                    id
+                }
+                None if observer => meta.add_keeper_observer(),
+                None => meta.add_keeper(),
+            };
+            Ok((new_id, vec![JournalEntry::AddKeeper { id: new_id, observer }]))
+        })?;
+        info!(id = %new_id, "Updating config to include new keeper");
 
         // We update the new node and start it before the other nodes. It must be online
         // for reconfiguration to succeed.
@@ -286,19 +1369,113 @@ impl Deployment {
             meta.server_ids.clone(),
         )?;
 
-        Ok(())
+        self.record(
+            op,
+            serde_json::json!({ "id": new_id, "observer": observer }),
+        )?;
+        journal::finish(&self.writer, &self.config.path)
     }
 
     /// Add a new clickhouse server replica
-    pub fn add_server(&mut self) -> Result<()> {
-        let (new_id, meta) = if let Some(meta) = &mut self.meta {
-            let new_id = meta.add_server();
-            println!("Updating config to include new replica: {new_id}");
-            meta.save(&self.config.path)?;
-            (new_id, meta.clone())
-        } else {
+    pub fn add_server(&mut self, context: &OperationContext) -> Result<()> {
+        self.add_server_node(None, context)
+    }
+
+    /// Add `count` new clickhouse server replicas at once, and return their
+    /// ids
+    ///
+    /// Unlike calling `add_server` `count` times, the configs of the other
+    /// servers are only rewritten once, and the new servers start together.
+    /// We then wait up to `timeout` for all of them to become healthy.
+    pub async fn add_servers(
+        &mut self,
+        count: usize,
+        timeout: Duration,
+        context: &OperationContext,
+    ) -> Result<Vec<ServerId>> {
+        let span = self.span("add-servers");
+        async {
+            let _op = self.begin_operation(context);
+            self.check_context()?;
+            let Some(meta) = &self.meta else {
+                bail!(MISSING_META);
+            };
+            let next_ids: Vec<ServerId> = (1..=count as u64)
+                .map(|i| meta.max_server_id + i.into())
+                .collect();
+            self.check_port_ids([], &next_ids)?;
+            let (new_ids, meta) = self.change_meta(|meta| {
+                let new_ids: Vec<ServerId> =
+                    (0..count).map(|_| meta.add_server()).collect();
+                let entries = new_ids
+                    .iter()
+                    .map(|&id| JournalEntry::AddServer { id })
+                    .collect();
+                Ok((new_ids, entries))
+            })?;
+            info!(ids = ?new_ids, "Updating config to include new replicas");
+
+            // Update clickhouse configs so they know about all the new replicas
+            self.generate_clickhouse_config(meta.keeper_ids, meta.server_ids)?;
+
+            for &id in &new_ids {
+                self.check_context()?;
+                self.start_server(id)?;
+            }
+            let nodes: Vec<_> =
+                new_ids.iter().map(|&id| NodeId::Server(id)).collect();
+            self.wait_for_nodes(&nodes, timeout).await?;
+
+            self.record("add-servers", serde_json::json!({ "ids": new_ids }))?;
+            journal::finish(&self.writer, &self.config.path)?;
+            Ok(new_ids)
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Add back server `id`, which was removed, and start it with the data
+    /// that is still in its directory
+    ///
+    /// Ids are otherwise never reused.
+    pub fn readd_server(
+        &mut self,
+        id: ServerId,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let dir = self.server_dir(id);
+        if !dir.exists() {
+            bail!("Replica {id} has no directory to come back with: {dir}");
+        }
+        self.add_server_node(Some(id), context)
+    }
+
+    /// Add server `readd` back, or a new server if it's `None`, and start it
+    fn add_server_node(
+        &mut self,
+        readd: Option<ServerId>,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let op = if readd.is_some() { "readd-server" } else { "add-server" };
+        let _span = self.span(op).entered();
+        let _op = self.begin_operation(context);
+        self.check_context()?;
+        let Some(meta) = &self.meta else {
             bail!(MISSING_META);
         };
+        let next_id = readd.unwrap_or(meta.max_server_id + 1.into());
+        self.check_port_ids([], [&next_id])?;
+        let (new_id, meta) = self.change_meta(|meta| {
+            let new_id = match readd {
+                Some(id) => {
+                    meta.readd_server(id)?;
+                    id
+                }
+                None => meta.add_server(),
+            };
+            Ok((new_id, vec![JournalEntry::AddServer { id: new_id }]))
+        })?;
+        info!(id = %new_id, "Updating config to include new replica");
 
         // Update clickhouse configs so they know about the new replica
         self.generate_clickhouse_config(meta.keeper_ids, meta.server_ids)?;
@@ -306,20 +1483,38 @@ impl Deployment {
         // Start the new replica
         self.start_server(new_id)?;
 
-        Ok(())
+        self.record(op, serde_json::json!({ "id": new_id }))?;
+        journal::finish(&self.writer, &self.config.path)
     }
 
     /// Remove a node from clickhouse keeper config at all replicas and stop the
     /// old replica.
-    pub fn remove_keeper(&mut self, id: KeeperId) -> Result<()> {
-        println!("Updating config to remove keeper: {id}");
-        let meta = if let Some(meta) = &mut self.meta {
-            meta.remove_keeper(id)?;
-            meta.save(&self.config.path)?;
-            meta.clone()
-        } else {
+    pub fn remove_keeper(
+        &mut self,
+        id: KeeperId,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let _span = self.span("remove-keeper").entered();
+        let _op = self.begin_operation(context);
+        self.check_context()?;
+        if !self.config.external_keepers.is_empty() {
+            bail!(EXTERNAL_KEEPERS);
+        }
+        info!(%id, "Updating config to remove keeper");
+        let Some(meta) = &self.meta else {
             bail!(MISSING_META);
         };
+        if meta.keeper_ids.contains(&id) {
+            self.validate_membership(
+                meta.keeper_ids.len() - 1,
+                meta.server_ids.len(),
+            )?;
+        }
+        let ((), meta) = self.change_meta(|meta| {
+            let observer = meta.is_keeper_observer(id);
+            meta.remove_keeper(id)?;
+            Ok(((), vec![JournalEntry::RemoveKeeper { id, observer }]))
+        })?;
 
         for id in &meta.keeper_ids {
             self.generate_keeper_config(*id, meta.keeper_ids.clone())?;
@@ -332,20 +1527,25 @@ impl Deployment {
             meta.server_ids.clone(),
         )?;
 
-        Ok(())
+        self.record("remove-keeper", serde_json::json!({ "id": id }))?;
+        journal::finish(&self.writer, &self.config.path)
     }
 
     /// Remove a node from clickhouse server config at all replicas and stop the
     /// old server.
-    pub fn remove_server(&mut self, id: ServerId) -> Result<()> {
-        println!("Updating config to remove clickhouse server: {id}");
-        let meta = if let Some(meta) = &mut self.meta {
+    pub fn remove_server(
+        &mut self,
+        id: ServerId,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let _span = self.span("remove-server").entered();
+        let _op = self.begin_operation(context);
+        self.check_context()?;
+        info!(%id, "Updating config to remove clickhouse server");
+        let ((), meta) = self.change_meta(|meta| {
             meta.remove_server(id)?;
-            meta.save(&self.config.path)?;
-            meta.clone()
-        } else {
-            bail!(MISSING_META);
-        };
+            Ok(((), vec![JournalEntry::RemoveServer { id }]))
+        })?;
 
         // Update clickhouse configs so they know about the removed keeper node
         self.generate_clickhouse_config(meta.keeper_ids, meta.server_ids)?;
@@ -353,168 +1553,235 @@ impl Deployment {
         // Stop the clickhouse server
         self.stop_server(id)?;
 
-        Ok(())
+        self.record("remove-server", serde_json::json!({ "id": id }))?;
+        journal::finish(&self.writer, &self.config.path)
+    }
+
+    /// Apply a membership `change` to a copy of our metadata, picking ports
+    /// for any new nodes, then journal the entries it returns and save it
+    ///
+    /// `self.meta` is only replaced once all of that succeeded, so we are
+    /// left as we were if the change can't be made, journaled or saved.
+    fn change_meta<T>(
+        &mut self,
+        change: impl FnOnce(
+            &mut ClickwardMetadata,
+        ) -> Result<(T, Vec<JournalEntry>)>,
+    ) -> Result<(T, ClickwardMetadata)> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut meta = meta.clone();
+        let (output, entries) = change(&mut meta)?;
+        if self.config.free_ports {
+            ports::pick_free_ports(&mut meta, &self.writer)?;
+        }
+        journal::begin_batch(&self.writer, &self.config.path, &entries)?;
+        self.writer.save_meta(&mut meta, &self.config.path)?;
+        self.meta = Some(meta.clone());
+        Ok((output, meta))
     }
 
-    pub fn start_keeper(&self, id: KeeperId) -> Result<()> {
-        let dir = self.config.path.join(format!("keeper-{id}"));
-        println!("Deploying keeper: {dir}");
+    pub(crate) fn spawn_keeper_with_backend(&self, id: KeeperId) -> Result<()> {
+        let dir = self.keeper_dir(id);
+        self.chown_node_dir(&format!("keeper-{id}"), &dir)?;
+        if let Backend::Docker { image } = &self.config.backend {
+            return self.start_keeper_container(id, image);
+        }
+        if let Some(host) = self.remote_host(&format!("keeper-{id}"), id.0) {
+            let config = dir.join("keeper-config.xml");
+            let pidfile = dir.join("keeper.pid");
+            return self.start_remote(
+                &format!("keeper-{id}"),
+                &host,
+                &dir,
+                Utf8Path::new(DEFAULT_CLICKHOUSE_BINARY),
+                &[
+                    "keeper",
+                    "-C",
+                    config.as_str(),
+                    "--pidfile",
+                    pidfile.as_str(),
+                ],
+            );
+        }
+        info!(%dir, "Deploying keeper");
         let config = dir.join("keeper-config.xml");
         let pidfile = dir.join("keeper.pid");
-        Command::new("clickhouse")
-            .arg("keeper")
-            .arg("-C")
-            .arg(config)
-            .arg("--pidfile")
-            .arg(pidfile)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .context("Failed to start keeper")?;
+        let (stdout, stderr) = process::output_files(&dir)?;
+        self.node_command(
+            &format!("keeper-{id}"),
+            Utf8Path::new(DEFAULT_CLICKHOUSE_BINARY),
+        )
+        .arg("keeper")
+        .arg("-C")
+        .arg(config)
+        .arg("--pidfile")
+        .arg(pidfile)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr)
+        .spawn()
+        .with_context(|| SpawnError { node: format!("keeper-{id}") })?;
         Ok(())
     }
 
-    pub fn start_server(&self, id: ServerId) -> Result<()> {
-        let dir = self.config.path.join(format!("clickhouse-{id}"));
-        println!("Deploying clickhouse server: {dir}");
+    pub(crate) fn spawn_server_with_backend(&self, id: ServerId) -> Result<()> {
+        let dir = self.server_dir(id);
+        self.chown_node_dir(&format!("clickhouse-{id}"), &dir)?;
+        if let Backend::Docker { image } = &self.config.backend {
+            return self.start_server_container(id, image);
+        }
+        if let Some(host) = self.remote_host(&format!("clickhouse-{id}"), id.0)
+        {
+            let config = dir.join("clickhouse-config.xml");
+            let pidfile = dir.join("clickhouse.pid");
+            return self.start_remote(
+                &format!("clickhouse-{id}"),
+                &host,
+                &dir,
+                &self.server_binary(id),
+                &[
+                    "server",
+                    "-C",
+                    config.as_str(),
+                    "--pidfile",
+                    pidfile.as_str(),
+                ],
+            );
+        }
+        info!(%dir, "Deploying clickhouse server");
         let config = dir.join("clickhouse-config.xml");
         let pidfile = dir.join("clickhouse.pid");
-        Command::new("clickhouse")
+        let (stdout, stderr) = process::output_files(&dir)?;
+        self.node_command(&format!("clickhouse-{id}"), &self.server_binary(id))
             .arg("server")
             .arg("-C")
             .arg(config)
             .arg("--pidfile")
             .arg(pidfile)
             .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr)
             .spawn()
-            .context("Failed to start clickhouse server")?;
+            .with_context(|| SpawnError { node: format!("clickhouse-{id}") })?;
         Ok(())
     }
 
-    pub fn stop_keeper(&self, id: KeeperId) -> Result<()> {
-        let dir = self.config.path.join(format!("keeper-{id}"));
+    /// Return the clickhouse binary that a given server runs
+    pub fn server_binary(&self, id: ServerId) -> Utf8PathBuf {
+        self.meta
+            .as_ref()
+            .and_then(|meta| meta.server_binaries.get(&id))
+            .map(|binary| binary.path.clone())
+            .unwrap_or_else(|| DEFAULT_CLICKHOUSE_BINARY.into())
+    }
+
+    pub(crate) fn stop_keeper_with_backend(&self, id: KeeperId) -> Result<()> {
+        let dir = self.keeper_dir(id);
+        match &self.config.backend {
+            Backend::Process => (),
+            Backend::Docker { .. } => return self.stop_container(&dir),
+            Backend::Ssh { .. } => return self.stop_remote(&dir, "keeper.pid"),
+        }
         let pidfile = dir.join("keeper.pid");
-        let pid = std::fs::read_to_string(&pidfile)?;
-        let pid = pid.trim_end();
-        println!("Stopping keeper: {dir} at pid {pid}");
-        Command::new("kill")
-            .arg("-9")
-            .arg(pid)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
+        let pid = platform::read_pidfile(&pidfile)?;
+        // The deployment may have been opened through a different path than
+        // it was started with, so only match the end of the config path
+        let config = format!("keeper-{id}/keeper-config.xml");
+        if !platform::is_running_as(pid, &config) {
+            std::fs::remove_file(&pidfile)?;
+            bail!("keeper-{id} is not running as pid {pid}");
+        }
+        info!(%dir, pid, "Stopping keeper");
+        self.kill_node_process(&format!("keeper-{id}"), pid)
             .context("Failed to kill keeper")?;
         std::fs::remove_file(&pidfile)?;
         Ok(())
     }
 
-    pub fn stop_server(&self, id: ServerId) -> Result<()> {
+    pub(crate) fn stop_server_with_backend(&self, id: ServerId) -> Result<()> {
         let name = format!("clickhouse-{id}");
         let dir = self.config.path.join(&name);
+        match &self.config.backend {
+            Backend::Process => (),
+            Backend::Docker { .. } => return self.stop_container(&dir),
+            Backend::Ssh { .. } => {
+                return self.stop_remote(&dir, "clickhouse.pid")
+            }
+        }
         let pidfile = dir.join("clickhouse.pid");
-        let pid = std::fs::read_to_string(&pidfile)?;
-        let pid = pid.trim_end();
-
-        // Retrieve the child process id
-        let output = Command::new("pgrep")
-            .arg("-P")
-            .arg(pid)
-            .output()
-            .context("failed to retreive child process for pid {pid}")?;
-        let child_pid = String::from_utf8(output.stdout)
-            .context("failed to parse child pid for pid {pid}")?;
-        let child_pid = child_pid.trim_end();
-
-        println!("Stopping clickhouse server {name}: pid - {pid}, child pid - {child_pid}");
-
-        // Kill the parent
-        Command::new("kill")
-            .arg("-9")
-            .arg(pid)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .context("Failed to kill clickhouse server")?;
+        let pid = platform::read_pidfile(&pidfile)?;
+        let config = format!("{name}/clickhouse-config.xml");
+        if !platform::is_running_as(pid, &config) {
+            std::fs::remove_file(&pidfile)?;
+            bail!("{name} is not running as pid {pid}");
+        }
 
-        // Kill the child
-        Command::new("kill")
-            .arg("-9")
-            .arg(child_pid)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
+        // The server runs under a watchdog process, which we must kill too
+        let child_pids = platform::child_pids(pid).with_context(|| {
+            format!("failed to retrieve child processes of pid {pid}")
+        })?;
+
+        info!(node = %name, pid, ?child_pids, "Stopping clickhouse server");
+
+        // Kill the parent first, so it doesn't restart the children
+        self.kill_node_process(&name, pid)
             .context("Failed to kill clickhouse server")?;
+        for child_pid in child_pids {
+            self.kill_node_process(&name, child_pid)
+                .context("Failed to kill clickhouse server")?;
+        }
         std::fs::remove_file(&pidfile)?;
 
         Ok(())
     }
 
+    /// Ensure that a deployment with the given number of nodes can work
+    ///
+    /// Clickhouse servers need a keeper ensemble to coordinate replication.
+    fn validate_membership(
+        &self,
+        num_keepers: usize,
+        num_servers: usize,
+    ) -> Result<()> {
+        if num_servers > 0
+            && num_keepers == 0
+            && self.config.external_keepers.is_empty()
+        {
+            bail!(
+                "Clickhouse servers require at least one keeper: \
+                 add keepers or configure external keepers"
+            );
+        }
+        Ok(())
+    }
+
     /// Deploy our clickhouse replicas and keeper cluster
-    pub fn deploy(&self) -> Result<()> {
-        let dirs: Vec<_> = self.config.path.read_dir_utf8()?.collect();
-
-        // Find all keeper replicas them
-        let keeper_dirs = dirs.iter().filter_map(|e| {
-            let entry = e.as_ref().unwrap();
-            if entry.path().file_name().unwrap().starts_with("keeper") {
-                Some(entry.path())
-            } else {
-                None
-            }
-        });
-        // Start all keepers
-        for dir in keeper_dirs {
-            println!("Deploying keeper: {dir}");
-            let config = dir.join("keeper-config.xml");
-            let pidfile = dir.join("keeper.pid");
-            Command::new("clickhouse")
-                .arg("keeper")
-                .arg("-C")
-                .arg(config)
-                .arg("--pidfile")
-                .arg(pidfile)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .spawn()
-                .context("Failed to start keeper")?;
-        }
-
-        // Find all clickhouse replicas
-        let clickhouse_dirs = dirs.iter().filter_map(|e| {
-            let entry = e.as_ref().unwrap();
-            if entry.path().file_name().unwrap().starts_with("clickhouse") {
-                Some(entry.path())
-            } else {
-                None
-            }
-        });
+    pub fn deploy(&self, context: &OperationContext) -> Result<()> {
+        let _span = self.span("deploy").entered();
+        let _op = self.begin_operation(context);
+        self.check_context()?;
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        self.validate_membership(meta.keeper_ids.len(), meta.server_ids.len())?;
+        self.check_port_ids(&meta.keeper_ids, &meta.server_ids)?;
+        self.check_ports(&meta.keeper_ids, &meta.server_ids)?;
 
-        // Start all clickhouse servers
-        for dir in clickhouse_dirs {
-            println!("Deploying clickhouse server: {dir}");
-            let config = dir.join("clickhouse-config.xml");
-            let pidfile = dir.join("clickhouse.pid");
-            Command::new("clickhouse")
-                .arg("server")
-                .arg("-C")
-                .arg(config)
-                .arg("--pidfile")
-                .arg(pidfile)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .spawn()
-                .context("Failed to start clickhouse server")?;
+        if self.config.minio.is_some() {
+            self.start_minio()?;
+        }
+        for id in &meta.keeper_ids {
+            self.check_context()?;
+            self.start_keeper(*id)?;
+        }
+        for id in &meta.server_ids {
+            self.check_context()?;
+            self.start_server(*id)?;
         }
 
-        Ok(())
+        self.record("deploy", serde_json::Value::Null)
     }
 
     /// Generate configuration for our clusters
@@ -523,12 +1790,128 @@ impl Deployment {
         num_keepers: u64,
         num_replicas: u64,
     ) -> Result<()> {
-        std::fs::create_dir_all(&self.config.path).unwrap();
+        let _span = self.span("gen-config").entered();
+        if num_keepers > 0 && !self.config.external_keepers.is_empty() {
+            bail!("Cannot create keepers when using external keepers");
+        }
+        if self.config.num_shards == Some(0) {
+            bail!("A deployment needs at least one shard");
+        }
+        let weights = self.config.shard_weights.len() as u64;
+        if weights > 0 && weights != self.num_shards() {
+            bail!(
+                "Got {weights} shard weights for {} shards",
+                self.num_shards()
+            );
+        }
+        self.validate_membership(num_keepers as usize, num_replicas as usize)?;
+        if self.config.free_ports && !self.config.backend.is_local() {
+            bail!("Free ports are only supported for local backends");
+        }
+        // The cluster name is an element name in `<remote_servers>`
+        if !is_valid_macro_name(&self.config.cluster_name) {
+            bail!("Invalid cluster name: {}", self.config.cluster_name);
+        }
+        let macro_names =
+            self.config.server_macros.values().flat_map(|m| m.keys());
+        for name in self.config.extra_macros.keys().chain(macro_names) {
+            if !is_valid_macro_name(name) {
+                bail!("Invalid macro name: {name}");
+            }
+        }
+        // Settings and users are XML elements, named like macros
+        for name in self.config.profile_settings.keys() {
+            if !is_valid_macro_name(name) {
+                bail!("Invalid setting name: {name}");
+            }
+        }
+        for user in &self.config.users {
+            if !is_valid_macro_name(&user.name) || user.name == "default" {
+                bail!("Invalid user name: {}", user.name);
+            }
+        }
+        for (name, params) in &self.config.named_collections {
+            if !is_valid_macro_name(name) {
+                bail!("Invalid named collection name: {name}");
+            }
+            for key in params.keys() {
+                if !is_valid_macro_name(key) {
+                    bail!(
+                        "Invalid parameter of named collection {name}: {key}"
+                    );
+                }
+            }
+        }
+        let brokers = [&self.config.kafka, &self.config.rabbitmq];
+        for broker in brokers.into_iter().flatten() {
+            for key in broker.settings.keys() {
+                if !is_valid_macro_name(key) {
+                    bail!("Invalid broker setting name: {key}");
+                }
+            }
+        }
+        if let Some(storage) = &self.config.storage {
+            let policies = storage.policies.iter();
+            let names = storage
+                .disks
+                .iter()
+                .map(|disk| &disk.name)
+                .chain(policies.clone().map(|policy| &policy.name))
+                .chain(policies.flat_map(|p| &p.volumes).map(|v| &v.name));
+            for name in names {
+                if !is_valid_macro_name(name) {
+                    bail!("Invalid disk, policy or volume name: {name}");
+                }
+            }
+        }
+        // Dictionary names are file names as well
+        for dictionary in &self.config.dictionaries {
+            if !is_valid_macro_name(&dictionary.name) {
+                bail!("Invalid dictionary name: {}", dictionary.name);
+            }
+        }
+
+        // Configs can be generated without clickhouse installed, in which
+        // case we can't check them against it
+        let version = match self.clickhouse_version() {
+            Ok(version) => {
+                self.check_clickhouse_version(version)?;
+                Some(version)
+            }
+            Err(e) => {
+                warn!("Failed to determine the clickhouse version: {e:#}");
+                None
+            }
+        };
+
+        self.writer.create_dir_all(&self.config.path)?;
 
         let keeper_ids: BTreeSet<KeeperId> =
             (1..=num_keepers).map(KeeperId).collect();
         let replica_ids: BTreeSet<ServerId> =
             (1..=num_replicas).map(ServerId).collect();
+        self.check_port_ids(&keeper_ids, &replica_ids)?;
+
+        // We are replacing any existing deployment, so take over its
+        // generation rather than treating it as a concurrent update. The
+        // metadata must be in place before generating configs, as they
+        // include its cluster secret.
+        let mut meta =
+            ClickwardMetadata::new(keeper_ids.clone(), replica_ids.clone());
+        if let Ok(old) = ClickwardMetadata::load(&self.config.path) {
+            meta.generation = old.generation;
+        }
+        meta.zookeeper_root = Some(format!(
+            "/clickward/{}-{}",
+            self.config.cluster_name,
+            &generate_secret()[..8]
+        ));
+        meta.clickhouse_version = version;
+        if self.config.free_ports {
+            ports::pick_free_ports(&mut meta, &self.writer)?;
+        }
+        self.meta = Some(meta);
+        self.check_ports(&keeper_ids, &replica_ids)?;
 
         self.generate_clickhouse_config(
             keeper_ids.clone(),
@@ -538,80 +1921,289 @@ impl Deployment {
             self.generate_keeper_config(*id, keeper_ids.clone())?;
         }
 
-        let meta = ClickwardMetadata::new(keeper_ids, replica_ids);
-        meta.save(&self.config.path)?;
-        self.meta = Some(meta);
+        if let Some(meta) = &mut self.meta {
+            self.writer.save_meta(meta, &self.config.path)?;
+        }
+        self.writer.save_config(&self.config)?;
+        self.record(
+            "gen-config",
+            serde_json::json!({
+                "num_keepers": num_keepers,
+                "num_replicas": num_replicas,
+            }),
+        )?;
 
         Ok(())
     }
+
+    /// Re-render the configs of all keepers and servers from our metadata and
+    /// `DeploymentConfig`, without changing membership
+    ///
+    /// Running nodes reload most settings automatically, but some, like
+    /// ports, only take effect after a restart.
+    pub fn regenerate_configs(&self) -> Result<()> {
+        let _span = self.span("regen-config").entered();
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        for id in &meta.keeper_ids {
+            self.generate_keeper_config(*id, meta.keeper_ids.clone())?;
+        }
+        self.generate_clickhouse_config(
+            meta.keeper_ids.clone(),
+            meta.server_ids.clone(),
+        )?;
+        self.record("regen-config", serde_json::Value::Null)
+    }
+
     fn generate_clickhouse_config(
         &self,
         keeper_ids: BTreeSet<KeeperId>,
         replica_ids: BTreeSet<ServerId>,
     ) -> Result<()> {
+        for (id, config) in
+            self.clickhouse_configs(&keeper_ids, &replica_ids)?
+        {
+            let dir = self.server_dir(id);
+            self.writer.create_dir_all(&dir.join("logs"))?;
+            self.writer.create_dir_all(&config.backups_path)?;
+            if let Some(storage) = &config.storage {
+                for path in storage.local_paths() {
+                    self.writer.create_dir_all(path)?;
+                }
+            }
+            self.writer.write(
+                &dir.join("clickhouse-config.xml"),
+                self.server_config_xml(id, &config)?.as_bytes(),
+            )?;
+            self.write_dictionaries(
+                &dir,
+                &config.listen_host,
+                config.tcp_port,
+            )?;
+            self.write_server_info(id, &config)?;
+            let node = NodeId::Server(id).to_string();
+            self.report(ProgressEvent::ConfigWritten { node });
+
+            if let Some(extra) = &self.config.extra_config_dir {
+                let node_extra = extra.join(format!("clickhouse-{id}"));
+                self.install_extra_configs(&dir, &[extra, &node_extra])?;
+            }
+            if let Some(host) =
+                self.remote_host(&format!("clickhouse-{id}"), id.0)
+            {
+                self.push_configs(&host, &dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the dictionary files of the server in `dir` with those of our
+    /// config
+    fn write_dictionaries(
+        &self,
+        dir: &Utf8Path,
+        listen_host: &str,
+        tcp_port: u16,
+    ) -> Result<()> {
+        let dictionaries = dir.join(DICTIONARIES_DIR);
+        if dictionaries.exists() {
+            self.writer.remove_dir_all(&dictionaries)?;
+        }
+        if self.config.dictionaries.is_empty() {
+            return Ok(());
+        }
+        self.writer.create_dir_all(&dictionaries)?;
+        for dictionary in &self.config.dictionaries {
+            self.writer.write(
+                &dictionaries.join(format!("{}.xml", dictionary.name)),
+                dictionary.to_xml(listen_host, tcp_port).as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Return the configs of the servers in `replica_ids` for a cluster of
+    /// `keeper_ids` and `replica_ids`
+    pub(crate) fn clickhouse_configs(
+        &self,
+        keeper_ids: &BTreeSet<KeeperId>,
+        replica_ids: &BTreeSet<ServerId>,
+    ) -> Result<Vec<(ServerId, ReplicaConfig)>> {
         let cluster = self.config.cluster_name.clone();
 
-        let servers: Vec<_> = replica_ids
-            .iter()
-            .map(|&id| ServerConfig {
-                host: "::1".to_string(),
-                port: self.config.base_ports.clickhouse_tcp + id.0 as u16,
-            })
-            .collect();
-        let remote_servers = RemoteServers {
-            cluster: cluster.clone(),
-            secret: "some-unique-value".to_string(),
-            replicas: servers,
+        // Servers that aren't active are left out of the clusters of the
+        // other servers, but not of their own. Shards left without replicas,
+        // i.e. after removing their last server, are left out altogether, as
+        // clickhouse rejects clusters with empty shards.
+        let shards_for = |viewer: ServerId| -> Result<Vec<Shard>> {
+            let mut shards = vec![];
+            for shard in 1..=self.num_shards() {
+                let replicas = replica_ids
+                    .iter()
+                    .filter(|&&id| self.server_shard(id) == shard)
+                    .filter(|&&id| {
+                        id == viewer
+                            || self
+                                .admin_state(NodeId::Server(id))
+                                .is_routable()
+                    })
+                    .map(|&id| {
+                        Ok(ShardReplica {
+                            server: ServerConfig {
+                                host: self.server_host(id),
+                                port: self.tcp_port(id)?,
+                            },
+                            priority: self.zone_priority(id),
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if !replicas.is_empty() {
+                    shards.push(Shard {
+                        weight: self.shard_weight(shard),
+                        replicas,
+                    });
+                }
+            }
+            Ok(shards)
         };
-
-        let keepers = KeeperConfigsForReplica {
-            nodes: keeper_ids
-                .iter()
-                .map(|&id| ServerConfig {
-                    host: "[::1]".to_string(),
-                    port: self.config.base_ports.keeper + id.0 as u16,
-                })
-                .collect(),
+        let linked = self
+            .meta
+            .as_ref()
+            .map(|meta| meta.linked_clusters.clone())
+            .unwrap_or_default();
+
+        let keepers = if self.config.external_keepers.is_empty() {
+            KeeperConfigsForReplica {
+                nodes: keeper_ids
+                    .iter()
+                    .map(|&id| {
+                        Ok(ServerConfig {
+                            host: url_host(&self.keeper_host(id)),
+                            port: self.keeper_port(id)?,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            }
+        } else {
+            KeeperConfigsForReplica {
+                nodes: self.config.external_keepers.clone(),
+            }
         };
 
-        for id in replica_ids {
-            let dir: Utf8PathBuf =
-                [self.config.path.as_str(), &format!("clickhouse-{id}")]
-                    .iter()
-                    .collect();
+        // Collections that are configured explicitly take precedence over
+        // those of brokers
+        let mut named_collections = self.config.named_collections.clone();
+        if let Some(kafka) = &self.config.kafka {
+            named_collections
+                .entry("kafka".to_string())
+                .or_insert_with(|| kafka.kafka_collection());
+        }
+        if let Some(rabbitmq) = &self.config.rabbitmq {
+            named_collections
+                .entry("rabbitmq".to_string())
+                .or_insert_with(|| rabbitmq.rabbitmq_collection());
+        }
+
+        let mut configs = vec![];
+        for &id in replica_ids {
+            let dir = self.server_dir(id);
             let logs: Utf8PathBuf = dir.join("logs");
-            std::fs::create_dir_all(&logs)?;
             let log = logs.join("clickhouse.log");
             let errorlog = logs.join("clickhouse.err.log");
             let data_path = dir.join("data");
             let config = ReplicaConfig {
                 logger: LogConfig {
-                    level: LogLevel::Trace,
+                    level: self.config.log_level,
                     log,
                     errorlog,
-                    size: "100M".to_string(),
-                    count: 1,
+                    size: self.config.log_size,
+                    count: self.config.log_count,
                 },
                 macros: Macros {
-                    shard: 1,
+                    shard: self.server_shard(id),
                     replica: id,
                     cluster: cluster.clone(),
+                    zone: self
+                        .zone(NodeId::Server(id))
+                        .map(|zone| zone.to_string()),
+                    extra: self.server_macros(id),
                 },
-                listen_host: "::1".to_string(),
-                http_port: self.config.base_ports.clickhouse_http + id.0 as u16,
-                tcp_port: self.config.base_ports.clickhouse_tcp + id.0 as u16,
-                interserver_http_port: self
+                listen_host: self.bind_host(NodeId::Server(id)),
+                http_port: self.http_port(id)?,
+                tcp_port: self.tcp_port(id)?,
+                interserver_http_port: self.interserver_http_port(id)?,
+                interserver_http_host: self.server_host(id),
+                interserver_http_credentials: self
                     .config
-                    .base_ports
-                    .clickhouse_interserver_http
-                    + id.0 as u16,
-                remote_servers: remote_servers.clone(),
+                    .interserver_credentials
+                    .clone(),
+                remote_servers: RemoteServers {
+                    cluster: cluster.clone(),
+                    secret: self.cluster_secret(),
+                    shards: shards_for(id)?,
+                    linked: linked.clone(),
+                },
                 keepers: keepers.clone(),
+                zookeeper_root: self.zookeeper_root(),
+                allow_replicated_database: self
+                    .config
+                    .replicated_database
+                    .is_some(),
+                async_insert: self.config.async_insert,
+                profile_settings: self.config.profile_settings.clone(),
+                users: self.config.users.clone(),
+                default_database: self.config.default_database.clone(),
+                storage: self
+                    .config
+                    .storage
+                    .as_ref()
+                    .map(|storage| storage.for_node(&dir)),
+                named_collections: named_collections.clone(),
+                dictionaries_config: (!self.config.dictionaries.is_empty())
+                    .then(|| dir.join(DICTIONARIES_DIR).join("*.xml")),
+                kafka: self.config.kafka.clone(),
+                rabbitmq: self.config.rabbitmq.clone(),
+                system_logs: self.config.system_logs,
+                limits: self.config.server_limits.clone(),
                 data_path,
+                backups_path: dir.join(BACKUPS_DISK),
+                prometheus: if self.config.prometheus {
+                    Some(PrometheusConfig {
+                        endpoint: PROMETHEUS_ENDPOINT.to_string(),
+                        port: self.clickhouse_metrics_port(id)?,
+                    })
+                } else {
+                    None
+                },
+                clickhouse_version: self.server_clickhouse_version(id),
             };
-            let mut f = File::create(dir.join("clickhouse-config.xml"))?;
-            f.write_all(config.to_xml().as_bytes())?;
-            f.flush()?;
+            configs.push((id, config));
+        }
+        Ok(configs)
+    }
+
+    /// Replace the `config.d` directory of the node in `dir` with the XML
+    /// files found directly inside each of `sources`
+    fn install_extra_configs(
+        &self,
+        dir: &Utf8Path,
+        sources: &[&Utf8Path],
+    ) -> Result<()> {
+        let config_d = dir.join("config.d");
+        if config_d.exists() {
+            self.writer.remove_dir_all(&config_d)?;
+        }
+        self.writer.create_dir_all(&config_d)?;
+        for src in sources.iter().filter(|src| src.is_dir()) {
+            for entry in src.read_dir_utf8()? {
+                let entry = entry?;
+                let path = entry.path();
+                if path.is_file() && path.extension() == Some("xml") {
+                    self.writer
+                        .copy(path, &config_d.join(entry.file_name()))?;
+                }
+            }
         }
         Ok(())
     }
@@ -622,46 +2214,152 @@ impl Deployment {
         this_keeper: KeeperId,
         keeper_ids: BTreeSet<KeeperId>,
     ) -> Result<()> {
-        let raft_servers: Vec<_> = keeper_ids
+        let dir = self.keeper_dir(this_keeper);
+        self.writer.create_dir_all(&dir.join("logs"))?;
+        let config = self.keeper_config(this_keeper, &keeper_ids)?;
+        self.writer.write(
+            &dir.join("keeper-config.xml"),
+            self.keeper_config_xml(this_keeper, &config)?.as_bytes(),
+        )?;
+        self.write_keeper_info(this_keeper, &config)?;
+        let node = NodeId::Keeper(this_keeper).to_string();
+        self.report(ProgressEvent::ConfigWritten { node });
+
+        if let Some(extra) = &self.config.extra_config_dir {
+            let node_extra = extra.join(format!("keeper-{this_keeper}"));
+            self.install_extra_configs(&dir, &[&node_extra])?;
+        }
+        let node = format!("keeper-{this_keeper}");
+        if let Some(host) = self.remote_host(&node, this_keeper.0) {
+            self.push_configs(&host, &dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the four letter words keepers accept, with the ones we send
+    /// added to those configured
+    fn keeper_four_letter_words(&self) -> Option<Vec<String>> {
+        let mut words = self.config.keeper_four_letter_words.clone()?;
+        if !words.iter().any(|word| word == "*") {
+            for word in REQUIRED_FOUR_LETTER_WORDS {
+                if !words.iter().any(|w| w == word) {
+                    words.push(word.to_string());
+                }
+            }
+        }
+        Some(words)
+    }
+
+    /// Return the config of `this_keeper` in a cluster of `keeper_ids`
+    pub(crate) fn keeper_config(
+        &self,
+        this_keeper: KeeperId,
+        keeper_ids: &BTreeSet<KeeperId>,
+    ) -> Result<KeeperConfig> {
+        let raft_servers = keeper_ids
             .iter()
-            .map(|id| RaftServerConfig {
-                id: *id,
-                hostname: "::1".to_string(),
-                port: self.config.base_ports.raft + id.0 as u16,
+            .map(|id| {
+                Ok(RaftServerConfig {
+                    id: *id,
+                    hostname: self.keeper_host(*id),
+                    port: self.raft_port(*id)?,
+                    can_become_leader: !self
+                        .meta
+                        .as_ref()
+                        .is_some_and(|meta| meta.is_keeper_observer(*id)),
+                    priority: self.keeper_priority(*id),
+                })
             })
-            .collect();
-        let dir: Utf8PathBuf =
-            [self.config.path.as_str(), &format!("keeper-{this_keeper}")]
-                .iter()
-                .collect();
+            .collect::<Result<Vec<_>>>()?;
+        let dir = self.keeper_dir(this_keeper);
         let logs: Utf8PathBuf = dir.join("logs");
-        std::fs::create_dir_all(&logs)?;
         let log = logs.join("clickhouse-keeper.log");
         let errorlog = logs.join("clickhouse-keeper.err.log");
-        let config = KeeperConfig {
+        Ok(KeeperConfig {
             logger: LogConfig {
-                level: LogLevel::Trace,
+                level: self.config.log_level,
                 log,
                 errorlog,
-                size: "100M".to_string(),
-                count: 1,
+                size: self.config.log_size,
+                count: self.config.log_count,
             },
-            listen_host: "::1".to_string(),
-            tcp_port: self.config.base_ports.keeper + this_keeper.0 as u16,
+            listen_host: self.bind_host(NodeId::Keeper(this_keeper)),
+            tcp_port: self.keeper_port(this_keeper)?,
             server_id: this_keeper,
             log_storage_path: dir.join("coordination").join("log"),
             snapshot_storage_path: dir.join("coordination").join("snapshots"),
             coordination_settings: KeeperCoordinationSettings {
-                operation_timeout_ms: 10000,
-                session_timeout_ms: 30000,
-                raft_logs_level: LogLevel::Trace,
+                operation_timeout: Duration::from_secs(10),
+                session_timeout: Duration::from_secs(30),
+                raft_logs_level: self.config.log_level,
+                snapshot_distance: self
+                    .config
+                    .keeper_snapshot_distance
+                    .unwrap_or(DEFAULT_KEEPER_SNAPSHOT_DISTANCE),
             },
-            raft_config: RaftServers { servers: raft_servers.clone() },
-        };
-        let mut f = File::create(dir.join("keeper-config.xml"))?;
-        f.write_all(config.to_xml().as_bytes())?;
-        f.flush()?;
+            raft_config: RaftServers { servers: raft_servers },
+            prometheus: if self.config.prometheus {
+                Some(PrometheusConfig {
+                    endpoint: PROMETHEUS_ENDPOINT.to_string(),
+                    port: self.keeper_metrics_port(this_keeper)?,
+                })
+            } else {
+                None
+            },
+            http_control_port: if self.config.keeper_http_control {
+                Some(self.keeper_http_control_port(this_keeper)?)
+            } else {
+                None
+            },
+            four_letter_words: self.keeper_four_letter_words(),
+            clickhouse_version: self.generated_clickhouse_version(),
+        })
+    }
+}
 
-        Ok(())
+/// Generate a random secret
+///
+/// Each `RandomState` is seeded randomly, which is plenty for the secrets of
+/// test clusters.
+fn generate_secret() -> String {
+    let state = RandomState::new();
+    format!("{:016x}{:016x}", state.hash_one(0u8), state.hash_one(1u8))
+}
+
+/// Resolve `host` and `port` to a socket address
+fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {host}"))?
+        .next()
+        .with_context(|| format!("no addresses found for {host}"))
+}
+
+/// Return `host` in the form used in URLs, with IPv6 addresses in brackets
+pub(crate) fn url_host(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
     }
 }
+
+/// Write `contents` to `path` such that a crash never leaves a partially
+/// written file behind
+///
+/// We write to a temporary file in the same directory, sync it to disk, and
+/// then rename it over `path`.
+pub(crate) fn write_atomic(path: &Utf8Path, contents: &[u8]) -> Result<()> {
+    let Some(file_name) = path.file_name() else {
+        bail!("Invalid path: {path}");
+    };
+    let tmp = path.with_file_name(format!(".{file_name}.tmp"));
+    let mut f = File::create(&tmp)
+        .with_context(|| format!("Failed to create {tmp}"))?;
+    f.write_all(contents).with_context(|| format!("Failed to write {tmp}"))?;
+    f.sync_all().with_context(|| format!("Failed to sync {tmp}"))?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Failed to rename {tmp} to {path}"))?;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{
+    is_valid_macro_name, LinkedCluster, LinkedShard, ServerConfig,
+};
+use crate::{Deployment, MISSING_META};
+use anyhow::{bail, Result};
+
+impl Deployment {
+    /// Make the servers of `other` queryable from our servers as the
+    /// cluster `alias`, i.e. with `cluster('<alias>', ...)`
+    ///
+    /// The servers of `other` are added as an extra cluster in the
+    /// `<remote_servers>` of our configs, which running servers reload
+    /// automatically, with the same shards as in `other`. This records the
+    /// servers `other` has now, so link again after adding or removing
+    /// servers there. Queries to linked
+    /// clusters authenticate as the default user, as the clusters don't
+    /// share a secret.
+    pub fn link_remote(
+        &mut self,
+        other: &Deployment,
+        alias: &str,
+    ) -> Result<()> {
+        // The alias is an element name in `<remote_servers>`
+        if !is_valid_macro_name(alias) {
+            bail!("invalid cluster name: {alias}");
+        }
+        if alias == self.config.cluster_name {
+            bail!("{alias} is the name of this deployment's own cluster");
+        }
+        let Some(other_meta) = &other.meta else {
+            bail!("{}: {MISSING_META}", other.config.path);
+        };
+        let mut shards = vec![];
+        for shard in 1..=other.num_shards() {
+            let replicas = other_meta
+                .server_ids
+                .iter()
+                .filter(|&&id| other.server_shard(id) == shard)
+                .map(|&id| {
+                    Ok(ServerConfig {
+                        host: other.server_host(id),
+                        port: other.tcp_port(id)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // Clickhouse rejects clusters with empty shards
+            if !replicas.is_empty() {
+                let weight = other.shard_weight(shard);
+                shards.push(LinkedShard { weight, replicas });
+            }
+        }
+
+        if shards.is_empty() {
+            bail!("{} has no servers to link", other.config.path);
+        }
+
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.linked_clusters
+            .insert(alias.to_string(), LinkedCluster { shards });
+        self.writer.save_meta(meta, &self.config.path)?;
+        let meta = meta.clone();
+        self.generate_clickhouse_config(meta.keeper_ids, meta.server_ids)?;
+        self.record(
+            "link-remote",
+            serde_json::json!({
+                "alias": alias,
+                "path": other.config.path.as_str(),
+            }),
+        )
+    }
+
+    /// Remove a cluster added with `link_remote`
+    pub fn unlink_remote(&mut self, alias: &str) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        if meta.linked_clusters.remove(alias).is_none() {
+            bail!("No linked cluster named {alias}");
+        }
+        self.writer.save_meta(meta, &self.config.path)?;
+        let meta = meta.clone();
+        self.generate_clickhouse_config(meta.keeper_ids, meta.server_ids)?;
+        self.record("unlink-remote", serde_json::json!({ "alias": alias }))
+    }
+}
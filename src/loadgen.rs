@@ -0,0 +1,160 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ClickhouseClient, Deployment, MISSING_META};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+/// The longest random strings we generate
+const MAX_STRING_LENGTH: u64 = 16;
+
+/// The longest random arrays we generate
+const MAX_ARRAY_LENGTH: u64 = 4;
+
+#[derive(Debug, Deserialize)]
+struct ColumnRow {
+    name: String,
+    #[serde(rename = "type")]
+    ty: String,
+}
+
+/// The outcome of `Deployment::insert_random`
+#[derive(Debug, Clone)]
+pub struct InsertReport {
+    /// The number of rows inserted
+    pub rows: u64,
+    /// The number of servers rows were inserted through
+    pub servers: usize,
+    pub elapsed: Duration,
+}
+
+impl InsertReport {
+    pub fn rows_per_sec(&self) -> f64 {
+        self.rows as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl Deployment {
+    /// Insert `rows` rows of random data into `table` in batches of
+    /// `batch_size`
+    ///
+    /// `table` may be qualified with a database. Its columns are read from
+    /// `system.columns`, and each batch is generated by the server with the
+    /// `generateRandom` table function, so every column type is supported.
+    /// When `parallel` is set, batches are spread across all live servers
+    /// and inserted concurrently. Otherwise they are all inserted through the
+    /// first live server, one at a time.
+    pub async fn insert_random(
+        &self,
+        table: &str,
+        rows: u64,
+        batch_size: u64,
+        parallel: bool,
+    ) -> Result<InsertReport> {
+        if batch_size == 0 {
+            bail!("batch size must be at least 1");
+        }
+        let clients = if parallel {
+            self.live_servers().await?
+        } else {
+            vec![self.any_live_server().await?]
+        };
+        let insert = self.random_insert_sql(&clients[0], table).await?;
+
+        // Vary the seed so that repeated runs insert different rows
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let num_batches = rows.div_ceil(batch_size);
+
+        let start = Instant::now();
+        let mut tasks = JoinSet::new();
+        for (i, client) in clients.iter().enumerate() {
+            let client = client.clone();
+            let insert = insert.clone();
+            let batches: Vec<u64> =
+                (i as u64..num_batches).step_by(clients.len()).collect();
+            tasks.spawn(async move {
+                for batch in batches {
+                    let n = batch_size.min(rows - batch * batch_size);
+                    let sql = insert
+                        .replace("{seed}", &(seed + batch).to_string())
+                        .replace("{limit}", &n.to_string());
+                    client.execute(&sql).await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.context("insert task panicked")??;
+        }
+
+        Ok(InsertReport {
+            rows,
+            servers: clients.len(),
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Return an `INSERT` statement of random rows into `table`, with
+    /// `{seed}` and `{limit}` placeholders for each batch
+    async fn random_insert_sql(
+        &self,
+        client: &ClickhouseClient,
+        table: &str,
+    ) -> Result<String> {
+        let (database, name) = match table.split_once('.') {
+            Some((database, name)) => (format!("'{database}'"), name),
+            None => ("currentDatabase()".to_string(), table),
+        };
+
+        // Columns computed by the server can't be inserted into
+        let columns: Vec<ColumnRow> = client
+            .query(&format!(
+                "SELECT name, type FROM system.columns \
+                 WHERE database = {database} AND table = '{name}' \
+                    AND default_kind NOT IN ('MATERIALIZED', 'ALIAS') \
+                 ORDER BY position"
+            ))
+            .await?
+            .rows()?;
+        if columns.is_empty() {
+            bail!("table {table} does not exist or has no columns");
+        }
+
+        let names: Vec<String> =
+            columns.iter().map(|c| format!("`{}`", c.name)).collect();
+        let structure: Vec<String> =
+            columns.iter().map(|c| format!("`{}` {}", c.name, c.ty)).collect();
+        let structure = structure.join(", ").replace('\'', "\\'");
+        Ok(format!(
+            "INSERT INTO {table} ({}) \
+             SELECT * FROM generateRandom('{structure}', {{seed}}, \
+                {MAX_STRING_LENGTH}, {MAX_ARRAY_LENGTH}) \
+             LIMIT {{limit}}",
+            names.join(", ")
+        ))
+    }
+
+    /// Return clients for all servers that respond to a query
+    async fn live_servers(&self) -> Result<Vec<ClickhouseClient>> {
+        let Some(meta) = self.meta() else {
+            bail!(MISSING_META);
+        };
+        let mut clients = vec![];
+        for id in &meta.server_ids {
+            let client = self.http_client(*id)?;
+            if client.execute("SELECT 1").await.is_ok() {
+                clients.push(client);
+            }
+        }
+        if clients.is_empty() {
+            bail!("no clickhouse servers are responding");
+        }
+        Ok(clients)
+    }
+}
@@ -0,0 +1,252 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, NodeKind, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+/// How often we check log files for new data when following them
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The size of the chunks read from the end of a file when tailing it
+const TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Which log files to read from a deployment
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    /// Only read logs of nodes with this id
+    pub id: Option<u64>,
+    /// Only read logs of nodes of this kind
+    pub kind: Option<NodeKind>,
+    /// Read the error logs instead of the full logs
+    pub errors: bool,
+    /// The number of trailing lines to read from each log file
+    pub lines: usize,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter { id: None, kind: None, errors: false, lines: 20 }
+    }
+}
+
+/// A single line of a node's log
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    /// The name of the node the line came from, i.e. `keeper-1`
+    pub node: String,
+    pub line: String,
+}
+
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.node, self.line)
+    }
+}
+
+/// A log file being followed, along with how much of it we have read
+struct FollowedLog {
+    node: String,
+    path: Utf8PathBuf,
+    offset: u64,
+    partial: String,
+}
+
+impl Deployment {
+    /// Return the names and paths of all log files matching `filter`
+    pub fn log_files(
+        &self,
+        filter: &LogFilter,
+    ) -> Result<Vec<(String, Utf8PathBuf)>> {
+        let Some(meta) = self.meta() else {
+            bail!(MISSING_META);
+        };
+        let id_matches = |id: u64| filter.id.is_none_or(|f| f == id);
+        let kind_matches = |kind| filter.kind.is_none_or(|f| f == kind);
+
+        let mut files = vec![];
+        if kind_matches(NodeKind::Keeper) {
+            let file = if filter.errors {
+                "clickhouse-keeper.err.log"
+            } else {
+                "clickhouse-keeper.log"
+            };
+            for id in meta.keeper_ids.iter().filter(|id| id_matches(id.0)) {
+                let path = self.keeper_dir(*id).join("logs").join(file);
+                files.push((format!("keeper-{id}"), path));
+            }
+        }
+        if kind_matches(NodeKind::Server) {
+            let file = if filter.errors {
+                "clickhouse.err.log"
+            } else {
+                "clickhouse.log"
+            };
+            for id in meta.server_ids.iter().filter(|id| id_matches(id.0)) {
+                let path = self.server_dir(*id).join("logs").join(file);
+                files.push((format!("clickhouse-{id}"), path));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Return the last `filter.lines` lines of every matching log file
+    ///
+    /// Lines from different nodes are interleaved by their timestamps.
+    /// Missing log files are skipped, as the node may not have started yet.
+    pub fn logs(&self, filter: &LogFilter) -> Result<Vec<LogLine>> {
+        let mut entries = vec![];
+        for (node, path) in self.log_files(filter)? {
+            if !path.exists() {
+                continue;
+            }
+            let (lines, _) = tail_lines(&path, filter.lines)?;
+            entries.extend(
+                attach_timestamps(lines).into_iter().map(|(ts, line)| {
+                    (ts, LogLine { node: node.clone(), line })
+                }),
+            );
+        }
+
+        // Clickhouse log timestamps sort lexicographically. The sort is
+        // stable so lines without their own timestamp stay in order.
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries.into_iter().map(|(_, line)| line).collect())
+    }
+
+    /// Print the last `filter.lines` lines of every matching log file and
+    /// then keep calling `f` with new lines as they are written
+    ///
+    /// This never returns unless an error occurs.
+    pub async fn follow_logs<F: FnMut(LogLine)>(
+        &self,
+        filter: &LogFilter,
+        mut f: F,
+    ) -> Result<()> {
+        let mut followed = vec![];
+        let mut initial = vec![];
+        for (node, path) in self.log_files(filter)? {
+            let offset = if path.exists() {
+                let (lines, offset) = tail_lines(&path, filter.lines)?;
+                initial.extend(attach_timestamps(lines).into_iter().map(
+                    |(ts, line)| (ts, LogLine { node: node.clone(), line }),
+                ));
+                offset
+            } else {
+                0
+            };
+            followed.push(FollowedLog {
+                node,
+                path,
+                offset,
+                partial: String::new(),
+            });
+        }
+        initial.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, line) in initial {
+            f(line);
+        }
+
+        loop {
+            for log in &mut followed {
+                for line in log.read_new_lines()? {
+                    f(LogLine { node: log.node.clone(), line });
+                }
+            }
+            self.pause(FOLLOW_POLL_INTERVAL).await?;
+        }
+    }
+}
+
+impl FollowedLog {
+    /// Return all complete lines written since the last call
+    fn read_new_lines(&mut self) -> Result<Vec<String>> {
+        let Ok(metadata) = self.path.metadata() else {
+            // The node hasn't created its log yet
+            return Ok(vec![]);
+        };
+        let len = metadata.len();
+        if len < self.offset {
+            // The log was rotated underneath us. Start from the beginning.
+            self.offset = 0;
+            self.partial.clear();
+        }
+        if len == self.offset {
+            return Ok(vec![]);
+        }
+
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path))?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        self.offset += buf.len() as u64;
+        self.partial.push_str(&String::from_utf8_lossy(&buf));
+
+        // Hold on to any trailing partial line until it is complete
+        let mut lines: Vec<String> =
+            self.partial.split('\n').map(str::to_string).collect();
+        self.partial = lines.pop().unwrap_or_default();
+        Ok(lines)
+    }
+}
+
+/// Read the last `n` lines of the file at `path`
+///
+/// Returns the lines along with the file offset just past the last complete
+/// line, so that the file can be followed from there.
+pub(crate) fn tail_lines(
+    path: &Utf8Path,
+    n: usize,
+) -> Result<(Vec<String>, u64)> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {path}"))?;
+    let len = file.metadata()?.len();
+
+    // Read chunks backwards from the end until we have enough lines
+    let mut start = len;
+    let mut buf = vec![];
+    while start > 0 && buf.iter().filter(|&&b| b == b'\n').count() <= n {
+        let chunk_start = start.saturating_sub(TAIL_CHUNK_SIZE);
+        let mut chunk = vec![0; (start - chunk_start) as usize];
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&buf);
+        buf = chunk;
+        start = chunk_start;
+    }
+
+    // Drop any trailing partial line, which will be picked up when following
+    let complete = buf.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    let end = start + complete as u64;
+    let text = String::from_utf8_lossy(&buf[..complete]);
+    let lines: Vec<&str> = text.lines().collect();
+    let skip = lines.len().saturating_sub(n);
+    Ok((lines[skip..].iter().map(|s| s.to_string()).collect(), end))
+}
+
+/// Pair each line with the timestamp of the log entry it belongs to
+///
+/// Clickhouse log lines start with a `YYYY.MM.DD HH:MM:SS.ffffff` timestamp.
+/// Continuation lines, such as stack traces, inherit the timestamp of the
+/// line before them.
+fn attach_timestamps(lines: Vec<String>) -> Vec<(String, String)> {
+    const TIMESTAMP_LEN: usize = "YYYY.MM.DD HH:MM:SS.ffffff".len();
+    let mut current = String::new();
+    lines
+        .into_iter()
+        .map(|line| {
+            let ts = line.get(..TIMESTAMP_LEN).unwrap_or_default();
+            if ts.starts_with(|c: char| c.is_ascii_digit())
+                && ts.as_bytes().get(4) == Some(&b'.')
+            {
+                current = ts.to_string();
+            }
+            (current.clone(), line)
+        })
+        .collect()
+}
@@ -0,0 +1,366 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    Deployment, KeeperId, NodeAdminState, NodeId, PlanStep, ProgressEvent,
+    ServerId, MISSING_META,
+};
+use anyhow::{bail, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Starts, stops, and checks on the processes of nodes
+///
+/// `Deployment` does all of its process management through this trait, so
+/// that library consumers can substitute their own implementation, i.e. a
+/// mock that records calls without launching clickhouse. Configs are
+/// generated before nodes are spawned, and can be found via `deployment`.
+pub trait ProcessManager: Send + Sync {
+    /// Start a keeper whose config has already been generated
+    fn spawn_keeper(&self, deployment: &Deployment, id: KeeperId)
+        -> Result<()>;
+
+    /// Start a server whose config has already been generated
+    fn spawn_server(&self, deployment: &Deployment, id: ServerId)
+        -> Result<()>;
+
+    /// Stop a node that was spawned
+    fn stop(&self, deployment: &Deployment, node: NodeId) -> Result<()>;
+
+    /// Return true if a node that was spawned is still running
+    fn is_alive(&self, deployment: &Deployment, node: NodeId) -> bool;
+
+    /// Return true if a node was spawned and has not been stopped since,
+    /// whether or not it is still running
+    fn is_started(&self, deployment: &Deployment, node: NodeId) -> bool;
+}
+
+/// The default `ProcessManager`, which runs nodes with the `Backend` of the
+/// deployment's config
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackendProcessManager;
+
+impl ProcessManager for BackendProcessManager {
+    fn spawn_keeper(
+        &self,
+        deployment: &Deployment,
+        id: KeeperId,
+    ) -> Result<()> {
+        deployment.spawn_keeper_with_backend(id)
+    }
+
+    fn spawn_server(
+        &self,
+        deployment: &Deployment,
+        id: ServerId,
+    ) -> Result<()> {
+        deployment.spawn_server_with_backend(id)
+    }
+
+    fn stop(&self, deployment: &Deployment, node: NodeId) -> Result<()> {
+        match node {
+            NodeId::Keeper(id) => deployment.stop_keeper_with_backend(id),
+            NodeId::Server(id) => deployment.stop_server_with_backend(id),
+        }
+    }
+
+    fn is_alive(&self, deployment: &Deployment, node: NodeId) -> bool {
+        deployment.is_alive_with_backend(node)
+    }
+
+    fn is_started(&self, deployment: &Deployment, node: NodeId) -> bool {
+        deployment.is_started_with_backend(node)
+    }
+}
+
+impl Deployment {
+    /// Use `processes` to manage the processes of nodes rather than the
+    /// configured backend
+    pub fn with_process_manager(
+        mut self,
+        processes: Arc<dyn ProcessManager>,
+    ) -> Deployment {
+        self.processes = processes;
+        self
+    }
+
+    pub fn start_keeper(&self, id: KeeperId) -> Result<()> {
+        if self.writer.skip(PlanStep::Start(NodeId::Keeper(id))) {
+            return Ok(());
+        }
+        self.processes.spawn_keeper(self, id)?;
+        self.record_lifecycle(NodeId::Keeper(id), "start")?;
+        self.record_node("start", NodeId::Keeper(id))
+    }
+
+    pub fn start_server(&self, id: ServerId) -> Result<()> {
+        if self.writer.skip(PlanStep::Start(NodeId::Server(id))) {
+            return Ok(());
+        }
+        self.processes.spawn_server(self, id)?;
+        self.record_lifecycle(NodeId::Server(id), "start")?;
+        self.record_node("start", NodeId::Server(id))
+    }
+
+    pub fn stop_keeper(&self, id: KeeperId) -> Result<()> {
+        if self.writer.skip(PlanStep::Stop(NodeId::Keeper(id))) {
+            return Ok(());
+        }
+        self.processes.stop(self, NodeId::Keeper(id))?;
+        self.record_lifecycle(NodeId::Keeper(id), "stop")?;
+        self.record_node("stop", NodeId::Keeper(id))
+    }
+
+    pub fn stop_server(&self, id: ServerId) -> Result<()> {
+        if self.writer.skip(PlanStep::Stop(NodeId::Server(id))) {
+            return Ok(());
+        }
+        self.processes.stop(self, NodeId::Server(id))?;
+        self.record_lifecycle(NodeId::Server(id), "stop")?;
+        self.record_node("stop", NodeId::Server(id))
+    }
+
+    /// Record an operation on a single node in the history, and report it
+    /// as progress if it started or stopped the node
+    pub(crate) fn record_node(
+        &self,
+        operation: &str,
+        node: NodeId,
+    ) -> Result<()> {
+        let params = serde_json::json!({ "node": node.to_string() });
+        self.append_history(operation, params)?;
+        let node = node.to_string();
+        match operation {
+            "start" | "restart" => {
+                self.report(ProgressEvent::NodeStarted { node })
+            }
+            "stop" => self.report(ProgressEvent::NodeStopped { node }),
+            _ => self.report(ProgressEvent::OperationFinished {
+                operation: operation.to_string(),
+            }),
+        }
+        Ok(())
+    }
+
+    /// Return true if the node is running
+    pub fn is_alive(&self, node: NodeId) -> bool {
+        self.processes.is_alive(self, node)
+    }
+
+    /// Start every node that isn't running, except those stopped with
+    /// `set_admin_state`
+    ///
+    /// Keepers start first, and servers only once the keepers have elected a
+    /// leader, as servers need keeper to start up. We wait up to `timeout`
+    /// for the keepers to reach quorum and for each server to become healthy.
+    pub async fn start_all(&self, timeout: Duration) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if self.config.minio.is_some() && !self.is_minio_started() {
+            self.start_minio()?;
+        }
+        let stopped = |node| self.admin_state(node) == NodeAdminState::Stopped;
+        for &id in &meta.keeper_ids {
+            let node = NodeId::Keeper(id);
+            if !stopped(node) && !self.is_alive(node) {
+                self.check_context()?;
+                self.start_keeper(id)?;
+            }
+        }
+        if !meta.keeper_ids.is_empty() {
+            self.wait_for_keeper_quorum(timeout).await?;
+        }
+        for &id in &meta.server_ids {
+            let node = NodeId::Server(id);
+            if !stopped(node) && !self.is_alive(node) {
+                self.check_context()?;
+                self.start_server(id)?;
+            }
+        }
+        for &id in &meta.server_ids {
+            if !stopped(NodeId::Server(id)) {
+                self.wait_for_server(id, timeout).await?;
+            }
+        }
+        self.record("start-all", serde_json::Value::Null)
+    }
+
+    /// Stop every running node, servers before keepers
+    ///
+    /// Stopping keepers first would leave servers unable to shut down their
+    /// replicated tables cleanly.
+    pub fn stop_all(&self) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        for &id in &meta.server_ids {
+            if self.is_alive(NodeId::Server(id)) {
+                self.stop_server(id)?;
+            }
+        }
+        for &id in &meta.keeper_ids {
+            if self.is_alive(NodeId::Keeper(id)) {
+                self.stop_keeper(id)?;
+            }
+        }
+        if self.is_minio_started() {
+            self.stop_minio()?;
+        }
+        self.record("stop-all", serde_json::Value::Null)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_secret, ClickwardMetadata};
+    use camino::Utf8PathBuf;
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+
+    /// A `ProcessManager` that records the nodes it starts and stops rather
+    /// than running them
+    #[derive(Default)]
+    struct MockProcessManager {
+        alive: Mutex<BTreeSet<NodeId>>,
+        calls: Mutex<Vec<(&'static str, NodeId)>>,
+        fail_spawn: Option<NodeId>,
+    }
+
+    impl MockProcessManager {
+        fn spawn(&self, node: NodeId) -> Result<()> {
+            self.calls.lock().unwrap().push(("spawn", node));
+            if self.fail_spawn == Some(node) {
+                bail!("failed to spawn {node}");
+            }
+            self.alive.lock().unwrap().insert(node);
+            Ok(())
+        }
+
+        fn calls(&self) -> Vec<(&'static str, NodeId)> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl ProcessManager for MockProcessManager {
+        fn spawn_keeper(&self, _: &Deployment, id: KeeperId) -> Result<()> {
+            self.spawn(NodeId::Keeper(id))
+        }
+
+        fn spawn_server(&self, _: &Deployment, id: ServerId) -> Result<()> {
+            self.spawn(NodeId::Server(id))
+        }
+
+        fn stop(&self, _: &Deployment, node: NodeId) -> Result<()> {
+            self.calls.lock().unwrap().push(("stop", node));
+            self.alive.lock().unwrap().remove(&node);
+            Ok(())
+        }
+
+        fn is_alive(&self, _: &Deployment, node: NodeId) -> bool {
+            self.alive.lock().unwrap().contains(&node)
+        }
+
+        fn is_started(&self, deployment: &Deployment, node: NodeId) -> bool {
+            self.is_alive(deployment, node)
+        }
+    }
+
+    /// A deployment in a temporary directory, removed when dropped
+    struct TestDeployment {
+        d: Deployment,
+        root: Utf8PathBuf,
+    }
+
+    impl Drop for TestDeployment {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// Return a deployment of 2 keepers and 2 servers whose processes are
+    /// managed by `processes`
+    fn deployment(processes: Arc<MockProcessManager>) -> TestDeployment {
+        let root =
+            Utf8PathBuf::try_from(std::env::temp_dir()).unwrap().join(format!(
+                "clickward-test-{}-{}",
+                std::process::id(),
+                &generate_secret()[..8]
+            ));
+        let mut d =
+            Deployment::new_with_default_port_config(root.clone(), "test")
+                .with_process_manager(processes);
+        let keeper_ids = BTreeSet::from([KeeperId(1), KeeperId(2)]);
+        let server_ids = BTreeSet::from([ServerId(1), ServerId(2)]);
+        for &id in &keeper_ids {
+            std::fs::create_dir_all(d.keeper_dir(id)).unwrap();
+        }
+        for &id in &server_ids {
+            std::fs::create_dir_all(d.server_dir(id)).unwrap();
+        }
+        d.meta = Some(ClickwardMetadata::new(keeper_ids, server_ids));
+        TestDeployment { d, root }
+    }
+
+    #[test]
+    fn stop_all_stops_servers_before_keepers() {
+        let processes = Arc::new(MockProcessManager::default());
+        let test = deployment(processes.clone());
+        for node in [
+            NodeId::Keeper(KeeperId(1)),
+            NodeId::Keeper(KeeperId(2)),
+            NodeId::Server(ServerId(2)),
+        ] {
+            processes.alive.lock().unwrap().insert(node);
+        }
+
+        test.d.stop_all().unwrap();
+
+        // Server 1 isn't running, so it isn't stopped
+        assert_eq!(
+            processes.calls(),
+            [
+                ("stop", NodeId::Server(ServerId(2))),
+                ("stop", NodeId::Keeper(KeeperId(1))),
+                ("stop", NodeId::Keeper(KeeperId(2))),
+            ]
+        );
+        assert!(processes.alive.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn start_records_spawned_nodes() {
+        let processes = Arc::new(MockProcessManager::default());
+        let test = deployment(processes.clone());
+
+        test.d.start_keeper(KeeperId(1)).unwrap();
+        test.d.start_server(ServerId(2)).unwrap();
+
+        assert_eq!(
+            processes.calls(),
+            [
+                ("spawn", NodeId::Keeper(KeeperId(1))),
+                ("spawn", NodeId::Server(ServerId(2))),
+            ]
+        );
+        assert!(test.d.is_alive(NodeId::Keeper(KeeperId(1))));
+        assert!(!test.d.is_alive(NodeId::Keeper(KeeperId(2))));
+        assert!(test.d.is_alive(NodeId::Server(ServerId(2))));
+    }
+
+    #[test]
+    fn spawn_failure_is_returned() {
+        let node = NodeId::Server(ServerId(1));
+        let processes = Arc::new(MockProcessManager {
+            fail_spawn: Some(node),
+            ..Default::default()
+        });
+        let test = deployment(processes.clone());
+
+        let err = test.d.start_server(ServerId(1)).unwrap_err();
+        assert_eq!(err.to_string(), format!("failed to spawn {node}"));
+        assert!(!test.d.is_alive(node));
+    }
+}
@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{DiskConfig, DiskKind};
+use crate::{platform, process, Deployment};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::process::{Command, Stdio};
+use tracing::info;
+
+/// The binary used to run MinIO when it's not overridden
+pub const DEFAULT_MINIO_BINARY: &str = "minio";
+
+/// The port MinIO listens on for the default cluster
+pub const DEFAULT_MINIO_PORT: u16 = 27000;
+
+/// The directory in a deployment that MinIO keeps its data and pid in
+const MINIO_DIR: &str = "minio";
+
+const MINIO_PIDFILE: &str = "minio.pid";
+
+/// A MinIO server that clickward runs alongside a deployment, so that
+/// servers can use `s3` disks without access to a real object store
+///
+/// MinIO always runs on the local machine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MinioConfig {
+    pub port: u16,
+    /// The bucket that disks store their objects in
+    pub bucket: String,
+    pub access_key_id: String,
+    /// MinIO rejects secrets shorter than 8 characters
+    pub secret_access_key: String,
+    #[serde(default)]
+    pub binary: Option<Utf8PathBuf>,
+}
+
+impl MinioConfig {
+    pub fn new(port: u16) -> MinioConfig {
+        MinioConfig {
+            port,
+            bucket: "clickward".to_string(),
+            access_key_id: "clickward".to_string(),
+            secret_access_key: "clickward-secret".to_string(),
+            binary: None,
+        }
+    }
+
+    /// The URL of our bucket
+    pub fn endpoint(&self) -> String {
+        format!("http://127.0.0.1:{}/{}", self.port, self.bucket)
+    }
+
+    /// An `s3` disk named `name` that stores objects under `name/` in our
+    /// bucket
+    pub fn disk(&self, name: &str) -> DiskConfig {
+        DiskConfig {
+            name: name.to_string(),
+            kind: DiskKind::S3 {
+                endpoint: format!("{}/{name}", self.endpoint()),
+                access_key_id: self.access_key_id.clone(),
+                secret_access_key: self.secret_access_key.clone(),
+            },
+        }
+    }
+}
+
+impl Deployment {
+    /// The directory that MinIO keeps its data and pid in
+    pub fn minio_dir(&self) -> Utf8PathBuf {
+        self.config.path.join(MINIO_DIR)
+    }
+
+    /// Return true if we started MinIO and haven't stopped it since
+    pub fn is_minio_started(&self) -> bool {
+        self.minio_dir().join(MINIO_PIDFILE).exists()
+    }
+
+    /// Start the MinIO server of this deployment, creating its bucket
+    pub fn start_minio(&self) -> Result<()> {
+        let Some(minio) = &self.config.minio else {
+            bail!("deployment is not configured with MinIO");
+        };
+        if self.is_minio_started() {
+            bail!("MinIO is already running");
+        }
+        let dir = self.minio_dir();
+        let data = dir.join("data");
+        // MinIO serves top level directories of a fresh data directory as
+        // buckets
+        std::fs::create_dir_all(data.join(&minio.bucket))
+            .with_context(|| format!("failed to create {data}"))?;
+
+        info!(%dir, "Deploying MinIO");
+        let binary = minio
+            .binary
+            .as_deref()
+            .unwrap_or(Utf8Path::new(DEFAULT_MINIO_BINARY));
+        let (stdout, stderr) = process::output_files(&dir)?;
+        let child = Command::new(binary)
+            .arg("server")
+            .arg(&data)
+            .arg("--address")
+            .arg(format!("127.0.0.1:{}", minio.port))
+            .env("MINIO_ROOT_USER", &minio.access_key_id)
+            .env("MINIO_ROOT_PASSWORD", &minio.secret_access_key)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn()
+            .context("Failed to start MinIO")?;
+        std::fs::write(dir.join(MINIO_PIDFILE), child.id().to_string())?;
+        self.record("start-minio", serde_json::json!({ "port": minio.port }))
+    }
+
+    /// Stop the MinIO server of this deployment
+    pub fn stop_minio(&self) -> Result<()> {
+        let pidfile = self.minio_dir().join(MINIO_PIDFILE);
+        let pid = platform::read_pidfile(&pidfile)?;
+        if !platform::is_running_as(pid, &format!("{MINIO_DIR}/data")) {
+            std::fs::remove_file(&pidfile)?;
+            bail!("MinIO is not running as pid {pid}");
+        }
+        info!(pid, "Stopping MinIO");
+        platform::kill(pid).context("Failed to kill MinIO")?;
+        std::fs::remove_file(&pidfile)?;
+        self.record("stop-minio", serde_json::Value::Null)
+    }
+}
@@ -0,0 +1,135 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{KeeperConfig, ReplicaConfig};
+use crate::{Deployment, KeeperId, NodeId, PortError, ServerId};
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The file in each node's directory describing the node
+pub const NODE_INFO_FILENAME: &str = "node.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeRole {
+    Keeper,
+    /// A keeper that never votes or becomes leader
+    KeeperObserver,
+    Server,
+}
+
+/// What a node was generated with, so tools don't have to parse its XML
+/// config to learn i.e. its http port
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub node: NodeId,
+    /// The name of the node, i.e. `keeper-1`
+    pub name: String,
+    pub role: NodeRole,
+    pub host: String,
+    /// The name a server reports in its logs and the client prompt
+    pub display_name: Option<String>,
+    pub dir: Utf8PathBuf,
+    pub config: Utf8PathBuf,
+    pub log: Utf8PathBuf,
+    pub errorlog: Utf8PathBuf,
+    /// Where a server keeps its data
+    pub data_path: Option<Utf8PathBuf>,
+    /// The database that queries on a server use unless they name another
+    #[serde(default)]
+    pub default_database: Option<String>,
+    /// The ports the node listens on, keyed by their purpose
+    pub ports: BTreeMap<String, u16>,
+}
+
+impl Deployment {
+    /// Return the manifest of a node, as written when its config was last
+    /// generated
+    pub fn node_info(&self, node: NodeId) -> Result<NodeInfo> {
+        let path = self.node_dir(node).join(NODE_INFO_FILENAME);
+        let json = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {path}"))?;
+        let info = serde_json::from_str(&json)
+            .with_context(|| format!("failed to parse {path}"))?;
+        Ok(info)
+    }
+
+    /// Write the manifest of a keeper generated with `config`
+    pub(crate) fn write_keeper_info(
+        &self,
+        id: KeeperId,
+        config: &KeeperConfig,
+    ) -> Result<()> {
+        let observer =
+            self.meta.as_ref().is_some_and(|meta| meta.is_keeper_observer(id));
+        let dir = self.keeper_dir(id);
+        let info = NodeInfo {
+            node: NodeId::Keeper(id),
+            name: format!("keeper-{id}"),
+            role: if observer {
+                NodeRole::KeeperObserver
+            } else {
+                NodeRole::Keeper
+            },
+            host: self.keeper_host(id),
+            display_name: None,
+            config: dir.join("keeper-config.xml"),
+            log: config.logger.log.clone(),
+            errorlog: config.logger.errorlog.clone(),
+            data_path: None,
+            default_database: None,
+            ports: self.ports_by_name(&[id], &[])?,
+            dir,
+        };
+        self.write_node_info(&info)
+    }
+
+    /// Write the manifest of a server generated with `config`
+    pub(crate) fn write_server_info(
+        &self,
+        id: ServerId,
+        config: &ReplicaConfig,
+    ) -> Result<()> {
+        let dir = self.server_dir(id);
+        let info = NodeInfo {
+            node: NodeId::Server(id),
+            name: format!("clickhouse-{id}"),
+            role: NodeRole::Server,
+            host: self.server_host(id),
+            display_name: Some(format!("{}-{id}", config.macros.cluster)),
+            config: dir.join("clickhouse-config.xml"),
+            log: config.logger.log.clone(),
+            errorlog: config.logger.errorlog.clone(),
+            data_path: Some(config.data_path.clone()),
+            default_database: Some(
+                config
+                    .default_database
+                    .clone()
+                    .unwrap_or_else(|| "default".to_string()),
+            ),
+            ports: self.ports_by_name(&[], &[id])?,
+            dir,
+        };
+        self.write_node_info(&info)
+    }
+
+    fn ports_by_name(
+        &self,
+        keeper_ids: &[KeeperId],
+        server_ids: &[ServerId],
+    ) -> Result<BTreeMap<String, u16>, PortError> {
+        Ok(self
+            .node_ports(keeper_ids, server_ids)?
+            .into_iter()
+            .map(|port| (port.name.to_string(), port.port))
+            .collect())
+    }
+
+    fn write_node_info(&self, info: &NodeInfo) -> Result<()> {
+        let json = serde_json::to_string_pretty(info)?;
+        self.writer.write(&info.dir.join(NODE_INFO_FILENAME), json.as_bytes())
+    }
+}
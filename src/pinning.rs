@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The binary that pins nodes to CPUs
+pub const TASKSET_BINARY: &str = "taskset";
+
+/// The binary that binds nodes to NUMA nodes
+pub const NUMACTL_BINARY: &str = "numactl";
+
+/// How spawned nodes are pinned to CPUs, so that nodes don't compete for
+/// cores in performance experiments
+///
+/// Nodes are numbered keepers first and then servers, in order of id, among
+/// the nodes of the deployment when they start. Pinning is ignored on
+/// Windows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PinningPolicy {
+    /// Give each node `cores_per_node` cores of its own with `taskset`,
+    /// counting from `first_core`
+    Exclusive {
+        cores_per_node: usize,
+        #[serde(default)]
+        first_core: usize,
+    },
+    /// Pin nodes to CPU lists as taken by `taskset -c`, i.e. `0-3` or `0,2`,
+    /// keyed by node name, i.e. `keeper-1`
+    ///
+    /// Nodes that aren't listed aren't pinned.
+    Cpus { cpus: BTreeMap<String, String> },
+    /// Bind the CPUs and memory of nodes to NUMA nodes with `numactl`,
+    /// assigned round robin
+    ///
+    /// Docker containers only have their memory bound, as docker can only
+    /// restrict containers to lists of CPUs.
+    Numa { nodes: Vec<u32> },
+}
+
+/// Where a single node is pinned
+enum Pinning {
+    Cpus(String),
+    NumaNode(u32),
+}
+
+impl Deployment {
+    /// Return where the node named `node` is pinned, if anywhere
+    fn pinning(&self, node: &str) -> Option<Pinning> {
+        let policy = self.config.cpu_pinning.as_ref()?;
+        if cfg!(windows) {
+            return None;
+        }
+        let index = || {
+            let meta = self.meta.as_ref()?;
+            let keepers = meta.keeper_ids.iter().map(|&id| NodeId::Keeper(id));
+            let servers = meta.server_ids.iter().map(|&id| NodeId::Server(id));
+            keepers.chain(servers).position(|n| n.to_string() == node)
+        };
+        match policy {
+            PinningPolicy::Exclusive { cores_per_node, first_core } => {
+                if *cores_per_node == 0 {
+                    return None;
+                }
+                let first = first_core + index()? * cores_per_node;
+                let last = first + cores_per_node - 1;
+                Some(Pinning::Cpus(format!("{first}-{last}")))
+            }
+            PinningPolicy::Cpus { cpus } => {
+                cpus.get(node).cloned().map(Pinning::Cpus)
+            }
+            PinningPolicy::Numa { nodes } => {
+                if nodes.is_empty() {
+                    return None;
+                }
+                Some(Pinning::NumaNode(nodes[index()? % nodes.len()]))
+            }
+        }
+    }
+
+    /// Return the command that the node named `node` runs under to pin it,
+    /// which `exec`s the rest of its command line
+    pub(crate) fn pinning_shim(&self, node: &str) -> Vec<String> {
+        match self.pinning(node) {
+            Some(Pinning::Cpus(cpus)) => {
+                vec![TASKSET_BINARY.to_string(), "-c".to_string(), cpus]
+            }
+            Some(Pinning::NumaNode(numa)) => vec![
+                NUMACTL_BINARY.to_string(),
+                format!("--cpunodebind={numa}"),
+                format!("--membind={numa}"),
+            ],
+            None => vec![],
+        }
+    }
+
+    /// Return the `docker run` arguments that pin the node named `node`
+    pub(crate) fn docker_pinning_args(&self, node: &str) -> Vec<String> {
+        match self.pinning(node) {
+            Some(Pinning::Cpus(cpus)) => vec![format!("--cpuset-cpus={cpus}")],
+            Some(Pinning::NumaNode(numa)) => {
+                vec![format!("--cpuset-mems={numa}")]
+            }
+            None => vec![],
+        }
+    }
+}
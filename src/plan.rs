@@ -0,0 +1,280 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::context::CurrentOperation;
+use crate::{
+    write_atomic, ClickwardMetadata, Deployment, DeploymentConfig, KeeperId,
+    NoProgress, NodeId, OperationContext, ServerId, CLICKWARD_CONFIG_FILENAME,
+    CLICKWARD_META_FILENAME,
+};
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A single step of a mutating operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanStep {
+    Write(Utf8PathBuf),
+    Remove(Utf8PathBuf),
+    /// Copy the configs in a node directory to the remote host it runs on
+    Push {
+        host: String,
+        dir: Utf8PathBuf,
+    },
+    /// Pick free ports for a node that has none yet
+    PickPorts(NodeId),
+    Start(NodeId),
+    Stop(NodeId),
+    /// Hand leadership of the keeper cluster to a keeper
+    RequestLeadership(KeeperId),
+}
+
+impl Display for PlanStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanStep::Write(path) => write!(f, "write {path}"),
+            PlanStep::Remove(path) => write!(f, "remove {path}"),
+            PlanStep::Push { host, dir } => {
+                write!(f, "copy configs in {dir} to {host}")
+            }
+            PlanStep::PickPorts(node) => {
+                write!(f, "pick free ports for {node}")
+            }
+            PlanStep::Start(node) => write!(f, "start {node}"),
+            PlanStep::Stop(node) => write!(f, "stop {node}"),
+            PlanStep::RequestLeadership(id) => {
+                write!(f, "hand keeper leadership to keeper-{id}")
+            }
+        }
+    }
+}
+
+/// What a mutating operation would do, in order, without doing it
+///
+/// Plans are made by running the operation itself with its changes recorded
+/// rather than made, so they include every check the operation makes.
+/// Appends to the history and event logs aren't listed.
+#[derive(Debug, Clone)]
+pub struct Plan {
+    /// The name of the operation, as recorded in the history
+    pub operation: &'static str,
+    pub steps: Vec<PlanStep>,
+    /// The keepers that are members of the cluster afterwards
+    pub keeper_ids: BTreeSet<KeeperId>,
+    /// The servers that are members of the cluster afterwards
+    pub server_ids: BTreeSet<ServerId>,
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Plan for {}:", self.operation)?;
+        for step in &self.steps {
+            writeln!(f, "  {step}")?;
+        }
+        let join = |ids: Vec<String>| ids.join(", ");
+        writeln!(f, "Resulting membership:")?;
+        writeln!(
+            f,
+            "  keepers: {}",
+            join(self.keeper_ids.iter().map(|id| id.to_string()).collect())
+        )?;
+        write!(
+            f,
+            "  servers: {}",
+            join(self.server_ids.iter().map(|id| id.to_string()).collect())
+        )
+    }
+}
+
+/// Makes the changes of operations, i.e. writing files and starting nodes,
+/// or records them as the steps of a plan in a dry run
+///
+/// Operations make their changes through this rather than directly, so
+/// that a plan is the operation itself and can't leave out a step of it.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum FileWriter {
+    #[default]
+    Disk,
+    DryRun(Arc<Mutex<Vec<PlanStep>>>),
+}
+
+impl FileWriter {
+    /// Record `step` if this is a dry run
+    pub(crate) fn note(&self, step: PlanStep) {
+        if let FileWriter::DryRun(steps) = self {
+            steps.lock().unwrap().push(step);
+        }
+    }
+
+    /// Record `step` if this is a dry run, and return true if so, in which
+    /// case the caller must not carry it out
+    pub(crate) fn skip(&self, step: PlanStep) -> bool {
+        self.note(step);
+        self.is_dry_run()
+    }
+
+    pub(crate) fn is_dry_run(&self) -> bool {
+        matches!(self, FileWriter::DryRun(_))
+    }
+
+    /// Write `contents` to `path` like `write_atomic`
+    pub(crate) fn write(&self, path: &Utf8Path, contents: &[u8]) -> Result<()> {
+        if self.skip(PlanStep::Write(path.to_path_buf())) {
+            return Ok(());
+        }
+        write_atomic(path, contents)
+    }
+
+    pub(crate) fn copy(&self, from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+        if self.skip(PlanStep::Write(to.to_path_buf())) {
+            return Ok(());
+        }
+        std::fs::copy(from, to)
+            .with_context(|| format!("failed to copy {from}"))?;
+        Ok(())
+    }
+
+    pub(crate) fn remove_file(&self, path: &Utf8Path) -> Result<()> {
+        if self.skip(PlanStep::Remove(path.to_path_buf())) {
+            return Ok(());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove {path}"))
+    }
+
+    pub(crate) fn remove_dir_all(&self, path: &Utf8Path) -> Result<()> {
+        if self.skip(PlanStep::Remove(path.to_path_buf())) {
+            return Ok(());
+        }
+        std::fs::remove_dir_all(path)
+            .with_context(|| format!("failed to remove {path}"))
+    }
+
+    /// Create `path` and its parents, which a dry run leaves out of the plan
+    /// as the files written to them are listed
+    pub(crate) fn create_dir_all(&self, path: &Utf8Path) -> Result<()> {
+        if self.is_dry_run() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("failed to create {path}"))
+    }
+
+    /// Save `meta` to `deployment_dir` like `ClickwardMetadata::save`
+    pub(crate) fn save_meta(
+        &self,
+        meta: &mut ClickwardMetadata,
+        deployment_dir: &Utf8Path,
+    ) -> Result<()> {
+        let path = deployment_dir.join(CLICKWARD_META_FILENAME);
+        if self.skip(PlanStep::Write(path)) {
+            return Ok(());
+        }
+        meta.save(deployment_dir)
+    }
+
+    /// Save `config` like `DeploymentConfig::save`
+    pub(crate) fn save_config(&self, config: &DeploymentConfig) -> Result<()> {
+        let path = config.path.join(CLICKWARD_CONFIG_FILENAME);
+        if self.skip(PlanStep::Write(path)) {
+            return Ok(());
+        }
+        config.save()
+    }
+}
+
+impl Deployment {
+    /// Plan `generate_config`
+    pub fn plan_generate_config(
+        &self,
+        num_keepers: u64,
+        num_replicas: u64,
+    ) -> Result<Plan> {
+        let mut d = self.dry_run();
+        d.generate_config(num_keepers, num_replicas)?;
+        Ok(d.into_plan("gen-config"))
+    }
+
+    /// Plan `add_keeper`, or `add_keeper_observer` if `observer` is true
+    pub fn plan_add_keeper(&self, observer: bool) -> Result<Plan> {
+        let mut d = self.dry_run();
+        if observer {
+            d.add_keeper_observer(&OperationContext::default())?;
+        } else {
+            d.add_keeper(&OperationContext::default())?;
+        }
+        Ok(d.into_plan("add-keeper"))
+    }
+
+    /// Plan `add_keepers`
+    pub async fn plan_add_keepers(&self, count: usize) -> Result<Plan> {
+        let mut d = self.dry_run();
+        d.add_keepers(count, Duration::ZERO, &OperationContext::default())
+            .await?;
+        Ok(d.into_plan("add-keepers"))
+    }
+
+    /// Plan `add_server`
+    pub fn plan_add_server(&self) -> Result<Plan> {
+        let mut d = self.dry_run();
+        d.add_server(&OperationContext::default())?;
+        Ok(d.into_plan("add-server"))
+    }
+
+    /// Plan `add_servers`
+    pub async fn plan_add_servers(&self, count: usize) -> Result<Plan> {
+        let mut d = self.dry_run();
+        d.add_servers(count, Duration::ZERO, &OperationContext::default())
+            .await?;
+        Ok(d.into_plan("add-servers"))
+    }
+
+    /// Plan `remove_keeper_safely`, which checks quorum with the running
+    /// keepers unless `force` is true
+    pub async fn plan_remove_keeper(
+        &self,
+        id: KeeperId,
+        force: bool,
+    ) -> Result<Plan> {
+        let mut d = self.dry_run();
+        d.remove_keeper_safely(id, force, &OperationContext::default()).await?;
+        Ok(d.into_plan("remove-keeper"))
+    }
+
+    /// Plan `remove_server`
+    pub fn plan_remove_server(&self, id: ServerId) -> Result<Plan> {
+        let mut d = self.dry_run();
+        d.remove_server(id, &OperationContext::default())?;
+        Ok(d.into_plan("remove-server"))
+    }
+
+    /// Return a copy of this deployment whose operations record their
+    /// changes rather than making them
+    fn dry_run(&self) -> Deployment {
+        Deployment {
+            config: self.config.clone(),
+            meta: self.meta.clone(),
+            processes: self.processes.clone(),
+            progress: Arc::new(NoProgress),
+            operation: CurrentOperation::default(),
+            writer: FileWriter::DryRun(Arc::default()),
+        }
+    }
+
+    /// Return the plan recorded by operations on a `dry_run` deployment
+    fn into_plan(self, operation: &'static str) -> Plan {
+        let steps = match self.writer {
+            FileWriter::Disk => vec![],
+            FileWriter::DryRun(steps) => steps.lock().unwrap().clone(),
+        };
+        let (keeper_ids, server_ids) = self
+            .meta
+            .map(|meta| (meta.keeper_ids, meta.server_ids))
+            .unwrap_or_default();
+        Plan { operation, steps, keeper_ids, server_ids }
+    }
+}
@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Managing local processes and files, which differs between Unix and
+//! Windows
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use std::process::{Command, Stdio};
+
+/// Read the pid written to `path` by a node or by us
+pub(crate) fn read_pidfile(path: &Utf8Path) -> Result<u32> {
+    let pid = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {path}"))?;
+    pid.trim_end()
+        .parse()
+        .with_context(|| format!("invalid pid in {path}: {pid}"))
+}
+
+/// Run `cmd` to completion, failing if it exits unsuccessfully
+#[cfg(windows)]
+fn run(cmd: &mut Command) -> Result<String> {
+    let output = cmd
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {cmd:?}"))?;
+    if !output.status.success() {
+        bail!(
+            "{cmd:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Return true if the process `pid` exists and its command line contains
+/// `expected`, i.e. the path of a node's config
+///
+/// Pids are reused once a process exits, so a pid read from a stale pidfile
+/// may belong to an unrelated process. Where command lines can't be read,
+/// any running process is assumed to be the expected one.
+pub(crate) fn is_running_as(pid: u32, expected: &str) -> bool {
+    is_running(pid) && cmdline(pid).is_none_or(|cmd| cmd.contains(expected))
+}
+
+/// Parse one pid per line, ignoring blank lines
+fn parse_pids(s: &str) -> Result<Vec<u32>> {
+    s.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse().with_context(|| format!("invalid pid {line}")))
+        .collect()
+}
+
+/// Forcefully terminate the process `pid`
+///
+/// This fails if the process no longer exists.
+#[cfg(unix)]
+pub(crate) fn kill(pid: u32) -> Result<()> {
+    use nix::sys::signal::{kill, Signal};
+    kill(nix_pid(pid)?, Signal::SIGKILL)
+        .with_context(|| format!("failed to kill pid {pid}"))
+}
+
+/// Return true if the process `pid` exists
+#[cfg(unix)]
+pub(crate) fn is_running(pid: u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    let Ok(pid) = nix_pid(pid) else {
+        return false;
+    };
+    // Signal 0 only checks whether we could signal the process. A process
+    // that exists but belongs to another user is still running.
+    matches!(kill(pid, None), Ok(()) | Err(Errno::EPERM))
+}
+
+#[cfg(unix)]
+fn nix_pid(pid: u32) -> Result<nix::unistd::Pid> {
+    // Negative pids and 0 signal process groups rather than a process
+    match i32::try_from(pid) {
+        Ok(raw) if raw > 0 => Ok(nix::unistd::Pid::from_raw(raw)),
+        _ => bail!("invalid pid {pid}"),
+    }
+}
+
+/// Return the command line of the process `pid` with arguments separated by
+/// spaces, or `None` if it can't be read
+///
+/// This is only available where there is a `/proc` filesystem.
+#[cfg(unix)]
+fn cmdline(pid: u32) -> Option<String> {
+    let raw = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    Some(String::from_utf8_lossy(&raw).replace('\0', " "))
+}
+
+/// Return the pids of the direct children of the process `pid`
+#[cfg(unix)]
+pub(crate) fn child_pids(pid: u32) -> Result<Vec<u32>> {
+    let output = Command::new("pgrep")
+        .arg("-P")
+        .arg(pid.to_string())
+        .stdin(Stdio::null())
+        .output()
+        .context("failed to run pgrep")?;
+    // pgrep exits with 1 when nothing matches
+    match output.status.code() {
+        Some(0) | Some(1) => {
+            parse_pids(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => bail!(
+            "pgrep failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ),
+    }
+}
+
+#[cfg(windows)]
+fn cmdline(_pid: u32) -> Option<String> {
+    None
+}
+
+/// Forcefully terminate the process `pid`
+#[cfg(windows)]
+pub(crate) fn kill(pid: u32) -> Result<()> {
+    run(Command::new("taskkill").arg("/F").arg("/PID").arg(pid.to_string()))?;
+    Ok(())
+}
+
+/// Return true if the process `pid` exists
+#[cfg(windows)]
+pub(crate) fn is_running(pid: u32) -> bool {
+    // `tasklist` succeeds whether or not the filter matches, so look for the
+    // pid in its CSV output
+    run(Command::new("tasklist")
+        .arg("/FI")
+        .arg(format!("PID eq {pid}"))
+        .arg("/FO")
+        .arg("CSV")
+        .arg("/NH"))
+    .is_ok_and(|out| out.contains(&format!("\"{pid}\"")))
+}
+
+/// Return the pids of the direct children of the process `pid`
+#[cfg(windows)]
+pub(crate) fn child_pids(pid: u32) -> Result<Vec<u32>> {
+    let out = run(Command::new("powershell")
+        .arg("-NoProfile")
+        .arg("-Command")
+        .arg(format!(
+            "(Get-CimInstance Win32_Process \
+             -Filter 'ParentProcessId={pid}').ProcessId"
+        )))?;
+    parse_pids(&out)
+}
+
+/// Create a symlink at `link` pointing to `target`
+#[cfg(unix)]
+pub(crate) fn symlink(target: &Utf8Path, link: &Utf8Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)
+        .with_context(|| format!("failed to create symlink {link}"))
+}
+
+/// Create a symlink at `link` pointing to `target`
+///
+/// Windows distinguishes symlinks to directories from those to files, so
+/// `target` must already exist.
+#[cfg(windows)]
+pub(crate) fn symlink(target: &Utf8Path, link: &Utf8Path) -> Result<()> {
+    let result = if target.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    };
+    result.with_context(|| format!("failed to create symlink {link}"))
+}
+
+/// Return the uid and gid that own `path`
+#[cfg(unix)]
+pub(crate) fn owner(path: &Utf8Path) -> Result<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("failed to read the owner of {path}"))?;
+    Ok((metadata.uid(), metadata.gid()))
+}
+
+/// Return the uid and gid that own `path`, which Windows doesn't have
+#[cfg(windows)]
+pub(crate) fn owner(_path: &Utf8Path) -> Result<(u32, u32)> {
+    bail!("file owners are not supported on Windows")
+}
+
+/// Make `path` writable by its owner
+#[cfg(unix)]
+pub(crate) fn make_writable(path: &Utf8Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o200);
+    std::fs::set_permissions(path, permissions)
+        .with_context(|| format!("failed to make {path} writable"))
+}
+
+/// Make `path` writable
+#[cfg(windows)]
+pub(crate) fn make_writable(path: &Utf8Path) -> Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(path, permissions)
+        .with_context(|| format!("failed to make {path} writable"))
+}
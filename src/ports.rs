@@ -0,0 +1,635 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    BasePorts, ClickwardMetadata, Deployment, FileWriter, KeeperId, NodeId,
+    PlanStep, ServerId, CLUSTER_PORT_STRIDE,
+};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::net::{Ipv6Addr, TcpListener};
+
+/// How many ports we try to bind for each free port we need before giving up
+const FREE_PORT_ATTEMPTS: usize = 10;
+
+/// A port that a node listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodePort {
+    /// The name of the node, i.e. `keeper-1`
+    pub node: String,
+    /// The host the node runs on
+    pub host: String,
+    /// What the port is used for, i.e. `raft`
+    pub name: &'static str,
+    pub port: u16,
+}
+
+impl Display for NodePort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} port {} on {}",
+            self.node, self.name, self.port, self.host
+        )
+    }
+}
+
+/// Why a port can't be used by a node
+#[derive(Debug, Clone)]
+pub enum PortConflict {
+    /// Another node in the deployment uses the same port
+    Node { port: NodePort, other: NodePort },
+    /// The port could not be bound, most likely because another process
+    /// is listening on it
+    InUse { port: NodePort, error: String },
+}
+
+impl Display for PortConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortConflict::Node { port, other } => {
+                write!(f, "{port} is also the {other}")
+            }
+            PortConflict::InUse { port, error } => {
+                write!(f, "{port} is unavailable: {error}")
+            }
+        }
+    }
+}
+
+/// Returned when the ports of a deployment conflict with each other or with
+/// ports already in use
+#[derive(Debug)]
+pub struct PortConflicts {
+    pub conflicts: Vec<PortConflict>,
+}
+
+impl Display for PortConflicts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "port conflicts:")?;
+        for conflict in &self.conflicts {
+            write!(f, "\n    {conflict}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PortConflicts {}
+
+/// The ports picked for a keeper with `DeploymentConfig::free_ports`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeeperPorts {
+    pub keeper: u16,
+    pub raft: u16,
+    pub metrics: u16,
+    pub http_control: u16,
+}
+
+/// The ports picked for a server with `DeploymentConfig::free_ports`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerPorts {
+    pub tcp: u16,
+    pub http: u16,
+    pub interserver_http: u16,
+    pub metrics: u16,
+}
+
+/// The largest node id that gets ports, as each kind of port has a block of
+/// `CLUSTER_PORT_STRIDE` ports above its base port, and ids are offsets into
+/// it
+pub const MAX_PORT_NODE_ID: u64 = CLUSTER_PORT_STRIDE as u64 - 1;
+
+/// A kind of port that every keeper or every server gets one of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortBlock {
+    Keeper,
+    Raft,
+    KeeperMetrics,
+    KeeperHttpControl,
+    ClickhouseTcp,
+    ClickhouseHttp,
+    ClickhouseInterserverHttp,
+    ClickhouseMetrics,
+}
+
+impl PortBlock {
+    /// The base port of this block in `base`
+    pub fn base(self, base: &BasePorts) -> u16 {
+        match self {
+            PortBlock::Keeper => base.keeper,
+            PortBlock::Raft => base.raft,
+            PortBlock::KeeperMetrics => base.keeper_metrics,
+            PortBlock::KeeperHttpControl => base.keeper_http_control(),
+            PortBlock::ClickhouseTcp => base.clickhouse_tcp,
+            PortBlock::ClickhouseHttp => base.clickhouse_http,
+            PortBlock::ClickhouseInterserverHttp => {
+                base.clickhouse_interserver_http
+            }
+            PortBlock::ClickhouseMetrics => base.clickhouse_metrics,
+        }
+    }
+}
+
+impl Display for PortBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PortBlock::Keeper => "keeper",
+            PortBlock::Raft => "raft",
+            PortBlock::KeeperMetrics => "keeper metrics",
+            PortBlock::KeeperHttpControl => "keeper http control",
+            PortBlock::ClickhouseTcp => "clickhouse tcp",
+            PortBlock::ClickhouseHttp => "clickhouse http",
+            PortBlock::ClickhouseInterserverHttp => {
+                "clickhouse interserver http"
+            }
+            PortBlock::ClickhouseMetrics => "clickhouse metrics",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Why ports can't be allocated from a set of base ports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortError {
+    /// A node id is too large to get a port in its block
+    IdOutOfRange { block: PortBlock, id: u64 },
+    /// A block extends past the largest port
+    Overflow { block: PortBlock, base: u16 },
+    /// Two blocks share ports
+    Overlap { block: PortBlock, other: PortBlock, base: u16, other_base: u16 },
+}
+
+impl Display for PortError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortError::IdOutOfRange { block, id } => write!(
+                f,
+                "node id {id} has no {block} port, as ids must be at most \
+                 {MAX_PORT_NODE_ID}"
+            ),
+            PortError::Overflow { block, base } => write!(
+                f,
+                "{block} ports starting at {base} don't fit below port {}",
+                u16::MAX
+            ),
+            PortError::Overlap { block, other, base, other_base } => write!(
+                f,
+                "{block} ports starting at {base} overlap {other} ports \
+                 starting at {other_base}, as base ports must be at least \
+                 {CLUSTER_PORT_STRIDE} apart"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PortError {}
+
+/// Hands out the ports of nodes from validated blocks of base ports
+///
+/// Creating one checks that its blocks fit below the largest port and don't
+/// overlap, after which the port of any id up to `MAX_PORT_NODE_ID` can be
+/// computed without overflowing or colliding.
+#[derive(Debug, Clone)]
+pub struct PortAllocator {
+    base: BasePorts,
+    blocks: Vec<PortBlock>,
+}
+
+impl PortAllocator {
+    /// Validate `blocks` of `base`, which are the only ones we allocate from
+    pub fn new(
+        base: &BasePorts,
+        blocks: &[PortBlock],
+    ) -> Result<PortAllocator, PortError> {
+        let max = MAX_PORT_NODE_ID as u16;
+        for (i, &block) in blocks.iter().enumerate() {
+            let start = block.base(base);
+            if start.checked_add(max).is_none() {
+                return Err(PortError::Overflow { block, base: start });
+            }
+            for &other in &blocks[..i] {
+                let other_start = other.base(base);
+                if start.abs_diff(other_start) < CLUSTER_PORT_STRIDE {
+                    return Err(PortError::Overlap {
+                        block,
+                        other,
+                        base: start,
+                        other_base: other_start,
+                    });
+                }
+            }
+        }
+        Ok(PortAllocator { base: base.clone(), blocks: blocks.to_vec() })
+    }
+
+    /// Return the port of the node with `id` in `block`
+    pub fn port(&self, block: PortBlock, id: u64) -> Result<u16, PortError> {
+        if id > MAX_PORT_NODE_ID {
+            return Err(PortError::IdOutOfRange { block, id });
+        }
+        let base = block.base(&self.base);
+        if !self.blocks.contains(&block) {
+            // Unvalidated blocks may still be out of range
+            return base
+                .checked_add(id as u16)
+                .ok_or(PortError::Overflow { block, base });
+        }
+        Ok(base + id as u16)
+    }
+}
+
+impl Deployment {
+    /// Return the blocks of ports that our nodes use
+    fn port_blocks(&self) -> Vec<PortBlock> {
+        let mut blocks = vec![PortBlock::Keeper, PortBlock::Raft];
+        if self.config.prometheus {
+            blocks.push(PortBlock::KeeperMetrics);
+        }
+        if self.config.keeper_http_control {
+            blocks.push(PortBlock::KeeperHttpControl);
+        }
+        blocks.extend([
+            PortBlock::ClickhouseTcp,
+            PortBlock::ClickhouseHttp,
+            PortBlock::ClickhouseInterserverHttp,
+        ]);
+        if self.config.prometheus {
+            blocks.push(PortBlock::ClickhouseMetrics);
+        }
+        blocks
+    }
+
+    /// Return an allocator for the ports of our nodes, failing if our base
+    /// ports overflow or overlap
+    pub fn port_allocator(&self) -> Result<PortAllocator, PortError> {
+        PortAllocator::new(&self.config.base_ports, &self.port_blocks())
+    }
+
+    /// Return the port of the node with `id` in `block` of our base ports,
+    /// failing rather than wrapping around if it has none
+    pub(crate) fn base_port(
+        &self,
+        block: PortBlock,
+        id: u64,
+    ) -> Result<u16, PortError> {
+        self.port_allocator()?.port(block, id)
+    }
+
+    /// Check that the given nodes get valid ports from our base ports
+    ///
+    /// This must pass before their ports are computed, or they may wrap
+    /// around or run into the ports of other nodes.
+    pub fn check_port_ids<'a>(
+        &self,
+        keeper_ids: impl IntoIterator<Item = &'a KeeperId>,
+        server_ids: impl IntoIterator<Item = &'a ServerId>,
+    ) -> Result<(), PortError> {
+        if self.config.free_ports {
+            return Ok(());
+        }
+        let allocator = self.port_allocator()?;
+        for id in keeper_ids {
+            allocator.port(PortBlock::Keeper, id.0)?;
+        }
+        for id in server_ids {
+            allocator.port(PortBlock::ClickhouseTcp, id.0)?;
+        }
+        Ok(())
+    }
+
+    /// Return the ports picked for keeper `id`, if it has any
+    pub(crate) fn picked_keeper_ports(
+        &self,
+        id: KeeperId,
+    ) -> Option<KeeperPorts> {
+        self.meta.as_ref()?.keeper_ports.get(&id).copied()
+    }
+
+    /// Return the ports picked for server `id`, if it has any
+    pub(crate) fn picked_server_ports(
+        &self,
+        id: ServerId,
+    ) -> Option<ServerPorts> {
+        self.meta.as_ref()?.server_ports.get(&id).copied()
+    }
+
+    /// Return all ports that the given nodes listen on
+    pub fn node_ports<'a>(
+        &self,
+        keeper_ids: impl IntoIterator<Item = &'a KeeperId>,
+        server_ids: impl IntoIterator<Item = &'a ServerId>,
+    ) -> Result<Vec<NodePort>, PortError> {
+        let prometheus = self.config.prometheus;
+        let mut ports = vec![];
+        for id in keeper_ids {
+            let node = NodeId::Keeper(*id).to_string();
+            let host = self.keeper_host(*id);
+            let mut push = |name, port| {
+                ports.push(NodePort {
+                    node: node.clone(),
+                    host: host.clone(),
+                    name,
+                    port,
+                })
+            };
+            push("keeper", self.keeper_port(*id)?);
+            push("raft", self.raft_port(*id)?);
+            if prometheus {
+                push("metrics", self.keeper_metrics_port(*id)?);
+            }
+            if self.config.keeper_http_control {
+                push("http control", self.keeper_http_control_port(*id)?);
+            }
+        }
+        for id in server_ids {
+            let node = NodeId::Server(*id).to_string();
+            let host = self.server_host(*id);
+            let mut push = |name, port| {
+                ports.push(NodePort {
+                    node: node.clone(),
+                    host: host.clone(),
+                    name,
+                    port,
+                })
+            };
+            push("tcp", self.tcp_port(*id)?);
+            push("http", self.http_port(*id)?);
+            push("interserver http", self.interserver_http_port(*id)?);
+            if prometheus {
+                push("metrics", self.clickhouse_metrics_port(*id)?);
+            }
+        }
+        Ok(ports)
+    }
+
+    /// Check that the given nodes can listen on all of their ports
+    ///
+    /// Ports must be distinct among nodes on the same host. For local
+    /// backends, the ports of nodes that aren't running must also be
+    /// bindable on `::1`, which is where all nodes listen. Fails with
+    /// `PortConflicts` describing every conflict, or with `PortError` if the
+    /// nodes get no valid ports at all.
+    pub fn check_ports<'a>(
+        &self,
+        keeper_ids: impl IntoIterator<Item = &'a KeeperId>,
+        server_ids: impl IntoIterator<Item = &'a ServerId>,
+    ) -> Result<()> {
+        let keeper_ids: Vec<KeeperId> =
+            keeper_ids.into_iter().copied().collect();
+        let server_ids: Vec<ServerId> =
+            server_ids.into_iter().copied().collect();
+        // Running nodes hold their own ports, which aren't conflicts
+        let running: BTreeSet<String> = keeper_ids
+            .iter()
+            .map(|&id| NodeId::Keeper(id))
+            .chain(server_ids.iter().map(|&id| NodeId::Server(id)))
+            .filter(|&node| self.is_alive(node))
+            .map(|node| node.to_string())
+            .collect();
+        let mut conflicts = vec![];
+        let mut seen: BTreeMap<(String, u16), NodePort> = BTreeMap::new();
+        let local = self.config.backend.is_local();
+        for port in self.node_ports(&keeper_ids, &server_ids)? {
+            let key = (port.host.clone(), port.port);
+            if let Some(other) = seen.get(&key) {
+                conflicts
+                    .push(PortConflict::Node { port, other: other.clone() });
+                continue;
+            }
+            if !local || running.contains(&port.node) {
+                seen.insert(key, port);
+                continue;
+            }
+            if let Err(e) = TcpListener::bind((Ipv6Addr::LOCALHOST, port.port))
+            {
+                conflicts.push(PortConflict::InUse {
+                    port: port.clone(),
+                    error: e.to_string(),
+                });
+            }
+            seen.insert(key, port);
+        }
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(PortConflicts { conflicts }.into())
+        }
+    }
+}
+
+/// Pick free ports for the nodes in `meta` that don't have any yet
+///
+/// Ports are picked by binding to port 0 on `::1` and letting the OS choose,
+/// avoiding ports already picked for other nodes. Nothing stops another
+/// process from taking a port between picking it and starting its node.
+pub(crate) fn pick_free_ports(
+    meta: &mut ClickwardMetadata,
+    writer: &FileWriter,
+) -> Result<()> {
+    let keepers: Vec<KeeperId> = meta
+        .keeper_ids
+        .iter()
+        .filter(|id| !meta.keeper_ports.contains_key(id))
+        .copied()
+        .collect();
+    let servers: Vec<ServerId> = meta
+        .server_ids
+        .iter()
+        .filter(|id| !meta.server_ports.contains_key(id))
+        .copied()
+        .collect();
+    let mut used = BTreeSet::new();
+    for ports in meta.keeper_ports.values() {
+        used.extend([
+            ports.keeper,
+            ports.raft,
+            ports.metrics,
+            ports.http_control,
+        ]);
+    }
+    for ports in meta.server_ports.values() {
+        used.extend([
+            ports.tcp,
+            ports.http,
+            ports.interserver_http,
+            ports.metrics,
+        ]);
+    }
+
+    let mut free =
+        free_ports(4 * (keepers.len() + servers.len()), &used)?.into_iter();
+    let mut next = || free.next().expect("picked a port for each use");
+    for id in keepers {
+        let ports = KeeperPorts {
+            keeper: next(),
+            raft: next(),
+            metrics: next(),
+            http_control: next(),
+        };
+        writer.note(PlanStep::PickPorts(NodeId::Keeper(id)));
+        meta.keeper_ports.insert(id, ports);
+    }
+    for id in servers {
+        let ports = ServerPorts {
+            tcp: next(),
+            http: next(),
+            interserver_http: next(),
+            metrics: next(),
+        };
+        writer.note(PlanStep::PickPorts(NodeId::Server(id)));
+        meta.server_ports.insert(id, ports);
+    }
+    Ok(())
+}
+
+/// Return `count` distinct free ports that aren't in `used`
+fn free_ports(count: usize, used: &BTreeSet<u16>) -> Result<Vec<u16>> {
+    // Hold on to every listener until we're done, so that the OS doesn't
+    // hand out the same port twice
+    let mut listeners = vec![];
+    let mut ports = vec![];
+    for _ in 0..count * FREE_PORT_ATTEMPTS {
+        if ports.len() == count {
+            break;
+        }
+        let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0))
+            .context("failed to bind a free port")?;
+        let port = listener.local_addr()?.port();
+        if !used.contains(&port) {
+            ports.push(port);
+        }
+        listeners.push(listener);
+    }
+    if ports.len() < count {
+        bail!("found only {} of {count} free ports", ports.len());
+    }
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_BASE_PORTS;
+
+    const ALL_BLOCKS: [PortBlock; 8] = [
+        PortBlock::Keeper,
+        PortBlock::Raft,
+        PortBlock::KeeperMetrics,
+        PortBlock::KeeperHttpControl,
+        PortBlock::ClickhouseTcp,
+        PortBlock::ClickhouseHttp,
+        PortBlock::ClickhouseInterserverHttp,
+        PortBlock::ClickhouseMetrics,
+    ];
+
+    #[test]
+    fn default_base_ports_are_valid() {
+        let allocator =
+            PortAllocator::new(&DEFAULT_BASE_PORTS, &ALL_BLOCKS).unwrap();
+        for block in ALL_BLOCKS {
+            let base = block.base(&DEFAULT_BASE_PORTS);
+            assert_eq!(allocator.port(block, 0), Ok(base));
+            assert_eq!(allocator.port(block, 1), Ok(base + 1));
+            assert_eq!(
+                allocator.port(block, MAX_PORT_NODE_ID),
+                Ok(base + MAX_PORT_NODE_ID as u16)
+            );
+        }
+    }
+
+    #[test]
+    fn ids_above_max_have_no_port() {
+        let allocator =
+            PortAllocator::new(&DEFAULT_BASE_PORTS, &ALL_BLOCKS).unwrap();
+        for id in [MAX_PORT_NODE_ID + 1, u64::from(u16::MAX) + 1, u64::MAX] {
+            assert_eq!(
+                allocator.port(PortBlock::Keeper, id),
+                Err(PortError::IdOutOfRange { block: PortBlock::Keeper, id })
+            );
+        }
+    }
+
+    #[test]
+    fn blocks_must_fit_below_max_port() {
+        let highest = u16::MAX - MAX_PORT_NODE_ID as u16;
+        let base =
+            BasePorts { clickhouse_metrics: highest, ..DEFAULT_BASE_PORTS };
+        let allocator = PortAllocator::new(&base, &ALL_BLOCKS).unwrap();
+        assert_eq!(
+            allocator.port(PortBlock::ClickhouseMetrics, MAX_PORT_NODE_ID),
+            Ok(u16::MAX)
+        );
+
+        let base =
+            BasePorts { clickhouse_metrics: highest + 1, ..DEFAULT_BASE_PORTS };
+        assert_eq!(
+            PortAllocator::new(&base, &ALL_BLOCKS).unwrap_err(),
+            PortError::Overflow {
+                block: PortBlock::ClickhouseMetrics,
+                base: highest + 1,
+            }
+        );
+    }
+
+    #[test]
+    fn unvalidated_blocks_fail_rather_than_wrap() {
+        // Metrics ports aren't validated unless prometheus is enabled
+        let base = BasePorts { keeper_metrics: u16::MAX, ..DEFAULT_BASE_PORTS };
+        let allocator =
+            PortAllocator::new(&base, &[PortBlock::Keeper, PortBlock::Raft])
+                .unwrap();
+        assert_eq!(allocator.port(PortBlock::KeeperMetrics, 0), Ok(u16::MAX));
+        assert_eq!(
+            allocator.port(PortBlock::KeeperMetrics, 1),
+            Err(PortError::Overflow {
+                block: PortBlock::KeeperMetrics,
+                base: u16::MAX,
+            })
+        );
+    }
+
+    #[test]
+    fn blocks_must_not_overlap() {
+        let keeper = DEFAULT_BASE_PORTS.keeper;
+        let base = BasePorts {
+            raft: keeper + CLUSTER_PORT_STRIDE - 1,
+            ..DEFAULT_BASE_PORTS
+        };
+        assert_eq!(
+            PortAllocator::new(&base, &ALL_BLOCKS).unwrap_err(),
+            PortError::Overlap {
+                block: PortBlock::Raft,
+                other: PortBlock::Keeper,
+                base: keeper + CLUSTER_PORT_STRIDE - 1,
+                other_base: keeper,
+            }
+        );
+
+        let base = BasePorts {
+            raft: keeper + CLUSTER_PORT_STRIDE,
+            ..DEFAULT_BASE_PORTS
+        };
+        assert!(PortAllocator::new(&base, &ALL_BLOCKS).is_ok());
+    }
+
+    #[test]
+    fn clusters_a_stride_apart_do_not_overlap() {
+        let first =
+            PortAllocator::new(&DEFAULT_BASE_PORTS, &ALL_BLOCKS).unwrap();
+        let offset = DEFAULT_BASE_PORTS.offset(CLUSTER_PORT_STRIDE).unwrap();
+        let second = PortAllocator::new(&offset, &ALL_BLOCKS).unwrap();
+        for block in ALL_BLOCKS {
+            let last = first.port(block, MAX_PORT_NODE_ID).unwrap();
+            assert!(last < second.port(block, 0).unwrap());
+            for other in ALL_BLOCKS.into_iter().filter(|&other| other != block)
+            {
+                let start = second.port(other, 0).unwrap();
+                let end = second.port(other, MAX_PORT_NODE_ID).unwrap();
+                assert!(!(start..=end).contains(&last));
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{LogLevel, ServerLimits};
+use crate::DeploymentConfig;
+use serde::{Deserialize, Serialize};
+
+/// The number of keeper log entries between snapshots, as upstream
+pub const DEFAULT_KEEPER_SNAPSHOT_DISTANCE: u64 = 100_000;
+
+/// A coordinated bundle of resource settings for all nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// As little memory, threads and disk as possible, for clusters of many
+    /// nodes on a laptop
+    Minimal,
+    /// The settings a `DeploymentConfig` is created with
+    Default,
+    /// Generous limits for load testing a few servers
+    Stress,
+}
+
+impl DeploymentConfig {
+    /// Return this config with the settings of `preset`
+    ///
+    /// Only resource settings and log levels are changed.
+    pub fn preset(self, preset: Preset) -> DeploymentConfig {
+        let (log_level, server_limits, keeper_snapshot_distance) = match preset
+        {
+            Preset::Minimal => (
+                LogLevel::Information,
+                ServerLimits {
+                    max_server_memory_usage_to_ram_ratio: 0.02,
+                    max_concurrent_queries: 20,
+                    max_connections: 64,
+                    mark_cache_size: 32 * 1024 * 1024,
+                    uncompressed_cache_size: 0,
+                    // Tables fail to be created if this times the merges
+                    // and mutations concurrency ratio of 2 is less than the
+                    // 20 free entries required to execute mutations
+                    background_pool_size: 10,
+                    background_schedule_pool_size: 16,
+                },
+                Some(10_000),
+            ),
+            Preset::Default => (LogLevel::Trace, ServerLimits::default(), None),
+            Preset::Stress => (
+                LogLevel::Information,
+                ServerLimits {
+                    max_server_memory_usage_to_ram_ratio: 0.25,
+                    max_concurrent_queries: 200,
+                    max_connections: 1024,
+                    mark_cache_size: 1024 * 1024 * 1024,
+                    uncompressed_cache_size: 256 * 1024 * 1024,
+                    background_pool_size: 32,
+                    background_schedule_pool_size: 128,
+                },
+                None,
+            ),
+        };
+        DeploymentConfig {
+            log_level,
+            server_limits,
+            keeper_snapshot_distance,
+            ..self
+        }
+    }
+}
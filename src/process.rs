@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::logs::tail_lines;
+use crate::Deployment;
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::process::Command;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The file in a node's directory that its stdout is appended to
+pub const STDOUT_FILENAME: &str = "stdout.log";
+
+/// The file in a node's directory that its stderr is appended to
+pub const STDERR_FILENAME: &str = "stderr.log";
+
+/// The number of lines of a node's output included in error messages
+const OUTPUT_TAIL_LINES: usize = 20;
+
+/// Returned when the process of a node can't be spawned, i.e. because its
+/// binary is missing
+#[derive(Error, Debug)]
+#[error("failed to start {node}")]
+pub struct SpawnError {
+    /// The name of the node, i.e. `keeper-1`
+    pub node: String,
+}
+
+/// A limit on a resource of a spawned node, as taken by `ulimit`
+///
+/// Limits are parsed when configs are loaded, so that nothing but a number
+/// or `unlimited` ever reaches the shell that applies them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ResourceLimit {
+    Unlimited,
+    Limit(u64),
+}
+
+impl Display for ResourceLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceLimit::Unlimited => write!(f, "unlimited"),
+            ResourceLimit::Limit(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+impl FromStr for ResourceLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unlimited" {
+            return Ok(ResourceLimit::Unlimited);
+        }
+        s.parse().map(ResourceLimit::Limit).map_err(|_| {
+            format!(
+                "invalid resource limit {s:?}: expected a number or unlimited"
+            )
+        })
+    }
+}
+
+impl TryFrom<String> for ResourceLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ResourceLimit> for String {
+    fn from(limit: ResourceLimit) -> String {
+        limit.to_string()
+    }
+}
+
+/// Resource limits applied to spawned nodes via `ulimit`
+///
+/// Limits that are `None` are inherited from clickward.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessLimits {
+    /// Maximum number of open file descriptors (`ulimit -n`)
+    #[serde(default)]
+    pub nofile: Option<ResourceLimit>,
+    /// Maximum size of core files (`ulimit -c`)
+    #[serde(default)]
+    pub core: Option<ResourceLimit>,
+    /// Maximum virtual memory size in KiB (`ulimit -v`)
+    #[serde(default)]
+    pub virtual_memory: Option<ResourceLimit>,
+}
+
+impl ProcessLimits {
+    pub fn is_empty(&self) -> bool {
+        self == &ProcessLimits::default()
+    }
+
+    /// Return a `ProcessLimits` with each limit of `other` taking precedence
+    /// over ours
+    pub fn merge(&self, other: &ProcessLimits) -> ProcessLimits {
+        ProcessLimits {
+            nofile: other.nofile.or(self.nofile),
+            core: other.core.or(self.core),
+            virtual_memory: other.virtual_memory.or(self.virtual_memory),
+        }
+    }
+
+    /// These limits in the form taken by `docker run --ulimit`
+    ///
+    /// Docker takes the address space limit in bytes rather than KiB.
+    pub(crate) fn docker_ulimits(&self) -> Vec<String> {
+        let value = |limit: ResourceLimit, scale: u64| match limit {
+            ResourceLimit::Unlimited => "-1".to_string(),
+            ResourceLimit::Limit(n) => n.saturating_mul(scale).to_string(),
+        };
+        let mut ulimits = vec![];
+        if let Some(limit) = self.nofile {
+            ulimits.push(format!("nofile={}", value(limit, 1)));
+        }
+        if let Some(limit) = self.core {
+            ulimits.push(format!("core={}", value(limit, 1)));
+        }
+        if let Some(limit) = self.virtual_memory {
+            ulimits.push(format!("as={}", value(limit, 1024)));
+        }
+        ulimits
+    }
+
+    /// Shell commands that apply these limits
+    pub(crate) fn ulimit_commands(&self) -> String {
+        let mut s = String::new();
+        for (flag, limit) in [
+            ("-n", self.nofile),
+            ("-c", self.core),
+            ("-v", self.virtual_memory),
+        ] {
+            if let Some(limit) = limit {
+                s.push_str(&format!("ulimit {flag} {limit} && "));
+            }
+        }
+        s
+    }
+}
+
+/// Process settings for a single node that override the deployment-wide
+/// `process_env` and `process_limits`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeProcessConfig {
+    /// Extra environment variables, overriding deployment-wide ones
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub limits: ProcessLimits,
+    /// The OS user that runs the node, via `sudo`, rather than us
+    ///
+    /// The node's directory is given to this user when it starts, so we
+    /// must be able to run `sudo` without a password. Only local backends
+    /// support this.
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+impl Deployment {
+    /// Return the environment and resource limits of the node named `node`,
+    /// taking into account its overrides
+    pub(crate) fn node_process(
+        &self,
+        node: &str,
+    ) -> (BTreeMap<String, String>, ProcessLimits) {
+        let mut env = self.config.process_env.clone();
+        let mut limits = self.config.process_limits.clone();
+        if let Some(overrides) = self.config.node_process.get(node) {
+            env.extend(overrides.env.clone());
+            limits = limits.merge(&overrides.limits);
+        }
+        (env, limits)
+    }
+
+    /// Build the command that runs `binary` for the node named `node`, i.e.
+    /// `keeper-1`, with its environment and resource limits applied
+    ///
+    /// When limits are set, the binary is run via `sh`, which applies them
+    /// and then `exec`s the binary so that it keeps the same pid. Limits are
+    /// ignored on Windows, which has no `ulimit`. A node with a clock skew
+    /// runs under `faketime`, which also `exec`s the binary, as do `taskset`
+    /// and `numactl` for a pinned node. A node with a user runs under
+    /// `sudo`, which resets the environment, so it's passed through `env`.
+    pub(crate) fn node_command(
+        &self,
+        node: &str,
+        binary: &Utf8Path,
+    ) -> Command {
+        let (env, limits) = self.node_process(node);
+        let mut argv = self.pinning_shim(node);
+        argv.extend(self.clock_shim(node).into_iter().flatten());
+        argv.push(binary.to_string());
+        if !limits.is_empty() && !cfg!(windows) {
+            let script = format!("{}exec \"$@\"", limits.ulimit_commands());
+            argv.splice(0..0, ["sh", "-c", &script, "sh"].map(String::from));
+        }
+        if let Some(user) = self.node_user(node) {
+            let mut prefix = ["sudo", "-n", "-u", user, "--", "env"]
+                .map(String::from)
+                .to_vec();
+            prefix.extend(
+                env.iter().map(|(key, value)| format!("{key}={value}")),
+            );
+            argv.splice(0..0, prefix);
+        }
+        let mut cmd = Command::new(&argv[0]);
+        cmd.args(&argv[1..]);
+        cmd.envs(env);
+        cmd
+    }
+}
+
+/// Open the files in a node's directory that its stdout and stderr are
+/// appended to
+pub(crate) fn output_files(dir: &Utf8Path) -> Result<(File, File)> {
+    let open = |name| {
+        let path = dir.join(name);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {path}"))
+    };
+    Ok((open(STDOUT_FILENAME)?, open(STDERR_FILENAME)?))
+}
+
+/// Return the last lines a node wrote to stdout and stderr, formatted to be
+/// appended to an error message
+///
+/// This is empty if the node hasn't written anything.
+pub(crate) fn output_tail(dir: &Utf8Path) -> String {
+    let mut s = String::new();
+    for name in [STDERR_FILENAME, STDOUT_FILENAME] {
+        let path = dir.join(name);
+        let Ok((lines, _)) = tail_lines(&path, OUTPUT_TAIL_LINES) else {
+            continue;
+        };
+        if lines.is_empty() {
+            continue;
+        }
+        s.push_str(&format!("\n\nlast lines of {path}:"));
+        for line in lines {
+            s.push_str(&format!("\n    {line}"));
+        }
+    }
+    s
+}
@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::history::timestamp;
+use crate::Deployment;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::Arc;
+
+/// A step of an operation on a deployment
+///
+/// Nodes are named as in their directories, i.e. `keeper-1`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// The config of a node was written, or rewritten
+    ConfigWritten {
+        node: String,
+    },
+    NodeStarted {
+        node: String,
+    },
+    NodeStopped {
+        node: String,
+    },
+    /// We started waiting for a node to become healthy
+    WaitingForNode {
+        node: String,
+    },
+    NodeHealthy {
+        node: String,
+    },
+    /// We started waiting for the keepers to elect a leader
+    WaitingForQuorum,
+    QuorumReached,
+    /// An operation that changed the deployment finished, and was recorded
+    /// in its history under `operation`
+    OperationFinished {
+        operation: String,
+    },
+}
+
+/// Receives the progress of operations on a `Deployment`
+///
+/// Progress is reported synchronously as operations run, so sinks should be
+/// quick.
+pub trait ProgressSink: Send + Sync {
+    fn report(&self, event: &ProgressEvent);
+}
+
+/// The default `ProgressSink`, which ignores progress
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn report(&self, _event: &ProgressEvent) {}
+}
+
+/// A `ProgressSink` that prints each event to stdout as a line of JSON, i.e.
+/// `{"event":"node_started","node":"keeper-1","timestamp":"..."}`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonProgress;
+
+impl ProgressSink for JsonProgress {
+    fn report(&self, event: &ProgressEvent) {
+        let Ok(serde_json::Value::Object(mut line)) =
+            serde_json::to_value(event)
+        else {
+            return;
+        };
+        line.insert("timestamp".to_string(), timestamp().into());
+        let json = serde_json::Value::Object(line);
+        // Flush each line, so that readers see events as they happen
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{json}");
+        let _ = stdout.flush();
+    }
+}
+
+impl Deployment {
+    /// Report the progress of operations to `progress`
+    pub fn with_progress(
+        mut self,
+        progress: Arc<dyn ProgressSink>,
+    ) -> Deployment {
+        self.progress = progress;
+        self
+    }
+
+    pub(crate) fn report(&self, event: ProgressEvent) {
+        self.progress.report(&event);
+    }
+}
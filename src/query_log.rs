@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, ServerId};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::fmt::Display;
+
+/// Which entries of `system.query_log` to fetch
+#[derive(Debug, Clone)]
+pub struct QueryLogFilter {
+    /// Only fetch queries whose text contains this string
+    pub query_contains: Option<String>,
+    /// Only fetch queries that failed
+    pub errors: bool,
+    /// The maximum number of entries to fetch, newest first
+    pub limit: u64,
+}
+
+impl Default for QueryLogFilter {
+    fn default() -> Self {
+        QueryLogFilter { query_contains: None, errors: false, limit: 20 }
+    }
+}
+
+/// A finished query from `system.query_log`
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryLogEntry {
+    pub event_time: String,
+    pub query_id: String,
+    /// `QueryFinish`, `ExceptionBeforeStart`, or `ExceptionWhileProcessing`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub query_duration_ms: u64,
+    pub read_rows: u64,
+    pub written_rows: u64,
+    pub exception: String,
+    pub query: String,
+}
+
+impl QueryLogEntry {
+    pub const HEADER: &'static str = "TIME                 DURATION     \
+                                      READ  WRITTEN QUERY";
+}
+
+impl Display for QueryLogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let duration = format!("{}ms", self.query_duration_ms);
+        write!(
+            f,
+            "{:<20} {:>8} {:>8} {:>8} {}",
+            self.event_time,
+            duration,
+            self.read_rows,
+            self.written_rows,
+            self.query.replace('\n', " ")
+        )?;
+        if !self.exception.is_empty() {
+            write!(f, "\n    {}", self.exception.replace('\n', " "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Deployment {
+    /// Return the most recent entries of `system.query_log` on a given
+    /// server, newest first
+    ///
+    /// Logs are flushed first, so that queries that just finished are
+    /// included. This requires the deployment to be configured with
+    /// `system_logs`.
+    pub async fn query_log(
+        &self,
+        id: ServerId,
+        filter: &QueryLogFilter,
+    ) -> Result<Vec<QueryLogEntry>> {
+        if !self.config.system_logs {
+            bail!("deployment is not configured with system_logs");
+        }
+        let client = self.http_client(id)?;
+        client.execute("SYSTEM FLUSH LOGS").await?;
+
+        let mut conditions = vec!["type != 'QueryStart'".to_string()];
+        if let Some(s) = &filter.query_contains {
+            let s = s.replace('\\', "\\\\").replace('\'', "\\'");
+            conditions.push(format!("position(query, '{s}') > 0"));
+        }
+        if filter.errors {
+            conditions.push("exception != ''".to_string());
+        }
+        let entries = client
+            .query(&format!(
+                "SELECT toString(event_time) AS event_time, query_id, \
+                    toString(type) AS type, query_duration_ms, read_rows, \
+                    written_rows, exception, query \
+                 FROM system.query_log \
+                 WHERE {} \
+                 ORDER BY event_time_microseconds DESC \
+                 LIMIT {} \
+                 SETTINGS output_format_json_quote_64bit_integers = 0",
+                conditions.join(" AND "),
+                filter.limit
+            ))
+            .await?
+            .rows()?;
+        Ok(entries)
+    }
+}
@@ -0,0 +1,89 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, KeeperClient, KeeperId, NodeId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long we wait for a keeper to pick up the raft configuration of its
+/// regenerated config
+const KEEPER_RELOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often we check whether a keeper picked up its config
+const KEEPER_RELOAD_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+impl Deployment {
+    /// Make a running node apply its current config file, and fail if it
+    /// doesn't
+    ///
+    /// Servers run `SYSTEM RELOAD CONFIG`, which fails if the config is
+    /// invalid. Keepers re-read their config on their own whenever it
+    /// changes, so for them we wait until the raft configuration they report
+    /// matches our keepers. Settings that only apply on startup, like ports,
+    /// still need a restart.
+    pub async fn reload_config(&self, node: NodeId) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        match node {
+            NodeId::Keeper(id) => {
+                if !meta.keeper_ids.contains(&id) {
+                    bail!("No such keeper: {id}");
+                }
+                self.wait_for_keeper_config(id, &meta.keeper_ids).await?;
+            }
+            NodeId::Server(id) => {
+                if !meta.server_ids.contains(&id) {
+                    bail!("No such replica: {id}");
+                }
+                info!(%id, "Reloading server config");
+                self.http_client(id)?
+                    .execute("SYSTEM RELOAD CONFIG")
+                    .await
+                    .with_context(|| format!("failed to reload {node}"))?;
+            }
+        }
+        self.record_node("reload", node)
+    }
+
+    /// Reload the config of every keeper and server
+    pub async fn reload_configs(&self) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let keepers = meta.keeper_ids.iter().map(|&id| NodeId::Keeper(id));
+        let servers = meta.server_ids.iter().map(|&id| NodeId::Server(id));
+        for node in keepers.chain(servers) {
+            self.reload_config(node).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait until keeper `id` reports exactly `keeper_ids` as its raft
+    /// configuration
+    async fn wait_for_keeper_config(
+        &self,
+        id: KeeperId,
+        keeper_ids: &BTreeSet<KeeperId>,
+    ) -> Result<()> {
+        let client = KeeperClient::new(self.keeper_addr(id)?);
+        let deadline = Instant::now() + KEEPER_RELOAD_TIMEOUT;
+        loop {
+            let members: BTreeSet<KeeperId> =
+                client.config().await?.into_keys().collect();
+            if &members == keeper_ids {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "keeper {id} has members {members:?} rather than \
+                     {keeper_ids:?} in its raft configuration"
+                );
+            }
+            self.pause(KEEPER_RELOAD_POLL_INTERVAL).await?;
+        }
+    }
+}
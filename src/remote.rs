@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::process::{STDERR_FILENAME, STDOUT_FILENAME};
+use crate::{Backend, Deployment, PlanStep};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::process::{Command, Stdio};
+use tracing::info;
+
+/// The file in a node's local directory recording the host it was started
+/// on, which exists only while the node is started
+pub const REMOTE_HOST_FILENAME: &str = "remote.host";
+
+/// The config files of a node that are copied to its host
+const CONFIG_FILES: &[&str] =
+    &["clickhouse-config.xml", "keeper-config.xml", "config.d"];
+
+impl Deployment {
+    /// Return the host of the node named `node` with id `id`, if the
+    /// deployment uses the ssh backend
+    ///
+    /// Nodes without an explicit host are assigned round robin by id.
+    pub(crate) fn remote_host(&self, node: &str, id: u64) -> Option<String> {
+        let Backend::Ssh { hosts, node_hosts } = &self.config.backend else {
+            return None;
+        };
+        if let Some(host) = node_hosts.get(node) {
+            return Some(host.clone());
+        }
+        if hosts.is_empty() {
+            return None;
+        }
+        let i = (id.saturating_sub(1) % hosts.len() as u64) as usize;
+        Some(hosts[i].clone())
+    }
+
+    /// Copy the config files of the node in `dir` to the same path on `host`
+    pub(crate) fn push_configs(
+        &self,
+        host: &str,
+        dir: &Utf8Path,
+    ) -> Result<()> {
+        let step =
+            PlanStep::Push { host: host.to_string(), dir: dir.to_path_buf() };
+        if self.writer.skip(step) {
+            return Ok(());
+        }
+        let remote_dir = remote_path(dir)?;
+        self.ssh(
+            host,
+            &format!("mkdir -p {}", quote(remote_dir.join("logs"))),
+        )?;
+        let mut cmd = Command::new("scp");
+        cmd.arg("-q").arg("-r");
+        for file in CONFIG_FILES {
+            let path = dir.join(file);
+            if path.exists() {
+                cmd.arg(path);
+            }
+        }
+        let output = self
+            .output(
+                cmd.arg(format!("{host}:{remote_dir}/")).stdin(Stdio::null()),
+            )
+            .context("Failed to run scp")?;
+        if !output.status.success() {
+            bail!(
+                "failed to copy configs to {host}: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        Ok(())
+    }
+
+    /// Start the node in `dir` on `host` as `<binary> <args..>` and record
+    /// where it runs
+    ///
+    /// The node is started from the same working directory as ours, so that
+    /// relative paths in generated configs resolve the same way on `host`.
+    pub(crate) fn start_remote(
+        &self,
+        node: &str,
+        host: &str,
+        dir: &Utf8Path,
+        binary: &Utf8Path,
+        args: &[&str],
+    ) -> Result<()> {
+        info!(%node, %host, %dir, "Deploying remote node");
+        let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)?;
+        let remote_dir = remote_path(dir)?;
+        let (env, limits) = self.node_process(node);
+
+        let mut script = format!("(cd {} && ", quote(&cwd));
+        script.push_str(&limits.ulimit_commands());
+        script.push_str("exec nohup env");
+        for (key, value) in env {
+            script.push_str(&format!(" {}", quote(format!("{key}={value}"))));
+        }
+        for arg in self.pinning_shim(node) {
+            script.push_str(&format!(" {}", quote(arg)));
+        }
+        for arg in self.clock_shim(node).into_iter().flatten() {
+            script.push_str(&format!(" {}", quote(arg)));
+        }
+        script.push_str(&format!(" {}", quote(binary)));
+        for arg in args {
+            script.push_str(&format!(" {}", quote(arg)));
+        }
+        script.push_str(&format!(
+            ") </dev/null >>{} 2>>{} &",
+            quote(remote_dir.join(STDOUT_FILENAME)),
+            quote(remote_dir.join(STDERR_FILENAME)),
+        ));
+        self.ssh(host, &script)?;
+
+        std::fs::write(dir.join(REMOTE_HOST_FILENAME), host)?;
+        Ok(())
+    }
+
+    /// Kill the node in `dir`, along with any children, using its pidfile on
+    /// the host it was started on
+    pub(crate) fn stop_remote(
+        &self,
+        dir: &Utf8Path,
+        pidfile: &str,
+    ) -> Result<()> {
+        let marker = dir.join(REMOTE_HOST_FILENAME);
+        let host = std::fs::read_to_string(&marker)
+            .with_context(|| format!("failed to read {marker}"))?;
+        let host = host.trim_end();
+        info!(%host, %dir, "Stopping remote node");
+        let pidfile = quote(remote_path(dir)?.join(pidfile));
+        self.ssh(
+            host,
+            &format!(
+                "pid=$(cat {pidfile}) && \
+                 {{ pkill -9 -P $pid; kill -9 $pid; rm -f {pidfile}; }}"
+            ),
+        )?;
+        std::fs::remove_file(&marker)?;
+        Ok(())
+    }
+
+    /// Run a shell script on `host`
+    fn ssh(&self, host: &str, script: &str) -> Result<()> {
+        let output = self
+            .output(
+                Command::new("ssh")
+                    .arg("-o")
+                    .arg("BatchMode=yes")
+                    .arg(host)
+                    .arg(script)
+                    .stdin(Stdio::null()),
+            )
+            .with_context(|| format!("Failed to run ssh to {host}"))?;
+        if !output.status.success() {
+            bail!(
+                "command on {host} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Return the absolute path of a local node directory, which is also its path
+/// on remote hosts
+pub(crate) fn remote_path(dir: &Utf8Path) -> Result<Utf8PathBuf> {
+    if dir.is_absolute() {
+        return Ok(dir.to_path_buf());
+    }
+    let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)?;
+    Ok(cwd.join(dir))
+}
+
+/// Quote `s` for use as a single word in a shell script
+pub(crate) fn quote(s: impl AsRef<str>) -> String {
+    format!("'{}'", s.as_ref().replace('\'', r"'\''"))
+}
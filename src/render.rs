@@ -0,0 +1,65 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, DICTIONARIES_DIR, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeMap;
+
+impl Deployment {
+    /// Render every config file of the deployment from our metadata without
+    /// touching the filesystem
+    ///
+    /// Files are keyed by their path relative to the deployment directory,
+    /// i.e. `keeper-1/keeper-config.xml`. Extra configs copied into
+    /// `config.d` are not included. The contents refer to log, data and
+    /// storage directories by their absolute paths under the deployment
+    /// directory, as the nodes need them to. The output only depends on the
+    /// config and metadata, so for golden file tests generate the deployment
+    /// at a fixed path, or replace it in the output, and set
+    /// `cluster_secret` and `zookeeper_root`, which are otherwise random.
+    pub fn render_configs(&self) -> Result<BTreeMap<Utf8PathBuf, String>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut files = BTreeMap::new();
+        for &id in &meta.keeper_ids {
+            let config = self.keeper_config(id, &meta.keeper_ids)?;
+            files.insert(
+                Utf8PathBuf::from(format!("keeper-{id}/keeper-config.xml")),
+                self.keeper_config_xml(id, &config)?,
+            );
+        }
+        for (id, config) in
+            self.clickhouse_configs(&meta.keeper_ids, &meta.server_ids)?
+        {
+            let dir = Utf8PathBuf::from(format!("clickhouse-{id}"));
+            files.insert(
+                dir.join("clickhouse-config.xml"),
+                self.server_config_xml(id, &config)?,
+            );
+            for dictionary in &self.config.dictionaries {
+                files.insert(
+                    dir.join(DICTIONARIES_DIR)
+                        .join(format!("{}.xml", dictionary.name)),
+                    dictionary.to_xml(&config.listen_host, config.tcp_port),
+                );
+            }
+        }
+        Ok(files)
+    }
+
+    /// Write the output of `render_configs` under `dir`
+    pub fn write_rendered_configs(&self, dir: &Utf8Path) -> Result<()> {
+        for (path, contents) in self.render_configs()? {
+            let path = dir.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, contents)
+                .with_context(|| format!("failed to write {path}"))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, ServerId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::info;
+
+/// The state of a single replicated table on a single server
+#[derive(Debug, Clone)]
+pub struct ReplicaStatus {
+    pub server: ServerId,
+    pub database: String,
+    pub table: String,
+    /// The replica can't accept writes, usually because it lost its keeper
+    /// session
+    pub is_readonly: bool,
+    pub is_session_expired: bool,
+    /// Seconds since the oldest entry in the queue was created, or 0 if the
+    /// replica is up to date
+    pub absolute_delay: u64,
+    /// Entries in the replication queue
+    pub queue_size: u64,
+    pub inserts_in_queue: u64,
+    pub merges_in_queue: u64,
+    /// Entries of the shared replication log not yet copied to the queue
+    pub log_lag: u64,
+    /// Entries in the queue whose last attempt failed
+    pub failed_in_queue: u64,
+    pub active_replicas: u64,
+    pub total_replicas: u64,
+}
+
+/// The replication state of every replicated table across a deployment
+#[derive(Debug, Clone, Default)]
+pub struct ReplicationStatus {
+    pub replicas: Vec<ReplicaStatus>,
+    /// Servers that could not be queried
+    pub unreachable: Vec<ServerId>,
+}
+
+impl ReplicationStatus {
+    /// Return true if every server is reachable and every replica is
+    /// writable with nothing left to replicate
+    pub fn is_caught_up(&self) -> bool {
+        self.unreachable.is_empty()
+            && self
+                .replicas
+                .iter()
+                .all(|r| !r.is_readonly && r.queue_size == 0 && r.log_lag == 0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplicaRow {
+    database: String,
+    table: String,
+    is_readonly: bool,
+    is_session_expired: bool,
+    absolute_delay: u64,
+    queue_size: u64,
+    inserts_in_queue: u64,
+    merges_in_queue: u64,
+    log_max_index: u64,
+    log_pointer: u64,
+    active_replicas: u64,
+    total_replicas: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueRow {
+    database: String,
+    table: String,
+    failed: u64,
+}
+
+impl Deployment {
+    /// Query `system.replicas` and `system.replication_queue` on every
+    /// server
+    pub async fn replication_status(&self) -> Result<ReplicationStatus> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut status = ReplicationStatus::default();
+        for id in &meta.server_ids {
+            match self.server_replication_status(*id).await {
+                Ok(replicas) => status.replicas.extend(replicas),
+                Err(_) => status.unreachable.push(*id),
+            }
+        }
+        Ok(status)
+    }
+
+    async fn server_replication_status(
+        &self,
+        id: ServerId,
+    ) -> Result<Vec<ReplicaStatus>> {
+        let client = self.http_client(id)?;
+        let replicas: Vec<ReplicaRow> = client
+            .query(
+                "SELECT database, table, toBool(is_readonly) AS is_readonly, \
+                    toBool(is_session_expired) AS is_session_expired, \
+                    absolute_delay, queue_size, inserts_in_queue, \
+                    merges_in_queue, log_max_index, log_pointer, \
+                    active_replicas, total_replicas \
+                 FROM system.replicas \
+                 ORDER BY database, table \
+                 SETTINGS output_format_json_quote_64bit_integers = 0",
+            )
+            .await?
+            .rows()?;
+        let failed: BTreeMap<(String, String), u64> = client
+            .query(
+                "SELECT database, table, \
+                    countIf(last_exception != '') AS failed \
+                 FROM system.replication_queue \
+                 GROUP BY database, table \
+                 SETTINGS output_format_json_quote_64bit_integers = 0",
+            )
+            .await?
+            .rows::<QueueRow>()?
+            .into_iter()
+            .map(|row| ((row.database, row.table), row.failed))
+            .collect();
+
+        let replicas = replicas
+            .into_iter()
+            .map(|r| {
+                let key = (r.database.clone(), r.table.clone());
+                ReplicaStatus {
+                    server: id,
+                    failed_in_queue: failed.get(&key).copied().unwrap_or(0),
+                    database: r.database,
+                    table: r.table,
+                    is_readonly: r.is_readonly,
+                    is_session_expired: r.is_session_expired,
+                    absolute_delay: r.absolute_delay,
+                    queue_size: r.queue_size,
+                    inserts_in_queue: r.inserts_in_queue,
+                    merges_in_queue: r.merges_in_queue,
+                    // The pointer can briefly run ahead of the index we
+                    // read, as the two aren't read atomically
+                    log_lag: r.log_max_index.saturating_sub(r.log_pointer),
+                    active_replicas: r.active_replicas,
+                    total_replicas: r.total_replicas,
+                }
+            })
+            .collect();
+        Ok(replicas)
+    }
+
+    /// Fail unless every replicated table on server `id` has another
+    /// replica in the same shard that is writable and caught up, so that
+    /// removing `id` loses no data
+    ///
+    /// Parts inserted on `id` show up in the queue or log of the other
+    /// replicas until they've fetched them, so an empty queue and log
+    /// means they have everything `id` has.
+    pub async fn check_server_removal(&self, id: ServerId) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !meta.server_ids.contains(&id) {
+            bail!("No such replica: {id}");
+        }
+        let tables =
+            self.server_replication_status(id).await.with_context(|| {
+                format!(
+                    "failed to query replicas on server {id}, so we can't \
+                     tell whether removing it loses data"
+                )
+            })?;
+        if tables.is_empty() {
+            return Ok(());
+        }
+
+        let shard = self.server_shard(id);
+        let mut caught_up = BTreeSet::new();
+        for &other in &meta.server_ids {
+            if other == id || self.server_shard(other) != shard {
+                continue;
+            }
+            let Ok(replicas) = self.server_replication_status(other).await
+            else {
+                continue;
+            };
+            caught_up.extend(
+                replicas
+                    .into_iter()
+                    .filter(|r| {
+                        !r.is_readonly && r.queue_size == 0 && r.log_lag == 0
+                    })
+                    .map(|r| (r.database, r.table)),
+            );
+        }
+
+        let missing: Vec<String> = tables
+            .into_iter()
+            .filter(|r| {
+                !caught_up.contains(&(r.database.clone(), r.table.clone()))
+            })
+            .map(|r| format!("{}.{}", r.database, r.table))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "removing server {id} may lose data: no other replica of {} \
+                 is caught up",
+                missing.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Remove the replica metadata that removed server `id` left in keeper,
+    /// by running `SYSTEM DROP REPLICA` on the remaining servers of its shard
+    ///
+    /// Without this, the replica stays registered under the keeper path of
+    /// each of its replicated tables, which breaks a new replica that reuses
+    /// its name. The server must already be removed with `remove_server`,
+    /// since clickhouse refuses to drop an active replica. The replica of the
+    /// `replicated_database`, if any, is dropped too.
+    pub async fn drop_server_replica(&self, id: ServerId) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if meta.server_ids.contains(&id) {
+            bail!("server {id} must be removed before dropping its replica");
+        }
+        let shard = self.server_shard(id);
+        let replica = self
+            .server_macros(id)
+            .get("replica")
+            .cloned()
+            .unwrap_or_else(|| id.to_string());
+        let statements = drop_replica_statements(
+            &replica,
+            shard,
+            self.config.replicated_database.as_deref(),
+        );
+
+        let mut dropped = false;
+        for &other in &meta.server_ids {
+            if self.server_shard(other) != shard {
+                continue;
+            }
+            info!(%id, %other, "Dropping replica of removed server");
+            let client = self.http_client(other)?;
+            for sql in &statements {
+                client.execute(sql).await.with_context(|| {
+                    format!("failed to drop replica {replica} on {other}")
+                })?;
+            }
+            dropped = true;
+        }
+        if !dropped {
+            bail!("no remaining server in shard {shard} to drop {id} from");
+        }
+        self.record("drop-replica", serde_json::json!({ "id": id }))
+    }
+}
+
+/// Return the statements that drop `replica` of `shard` from the tables of
+/// a server, and from `replicated_database` if there is one
+///
+/// Both names come from our config, so they are quoted rather than trusted.
+fn drop_replica_statements(
+    replica: &str,
+    shard: u64,
+    replicated_database: Option<&str>,
+) -> Vec<String> {
+    let replica = replica.replace('\\', "\\\\").replace('\'', "\\'");
+    let mut statements = vec![format!("SYSTEM DROP REPLICA '{replica}'")];
+    if let Some(database) = replicated_database {
+        let database = database.replace('\\', "\\\\").replace('`', "\\`");
+        statements.push(format!(
+            "SYSTEM DROP DATABASE REPLICA '{replica}' FROM SHARD '{shard}' \
+             FROM DATABASE `{database}`"
+        ));
+    }
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_replica_statements_quote_names() {
+        assert_eq!(
+            drop_replica_statements("r'1\\", 2, Some("db`x")),
+            [
+                r"SYSTEM DROP REPLICA 'r\'1\\'",
+                r"SYSTEM DROP DATABASE REPLICA 'r\'1\\' FROM SHARD '2' FROM DATABASE `db\`x`",
+            ]
+        );
+        assert_eq!(
+            drop_replica_statements("3", 1, None),
+            ["SYSTEM DROP REPLICA '3'"]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, KeeperId, OperationContext, ServerId, MISSING_META};
+use anyhow::{bail, Result};
+use std::time::Duration;
+use tracing::info;
+
+impl Deployment {
+    /// Add or remove nodes until there are `servers` servers and `keepers`
+    /// keepers, where `None` leaves that kind of node as it is
+    ///
+    /// Nodes are changed one at a time, waiting up to `timeout` after each
+    /// change for the new node to become healthy and for the keepers to
+    /// have quorum. Keepers are added before servers change and removed
+    /// after, so that servers always have keepers to talk to. The nodes with
+    /// the highest ids are removed first. `context` bounds the whole of it,
+    /// and ends the waits early if it says to stop.
+    pub async fn scale_to(
+        &mut self,
+        servers: Option<usize>,
+        keepers: Option<usize>,
+        timeout: Duration,
+        context: &OperationContext,
+    ) -> Result<()> {
+        let _op = self.begin_operation(context);
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let current_servers = meta.server_ids.len();
+        let current_keepers = meta.keeper_ids.len();
+        let servers = servers.unwrap_or(current_servers);
+        let keepers = keepers.unwrap_or(current_keepers);
+        self.validate_membership(keepers, servers)?;
+        info!(
+            current_keepers,
+            current_servers, keepers, servers, "Scaling deployment"
+        );
+
+        // Ids are never reused, so added nodes get ids past the largest ones
+        let new_keepers: Vec<_> = (1..)
+            .map(|i| KeeperId(meta.max_keeper_id.0 + i))
+            .take(keepers.saturating_sub(current_keepers))
+            .collect();
+        let new_servers: Vec<_> = (1..)
+            .map(|i| ServerId(meta.max_server_id.0 + i))
+            .take(servers.saturating_sub(current_servers))
+            .collect();
+        let old_keepers: Vec<_> = meta
+            .keeper_ids
+            .iter()
+            .rev()
+            .take(current_keepers.saturating_sub(keepers))
+            .copied()
+            .collect();
+        let old_servers: Vec<_> = meta
+            .server_ids
+            .iter()
+            .rev()
+            .take(current_servers.saturating_sub(servers))
+            .copied()
+            .collect();
+
+        for id in new_keepers {
+            self.add_keeper(context)?;
+            self.wait_for_keeper(id, timeout).await?;
+            self.wait_for_keeper_quorum(timeout).await?;
+        }
+        for id in old_servers {
+            self.remove_server(id, context)?;
+        }
+        for id in new_servers {
+            self.add_server(context)?;
+            self.wait_for_server(id, timeout).await?;
+        }
+        for id in old_keepers {
+            self.remove_keeper_safely(id, false, context).await?;
+            self.wait_for_keeper_quorum(timeout).await?;
+        }
+
+        self.record(
+            "scale",
+            serde_json::json!({ "servers": servers, "keepers": keepers }),
+        )
+    }
+}
@@ -0,0 +1,141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::info;
+
+#[derive(Debug, Deserialize)]
+struct ReplicaRow {
+    database: String,
+    table: String,
+}
+
+impl Deployment {
+    /// Apply the schema in the SQL file at `path` across the cluster
+    ///
+    /// Each statement is run `ON CLUSTER` and waited on for up to `timeout`,
+    /// after which every server syncs its replicated tables. The hash of the
+    /// schema is recorded in our metadata, and a schema that was already
+    /// applied is skipped, in which case we return `false`. A changed schema
+    /// is applied in full, so its statements should be idempotent, i.e. use
+    /// `IF NOT EXISTS`.
+    pub async fn seed(
+        &mut self,
+        path: &Utf8Path,
+        timeout: Duration,
+    ) -> Result<bool> {
+        let schema = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {path}"))?;
+        let hash = format!("{:016x}", fnv1a(schema.as_bytes()));
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if meta.seed_hash.as_deref() == Some(hash.as_str()) {
+            info!(%path, "Schema was already applied");
+            return Ok(false);
+        }
+
+        for statement in split_statements(&schema) {
+            info!(%statement, "Applying schema statement");
+            self.ddl_on_cluster(&statement, timeout).await?;
+        }
+        self.sync_replicas().await?;
+
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.seed_hash = Some(hash.clone());
+        self.writer.save_meta(meta, &self.config.path)?;
+        self.record("seed", serde_json::json!({ "path": path, "hash": hash }))?;
+        Ok(true)
+    }
+
+    /// Wait for every replicated table on every server to catch up with its
+    /// replication queue
+    async fn sync_replicas(&self) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        for id in &meta.server_ids {
+            let client = self.http_client(*id)?;
+            let replicas: Vec<ReplicaRow> = client
+                .query("SELECT database, table FROM system.replicas")
+                .await?
+                .rows()?;
+            for ReplicaRow { database, table } in replicas {
+                client
+                    .execute(&format!(
+                        "SYSTEM SYNC REPLICA `{database}`.`{table}`"
+                    ))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split a SQL script into its statements, dropping comments
+///
+/// Semicolons inside quoted strings and identifiers don't end a statement.
+fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut current = String::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => {
+                current.push(c);
+                while let Some(q) = chars.next() {
+                    current.push(q);
+                    if q == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            current.push(escaped);
+                        }
+                    } else if q == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        current.push('\n');
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+                current.push(' ');
+            }
+            ';' => statements.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    statements.push(current);
+    statements
+        .into_iter()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// The 64-bit FNV-1a hash of `bytes`, which is stable across builds unlike
+/// the hashers in `std`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
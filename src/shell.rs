@@ -0,0 +1,63 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, KeeperClient, KeeperId, ServerId, MISSING_META};
+use anyhow::{bail, Result};
+use std::process::Command;
+
+impl Deployment {
+    /// Run `commands` of `clickhouse keeper-client` against keeper `id`, i.e.
+    /// `ls /clickward` or `rmr /clickward/old`, and return their output
+    pub async fn keeper_client_run(
+        &self,
+        id: KeeperId,
+        commands: &[&str],
+    ) -> Result<String> {
+        let client = self.keeper_shell_client(id)?;
+        Ok(client.run(commands).await?)
+    }
+
+    /// Return the command that runs an interactive `clickhouse
+    /// keeper-client` connected to keeper `id`
+    pub fn keeper_shell(&self, id: KeeperId) -> Result<Command> {
+        Ok(self.keeper_shell_client(id)?.shell())
+    }
+
+    /// Return the command that runs an interactive `clickhouse client`
+    /// connected to server `id`, using `database` if given
+    ///
+    /// The client is the binary that the server runs, so that it speaks the
+    /// same protocol version.
+    pub fn server_shell(
+        &self,
+        id: ServerId,
+        database: Option<&str>,
+    ) -> Result<Command> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !meta.server_ids.contains(&id) {
+            bail!("No such replica: {id}");
+        }
+        let mut cmd = Command::new(self.server_binary(id));
+        cmd.arg("client")
+            .arg("--host")
+            .arg(self.server_host(id))
+            .arg("--port")
+            .arg(self.tcp_port(id)?.to_string());
+        if let Some(database) = database {
+            cmd.arg("--database").arg(database);
+        }
+        Ok(cmd)
+    }
+
+    fn keeper_shell_client(&self, id: KeeperId) -> Result<KeeperClient> {
+        if let Some(meta) = &self.meta {
+            if !meta.keeper_ids.contains(&id) {
+                bail!("No such keeper: {id}");
+            }
+        }
+        Ok(KeeperClient::new(self.keeper_addr(id)?))
+    }
+}
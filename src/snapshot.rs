@@ -0,0 +1,297 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::clone::copy_tree;
+use crate::{
+    ClickwardMetadata, Deployment, DeploymentConfig, KeeperId, NodeId,
+    ServerId, CLICKWARD_CONFIG_FILENAME, CLICKWARD_META_FILENAME, MISSING_META,
+};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::info;
+
+/// Node data lives on remote hosts, where we can't copy it
+const REMOTE_SNAPSHOTS: &str =
+    "Snapshots are not supported for deployments on remote hosts";
+
+/// The directory below the deployment directory where snapshots live
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+impl Deployment {
+    /// Return the directory where the snapshot called `name` lives
+    pub fn snapshot_dir(&self, name: &str) -> Utf8PathBuf {
+        self.config.path.join(SNAPSHOTS_DIR).join(name)
+    }
+
+    /// Return the names of all snapshots of this deployment
+    pub fn snapshots(&self) -> Result<Vec<String>> {
+        let dir = self.config.path.join(SNAPSHOTS_DIR);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in dir.read_dir_utf8()? {
+            names.push(entry?.file_name().to_string());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Copy the configs, data, and keeper coordination state of every node,
+    /// along with our metadata, into `deployment/snapshots/<name>`
+    ///
+    /// Running nodes are stopped while the copy is taken and started again
+    /// afterwards. Logs are not part of a snapshot.
+    pub fn snapshot(&self, name: &str) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !self.config.backend.is_local() {
+            bail!(REMOTE_SNAPSHOTS);
+        }
+        check_snapshot_name(name)?;
+        let dest = self.snapshot_dir(name);
+        if dest.exists() {
+            bail!("snapshot {name} already exists");
+        }
+
+        let (keepers, servers) = self.running_nodes(meta);
+        self.stop_nodes(&keepers, &servers)?;
+
+        info!(%name, %dest, "Taking snapshot");
+        std::fs::create_dir_all(&dest)?;
+        for id in &meta.keeper_ids {
+            let dir = self.keeper_dir(*id);
+            copy_node_dir(&dir, &dest.join(dir.file_name().unwrap()))?;
+        }
+        for id in &meta.server_ids {
+            let dir = self.server_dir(*id);
+            copy_node_dir(&dir, &dest.join(dir.file_name().unwrap()))?;
+        }
+        for file in [CLICKWARD_META_FILENAME, CLICKWARD_CONFIG_FILENAME] {
+            let src = self.config.path.join(file);
+            if src.exists() {
+                std::fs::copy(&src, dest.join(file))
+                    .with_context(|| format!("failed to copy {src}"))?;
+            }
+        }
+
+        self.start_nodes(&keepers, &servers)?;
+        self.record("snapshot", serde_json::json!({ "name": name }))
+    }
+
+    /// Reset the deployment to the state captured in snapshot `name`
+    ///
+    /// All nodes are stopped, and the nodes that were running before the
+    /// restore are started again afterwards, as long as they are part of
+    /// the snapshot.
+    pub fn restore(&mut self, name: &str) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if !self.config.backend.is_local() {
+            bail!(REMOTE_SNAPSHOTS);
+        }
+        check_snapshot_name(name)?;
+        let src = self.snapshot_dir(name);
+        if !src.exists() {
+            bail!("No such snapshot: {name}");
+        }
+
+        let (keepers, servers) = self.running_nodes(meta);
+        self.stop_nodes(&keepers, &servers)?;
+
+        info!(%name, %src, "Restoring snapshot");
+        for entry in src.read_dir_utf8()? {
+            let entry = entry?;
+            let dest = self.config.path.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                // Keep the logs of the live node around for debugging
+                if dest.exists() {
+                    remove_node_state(&dest)?;
+                }
+                copy_node_dir(entry.path(), &dest)?;
+            } else {
+                std::fs::copy(entry.path(), &dest).with_context(|| {
+                    format!("failed to copy {}", entry.path())
+                })?;
+            }
+        }
+
+        let meta = ClickwardMetadata::load(&self.config.path)?;
+        if self.config.path.join(CLICKWARD_CONFIG_FILENAME).exists() {
+            let root = self.config.path.parent().unwrap_or(&self.config.path);
+            self.config = DeploymentConfig::load(root.to_path_buf())?;
+        }
+        let keepers: Vec<_> = keepers
+            .into_iter()
+            .filter(|id| meta.keeper_ids.contains(id))
+            .collect();
+        let servers: Vec<_> = servers
+            .into_iter()
+            .filter(|id| meta.server_ids.contains(id))
+            .collect();
+        self.meta = Some(meta);
+
+        self.start_nodes(&keepers, &servers)?;
+        self.record("restore", serde_json::json!({ "name": name }))
+    }
+
+    /// Return the ids of all nodes that are running
+    fn running_nodes(
+        &self,
+        meta: &ClickwardMetadata,
+    ) -> (Vec<KeeperId>, Vec<ServerId>) {
+        let keepers = meta
+            .keeper_ids
+            .iter()
+            .filter(|id| self.is_alive(NodeId::Keeper(**id)))
+            .copied()
+            .collect();
+        let servers = meta
+            .server_ids
+            .iter()
+            .filter(|id| self.is_alive(NodeId::Server(**id)))
+            .copied()
+            .collect();
+        (keepers, servers)
+    }
+
+    fn stop_nodes(
+        &self,
+        keepers: &[KeeperId],
+        servers: &[ServerId],
+    ) -> Result<()> {
+        for id in servers {
+            self.stop_server(*id)?;
+        }
+        for id in keepers {
+            self.stop_keeper(*id)?;
+        }
+        Ok(())
+    }
+
+    fn start_nodes(
+        &self,
+        keepers: &[KeeperId],
+        servers: &[ServerId],
+    ) -> Result<()> {
+        for id in keepers {
+            self.start_keeper(*id)?;
+        }
+        for id in servers {
+            self.start_server(*id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fail unless `name` can name a directory inside our snapshots directory
+fn check_snapshot_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+    {
+        bail!("invalid snapshot name: {name}");
+    }
+    Ok(())
+}
+
+/// Recursively copy a node directory, skipping its logs, pidfile, container
+/// id and remote host
+fn copy_node_dir(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    copy_tree(src, dest, &|path| {
+        path.file_name() == Some("logs")
+            || path.extension() == Some("pid")
+            || path.extension() == Some("cid")
+            || path.extension() == Some("host")
+    })
+}
+
+/// Remove everything but the logs from a node directory
+fn remove_node_state(dir: &Utf8Path) -> Result<()> {
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_name() == "logs" {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_secret, platform};
+    use std::collections::BTreeSet;
+
+    /// A temporary directory, removed when dropped
+    struct TempDir(Utf8PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn snapshot_and_restore_directory_symlinks() {
+        let root = TempDir(
+            Utf8PathBuf::try_from(std::env::temp_dir()).unwrap().join(format!(
+                "clickward-test-{}-{}",
+                std::process::id(),
+                &generate_secret()[..8]
+            )),
+        );
+        let mut d =
+            Deployment::new_with_default_port_config(root.0.clone(), "test");
+        let mut meta = ClickwardMetadata::new(
+            BTreeSet::new(),
+            BTreeSet::from([ServerId(1)]),
+        );
+        std::fs::create_dir_all(d.server_dir(ServerId(1))).unwrap();
+        meta.save(&d.config.path).unwrap();
+        d.meta = Some(meta);
+
+        // Atomic databases link their table directories into `store`, with
+        // canonical absolute paths
+        let dir = d.server_dir(ServerId(1)).canonicalize_utf8().unwrap();
+        let store = dir.join("store/abc");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join("data.bin"), "data").unwrap();
+        std::fs::create_dir_all(dir.join("data/db")).unwrap();
+        let table = dir.join("data/db/table");
+        platform::symlink(&store, &table).unwrap();
+        std::fs::create_dir_all(dir.join("logs")).unwrap();
+        std::fs::write(dir.join("logs/clickhouse.log"), "log").unwrap();
+
+        d.snapshot("before").unwrap();
+
+        let snapshot = d.snapshot_dir("before").join("clickhouse-1");
+        let link = snapshot.join("data/db/table").read_link_utf8().unwrap();
+        assert!(link.starts_with(snapshot.canonicalize_utf8().unwrap()));
+        assert_eq!(
+            std::fs::read_to_string(link.join("data.bin")).unwrap(),
+            "data"
+        );
+        assert!(!snapshot.join("logs").exists());
+
+        std::fs::write(store.join("data.bin"), "changed").unwrap();
+        d.restore("before").unwrap();
+
+        let link = dir.join("data/db/table").read_link_utf8().unwrap();
+        assert_eq!(link, store);
+        assert_eq!(
+            std::fs::read_to_string(table.join("data.bin")).unwrap(),
+            "data"
+        );
+        assert!(dir.join("logs/clickhouse.log").exists());
+    }
+}
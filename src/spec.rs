@@ -0,0 +1,246 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{LogLevel, UserConfig};
+use crate::{
+    BasePorts, Deployment, DeploymentConfig, InvalidConfig, MISSING_META,
+};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+
+/// A deployment described in a single JSON or YAML file, for topologies
+/// that are unwieldy to build from flags
+///
+/// Only `replicas` is required. Everything else that's left out keeps the
+/// value of the `DeploymentConfig` that the spec is applied to.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeploymentSpec {
+    #[serde(default)]
+    pub cluster_name: Option<String>,
+    /// The number of clickward managed keepers
+    #[serde(default)]
+    pub keepers: u64,
+    /// The number of replicas of each shard
+    pub replicas: u64,
+    #[serde(default)]
+    pub shards: Option<u64>,
+    /// The weight of each shard for distributed inserts
+    #[serde(default)]
+    pub shard_weights: Vec<u32>,
+    #[serde(default)]
+    pub base_ports: Option<BasePorts>,
+    /// Pick free ports for each node, rather than using base ports
+    #[serde(default)]
+    pub free_ports: bool,
+    #[serde(default)]
+    pub log_level: Option<LogLevel>,
+    /// Settings of the `default` profile of every server
+    #[serde(default, deserialize_with = "scalar_values")]
+    pub settings: BTreeMap<String, String>,
+    /// Users that every server has besides `default`
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// Macros that every server has
+    #[serde(default, deserialize_with = "scalar_values")]
+    pub macros: BTreeMap<String, String>,
+    #[serde(default)]
+    pub replicated_database: Option<String>,
+    #[serde(default)]
+    pub default_database: Option<String>,
+}
+
+/// Deserialize a map of strings that may also be written as numbers or
+/// booleans, i.e. `max_threads: 4`
+fn scalar_values<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error> {
+    BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(s) => Ok((key, s)),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+                Ok((key, value.to_string()))
+            }
+            value => Err(D::Error::custom(format!(
+                "expected a string, number or boolean for {key}: {value}"
+            ))),
+        })
+        .collect()
+}
+
+impl DeploymentSpec {
+    /// Read a spec from the file at `path`, which is YAML if it is named
+    /// `*.yaml` or `*.yml`, and JSON otherwise
+    pub fn load(path: &Utf8Path) -> Result<DeploymentSpec> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {path}"))?;
+        let yaml = matches!(path.extension(), Some("yaml" | "yml"));
+        DeploymentSpec::parse(&contents, yaml).with_context(|| InvalidConfig {
+            what: "deployment spec",
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Parse a spec from `contents`, which are YAML if `yaml` is set, and
+    /// JSON otherwise
+    fn parse(contents: &str, yaml: bool) -> Result<DeploymentSpec> {
+        if yaml {
+            Ok(serde_yaml::from_str(contents)?)
+        } else {
+            Ok(serde_json::from_str(contents)?)
+        }
+    }
+
+    /// Return the number of servers across all shards
+    pub fn num_servers(&self) -> u64 {
+        self.replicas * self.shards.unwrap_or(1)
+    }
+
+    /// Return `base` with everything this spec sets
+    pub fn apply(&self, base: DeploymentConfig) -> DeploymentConfig {
+        let spec = self.clone();
+        DeploymentConfig {
+            cluster_name: spec.cluster_name.unwrap_or(base.cluster_name),
+            base_ports: spec.base_ports.unwrap_or(base.base_ports),
+            free_ports: spec.free_ports || base.free_ports,
+            log_level: spec.log_level.unwrap_or(base.log_level),
+            num_shards: spec.shards.or(base.num_shards),
+            shard_weights: if spec.shard_weights.is_empty() {
+                base.shard_weights
+            } else {
+                spec.shard_weights
+            },
+            profile_settings: base
+                .profile_settings
+                .into_iter()
+                .chain(spec.settings)
+                .collect(),
+            users: base.users.into_iter().chain(spec.users).collect(),
+            extra_macros: base
+                .extra_macros
+                .into_iter()
+                .chain(spec.macros)
+                .collect(),
+            replicated_database: spec
+                .replicated_database
+                .or(base.replicated_database),
+            default_database: spec.default_database.or(base.default_database),
+            ..base
+        }
+    }
+}
+
+impl Deployment {
+    /// Describe this deployment as a spec that recreates it when applied to
+    /// a default config
+    ///
+    /// Recreated nodes are numbered from 1, and servers that don't fill
+    /// their last shard are rounded up to a full shard.
+    pub fn to_spec(&self) -> Result<DeploymentSpec> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let config = &self.config;
+        let shards = self.num_shards();
+        let servers = meta.server_ids.len() as u64;
+        Ok(DeploymentSpec {
+            cluster_name: Some(config.cluster_name.clone()),
+            keepers: meta.keeper_ids.len() as u64,
+            replicas: servers.div_ceil(shards),
+            shards: config.num_shards,
+            shard_weights: config.shard_weights.clone(),
+            base_ports: Some(config.base_ports.clone()),
+            free_ports: config.free_ports,
+            log_level: Some(config.log_level),
+            settings: config.profile_settings.clone(),
+            users: config.users.clone(),
+            macros: config.extra_macros.clone(),
+            replicated_database: config.replicated_database.clone(),
+            default_database: config.default_database.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON_SPEC: &str = r#"{
+        "cluster_name": "test",
+        "keepers": 3,
+        "replicas": 2,
+        "shards": 2,
+        "shard_weights": [1, 3],
+        "log_level": "debug",
+        "settings": { "max_threads": 4, "readonly": "1", "log_queries": true },
+        "users": [{ "name": "reader", "password": "secret" }],
+        "macros": { "region": "eu" }
+    }"#;
+
+    const YAML_SPEC: &str = "
+cluster_name: test
+keepers: 3
+replicas: 2
+shards: 2
+shard_weights: [1, 3]
+log_level: debug
+settings:
+  max_threads: 4
+  readonly: '1'
+  log_queries: true
+users:
+  - name: reader
+    password: secret
+macros:
+  region: eu
+";
+
+    fn check_spec(spec: &DeploymentSpec) {
+        assert_eq!(spec.cluster_name.as_deref(), Some("test"));
+        assert_eq!(spec.keepers, 3);
+        assert_eq!(spec.num_servers(), 4);
+        assert_eq!(spec.shard_weights, [1, 3]);
+        assert_eq!(spec.log_level, Some(LogLevel::Debug));
+        let settings: Vec<_> = spec
+            .settings
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            settings,
+            [("log_queries", "true"), ("max_threads", "4"), ("readonly", "1")]
+        );
+        assert_eq!(spec.users.len(), 1);
+        assert_eq!(spec.users[0].name, "reader");
+        assert_eq!(spec.users[0].password, "secret");
+        assert_eq!(spec.macros.get("region").map(String::as_str), Some("eu"));
+        assert!(spec.base_ports.is_none());
+        assert!(!spec.free_ports);
+    }
+
+    #[test]
+    fn parses_json_spec() {
+        check_spec(&DeploymentSpec::parse(JSON_SPEC, false).unwrap());
+    }
+
+    #[test]
+    fn parses_yaml_spec() {
+        check_spec(&DeploymentSpec::parse(YAML_SPEC, true).unwrap());
+    }
+
+    #[test]
+    fn rejects_spec_without_replicas() {
+        assert!(DeploymentSpec::parse(r#"{ "keepers": 3 }"#, false).is_err());
+        assert!(DeploymentSpec::parse("keepers: 3", true).is_err());
+    }
+
+    #[test]
+    fn rejects_nested_setting_values() {
+        let yaml = "replicas: 1\nsettings:\n  max_threads: [4]\n";
+        assert!(DeploymentSpec::parse(yaml, true).is_err());
+    }
+}
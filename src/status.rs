@@ -0,0 +1,240 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::logs::tail_lines;
+use crate::process::{STDERR_FILENAME, STDOUT_FILENAME};
+use crate::{platform, Backend, Deployment, NodeId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The file in a node's directory that we append every start and stop of
+/// the node to, as seconds since the Unix epoch followed by `start` or
+/// `stop`
+pub const LIFECYCLE_FILENAME: &str = "lifecycle.log";
+
+/// A node that exits this soon after starting, or starts
+/// `CRASH_LOOP_STARTS` times within this long, is crash looping
+pub const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(30);
+
+/// The number of starts within `CRASH_LOOP_WINDOW` after which a node is
+/// crash looping, even if it's running right now
+pub const CRASH_LOOP_STARTS: usize = 3;
+
+/// The number of lines of a node's error log attached to its status
+const ERROR_TAIL_LINES: usize = 20;
+
+/// What a node is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Running,
+    /// Never started, or stopped by clickward
+    Stopped,
+    /// Exited on its own a while after starting
+    Exited,
+    /// Exited on its own shortly after starting, or keeps being restarted
+    CrashLooping,
+}
+
+impl Display for NodeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            NodeState::Running => "running",
+            NodeState::Stopped => "stopped",
+            NodeState::Exited => "exited",
+            NodeState::CrashLooping => "crash-looping",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The state of a node, along with why it's in trouble if it is
+#[derive(Debug, Clone)]
+pub struct NodeStatus {
+    pub node: NodeId,
+    pub state: NodeState,
+    /// `None` unless the node is running as a local process
+    pub pid: Option<u32>,
+    /// The number of times the node was started within the last
+    /// `CRASH_LOOP_WINDOW`
+    pub recent_starts: usize,
+    /// The last lines of the node's error log and output, for nodes that
+    /// exited on their own
+    pub error_tail: Vec<String>,
+}
+
+/// Formats as a table row, i.e. `keeper-1  running  1234`
+impl Display for NodeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<16} {:<14} {:>8}",
+            self.node.to_string(),
+            self.state.to_string(),
+            self.pid.map_or_else(|| "-".to_string(), |pid| pid.to_string()),
+        )
+    }
+}
+
+impl NodeStatus {
+    /// The header of a table of `NodeStatus`es as displayed
+    pub const HEADER: &'static str = "NODE             STATE               PID";
+}
+
+impl Deployment {
+    /// Return the status of every node
+    pub fn statuses(&self) -> Result<Vec<NodeStatus>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let keepers = meta.keeper_ids.iter().map(|&id| NodeId::Keeper(id));
+        let servers = meta.server_ids.iter().map(|&id| NodeId::Server(id));
+        Ok(keepers.chain(servers).map(|node| self.node_status(node)).collect())
+    }
+
+    /// Return the status of a node
+    ///
+    /// A node that isn't running although we last started it exited on its
+    /// own. We take the last write to its error log or output as the time it
+    /// exited, and consider it crash looping if that is within
+    /// `CRASH_LOOP_WINDOW` of it starting.
+    pub fn node_status(&self, node: NodeId) -> NodeStatus {
+        let dir = self.node_dir(node);
+        let lifecycle = read_lifecycle(&dir);
+        let window_start = SystemTime::now()
+            .checked_sub(CRASH_LOOP_WINDOW)
+            .unwrap_or(UNIX_EPOCH);
+        let recent_starts = lifecycle
+            .iter()
+            .filter(|(time, event)| event == "start" && *time >= window_start)
+            .count();
+        let last_start = match lifecycle.last() {
+            Some((time, event)) if event == "start" => Some(*time),
+            _ => None,
+        };
+        let alive = self.is_alive(node);
+        let pid = match (&self.config.backend, alive) {
+            (Backend::Process, true) => {
+                platform::read_pidfile(&dir.join(pidfile(node))).ok()
+            }
+            _ => None,
+        };
+
+        let looping = recent_starts >= CRASH_LOOP_STARTS;
+        let state = if alive && looping {
+            NodeState::CrashLooping
+        } else if alive {
+            NodeState::Running
+        } else if let Some(started) = last_start {
+            if looping || self.exited_soon_after(node, started) {
+                NodeState::CrashLooping
+            } else {
+                NodeState::Exited
+            }
+        } else if self.processes.is_started(self, node) {
+            // Started before we kept a lifecycle
+            NodeState::Exited
+        } else {
+            NodeState::Stopped
+        };
+        let error_tail = match state {
+            NodeState::Exited | NodeState::CrashLooping => {
+                self.error_tail(node)
+            }
+            NodeState::Running | NodeState::Stopped => vec![],
+        };
+        NodeStatus { node, state, pid, recent_starts, error_tail }
+    }
+
+    /// Append a `start` or `stop` of a node to its lifecycle
+    pub(crate) fn record_lifecycle(
+        &self,
+        node: NodeId,
+        event: &str,
+    ) -> Result<()> {
+        let path = self.node_dir(node).join(LIFECYCLE_FILENAME);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {path}"))?;
+        writeln!(file, "{now} {event}")
+            .with_context(|| format!("failed to write {path}"))?;
+        Ok(())
+    }
+
+    /// Return true if a node that is no longer running last wrote to its
+    /// logs within `CRASH_LOOP_WINDOW` of `started`
+    fn exited_soon_after(&self, node: NodeId, started: SystemTime) -> bool {
+        let dir = self.node_dir(node);
+        let exited = [
+            self.error_log(node),
+            dir.join(STDERR_FILENAME),
+            dir.join(STDOUT_FILENAME),
+        ]
+        .iter()
+        .filter_map(|path| modified(path))
+        .max()
+        .unwrap_or(started);
+        // Timestamps in the lifecycle are truncated to seconds
+        exited.duration_since(started).map_or(true, |uptime| {
+            uptime <= CRASH_LOOP_WINDOW + Duration::from_secs(1)
+        })
+    }
+
+    /// Return the path of a node's error log
+    fn error_log(&self, node: NodeId) -> Utf8PathBuf {
+        let logs = self.node_dir(node).join("logs");
+        match node {
+            NodeId::Keeper(_) => logs.join("clickhouse-keeper.err.log"),
+            NodeId::Server(_) => logs.join("clickhouse.err.log"),
+        }
+    }
+
+    /// Return the last lines of a node's error log, or of its stderr if the
+    /// node failed before it could log anything
+    fn error_tail(&self, node: NodeId) -> Vec<String> {
+        let dir = self.node_dir(node);
+        [self.error_log(node), dir.join(STDERR_FILENAME)]
+            .iter()
+            .filter_map(|path| tail_lines(path, ERROR_TAIL_LINES).ok())
+            .map(|(lines, _)| lines)
+            .find(|lines| !lines.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+/// The pidfile of a node run as a local process
+fn pidfile(node: NodeId) -> &'static str {
+    match node {
+        NodeId::Keeper(_) => "keeper.pid",
+        NodeId::Server(_) => "clickhouse.pid",
+    }
+}
+
+/// Read the lifecycle of the node in `dir`, oldest first
+///
+/// Only the latest events matter, so older ones are not read.
+fn read_lifecycle(dir: &Utf8Path) -> Vec<(SystemTime, String)> {
+    let path = dir.join(LIFECYCLE_FILENAME);
+    let Ok((lines, _)) = tail_lines(&path, CRASH_LOOP_STARTS * 4) else {
+        return vec![];
+    };
+    lines
+        .iter()
+        .filter_map(|line| {
+            let (secs, event) = line.trim().split_once(' ')?;
+            let time = UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?);
+            Some((time, event.to_string()))
+        })
+        .collect()
+}
+
+fn modified(path: &Utf8Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
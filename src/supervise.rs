@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ClickwardMetadata, Deployment, NodeId};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How `Deployment::supervise` restarts nodes that exit unexpectedly
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// How often we check whether nodes are running
+    pub poll_interval: Duration,
+    /// How long we wait before the first restart of a node
+    pub initial_backoff: Duration,
+    /// The longest we wait between restarts of a node, as the wait doubles
+    /// after each restart
+    pub max_backoff: Duration,
+    /// The number of times a node is restarted before we give up on it
+    pub max_restarts: u32,
+    /// How long a restarted node has to stay up before it is considered
+    /// stable again, which resets its restart count and backoff
+    pub stable_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            poll_interval: Duration::from_secs(1),
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: 5,
+            stable_after: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What we know about restarting a single node
+struct RestartState {
+    restarts: u32,
+    backoff: Duration,
+    next_attempt: Instant,
+    /// When we last restarted the node
+    restarted_at: Instant,
+}
+
+impl Deployment {
+    /// Watch all started nodes and restart any that exit unexpectedly,
+    /// according to `policy`
+    ///
+    /// A node that stays up for `policy.stable_after` after a restart gets
+    /// its restart count and backoff back. Nodes stopped via clickward are
+    /// not restarted, and metadata is reloaded on every poll so that added
+    /// and removed nodes are picked up. Restarts are recorded in the events
+    /// log. This runs until an error occurs.
+    pub async fn supervise(&self, policy: &RestartPolicy) -> Result<()> {
+        let mut states: BTreeMap<NodeId, RestartState> = BTreeMap::new();
+        self.log_event("Supervising deployment")?;
+        loop {
+            let meta = ClickwardMetadata::load(&self.config.path)?;
+            let nodes: Vec<NodeId> = meta
+                .keeper_ids
+                .iter()
+                .map(|&id| NodeId::Keeper(id))
+                .chain(meta.server_ids.iter().map(|&id| NodeId::Server(id)))
+                .collect();
+
+            for node in nodes {
+                if !self.processes.is_started(self, node) {
+                    continue;
+                }
+                if self.processes.is_alive(self, node) {
+                    if states.get(&node).is_some_and(|state| {
+                        state.restarted_at.elapsed() >= policy.stable_after
+                    }) {
+                        states.remove(&node);
+                    }
+                    continue;
+                }
+                let state = states.entry(node).or_insert(RestartState {
+                    restarts: 0,
+                    backoff: policy.initial_backoff,
+                    next_attempt: Instant::now() + policy.initial_backoff,
+                    restarted_at: Instant::now(),
+                });
+                if state.restarts > policy.max_restarts
+                    || Instant::now() < state.next_attempt
+                {
+                    continue;
+                }
+                if state.restarts == policy.max_restarts {
+                    self.log_event(&format!(
+                        "{node} exited after {} restarts, giving up",
+                        state.restarts
+                    ))?;
+                    state.restarts += 1;
+                    continue;
+                }
+
+                state.restarts += 1;
+                self.log_event(&format!(
+                    "{node} exited unexpectedly, restarting ({}/{})",
+                    state.restarts, policy.max_restarts
+                ))?;
+                if let Err(e) = self.restart(node) {
+                    self.log_event(&format!("failed to restart {node}: {e}"))?;
+                }
+                state.backoff = (state.backoff * 2).min(policy.max_backoff);
+                state.next_attempt = Instant::now() + state.backoff;
+                state.restarted_at = Instant::now();
+            }
+
+            self.pause(policy.poll_interval).await?;
+        }
+    }
+
+    /// Clean up after a node that exited and start it again
+    fn restart(&self, node: NodeId) -> Result<()> {
+        // The node is already gone, so stopping only removes what it left
+        // behind, such as its pidfile
+        let _ = self.processes.stop(self, node);
+        match node {
+            NodeId::Keeper(id) => self.processes.spawn_keeper(self, id)?,
+            NodeId::Server(id) => self.processes.spawn_server(self, id)?,
+        }
+        self.record_lifecycle(node, "start")?;
+        self.record_node("restart", node)
+    }
+}
@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::health::HEALTH_POLL_INTERVAL;
+use crate::{Deployment, ServerId};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+/// A row of `system.clusters`: one replica of one shard of a cluster
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ClusterReplica {
+    pub cluster: String,
+    pub shard_num: u32,
+    pub replica_num: u32,
+    pub host_name: String,
+    pub host_address: String,
+    pub port: u16,
+    /// True if this replica is the server that was queried
+    pub is_local: bool,
+}
+
+/// A row of `system.zookeeper`: a node in the keeper tree
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ZookeeperNode {
+    pub name: String,
+    /// The path of the parent node
+    pub path: String,
+    pub value: String,
+    #[serde(rename = "numChildren")]
+    pub num_children: i32,
+}
+
+/// The topology of a deployment as seen by a live server
+#[derive(Debug, Clone)]
+pub struct SystemClusterView {
+    pub clusters: Vec<ClusterReplica>,
+    /// Macro names and their substitutions
+    pub macros: BTreeMap<String, String>,
+    /// The nodes at the root of the keeper tree
+    pub zookeeper: Vec<ZookeeperNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MacroRow {
+    r#macro: String,
+    substitution: String,
+}
+
+impl Deployment {
+    /// Return the contents of `system.clusters`, `system.macros`, and the root
+    /// of `system.zookeeper` on a given server
+    ///
+    /// This shows whether the generated configs produced the intended
+    /// topology.
+    pub async fn system_cluster_view(
+        &self,
+        id: ServerId,
+    ) -> Result<SystemClusterView> {
+        let client = self.http_client(id)?;
+        let clusters = client
+            .query(
+                "SELECT cluster, shard_num, replica_num, host_name, \
+                    host_address, port, toBool(is_local) AS is_local \
+                 FROM system.clusters \
+                 ORDER BY cluster, shard_num, replica_num",
+            )
+            .await?
+            .rows()?;
+        let macros = client
+            .query("SELECT macro, substitution FROM system.macros")
+            .await?
+            .rows::<MacroRow>()?
+            .into_iter()
+            .map(|row| (row.r#macro, row.substitution))
+            .collect();
+        let zookeeper = self.zookeeper_children(id, "/").await?;
+        Ok(SystemClusterView { clusters, macros, zookeeper })
+    }
+
+    /// Wait until every server in `expected` sees exactly the servers in
+    /// `expected` as the replicas of our cluster in `system.clusters`, or
+    /// fail after `timeout`
+    ///
+    /// Servers pick up a regenerated `<remote_servers>` asynchronously, i.e.
+    /// after `add_server`, so this tells when they all agree on membership.
+    pub async fn wait_for_cluster_view(
+        &self,
+        expected: &BTreeSet<ServerId>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let members: BTreeSet<(String, u16)> = expected
+            .iter()
+            .map(|&id| Ok((self.server_host(id), self.tcp_port(id)?)))
+            .collect::<Result<_>>()?;
+        let deadline = Instant::now() + timeout;
+        for &id in expected {
+            loop {
+                let seen = self.cluster_members(id).await;
+                if seen.as_ref().is_ok_and(|seen| *seen == members) {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    match seen {
+                        Ok(seen) => bail!(
+                            "timed out waiting for server {id} to see \
+                             {members:?} in its cluster, it sees {seen:?}"
+                        ),
+                        Err(e) => bail!(
+                            "timed out waiting for server {id} to see \
+                             {members:?} in its cluster: {e:#}"
+                        ),
+                    }
+                }
+                self.pause(HEALTH_POLL_INTERVAL).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the hosts and ports of the replicas of our cluster in
+    /// `system.clusters` on a given server
+    async fn cluster_members(
+        &self,
+        id: ServerId,
+    ) -> Result<BTreeSet<(String, u16)>> {
+        let cluster = self.config.cluster_name.replace('\'', "\\'");
+        let replicas: Vec<ClusterReplica> = self
+            .http_client(id)?
+            .query(&format!(
+                "SELECT cluster, shard_num, replica_num, host_name, \
+                    host_address, port, toBool(is_local) AS is_local \
+                 FROM system.clusters \
+                 WHERE cluster = '{cluster}'"
+            ))
+            .await?
+            .rows()?;
+        Ok(replicas.into_iter().map(|r| (r.host_name, r.port)).collect())
+    }
+
+    /// Return the children of `path` in the keeper tree, as seen through
+    /// `system.zookeeper` on a given server
+    pub async fn zookeeper_children(
+        &self,
+        id: ServerId,
+        path: &str,
+    ) -> Result<Vec<ZookeeperNode>> {
+        let path = path.replace('\\', "\\\\").replace('\'', "\\'");
+        let nodes = self
+            .http_client(id)?
+            .query(&format!(
+                "SELECT name, path, value, numChildren \
+                 FROM system.zookeeper \
+                 WHERE path = '{path}' \
+                 ORDER BY name"
+            ))
+            .await?
+            .rows()?;
+        Ok(nodes)
+    }
+}
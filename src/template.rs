@@ -0,0 +1,161 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{KeeperConfig, ReplicaConfig};
+use crate::{Deployment, KeeperId, NodeId, ServerId};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Templates that replace the configs we generate, for nodes that need a
+/// config shaped differently than ours
+///
+/// Templates are config files with `{{name}}` placeholders, which are
+/// replaced with the values of the node they are rendered for. This is
+/// deliberately plain substitution rather than an engine like Tera or
+/// handlebars: there are no conditionals, loops, filters or escaping, and
+/// values are inserted verbatim. Anything that varies with the peers of a
+/// node, like `raft_servers`, is given as XML we render ourselves, so that
+/// templates don't need loops to lay it out. Every node gets:
+///
+/// - `id`, `name` (i.e. `keeper-1`), `cluster`, `dir`, `log_dir`
+/// - `listen_host`, the host the node binds to, and `host`, the host other
+///   nodes reach it at
+/// - `log_level`, `log_size` and `log_count`
+/// - `default_config`, the config we would have generated
+///
+/// Keepers also get `tcp_port`, `raft_port`, `log_storage_path`,
+/// `snapshot_storage_path` and `raft_servers`, the `<server>` elements of
+/// every keeper in the ensemble.
+///
+/// Servers also get `tcp_port`, `http_port`, `interserver_http_port`,
+/// `shard`, `replica`, `data_path`, `macros`, `remote_servers` and
+/// `zookeeper`, where the last three are the XML elements of those names.
+///
+/// Nodes with prometheus enabled get `metrics_port` as well. Referring to
+/// any other name fails.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConfigTemplates {
+    /// The template of `keeper-config.xml`
+    #[serde(default)]
+    pub keeper: Option<Utf8PathBuf>,
+    /// The template of `clickhouse-config.xml`
+    #[serde(default)]
+    pub server: Option<Utf8PathBuf>,
+}
+
+impl Deployment {
+    /// Return the contents of `keeper-config.xml` for keeper `id`
+    pub(crate) fn keeper_config_xml(
+        &self,
+        id: KeeperId,
+        config: &KeeperConfig,
+    ) -> Result<String> {
+        let default_config = config.to_xml();
+        let Some(template) = &self.config.config_templates.keeper else {
+            return Ok(default_config);
+        };
+        let node = NodeId::Keeper(id);
+        let mut values = self.node_template_values(node);
+        values.extend([
+            ("tcp_port", config.tcp_port.to_string()),
+            ("raft_port", self.raft_port(id)?.to_string()),
+            ("log_storage_path", config.log_storage_path.to_string()),
+            ("snapshot_storage_path", config.snapshot_storage_path.to_string()),
+            ("raft_servers", config.raft_config.to_xml()),
+            ("log_level", config.logger.level.to_string()),
+            ("log_size", config.logger.size.to_string()),
+            ("log_count", config.logger.count.to_string()),
+            ("default_config", default_config),
+        ]);
+        if let Some(prometheus) = &config.prometheus {
+            values.insert("metrics_port", prometheus.port.to_string());
+        }
+        render_template(template, &values)
+    }
+
+    /// Return the contents of `clickhouse-config.xml` for server `id`
+    pub(crate) fn server_config_xml(
+        &self,
+        id: ServerId,
+        config: &ReplicaConfig,
+    ) -> Result<String> {
+        let default_config = config.to_xml();
+        let Some(template) = &self.config.config_templates.server else {
+            return Ok(default_config);
+        };
+        let node = NodeId::Server(id);
+        let mut values = self.node_template_values(node);
+        values.extend([
+            ("tcp_port", config.tcp_port.to_string()),
+            ("http_port", config.http_port.to_string()),
+            ("interserver_http_port", config.interserver_http_port.to_string()),
+            ("shard", config.macros.shard.to_string()),
+            ("replica", config.macros.replica.to_string()),
+            ("data_path", config.data_path.to_string()),
+            ("macros", config.macros.to_xml()),
+            ("remote_servers", config.remote_servers.to_xml()),
+            ("zookeeper", config.keepers.to_xml()),
+            ("log_level", config.logger.level.to_string()),
+            ("log_size", config.logger.size.to_string()),
+            ("log_count", config.logger.count.to_string()),
+            ("default_config", default_config),
+        ]);
+        if let Some(prometheus) = &config.prometheus {
+            values.insert("metrics_port", prometheus.port.to_string());
+        }
+        render_template(template, &values)
+    }
+
+    /// Return the template values that every node gets
+    fn node_template_values(
+        &self,
+        node: NodeId,
+    ) -> BTreeMap<&'static str, String> {
+        let (id, dir, host) = match node {
+            NodeId::Keeper(id) => {
+                (id.0, self.keeper_dir(id), self.keeper_host(id))
+            }
+            NodeId::Server(id) => {
+                (id.0, self.server_dir(id), self.server_host(id))
+            }
+        };
+        BTreeMap::from([
+            ("id", id.to_string()),
+            ("name", node.to_string()),
+            ("cluster", self.config.cluster_name.clone()),
+            ("log_dir", dir.join("logs").to_string()),
+            ("dir", dir.to_string()),
+            ("listen_host", self.bind_host(node)),
+            ("host", host),
+        ])
+    }
+}
+
+/// Replace the `{{name}}` placeholders of the template at `path` with
+/// `values`
+fn render_template(
+    path: &Utf8Path,
+    values: &BTreeMap<&'static str, String>,
+) -> Result<String> {
+    let template = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read template {path}"))?;
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            bail!("unterminated {{{{ in template {path}");
+        };
+        let name = rest[start + 2..start + end].trim();
+        let Some(value) = values.get(name) else {
+            bail!("unknown variable {name:?} in template {path}");
+        };
+        rendered.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
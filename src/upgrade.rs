@@ -0,0 +1,78 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, NodeBinary, NodeId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use std::process::Command;
+use std::time::Duration;
+use tracing::info;
+
+/// Return the output of `<binary> --version`
+pub fn binary_version(binary: &Utf8Path) -> Result<String> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("failed to run {binary} --version"))?;
+    if !output.status.success() {
+        bail!(
+            "{binary} --version failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Deployment {
+    /// Restart each clickhouse server, one at a time, running `binary`
+    ///
+    /// We wait up to `timeout` for each server to become healthy before
+    /// moving on to the next one, so that at most one replica is down at a
+    /// time. This works for downgrades as well. The binary and its version
+    /// are recorded per server in our metadata, so that later restarts keep
+    /// using it, and each server's config is regenerated for its version
+    /// before it restarts.
+    pub async fn rolling_upgrade(
+        &mut self,
+        binary: &Utf8Path,
+        timeout: Duration,
+    ) -> Result<()> {
+        let version = binary_version(binary)?;
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let server_ids = meta.server_ids.clone();
+
+        for id in server_ids {
+            info!(%id, %version, "Upgrading clickhouse server");
+            if self.is_alive(NodeId::Server(id)) {
+                self.stop_server(id)?;
+            }
+
+            let Some(meta) = &mut self.meta else {
+                bail!(MISSING_META);
+            };
+            meta.server_binaries.insert(
+                id,
+                NodeBinary {
+                    path: binary.to_path_buf(),
+                    version: version.clone(),
+                },
+            );
+            self.writer.save_meta(meta, &self.config.path)?;
+            let (keeper_ids, server_ids) =
+                (meta.keeper_ids.clone(), meta.server_ids.clone());
+
+            // The config may need to change for the new version
+            self.generate_clickhouse_config(keeper_ids, server_ids)?;
+            self.start_server(id)?;
+            self.wait_for_server(id, timeout).await?;
+        }
+
+        self.record(
+            "rolling-upgrade",
+            serde_json::json!({ "binary": binary, "version": version }),
+        )
+    }
+}
@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Backend, Deployment, NodeId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// The clock ticks per second that `/proc/<pid>/stat` reports CPU time in
+///
+/// This is 100 on every Linux platform we care about.
+const CLOCK_TICKS_PER_SEC: u64 = 100;
+
+/// The resources used by a node
+///
+/// Process stats are only available for nodes run by the process backend,
+/// and include the direct children of the node's process, as clickhouse
+/// servers run under a watchdog process.
+#[derive(Debug, Clone)]
+pub struct NodeResourceUsage {
+    pub node: NodeId,
+    /// `None` if the node isn't running
+    pub pid: Option<u32>,
+    pub rss_bytes: Option<u64>,
+    pub cpu_time: Option<Duration>,
+    pub open_fds: Option<usize>,
+    /// The total size of all files in the node's directory
+    pub disk_bytes: u64,
+}
+
+impl Display for NodeResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn or_dash<T: Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "-".to_string(), |v| v.to_string())
+        }
+        write!(
+            f,
+            "{:<16} {:>8} {:>10} {:>10} {:>6} {:>10}",
+            self.node.to_string(),
+            or_dash(self.pid),
+            or_dash(self.rss_bytes.map(|b| b / (1024 * 1024))),
+            or_dash(self.cpu_time.map(|t| format!("{:.1}", t.as_secs_f64()))),
+            or_dash(self.open_fds),
+            self.disk_bytes / (1024 * 1024),
+        )
+    }
+}
+
+impl NodeResourceUsage {
+    /// The header of a table of `NodeResourceUsage`s as displayed
+    pub const HEADER: &'static str =
+        "NODE                  PID    RSS(MB)     CPU(s)    FDS   DISK(MB)";
+}
+
+/// Usage of a single process, read from `/proc`
+#[derive(Debug, Default)]
+struct ProcessUsage {
+    rss_bytes: u64,
+    cpu_ticks: u64,
+    open_fds: usize,
+}
+
+impl Deployment {
+    /// Report the memory, CPU time, open files, and disk usage of each node
+    pub fn resource_usage(&self) -> Result<Vec<NodeResourceUsage>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let keepers = meta.keeper_ids.iter().map(|&id| NodeId::Keeper(id));
+        let servers = meta.server_ids.iter().map(|&id| NodeId::Server(id));
+        keepers.chain(servers).map(|node| self.node_usage(node)).collect()
+    }
+
+    fn node_usage(&self, node: NodeId) -> Result<NodeResourceUsage> {
+        let dir = self.node_dir(node);
+        let pidfile = match node {
+            NodeId::Keeper(_) => "keeper.pid",
+            NodeId::Server(_) => "clickhouse.pid",
+        };
+        let pid = match self.config.backend {
+            Backend::Process if self.is_alive(node) => {
+                std::fs::read_to_string(dir.join(pidfile))
+                    .ok()
+                    .and_then(|pid| pid.trim().parse().ok())
+            }
+            _ => None,
+        };
+        let usage = pid.and_then(|pid| {
+            let mut total = process_usage(pid)?;
+            for child in children(pid) {
+                if let Some(usage) = process_usage(child) {
+                    total.rss_bytes += usage.rss_bytes;
+                    total.cpu_ticks += usage.cpu_ticks;
+                    total.open_fds += usage.open_fds;
+                }
+            }
+            Some(total)
+        });
+        Ok(NodeResourceUsage {
+            node,
+            pid,
+            rss_bytes: usage.as_ref().map(|u| u.rss_bytes),
+            cpu_time: usage.as_ref().map(|u| {
+                Duration::from_millis(u.cpu_ticks * 1000 / CLOCK_TICKS_PER_SEC)
+            }),
+            open_fds: usage.as_ref().map(|u| u.open_fds),
+            disk_bytes: disk_usage(&dir)
+                .with_context(|| format!("failed to measure {dir}"))?,
+        })
+    }
+}
+
+/// Read the usage of a process from `/proc`, or `None` if it has exited
+fn process_usage(pid: u32) -> Option<ProcessUsage> {
+    let proc = Utf8Path::new("/proc").join(pid.to_string());
+
+    // The command name may contain spaces, so we skip past it before
+    // splitting. The fields after it start at `state`, the third field.
+    let stat = std::fs::read_to_string(proc.join("stat")).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+
+    let status = std::fs::read_to_string(proc.join("status")).ok()?;
+    let rss_kb: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap_or(0);
+
+    let open_fds = std::fs::read_dir(proc.join("fd")).ok()?.count();
+    Some(ProcessUsage {
+        rss_bytes: rss_kb * 1024,
+        cpu_ticks: utime + stime,
+        open_fds,
+    })
+}
+
+/// Return the pids of the direct children of a process
+fn children(pid: u32) -> Vec<u32> {
+    let path = format!("/proc/{pid}/task/{pid}/children");
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|child| child.parse().ok())
+        .collect()
+}
+
+/// Return the total size of all files under `dir`, without following
+/// symlinks
+///
+/// Running nodes remove files while we walk them, such as parts that were
+/// merged, so files and directories that are gone by the time we get to
+/// them count as empty.
+fn disk_usage(dir: &Utf8Path) -> Result<u64> {
+    let entries = match dir.read_dir_utf8() {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+    let mut total = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = match entry.file_type() {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            file_type => file_type?,
+        };
+        if file_type.is_dir() {
+            total += disk_usage(entry.path())?;
+        } else if file_type.is_file() {
+            match entry.metadata() {
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                metadata => total += metadata?.len(),
+            }
+        }
+    }
+    Ok(total)
+}
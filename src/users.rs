@@ -0,0 +1,82 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{platform, Deployment};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use std::process::{Command, Stdio};
+use tracing::info;
+
+impl Deployment {
+    /// Return the OS user that the node named `node`, i.e. `keeper-1`, runs
+    /// as, if it's configured with one
+    pub(crate) fn node_user(&self, node: &str) -> Option<&str> {
+        self.config.node_process.get(node)?.user.as_deref()
+    }
+
+    /// Give the user of the node named `node` ownership of its directory
+    /// `dir`, if it has a user
+    ///
+    /// The directory stays in our group and writable by it, so that we can
+    /// still write configs to it and remove pidfiles from it.
+    pub(crate) fn chown_node_dir(
+        &self,
+        node: &str,
+        dir: &Utf8Path,
+    ) -> Result<()> {
+        let Some(user) = self.node_user(node) else {
+            return Ok(());
+        };
+        if !self.config.backend.is_local() {
+            bail!("{node} has a user, which needs a local backend");
+        }
+        let (_, gid) = platform::owner(&self.config.path)?;
+        info!(%node, %user, %dir, "Changing owner");
+        sudo(&["chown", "-R", &format!("{user}:{gid}"), dir.as_str()])?;
+        sudo(&["chmod", "-R", "g+rwX", dir.as_str()])
+    }
+
+    /// Take back ownership of `dir` from the user of the node named `node`,
+    /// if it has a user, so that we can remove what the node wrote
+    pub(crate) fn reclaim_node_dir(
+        &self,
+        node: &str,
+        dir: &Utf8Path,
+    ) -> Result<()> {
+        if self.node_user(node).is_none() || !dir.exists() {
+            return Ok(());
+        }
+        let (uid, gid) = platform::owner(&self.config.path)?;
+        sudo(&["chown", "-R", &format!("{uid}:{gid}"), dir.as_str()])
+    }
+
+    /// Forcefully terminate process `pid` of the node named `node`, as its
+    /// user if it has one
+    pub(crate) fn kill_node_process(&self, node: &str, pid: u32) -> Result<()> {
+        match self.node_user(node) {
+            Some(user) => {
+                sudo(&["-u", user, "kill", "-KILL", &pid.to_string()])
+            }
+            None => platform::kill(pid),
+        }
+    }
+}
+
+/// Run `sudo` with `args`, failing rather than prompting for a password
+fn sudo(args: &[&str]) -> Result<()> {
+    let output = Command::new("sudo")
+        .arg("-n")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .context("failed to run sudo")?;
+    if !output.status.success() {
+        bail!(
+            "sudo {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(())
+}
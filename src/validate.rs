@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::element;
+use crate::{Deployment, KeeperId, NodeId, ServerId, MISSING_META};
+use anyhow::{bail, Context, Result};
+use camino::Utf8PathBuf;
+use std::fmt::Display;
+use tracing::{info, warn};
+
+/// A way in which the files of a deployment don't match its metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// A node in our metadata has no config
+    MissingConfig { node: NodeId, path: Utf8PathBuf },
+    /// A node's config has a different value than we would generate for an
+    /// element that identifies the node, i.e. a port or the server id
+    UnexpectedValue {
+        node: NodeId,
+        path: Utf8PathBuf,
+        element: &'static str,
+        expected: String,
+        found: Option<String>,
+    },
+    /// A node directory exists for a node that isn't in our metadata
+    OrphanedDir { node: NodeId, path: Utf8PathBuf },
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inconsistency::MissingConfig { node, path } => {
+                write!(f, "{node} has no config at {path}")
+            }
+            Inconsistency::UnexpectedValue {
+                node,
+                path,
+                element,
+                expected,
+                found,
+            } => {
+                let found = found.as_deref().unwrap_or("nothing");
+                write!(
+                    f,
+                    "{node} has {element} {found} instead of {expected} \
+                     in {path}"
+                )
+            }
+            Inconsistency::OrphanedDir { node, path } => {
+                write!(f, "{path} belongs to {node}, which isn't a member")
+            }
+        }
+    }
+}
+
+impl Deployment {
+    /// Cross-check our metadata against the node directories and configs on
+    /// disk, returning every inconsistency found
+    ///
+    /// Partial failures of membership changes can leave configs missing or
+    /// stale, or directories of removed nodes behind. If `repair` is true,
+    /// all configs are regenerated when any are missing or stale, and
+    /// orphaned directories of nodes that aren't running are moved aside to
+    /// `orphaned-<node>`. The returned inconsistencies are those found before
+    /// repairing.
+    pub fn validate(&self, repair: bool) -> Result<Vec<Inconsistency>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut found = vec![];
+
+        for &id in &meta.keeper_ids {
+            let node = NodeId::Keeper(id);
+            let path = self.keeper_dir(id).join("keeper-config.xml");
+            let expected = [
+                ("tcp_port", self.keeper_port(id)?.to_string()),
+                ("server_id", id.to_string()),
+            ];
+            found.extend(check_config(node, path, &expected)?);
+        }
+        for &id in &meta.server_ids {
+            let node = NodeId::Server(id);
+            let path = self.server_dir(id).join("clickhouse-config.xml");
+            let interserver_port = self.interserver_http_port(id)?;
+            let expected = [
+                ("tcp_port", self.tcp_port(id)?.to_string()),
+                ("http_port", self.http_port(id)?.to_string()),
+                ("interserver_http_port", interserver_port.to_string()),
+                ("replica", id.to_string()),
+            ];
+            found.extend(check_config(node, path, &expected)?);
+        }
+
+        for entry in self.config.path.read_dir_utf8()? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(node) = node_from_dir_name(entry.file_name()) else {
+                continue;
+            };
+            let is_member = match node {
+                NodeId::Keeper(id) => meta.keeper_ids.contains(&id),
+                NodeId::Server(id) => meta.server_ids.contains(&id),
+            };
+            if !is_member {
+                let path = entry.path().to_path_buf();
+                found.push(Inconsistency::OrphanedDir { node, path });
+            }
+        }
+
+        if repair {
+            self.repair(&found)?;
+        }
+        Ok(found)
+    }
+
+    fn repair(&self, inconsistencies: &[Inconsistency]) -> Result<()> {
+        let stale_configs = inconsistencies
+            .iter()
+            .any(|i| !matches!(i, Inconsistency::OrphanedDir { .. }));
+        if stale_configs {
+            info!("Regenerating configs");
+            self.regenerate_configs()?;
+        }
+        for inconsistency in inconsistencies {
+            let Inconsistency::OrphanedDir { node, path } = inconsistency
+            else {
+                continue;
+            };
+            if self.is_alive(*node) {
+                warn!(%path, %node, "Not moving directory of running node");
+                continue;
+            }
+            let to = self.config.path.join(format!("orphaned-{node}"));
+            info!(%path, %to, "Moving orphaned directory");
+            std::fs::rename(path, &to)
+                .with_context(|| format!("failed to move {path} to {to}"))?;
+        }
+        self.record(
+            "validate-repair",
+            serde_json::json!({ "inconsistencies": inconsistencies.len() }),
+        )
+    }
+}
+
+/// Check that the config of `node` at `path` has the `expected` values for
+/// each element
+fn check_config(
+    node: NodeId,
+    path: Utf8PathBuf,
+    expected: &[(&'static str, String)],
+) -> Result<Vec<Inconsistency>> {
+    if !path.exists() {
+        return Ok(vec![Inconsistency::MissingConfig { node, path }]);
+    }
+    let xml = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {path}"))?;
+    let mut found = vec![];
+    for (tag, expected) in expected {
+        let value = element(&xml, tag);
+        if value != Some(expected.as_str()) {
+            found.push(Inconsistency::UnexpectedValue {
+                node,
+                path: path.clone(),
+                element: tag,
+                expected: expected.clone(),
+                found: value.map(String::from),
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Return the node that a directory named i.e. `keeper-1` belongs to
+fn node_from_dir_name(name: &str) -> Option<NodeId> {
+    if let Some(id) = name.strip_prefix("keeper-") {
+        return id.parse().ok().map(|id| NodeId::Keeper(KeeperId(id)));
+    }
+    let id = name.strip_prefix("clickhouse-")?;
+    id.parse().ok().map(|id| NodeId::Server(ServerId(id)))
+}
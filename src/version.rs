@@ -0,0 +1,246 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::upgrade::binary_version;
+use crate::{Backend, Deployment, ServerId, DEFAULT_CLICKHOUSE_BINARY};
+use anyhow::{anyhow, bail, Context, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use tracing::warn;
+
+/// The oldest clickhouse that clickward generates configs for
+pub const MINIMUM_CLICKHOUSE_VERSION: ClickhouseVersion =
+    ClickhouseVersion::new(22, 8, 0);
+
+/// A clickhouse release, i.e. `24.3.1.2672`
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub struct ClickhouseVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// The build number, which is 0 for versions we compare against
+    pub build: u32,
+}
+
+impl ClickhouseVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        ClickhouseVersion { major, minor, patch, build: 0 }
+    }
+}
+
+impl Display for ClickhouseVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ClickhouseVersion { major, minor, patch, build } = self;
+        write!(f, "{major}.{minor}.{patch}.{build}")
+    }
+}
+
+/// Parses either a bare version, i.e. `24.3.1.2672`, or the output of
+/// `clickhouse --version`, i.e.
+/// `ClickHouse local version 24.3.1.2672 (official build).`
+impl FromStr for ClickhouseVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let word = s
+            .split_whitespace()
+            .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+            .ok_or_else(|| anyhow!("no clickhouse version in {s:?}"))?;
+        let mut parts = [0; 4];
+        let mut count = 0;
+        for part in word.trim_end_matches('.').split('.') {
+            let Some(slot) = parts.get_mut(count) else {
+                bail!("invalid clickhouse version: {word}");
+            };
+            *slot = part.parse().with_context(|| {
+                format!("invalid clickhouse version: {word}")
+            })?;
+            count += 1;
+        }
+        if count < 2 {
+            bail!("invalid clickhouse version: {word}");
+        }
+        let [major, minor, patch, build] = parts;
+        Ok(ClickhouseVersion { major, minor, patch, build })
+    }
+}
+
+/// A feature that clickward uses which not every supported clickhouse
+/// version has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionedFeature {
+    /// Databases using the `Replicated` engine, configured with
+    /// `DeploymentConfig::replicated_database`
+    ReplicatedDatabase,
+    /// `clickhouse keeper-client`, which we use to query keepers
+    KeeperClient,
+    /// Changing the raft configuration of keepers with `reconfig`, which
+    /// `<enable_reconfiguration>` configures
+    KeeperReconfiguration,
+    /// Adapting how long async inserts are buffered to the insert rate
+    AdaptiveAsyncInsert,
+    /// Keeper's HTTP control endpoint, enabled with
+    /// `DeploymentConfig::keeper_http_control`
+    KeeperHttpControl,
+}
+
+impl VersionedFeature {
+    /// The first clickhouse release that supports this feature
+    pub fn min_version(self) -> ClickhouseVersion {
+        match self {
+            VersionedFeature::ReplicatedDatabase => {
+                ClickhouseVersion::new(23, 3, 0)
+            }
+            VersionedFeature::KeeperClient => ClickhouseVersion::new(23, 5, 0),
+            VersionedFeature::KeeperReconfiguration => {
+                ClickhouseVersion::new(23, 9, 0)
+            }
+            VersionedFeature::AdaptiveAsyncInsert => {
+                ClickhouseVersion::new(24, 3, 0)
+            }
+            VersionedFeature::KeeperHttpControl => {
+                ClickhouseVersion::new(23, 5, 0)
+            }
+        }
+    }
+
+    /// Return true if `version` supports this feature
+    pub fn is_supported(self, version: ClickhouseVersion) -> bool {
+        version >= self.min_version()
+    }
+}
+
+impl Display for VersionedFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            VersionedFeature::ReplicatedDatabase => "Replicated databases",
+            VersionedFeature::KeeperClient => "clickhouse keeper-client",
+            VersionedFeature::KeeperReconfiguration => "keeper reconfiguration",
+            VersionedFeature::AdaptiveAsyncInsert => {
+                "adaptive async insert timeouts"
+            }
+            VersionedFeature::KeeperHttpControl => "keeper HTTP control",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl Deployment {
+    /// Run `clickhouse --version` the way our backend runs nodes and parse
+    /// its output
+    ///
+    /// With ssh, we assume that remote hosts run the same clickhouse as the
+    /// local host. Servers upgraded with `rolling_upgrade` may run another
+    /// version.
+    pub fn clickhouse_version(&self) -> Result<ClickhouseVersion> {
+        let output = match &self.config.backend {
+            Backend::Process | Backend::Ssh { .. } => {
+                binary_version(Utf8Path::new(DEFAULT_CLICKHOUSE_BINARY))?
+            }
+            Backend::Docker { image } => self.docker_version(image)?,
+        };
+        output.parse()
+    }
+
+    /// Return the clickhouse version that configs were last generated for,
+    /// if we could determine it
+    pub fn generated_clickhouse_version(&self) -> Option<ClickhouseVersion> {
+        self.meta.as_ref()?.clickhouse_version
+    }
+
+    /// Return the clickhouse version that server `id` runs, if known
+    ///
+    /// This is the version of the binary it was upgraded to with
+    /// `rolling_upgrade`, if any.
+    pub fn server_clickhouse_version(
+        &self,
+        id: ServerId,
+    ) -> Option<ClickhouseVersion> {
+        let meta = self.meta.as_ref()?;
+        match meta.server_binaries.get(&id) {
+            Some(binary) => binary.version.parse().ok(),
+            None => meta.clickhouse_version,
+        }
+    }
+
+    /// Fail if `version` is too old for our configs or lacks a feature that
+    /// our config needs, and warn about features it lacks that only some
+    /// operations need
+    ///
+    /// Features that configs can do without are left out of them for
+    /// versions that lack them instead.
+    pub(crate) fn check_clickhouse_version(
+        &self,
+        version: ClickhouseVersion,
+    ) -> Result<()> {
+        if version < MINIMUM_CLICKHOUSE_VERSION {
+            bail!(
+                "clickhouse {version} is older than the oldest supported \
+                 version, {MINIMUM_CLICKHOUSE_VERSION}"
+            );
+        }
+        let feature = VersionedFeature::ReplicatedDatabase;
+        if self.config.replicated_database.is_some()
+            && !feature.is_supported(version)
+        {
+            bail!(
+                "{feature} need clickhouse {}, but found {version}",
+                feature.min_version()
+            );
+        }
+        let feature = VersionedFeature::KeeperHttpControl;
+        if self.config.keeper_http_control && !feature.is_supported(version) {
+            warn!(
+                %version,
+                "{feature} needs clickhouse {}, so it's left out",
+                feature.min_version()
+            );
+        }
+        let feature = VersionedFeature::KeeperClient;
+        if !feature.is_supported(version) {
+            warn!(
+                %version,
+                "{feature} needs clickhouse {}, and won't work",
+                feature.min_version()
+            );
+        }
+        Ok(())
+    }
+
+    /// Return the output of `clickhouse --version` in `image`
+    fn docker_version(&self, image: &str) -> Result<String> {
+        let output = self
+            .output(
+                Command::new("docker")
+                    .arg("run")
+                    .arg("--rm")
+                    .arg(format!("--entrypoint={DEFAULT_CLICKHOUSE_BINARY}"))
+                    .arg(image)
+                    .arg("--version")
+                    .stdin(Stdio::null()),
+            )
+            .with_context(|| format!("failed to run clickhouse in {image}"))?;
+        if !output.status.success() {
+            bail!(
+                "clickhouse --version failed in {image}: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
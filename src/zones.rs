@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Deployment, NodeId, ServerId, MISSING_META};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use tracing::info;
+
+impl Deployment {
+    /// Return the zone of a node, if it has one
+    pub fn zone(&self, node: NodeId) -> Option<&str> {
+        let meta = self.meta.as_ref()?;
+        match node {
+            NodeId::Keeper(id) => meta.keeper_zones.get(&id),
+            NodeId::Server(id) => meta.server_zones.get(&id),
+        }
+        .map(String::as_str)
+    }
+
+    /// Return the nodes of every zone, keyed by zone
+    pub fn zones(&self) -> BTreeMap<String, Vec<NodeId>> {
+        let mut zones: BTreeMap<String, Vec<NodeId>> = BTreeMap::new();
+        let Some(meta) = &self.meta else {
+            return zones;
+        };
+        for (&id, zone) in &meta.keeper_zones {
+            zones.entry(zone.clone()).or_default().push(NodeId::Keeper(id));
+        }
+        for (&id, zone) in &meta.server_zones {
+            zones.entry(zone.clone()).or_default().push(NodeId::Server(id));
+        }
+        zones
+    }
+
+    /// Return the `<remote_servers>` priority of a server from the priority
+    /// of its zone
+    pub(crate) fn zone_priority(&self, id: ServerId) -> Option<u32> {
+        let zone = self.zone(NodeId::Server(id))?;
+        self.config.zone_priorities.get(zone).copied()
+    }
+
+    /// Put a node in a zone, or take it out of its zone with `None`
+    ///
+    /// Zones are failure domains, like racks or availability zones, that
+    /// `fail_zone` stops all at once. Servers get their zone in the `zone`
+    /// macro, so server configs are regenerated.
+    pub fn set_zone(
+        &mut self,
+        node: NodeId,
+        zone: Option<String>,
+    ) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        match node {
+            NodeId::Keeper(id) => {
+                if !meta.keeper_ids.contains(&id) {
+                    bail!("No such keeper: {id}");
+                }
+                match &zone {
+                    Some(zone) => meta.keeper_zones.insert(id, zone.clone()),
+                    None => meta.keeper_zones.remove(&id),
+                };
+            }
+            NodeId::Server(id) => {
+                if !meta.server_ids.contains(&id) {
+                    bail!("No such replica: {id}");
+                }
+                match &zone {
+                    Some(zone) => meta.server_zones.insert(id, zone.clone()),
+                    None => meta.server_zones.remove(&id),
+                };
+            }
+        }
+        self.writer.save_meta(meta, &self.config.path)?;
+        if let NodeId::Server(_) = node {
+            let keeper_ids = meta.keeper_ids.clone();
+            let server_ids = meta.server_ids.clone();
+            self.generate_clickhouse_config(keeper_ids, server_ids)?;
+        }
+        self.record(
+            "set-zone",
+            serde_json::json!({ "node": node.to_string(), "zone": zone }),
+        )
+    }
+
+    /// Stop every running node in a zone at once, servers before keepers,
+    /// to simulate the failure of the zone
+    ///
+    /// Returns the nodes that were stopped.
+    pub fn fail_zone(&self, zone: &str) -> Result<Vec<NodeId>> {
+        let Some(mut nodes) = self.zones().remove(zone) else {
+            bail!("No nodes in zone {zone}");
+        };
+        nodes.sort_by_key(|node| match node {
+            NodeId::Server(_) => 0,
+            NodeId::Keeper(_) => 1,
+        });
+        let mut stopped = vec![];
+        for node in nodes {
+            if self.is_alive(node) {
+                info!(%node, zone, "stopping node");
+                match node {
+                    NodeId::Keeper(id) => self.stop_keeper(id)?,
+                    NodeId::Server(id) => self.stop_server(id)?,
+                }
+                stopped.push(node);
+            }
+        }
+        self.record(
+            "fail-zone",
+            serde_json::json!({
+                "zone": zone,
+                "stopped": stopped
+                    .iter()
+                    .map(|node| node.to_string())
+                    .collect::<Vec<_>>(),
+            }),
+        )?;
+        Ok(stopped)
+    }
+}